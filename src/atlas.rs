@@ -0,0 +1,338 @@
+use std::{collections::{BTreeMap, HashMap}, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ml::{Action, Coords, State, StateType, Tile}, tiles::TileMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TileStats {
+    pub position: Coords,
+    pub fights_won: u32,
+    pub damage_taken: u32,
+    pub chests: u32,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AtlasFloor {
+    tiles: Vec<Tile>,
+    city: Option<Coords>,
+    stairs_down: Option<Coords>,
+    #[serde(default)]
+    stairs_up: Option<Coords>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    stats: Vec<TileStats>,
+    #[serde(skip)]
+    index: HashMap<Coords, usize>,
+}
+impl AtlasFloor {
+    fn reindex(&mut self) {
+        self.index = self.tiles.iter().enumerate().map(|(i, tile)|(tile.position, i)).collect();
+    }
+
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+
+    fn stats_mut(&mut self, position:Coords) -> &mut TileStats {
+        let index = match self.stats.iter().position(|stats|stats.position == position) {
+            Some(index) => index,
+            None => {
+                self.stats.push(TileStats { position, fights_won: 0, damage_taken: 0, chests: 0 });
+                self.stats.len() - 1
+            },
+        };
+        &mut self.stats[index]
+    }
+
+    fn record(&mut self, tiles:&TileMap) -> bool {
+        let mut changed = false;
+        for tile in tiles.iter() {
+            let mut tile = *tile;
+            tile.visited = false;
+            if tile.is_city && self.city != Some(tile.position) {
+                self.city = Some(tile.position);
+                changed = true;
+            }
+            if tile.is_go_down && self.stairs_down != Some(tile.position) {
+                self.stairs_down = Some(tile.position);
+                changed = true;
+            }
+            if tile.is_go_up && self.stairs_up != Some(tile.position) {
+                self.stairs_up = Some(tile.position);
+                changed = true;
+            }
+            match self.index.get(&tile.position) {
+                Some(i) => {
+                    if self.tiles[*i] != tile {
+                        self.tiles[*i] = tile;
+                        changed = true;
+                    }
+                },
+                None => {
+                    self.index.insert(tile.position, self.tiles.len());
+                    self.tiles.push(tile);
+                    changed = true;
+                },
+            }
+        }
+        changed
+    }
+
+    fn merge(&mut self, other:&AtlasFloor) -> bool {
+        let mut changed = false;
+        for (own, theirs) in [(&mut self.city, other.city), (&mut self.stairs_down, other.stairs_down), (&mut self.stairs_up, other.stairs_up)] {
+            if own.is_none() && theirs.is_some() {
+                *own = theirs;
+                changed = true;
+            }
+        }
+        for tile in &other.tiles {
+            match self.index.get(&tile.position) {
+                Some(i) => {
+                    let merged = merge_tile(self.tiles[*i], *tile);
+                    if self.tiles[*i] != merged {
+                        self.tiles[*i] = merged;
+                        changed = true;
+                    }
+                },
+                None => {
+                    self.index.insert(tile.position, self.tiles.len());
+                    self.tiles.push(*tile);
+                    changed = true;
+                },
+            }
+        }
+        for theirs in &other.stats {
+            let own = self.stats_mut(theirs.position);
+            let merged = TileStats {
+                position: own.position,
+                fights_won: own.fights_won.max(theirs.fights_won),
+                damage_taken: own.damage_taken.max(theirs.damage_taken),
+                chests: own.chests.max(theirs.chests),
+            };
+            if *own != merged {
+                *own = merged;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// Combines two sightings of the same tile. An explored tile wins over an unexplored one,
+/// and when both were explored a wall seen by either side is kept.
+fn merge_tile(own:Tile, theirs:Tile) -> Tile {
+    if own.explored && !theirs.explored {
+        return own;
+    }
+    if theirs.explored && !own.explored {
+        return theirs;
+    }
+    Tile {
+        trap: own.trap || theirs.trap,
+        north_passable: own.north_passable && theirs.north_passable,
+        east_passable: own.east_passable && theirs.east_passable,
+        south_passable: own.south_passable && theirs.south_passable,
+        west_passable: own.west_passable && theirs.west_passable,
+        ..own
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Atlas {
+    floors: BTreeMap<String, AtlasFloor>,
+    #[serde(skip)]
+    dirty: bool,
+}
+impl Atlas {
+    pub fn load(path:&Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(data) => match Self::from_json(&data) {
+                Ok(atlas) => atlas,
+                Err(err) => {
+                    println!("Failed to parse atlas {}: {err}", path.display());
+                    Atlas::default()
+                },
+            },
+            Err(_) => Atlas::default(),
+        }
+    }
+
+    pub fn save(&mut self, path:&Path) -> std::io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string(self).unwrap())?;
+        std::fs::rename(&tmp, path)?;
+        self.mark_saved();
+        Ok(())
+    }
+
+    pub fn from_json(data:&str) -> serde_json::Result<Self> {
+        serde_json::from_str::<Atlas>(data).map(|atlas|Self::from_floors(atlas.floors))
+    }
+
+    pub fn from_floors(mut floors:BTreeMap<String, AtlasFloor>) -> Self {
+        for floor in floors.values_mut() {
+            floor.reindex();
+        }
+        Self {
+            floors,
+            dirty: false,
+        }
+    }
+
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    pub fn floors(&self) -> &BTreeMap<String, AtlasFloor> {
+        &self.floors
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.dirty = false;
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn floor(&self, floor:&str) -> Option<&AtlasFloor> {
+        self.floors.get(floor)
+    }
+
+    pub fn replace(&mut self, floor:&str, tiles:&TileMap) {
+        let mut replaced = AtlasFloor::default();
+        replaced.record(tiles);
+        self.floors.insert(floor.to_owned(), replaced);
+        self.dirty = true;
+    }
+
+    /// Adds what `other` knows to this atlas, returning the number of floors that changed.
+    pub fn merge(&mut self, other:&Atlas) -> usize {
+        let mut changed = 0;
+        for (name, floor) in &other.floors {
+            let known = self.floors.contains_key(name);
+            if self.floors.entry(name.clone()).or_default().merge(floor) || !known {
+                changed += 1;
+            }
+        }
+        if changed > 0 {
+            self.dirty = true;
+        }
+        changed
+    }
+
+    pub fn observe(&mut self, previous:&State, state:&State, action:&Action, last_action:&Action) -> bool {
+        let (StateType::Dungeon, Some(position)) = (&previous.state_type, previous.get_position()) else {
+            return false;
+        };
+        let fight_won = previous.dungeon.enemy_health().is_some()
+            && state.dungeon.enemy_health().is_none()
+            && matches!(state.state_type, StateType::Dungeon)
+            && !matches!(last_action, Action::Flee)
+            && state.dungeon.dead_characters() <= previous.dungeon.dead_characters();
+        let damage_taken = previous.dungeon.characters().iter().zip(state.dungeon.characters())
+            .filter_map(|(before, after)|Some((before.health_pct? - after.health_pct?).max(0.0)))
+            .sum::<f32>() as u32;
+        let chest = matches!(action, Action::OpenChest | Action::OpenChestMagical);
+        if !fight_won && damage_taken == 0 && !chest {
+            return false;
+        }
+        let stats = self.floors.entry(previous.dungeon.atlas_key()).or_default().stats_mut(position);
+        stats.fights_won += fight_won as u32;
+        stats.damage_taken += damage_taken;
+        stats.chests += chest as u32;
+        self.dirty = true;
+        true
+    }
+
+    pub fn tile_stats(&self) -> BTreeMap<String, Vec<TileStats>> {
+        self.floors.iter()
+        .filter(|(_, floor)|!floor.stats.is_empty())
+        .map(|(name, floor)|(name.clone(), floor.stats.clone()))
+        .collect()
+    }
+
+    pub fn record(&mut self, floor:&str, tiles:&TileMap) {
+        if tiles.is_empty() {
+            return;
+        }
+        if self.floors.entry(floor.to_owned()).or_default().record(tiles) {
+            self.dirty = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(x:u32, y:u32, explored:bool) -> Tile {
+        Tile {
+            explored,
+            trap: false,
+            is_city: false,
+            is_go_down: false,
+            is_go_up: false,
+            has_chest: false,
+            visited: false,
+            position: Coords { x, y },
+            north_passable: true,
+            east_passable: true,
+            south_passable: true,
+            west_passable: true,
+        }
+    }
+
+    fn atlas(floors:&[(&str, Vec<Tile>)]) -> Atlas {
+        let mut atlas = Atlas::default();
+        for (name, tiles) in floors {
+            atlas.record(name, &tiles.iter().copied().collect());
+        }
+        atlas.mark_saved();
+        atlas
+    }
+
+    #[test]
+    fn merged_tile_keeps_walls_from_both_sides() {
+        let own = Tile { north_passable: false, ..tile(1, 1, true) };
+        let theirs = Tile { east_passable: false, trap: true, ..tile(1, 1, true) };
+        let merged = merge_tile(own, theirs);
+        assert!(!merged.north_passable && !merged.east_passable);
+        assert!(merged.south_passable && merged.west_passable);
+        assert!(merged.trap);
+    }
+
+    #[test]
+    fn explored_tile_wins_over_unexplored() {
+        let explored = Tile { west_passable: false, ..tile(1, 1, true) };
+        let unexplored = Tile { north_passable: false, ..tile(1, 1, false) };
+        assert_eq!(merge_tile(explored, unexplored), explored);
+        assert_eq!(merge_tile(unexplored, explored), explored);
+    }
+
+    #[test]
+    fn merge_counts_changed_floors() {
+        let mut own = atlas(&[("B1", vec![tile(0, 0, true)]), ("B2", vec![tile(0, 0, true)])]);
+        let theirs = atlas(&[
+            ("B1", vec![tile(0, 0, true)]),
+            ("B2", vec![tile(0, 0, true), tile(1, 0, true)]),
+            ("B3", vec![tile(5, 5, true)]),
+        ]);
+        assert_eq!(own.merge(&theirs), 2);
+        assert!(own.is_dirty());
+        assert_eq!(own.floor("B2").unwrap().tiles().len(), 2);
+        assert_eq!(own.floor("B3").unwrap().tiles().len(), 1);
+
+        own.mark_saved();
+        assert_eq!(own.merge(&theirs), 0);
+        assert!(!own.is_dirty());
+    }
+
+    #[test]
+    fn merge_fills_missing_landmarks_only() {
+        let mut own = atlas(&[("B1", vec![Tile { is_city: true, ..tile(0, 0, true) }])]);
+        let theirs = atlas(&[("B1", vec![Tile { is_city: true, ..tile(3, 3, true) }, Tile { is_go_down: true, ..tile(4, 4, true) }])]);
+        assert_eq!(own.merge(&theirs), 1);
+        let floor = own.floor("B1").unwrap();
+        assert_eq!(floor.city, Some(Coords { x: 0, y: 0 }));
+        assert_eq!(floor.stairs_down, Some(Coords { x: 4, y: 4 }));
+    }
+}
@@ -0,0 +1,156 @@
+use std::{sync::mpsc::{self, Receiver, TryRecvError}, time::{Duration, Instant}};
+
+use crate::{Opt, atlas::Atlas};
+
+const ATTEMPTS:usize = 3;
+
+#[derive(Debug)]
+pub enum SyncError {
+    HttpError(ureq::Error),
+    JsonError(serde_json::Error),
+    Conflict,
+}
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HttpError(err) => write!(f, "request failed: {err}"),
+            Self::JsonError(err) => write!(f, "json error: {err}"),
+            Self::Conflict => write!(f, "remote atlas kept changing, gave up after {ATTEMPTS} attempts"),
+        }
+    }
+}
+impl std::error::Error for SyncError {}
+impl From<ureq::Error> for SyncError {
+    fn from(value: ureq::Error) -> Self {
+        Self::HttpError(value)
+    }
+}
+impl From<serde_json::Error> for SyncError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::JsonError(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Remote {
+    url: String,
+    authorization: Option<String>,
+}
+impl Remote {
+    fn fetch(&self) -> Result<(Atlas, Option<String>), SyncError> {
+        let mut request = ureq::get(&self.url);
+        if let Some(authorization) = &self.authorization {
+            request = request.header("Authorization", authorization);
+        }
+        let mut response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::StatusCode(404)) => return Ok((Atlas::default(), None)),
+            Err(err) => return Err(err.into()),
+        };
+        let etag = response.headers().get("ETag").and_then(|etag|etag.to_str().ok()).map(str::to_owned);
+        let atlas = Atlas::from_json(&response.body_mut().read_to_string()?)?;
+        Ok((atlas, etag))
+    }
+
+    /// Uploads `atlas` unless the remote copy changed since it was fetched with `etag`.
+    /// Returns false on such a conflict.
+    fn store(&self, atlas:&Atlas, etag:Option<&str>) -> Result<bool, SyncError> {
+        let mut request = ureq::put(&self.url).header("Content-Type", "application/json");
+        if let Some(authorization) = &self.authorization {
+            request = request.header("Authorization", authorization);
+        }
+        request = match etag {
+            Some(etag) => request.header("If-Match", etag),
+            None => request.header("If-None-Match", "*"),
+        };
+        match request.send(serde_json::to_string(atlas)?) {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::StatusCode(412)) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Merges `local` into the remote atlas and uploads the result if it added anything.
+    fn sync(&self, local:&Atlas) -> Result<Atlas, SyncError> {
+        for _ in 0..ATTEMPTS {
+            let (mut merged, etag) = self.fetch()?;
+            if merged.merge(local) == 0 || self.store(&merged, etag.as_deref())? {
+                return Ok(merged);
+            }
+        }
+        Err(SyncError::Conflict)
+    }
+}
+
+/// Keeps the atlas in step with other bot instances through a JSON document on an HTTP server.
+///
+/// Any server that returns the document on GET and accepts PUT works, such as a WebDAV
+/// share or an S3-compatible bucket. Uploads are conditional on the ETag of the fetched copy
+/// so two instances syncing at once do not overwrite each other.
+pub struct AtlasSync {
+    remote: Remote,
+    interval: Duration,
+    last: Instant,
+    pending: Option<Receiver<Result<Atlas, SyncError>>>,
+}
+impl AtlasSync {
+    pub fn new(opt:&Opt) -> Option<Self> {
+        let url = opt.atlas_sync_url.clone()?;
+        Some(Self {
+            remote: Remote {
+                url,
+                authorization: opt.atlas_sync_token.as_ref().map(|token|format!("Bearer {token}")),
+            },
+            interval: Duration::from_secs(opt.atlas_sync_secs),
+            last: Instant::now(),
+            pending: None,
+        })
+    }
+
+    /// Syncs `atlas` with the remote copy, blocking until done. Returns the number of floors that changed locally.
+    pub fn sync(&mut self, atlas:&mut Atlas) -> Result<usize, SyncError> {
+        self.last = Instant::now();
+        let remote = self.remote.sync(atlas)?;
+        Ok(atlas.merge(&remote))
+    }
+
+    /// Applies the result of a finished background sync and starts the next one when it is due.
+    /// Returns whether `atlas` changed.
+    pub fn poll(&mut self, atlas:&mut Atlas) -> bool {
+        if let Some(pending) = &self.pending {
+            let result = match pending.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => {
+                    self.pending = None;
+                    return false;
+                },
+            };
+            self.pending = None;
+            return match result {
+                Ok(remote) => {
+                    let changed = atlas.merge(&remote);
+                    if changed > 0 {
+                        println!("Atlas sync updated {changed} floors");
+                    }
+                    changed > 0
+                },
+                Err(err) => {
+                    println!("Atlas sync failed: {err}");
+                    false
+                },
+            };
+        }
+        if self.last.elapsed() >= self.interval {
+            self.last = Instant::now();
+            let (sender, receiver) = mpsc::channel();
+            let remote = self.remote.clone();
+            let local = atlas.clone();
+            std::thread::spawn(move||{
+                let _ = sender.send(remote.sync(&local));
+            });
+            self.pending = Some(receiver);
+        }
+        false
+    }
+}
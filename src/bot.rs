@@ -0,0 +1,360 @@
+use std::{sync::Arc, time::Instant};
+
+use tokio::sync::watch;
+
+use crate::{ActionLog, LogEntry, Opt, Services, atlas::Atlas, control::Control, frames::LatestFrame, game::{Endor, GameAdapter}, metrics::Metrics, ml::{self, Action, State, StateType}, pipeline::{CapturedFrame, Executor}, screencap::ScreencapError, strategy::Strategy, timing::{self, Phase}, trace::DecisionTrace};
+
+#[derive(Debug)]
+pub enum TickError {
+    DeviceDisconnected(ScreencapError),
+    ScreenOff,
+    UnknownState,
+}
+impl std::fmt::Display for TickError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DeviceDisconnected(err) => write!(f, "screen capture failed: {err}"),
+            Self::ScreenOff => write!(f, "screen is off"),
+            Self::UnknownState => write!(f, "unknown state"),
+        }
+    }
+}
+impl std::error::Error for TickError {}
+
+/// The outcome of one [`Bot::tick`].
+#[derive(Debug, Clone)]
+pub struct Decision {
+    /// The state the tick started from.
+    pub previous: State,
+    /// The detected state, with the effect of `action` already applied.
+    pub state: State,
+    /// The action to execute next.
+    pub action: Action,
+    /// Fingerprint of the frame, used to skip detection when the screen has not changed.
+    pub fingerprint: u64,
+    /// The action log entry, recorded when the decision is committed.
+    pub entry: LogEntry,
+}
+
+/// Plays the game one captured frame at a time.
+///
+/// [`tick`](Bot::tick) detects the state on a frame and decides what to do without touching
+/// the device, [`execute`](Bot::execute) sends an action to the device and
+/// [`commit`](Bot::commit) makes a decision the current state. Keeping these apart lets a
+/// caller veto or delay actions, which is how the command line applies its rate limits and
+/// stop conditions.
+pub struct Bot {
+    opt: Opt,
+    services: Services,
+    adapter: Arc<dyn GameAdapter>,
+    strategy: Box<dyn Strategy + Send + Sync>,
+    state: watch::Sender<Arc<State>>,
+    atlas: Atlas,
+    frames: Arc<LatestFrame>,
+    log: Arc<ActionLog>,
+    metrics: Arc<Metrics>,
+    control: Arc<Control>,
+    executor: Executor,
+    last_action: Action,
+    last_fingerprint: Option<u64>,
+}
+impl Bot {
+    /// Creates a bot that continues from `state`, deciding with the strategy in `opt.policy`.
+    ///
+    /// Decisions and their traces are recorded in `log`. Actions are executed on a
+    /// background thread against `opt.device`, through the adb log and buttons in `services`.
+    pub fn new(opt:Opt, services:Services, state:State, atlas:Atlas, log:Arc<ActionLog>) -> Self {
+        Self::with_adapter(opt, services, state, atlas, log, Arc::new(Endor))
+    }
+
+    /// Like [`new`](Bot::new), but detects and acts through `adapter` instead of the built in game.
+    pub fn with_adapter(opt:Opt, services:Services, state:State, atlas:Atlas, log:Arc<ActionLog>, adapter:Arc<dyn GameAdapter>) -> Self {
+        let frames = Arc::new(LatestFrame::default());
+        Self {
+            strategy: opt.policy.strategy.build(&opt.policy),
+            executor: Executor::spawn(opt.device.clone(), opt.clone(), services.clone(), adapter.clone(), frames.clone()),
+            adapter,
+            opt,
+            services,
+            state: watch::Sender::new(Arc::new(state)),
+            atlas,
+            frames,
+            log,
+            metrics: Arc::new(Metrics::default()),
+            control: Arc::new(Control::default()),
+            last_action: Action::CloseAd,
+            last_fingerprint: None,
+        }
+    }
+
+    pub fn adapter(&self) -> Arc<dyn GameAdapter> {
+        self.adapter.clone()
+    }
+
+    /// The current state.
+    pub fn state(&self) -> Arc<State> {
+        self.state.borrow().clone()
+    }
+
+    /// Receives a new snapshot of the state after every commit and map edit.
+    /// Readers never block the bot.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<State>> {
+        self.state.subscribe()
+    }
+
+    /// Applies map edits queued on [`Control`] by the web interface and publishes the result.
+    ///
+    /// Returns the state from before the edits when there were any, so the change can be
+    /// pushed to websocket clients.
+    pub fn apply_tile_commands(&mut self) -> Option<Arc<State>> {
+        let commands = self.control.take_tile_commands();
+        if commands.is_empty() {
+            return None;
+        }
+        let mut state = (*self.state()).clone();
+        for command in &commands {
+            command.apply(&mut state.dungeon);
+        }
+        Some(self.state.send_replace(Arc::new(state)))
+    }
+
+    /// The last committed action.
+    pub fn last_action(&self) -> Action {
+        self.last_action
+    }
+
+    /// Forgets the last action and frame so the next tick starts fresh.
+    pub fn reset(&mut self) {
+        self.last_action = Action::CloseAd;
+        self.last_fingerprint = None;
+    }
+
+    pub fn atlas(&self) -> &Atlas {
+        &self.atlas
+    }
+
+    pub fn atlas_mut(&mut self) -> &mut Atlas {
+        &mut self.atlas
+    }
+
+    pub fn control(&self) -> Arc<Control> {
+        self.control.clone()
+    }
+
+    pub fn frames(&self) -> Arc<LatestFrame> {
+        self.frames.clone()
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn log(&self) -> Arc<ActionLog> {
+        self.log.clone()
+    }
+
+    /// Detects the state on `frame` and decides the next action.
+    ///
+    /// Nothing is executed, logged or counted and the current state is left alone; pass the
+    /// action to [`execute`](Bot::execute) and the decision to [`commit`](Bot::commit). When the frame
+    /// is identical to the last one detection is skipped and the last action repeated without
+    /// applying its effect to the state a second time. A manual action queued on [`Control`]
+    /// replaces the decided one.
+    pub fn tick(&mut self, frame:CapturedFrame) -> Result<Decision, TickError> {
+        self.metrics.tick();
+        let previous = (*self.state()).clone();
+        let old_state = previous.clone();
+        let (opt, last_action) = (&self.opt, self.last_action);
+        let img = frame.image.map_err(TickError::DeviceDisconnected)?;
+        //img.save_with_format("cap.png", image::ImageFormat::Png).unwrap();
+        if img.is_black() {
+            return Err(TickError::ScreenOff);
+        }
+        let fingerprint = img.fingerprint();
+        let (mut state, action, repeated) = if self.last_fingerprint == Some(fingerprint) {
+            self.metrics.duplicate_frame();
+            let mut state = old_state;
+            state.fight.observe(state.dungeon.enemy_health());
+            ml::observe_movement(&mut state, &last_action, true, &opt.policy);
+            if state.fight.escalation(opt.policy.fight_watchdog_ticks).is_some() || state.moves.recovery(opt.policy.stuck_ticks).is_some() {
+                let mut trace = DecisionTrace::new(&state);
+                let action = self.strategy.decide(&state, last_action, state.get_position(), &mut trace);
+                trace.finish(&action);
+                self.log.record_trace(trace);
+                println!("Frame unchanged, {action}");
+                (state, action, false)
+            }
+            else {
+                println!("Frame unchanged, repeating {last_action}");
+                (state, last_action, true)
+            }
+        }
+        else {
+            let old_position = old_state.get_position();
+            let old_dead = old_state.dungeon.dead_characters();
+            let started = Instant::now();
+            let result = self.adapter.detect(old_state, &img, &mut self.atlas);
+            let result = match &self.services.classifier {
+                Some(classifier) => {
+                    let (result, disagreed) = classifier.reconcile(img.image(), result, &previous, opt.classifier_mode, opt.classifier_min_confidence);
+                    if disagreed {
+                        self.metrics.classifier_disagreement();
+                    }
+                    result
+                },
+                None => result,
+            };
+            self.metrics.detection(started.elapsed());
+            timing::observe(Phase::Detection, started.elapsed());
+            self.frames.publish(img.into_image());
+            let mut state = match result {
+                Ok(state) => state,
+                Err(err) => {
+                    println!("{err:?}");
+                    self.metrics.unknown_state();
+                    return Err(TickError::UnknownState);
+                },
+            };
+            self.metrics.frame(state.state_type.name());
+            if let StateType::Dungeon = state.state_type {
+                self.metrics.deaths(state.dungeon.dead_characters().saturating_sub(old_dead) as u64);
+            }
+            ml::observe_movement(&mut state, &last_action, false, &opt.policy);
+            //println!("{:?}", state);
+            let mut trace = DecisionTrace::new(&state);
+            let action = self.strategy.decide(&state, last_action, old_position, &mut trace);
+            trace.finish(&action);
+            self.log.record_trace(trace);
+            if let Some(pos) = state.get_position() {
+                println!("position = {:?}", pos);
+            }
+            else {
+                println!("position = none");
+            }
+            println!("{action}");
+            (state, action, false)
+        };
+        let (action, repeated) = match self.control.take_action().map(|manual|(manual, manual.resolve(&state))) {
+            Some((_, Some(manual))) => {
+                println!("Manual override: {manual} instead of {action}");
+                (manual, false)
+            },
+            Some((manual, None)) => {
+                println!("Ignoring manual {manual:?}, position unknown");
+                (action, repeated)
+            },
+            None => (action, repeated),
+        };
+        let entry = self.log.entry(&state, &action);
+        //println!("{:?}", action);
+        // A repeated action is only sent again, its effect is already in the state.
+        if !opt.no_action && !repeated
+            && let Some(new_position) = self.adapter.apply(&mut state, &action) {
+            state.set_position(new_position);
+        }
+        Ok(Decision { previous, state, action, fingerprint, entry })
+    }
+
+    /// Sends `action` to the device, after the previous action has finished.
+    /// Does nothing with `--no-action`.
+    pub fn execute(&mut self, action:Action) {
+        if !self.opt.no_action {
+            self.executor.submit(action);
+        }
+    }
+
+    /// Waits for the last executed action to finish and returns when it did.
+    pub fn wait(&mut self) -> Instant {
+        self.executor.wait()
+    }
+
+    /// Makes `decision` the current state, publishes it to subscribers and returns it.
+    ///
+    /// The action is recorded in the log and metrics here, so vetoed decisions are not. Map
+    /// edits made through the web interface while the tick ran are applied first.
+    pub fn commit(&mut self, decision:Decision) -> Arc<State> {
+        let Decision { mut state, action, fingerprint, entry, .. } = decision;
+        self.log.push(entry);
+        if self.last_fingerprint != Some(fingerprint) {
+            match action {
+                action if action.is_attack() && !self.last_action.is_attack() => self.metrics.fight_started(),
+                Action::OpenChest | Action::OpenChestMagical => self.metrics.chest_opened(),
+                _ => {},
+            }
+        }
+        self.last_action = action;
+        self.last_fingerprint = if let Action::Redetect = action {
+            None
+        }
+        else {
+            Some(fingerprint)
+        };
+        for command in self.control.take_tile_commands() {
+            command.apply(&mut state.dungeon);
+        }
+        let snapshot = Arc::new(state);
+        self.state.send_replace(snapshot.clone());
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use image::DynamicImage;
+
+    use super::*;
+    use crate::{control::ManualAction, game::{ENDOR_LAYOUT, Layout}, ml::{BitmapWebp, Coords, StateError}};
+
+    /// Keeps whatever state it is given, so only the bot changes it.
+    struct Unchanged;
+    impl GameAdapter for Unchanged {
+        fn name(&self) -> &'static str {
+            "unchanged"
+        }
+
+        fn layout(&self) -> &Layout {
+            &ENDOR_LAYOUT
+        }
+
+        fn detect(&self, old_state:State, _image:&BitmapWebp, _atlas:&mut Atlas) -> Result<State, StateError> {
+            Ok(old_state)
+        }
+
+        fn apply(&self, state:&mut State, action:&Action) -> Option<Coords> {
+            ml::apply_action(state, action)
+        }
+
+        fn execute(&self, _device:&str, _opt:&Opt, _services:&Services, _action:&Action, _frame:Option<&DynamicImage>) {}
+
+        fn in_foreground(&self, _device:&str, _opt:&Opt, _services:&Services) -> Option<bool> {
+            Some(true)
+        }
+    }
+
+    fn frame(opt:&Opt) -> CapturedFrame {
+        let image = image::open("caps/dungeon.png").unwrap();
+        let divisor = (ENDOR_LAYOUT.screen.0 / image.width().max(1)).max(1);
+        CapturedFrame { started: Instant::now(), image: Ok(ml::BitmapWebp::from_image(image, divisor, opt, &Services::default())) }
+    }
+
+    #[test]
+    fn repeated_action_is_applied_once() {
+        let opt = Opt::parse_from(["endorbot"]);
+        let mut bot = Bot::with_adapter(opt.clone(), Services::default(), State::default(), Atlas::default(), Arc::new(ActionLog::new(10, Default::default())), Arc::new(Unchanged));
+        assert_eq!(bot.state().dungeon.floor_name(), "D1");
+
+        bot.control().queue_action(ManualAction::Action(Action::GoDown));
+        let decision = bot.tick(frame(&opt)).unwrap();
+        assert!(matches!(decision.action, Action::GoDown));
+        bot.commit(decision);
+        assert_eq!(bot.state().dungeon.floor_name(), "D2");
+
+        for _ in 0..2 {
+            let decision = bot.tick(frame(&opt)).unwrap();
+            assert!(matches!(decision.action, Action::GoDown));
+            bot.commit(decision);
+            assert_eq!(bot.state().dungeon.floor_name(), "D2");
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::path::PathBuf;
+
+use clap::Subcommand;
+
+use crate::{Opt, Services, buttons::Button, glyphcmd::parse_region, glyphs::Region};
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum ButtonCommand {
+    Capture {
+        image: PathBuf,
+        #[clap(long, value_enum)]
+        button: Button,
+        #[clap(long, value_parser = parse_region)]
+        region: Option<Region>,
+    },
+    Locate {
+        image: PathBuf,
+        #[clap(long, value_enum)]
+        button: Button,
+    },
+}
+
+#[derive(Debug)]
+pub enum ButtonCommandError {
+    ImageError(PathBuf, image::ImageError),
+    IoError(PathBuf, std::io::Error),
+    OutOfBounds(Region, u32, u32),
+    NoRegion(Button),
+    NoTemplate(Button),
+}
+impl std::fmt::Display for ButtonCommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ImageError(path, err) => write!(f, "image error on {}: {err}", path.display()),
+            Self::IoError(path, err) => write!(f, "failed to write {}: {err}", path.display()),
+            Self::OutOfBounds(region, width, height) => write!(f, "region {region:?} is outside the {width}x{height} image"),
+            Self::NoRegion(button) => write!(f, "no reference region for {button}, pass --region"),
+            Self::NoTemplate(button) => write!(f, "no template for {button}, capture one first"),
+        }
+    }
+}
+impl std::error::Error for ButtonCommandError {}
+
+pub fn run(opt:&Opt, services:&Services, command:&ButtonCommand) -> Result<String, ButtonCommandError> {
+    match command {
+        ButtonCommand::Capture { image, button, region } => {
+            let region = region.or(button.reference().map(|(_, region)|region)).ok_or(ButtonCommandError::NoRegion(*button))?;
+            let loaded = image::open(image).map_err(|err|ButtonCommandError::ImageError(image.clone(), err))?;
+            if region.x + region.width > loaded.width() || region.y + region.height > loaded.height() {
+                return Err(ButtonCommandError::OutOfBounds(region, loaded.width(), loaded.height()));
+            }
+            std::fs::create_dir_all(&opt.button_dir).map_err(|err|ButtonCommandError::IoError(opt.button_dir.clone(), err))?;
+            let out = opt.button_dir.join(format!("{}.png", button.name()));
+            loaded.crop_imm(region.x, region.y, region.width, region.height)
+            .save(&out)
+            .map_err(|err|ButtonCommandError::ImageError(out.clone(), err))?;
+            Ok(format!("Saved {button} template to {}", out.display()))
+        },
+        ButtonCommand::Locate { image, button } => {
+            let loaded = image::open(image).map_err(|err|ButtonCommandError::ImageError(image.clone(), err))?;
+            let ((x, y), score) = services.buttons.locate(*button, &loaded).ok_or(ButtonCommandError::NoTemplate(*button))?;
+            let (tap_x, tap_y) = services.buttons.position(*button, Some(&loaded));
+            Ok(format!("{button} best match at {x},{y} with score {score:.2}, tapping {tap_x},{tap_y}"))
+        },
+    }
+}
@@ -0,0 +1,144 @@
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use image::{DynamicImage, GrayImage, imageops::{self, FilterType}};
+
+use crate::{game::ENDOR_LAYOUT, glyphs::Region, template};
+
+const MIN_SCORE:f32 = 0.85;
+const REACH:(u32, u32) = (160, 240);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Button {
+    Fight,
+    Chest,
+    Stairs,
+    Confirm,
+}
+impl Button {
+    pub const ALL:[Button; 4] = [Button::Fight, Button::Chest, Button::Stairs, Button::Confirm];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Button::Fight => "fight",
+            Button::Chest => "chest",
+            Button::Stairs => "stairs",
+            Button::Confirm => "confirm",
+        }
+    }
+
+    /// Where the button is tapped when it cannot be located on the frame.
+    pub fn fallback(&self) -> (u32, u32) {
+        match self {
+            Button::Fight => (711, 1308),
+            Button::Chest => (798, 1312),
+            Button::Stairs => (715, 1316),
+            Button::Confirm => (680, 1440),
+        }
+    }
+
+    /// The size of the button around the position it is tapped at.
+    pub fn size(&self) -> (u32, u32) {
+        match self {
+            Button::Fight => (56, 50),
+            Button::Chest => (44, 50),
+            Button::Stairs => (52, 48),
+            Button::Confirm => (280, 96),
+        }
+    }
+
+    /// The icon on the button in the reference screenshot named by the first element.
+    pub fn reference(&self) -> Option<(&'static str, Region)> {
+        match self {
+            Button::Fight => Some(("fight.png", Region { x: 618, y: 1282, width: 56, height: 50 })),
+            Button::Chest => Some(("chest.png", Region { x: 678, y: 1282, width: 44, height: 50 })),
+            Button::Stairs => Some(("down.png", Region { x: 598, y: 1284, width: 52, height: 48 })),
+            Button::Confirm => None,
+        }
+    }
+}
+impl std::fmt::Display for Button {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug)]
+pub enum ButtonError {
+    IoError(PathBuf, std::io::Error),
+    ImageError(PathBuf, image::ImageError),
+}
+impl std::fmt::Display for ButtonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(path, err) => write!(f, "failed to read {}: {err}", path.display()),
+            Self::ImageError(path, err) => write!(f, "failed to load {}: {err}", path.display()),
+        }
+    }
+}
+impl std::error::Error for ButtonError {}
+
+/// Templates of buttons whose position moves with the layout, one `<button>.png` per button.
+#[derive(Debug, Default, Clone)]
+pub struct ButtonBank {
+    templates: Vec<(Button, GrayImage)>,
+}
+impl ButtonBank {
+    pub fn load(dir:&Path) -> Result<Self, ButtonError> {
+        if let Err(err) = std::fs::metadata(dir) {
+            return match err.kind() {
+                std::io::ErrorKind::NotFound => Ok(Self::default()),
+                _ => Err(ButtonError::IoError(dir.to_owned(), err)),
+            };
+        }
+        let mut templates = Vec::new();
+        for button in Button::ALL {
+            let path = dir.join(format!("{}.png", button.name()));
+            if path.exists() {
+                let image = image::open(&path).map_err(|err|ButtonError::ImageError(path.clone(), err))?;
+                templates.push((button, image.to_luma8()));
+            }
+        }
+        Ok(Self { templates })
+    }
+
+    pub fn len(&self) -> usize {
+        self.templates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    /// Finds the center of `button` on `frame`, searching around its usual position.
+    ///
+    /// Frames captured at a fraction of the screen resolution are searched with a template
+    /// shrunk to match, and the result is given in screen coordinates.
+    pub fn locate(&self, button:Button, frame:&DynamicImage) -> Option<((u32, u32), f32)> {
+        let (_, template) = self.templates.iter().find(|(candidate, _)|*candidate == button)?;
+        let divisor = (ENDOR_LAYOUT.screen.0 / frame.width().max(1)).max(1);
+        let template = if divisor > 1 {
+            imageops::resize(template, (template.width() / divisor).max(1), (template.height() / divisor).max(1), FilterType::Triangle)
+        }
+        else {
+            template.clone()
+        };
+        let (x, y) = (button.fallback().0 / divisor, button.fallback().1 / divisor);
+        let reach = (REACH.0 / divisor + template.width() / 2, REACH.1 / divisor + template.height() / 2);
+        let (left, top) = (x.saturating_sub(reach.0), y.saturating_sub(reach.1));
+        let right = (x + reach.0).min(frame.width());
+        let bottom = (y + reach.1).min(frame.height());
+        let window = frame.crop_imm(left, top, right.saturating_sub(left), bottom.saturating_sub(top)).to_luma8();
+        let found = template::find(&window, &template)?;
+        let center = (left + found.position.0 + template.width() / 2, top + found.position.1 + template.height() / 2);
+        Some(((center.0 * divisor, center.1 * divisor), found.score))
+    }
+
+    /// Where to tap `button`: its center on `frame` when it is found there, otherwise the static coordinate.
+    pub fn position(&self, button:Button, frame:Option<&DynamicImage>) -> (u32, u32) {
+        match frame.and_then(|frame|self.locate(button, frame)) {
+            Some((center, score)) if score >= MIN_SCORE => center,
+            _ => button.fallback(),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::{collections::BTreeMap, io::Write, path::{Path, PathBuf}};
+
+use image::{DynamicImage, GrayImage, imageops::{self, FilterType}};
+use serde::Serialize;
+
+use crate::{Opt, Services, game::{ENDOR_LAYOUT, Layout}, glyphs::Region, screencap::{self, ScreencapError}, template};
+
+const MIN_SCORE:f32 = 0.9;
+const SEARCH_FRACTION:f32 = 0.15;
+const PARTY_SLOT_Y:u32 = 560;
+const PARTY_SLOT_SPACING:u32 = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Screen {
+    Main,
+    City,
+    DungeonIdle,
+    Fight,
+}
+impl Screen {
+    const ALL:[Screen; 4] = [Screen::Main, Screen::City, Screen::DungeonIdle, Screen::Fight];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Screen::Main => "main",
+            Screen::City => "city",
+            Screen::DungeonIdle => "dungeon",
+            Screen::Fight => "fight",
+        }
+    }
+
+    fn prompt(&self) -> &'static str {
+        match self {
+            Screen::Main => "Open the main menu",
+            Screen::City => "Enter the city",
+            Screen::DungeonIdle => "Stand idle in the dungeon, outside a fight",
+            Screen::Fight => "Start a fight in the dungeon",
+        }
+    }
+}
+
+struct Anchor {
+    name: &'static str,
+    screen: Screen,
+    region: Region,
+}
+
+/// Parts of the reference screens that look the same on every account, in reference coordinates.
+const ANCHORS:[Anchor; 9] = [
+    Anchor { name: "main_city_button", screen: Screen::Main, region: Region { x: 450, y: 1236, width: 180, height: 48 } },
+    Anchor { name: "main_cart", screen: Screen::Main, region: Region { x: 930, y: 250, width: 112, height: 110 } },
+    Anchor { name: "city_cart", screen: Screen::City, region: Region { x: 930, y: 115, width: 112, height: 112 } },
+    Anchor { name: "city_dungeon", screen: Screen::City, region: Region { x: 770, y: 1940, width: 220, height: 160 } },
+    Anchor { name: "dungeon_compass", screen: Screen::DungeonIdle, region: Region { x: 574, y: 520, width: 44, height: 42 } },
+    Anchor { name: "party_health", screen: Screen::DungeonIdle, region: Region { x: 132, y: 456, width: 108, height: 168 } },
+    Anchor { name: "dungeon_dpad", screen: Screen::DungeonIdle, region: Region { x: 720, y: 2040, width: 84, height: 90 } },
+    Anchor { name: "fight_button", screen: Screen::Fight, region: Region { x: 600, y: 1272, width: 240, height: 70 } },
+    Anchor { name: "fight_flee", screen: Screen::Fight, region: Region { x: 586, y: 1414, width: 48, height: 56 } },
+];
+
+#[derive(Debug)]
+pub enum CalibrateError {
+    IoError(PathBuf, std::io::Error),
+    ImageError(PathBuf, image::ImageError),
+    ScreencapError(ScreencapError),
+    NoAnchors,
+}
+impl std::fmt::Display for CalibrateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(path, err) => write!(f, "io error on {}: {err}", path.display()),
+            Self::ImageError(path, err) => write!(f, "image error on {}: {err}", path.display()),
+            Self::ScreencapError(err) => write!(f, "screen capture failed: {err}"),
+            Self::NoAnchors => write!(f, "no anchors were found, is the game showing the requested screens?"),
+        }
+    }
+}
+impl std::error::Error for CalibrateError {}
+impl From<ScreencapError> for CalibrateError {
+    fn from(value: ScreencapError) -> Self {
+        Self::ScreencapError(value)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AnchorMatch {
+    pub screen: &'static str,
+    pub expected: (u32, u32),
+    pub found: (u32, u32),
+    pub score: f32,
+}
+impl AnchorMatch {
+    fn offset(&self) -> (i32, i32) {
+        (self.found.0 as i32 - self.expected.0 as i32, self.found.1 as i32 - self.expected.1 as i32)
+    }
+}
+
+/// Where the game draws things on a particular device, relative to the reference layout.
+#[derive(Debug, Clone, Serialize)]
+pub struct LayoutProfile {
+    pub device: String,
+    pub scale: f32,
+    pub offset: (i32, i32),
+    pub layout: Layout,
+    pub party_slot_y: u32,
+    pub party_slot_spacing: u32,
+    pub anchors: BTreeMap<&'static str, AnchorMatch>,
+}
+
+/// Finds `region` of `reference` in `capture`, searching around where it would be after scaling by `scale`.
+fn locate(screen:Screen, reference:&GrayImage, capture:&GrayImage, region:Region, scale:f32) -> Option<AnchorMatch> {
+    let template = imageops::crop_imm(reference, region.x, region.y, region.width, region.height).to_image();
+    let width = ((region.width as f32 * scale).round() as u32).max(1);
+    let height = ((region.height as f32 * scale).round() as u32).max(1);
+    let template = imageops::resize(&template, width, height, FilterType::Triangle);
+    let expected = ((region.x as f32 * scale).round() as u32, (region.y as f32 * scale).round() as u32);
+    let reach = ((capture.width() as f32 * SEARCH_FRACTION) as u32, (capture.height() as f32 * SEARCH_FRACTION) as u32);
+    let (left, top) = (expected.0.saturating_sub(reach.0), expected.1.saturating_sub(reach.1));
+    let right = (expected.0 + reach.0 + width).min(capture.width());
+    let bottom = (expected.1 + reach.1 + height).min(capture.height());
+    let window = imageops::crop_imm(capture, left, top, right.saturating_sub(left), bottom.saturating_sub(top)).to_image();
+    let found = template::find(&window, &template)?;
+    Some(AnchorMatch {
+        screen: screen.name(),
+        expected,
+        found: (left + found.position.0, top + found.position.1),
+        score: found.score,
+    })
+}
+
+fn load(path:&Path) -> Result<DynamicImage, CalibrateError> {
+    image::open(path).map_err(|err|CalibrateError::ImageError(path.to_owned(), err))
+}
+
+fn confirm(screen:Screen) -> Result<bool, CalibrateError> {
+    print!("{} and press enter (s skips): ", screen.prompt());
+    std::io::stdout().flush().ok();
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).map_err(|err|CalibrateError::IoError(PathBuf::from("stdin"), err))?;
+    Ok(line.trim() != "s")
+}
+
+fn median(mut values:Vec<i32>) -> i32 {
+    values.sort();
+    values.get(values.len() / 2).copied().unwrap_or_default()
+}
+
+/// Captures each reference screen, or reads it from `images`, locates the anchors on it and writes a profile to `out`.
+pub fn run(opt:&Opt, services:&Services, out:&Path, reference_dir:&Path, save_dir:&Path, images:Option<&Path>) -> Result<String, CalibrateError> {
+    std::fs::create_dir_all(save_dir).map_err(|err|CalibrateError::IoError(save_dir.to_owned(), err))?;
+    let mut screen_size = None;
+    let mut anchors = BTreeMap::new();
+    for screen in Screen::ALL {
+        let capture = match images {
+            Some(images) => {
+                let path = images.join(format!("{}.png", screen.name()));
+                if !path.exists() {
+                    println!("Skipping {}, {} does not exist", screen.name(), path.display());
+                    continue;
+                }
+                load(&path)?
+            },
+            None => {
+                if !confirm(screen)? {
+                    continue;
+                }
+                let capture = screencap::screencap(&opt.device, opt, services)?;
+                let path = save_dir.join(format!("{}.png", screen.name()));
+                capture.save(&path).map_err(|err|CalibrateError::ImageError(path.clone(), err))?;
+                println!("Saved {}", path.display());
+                capture
+            },
+        };
+        let reference = load(&reference_dir.join(format!("{}.png", screen.name())))?.to_luma8();
+        let capture = capture.to_luma8();
+        screen_size = Some(capture.dimensions());
+        let scale = capture.width() as f32 / reference.width() as f32;
+        for anchor in ANCHORS.iter().filter(|anchor|anchor.screen == screen) {
+            match locate(screen, &reference, &capture, anchor.region, scale) {
+                Some(found) if found.score >= MIN_SCORE => {
+                    println!("  {}: found at {:?}, expected {:?}, score {:.2}", anchor.name, found.found, found.expected, found.score);
+                    anchors.insert(anchor.name, found);
+                },
+                Some(found) => println!("  {}: not found, best score {:.2} at {:?}", anchor.name, found.score, found.found),
+                None => println!("  {}: not found", anchor.name),
+            }
+        }
+    }
+    let Some(screen) = screen_size.filter(|_|!anchors.is_empty()) else {
+        return Err(CalibrateError::NoAnchors);
+    };
+    let scale = screen.0 as f32 / ENDOR_LAYOUT.screen.0 as f32;
+    let offset = (
+        median(anchors.values().map(|anchor|anchor.offset().0).collect()),
+        median(anchors.values().map(|anchor|anchor.offset().1).collect()),
+    );
+    let offset_of = |name:&str|anchors.get(name).map(AnchorMatch::offset).unwrap_or(offset);
+    let place = |(x, y):(u32, u32), (dx, dy):(i32, i32)|(
+        ((x as f32 * scale).round() as i32 + dx).max(0) as u32,
+        ((y as f32 * scale).round() as i32 + dy).max(0) as u32,
+    );
+    let scaled = |value:u32|(value as f32 * scale).round() as u32;
+    let profile = LayoutProfile {
+        device: opt.device.clone(),
+        scale,
+        offset,
+        layout: Layout {
+            screen,
+            map_origin: place(ENDOR_LAYOUT.map_origin, offset_of("dungeon_compass")),
+            map_tile: (scaled(ENDOR_LAYOUT.map_tile.0), scaled(ENDOR_LAYOUT.map_tile.1)),
+            map_tiles: ENDOR_LAYOUT.map_tiles,
+        },
+        party_slot_y: place((0, PARTY_SLOT_Y), offset_of("party_health")).1,
+        party_slot_spacing: scaled(PARTY_SLOT_SPACING),
+        anchors,
+    };
+    std::fs::write(out, serde_json::to_string_pretty(&profile).unwrap()).map_err(|err|CalibrateError::IoError(out.to_owned(), err))?;
+    Ok(format!(
+        "Found {} of {} anchors, wrote {}\nSuggested config:\n[party]\nslot_y = {}\nslot_spacing = {}",
+        profile.anchors.len(), ANCHORS.len(), out.display(), profile.party_slot_y, profile.party_slot_spacing,
+    ))
+}
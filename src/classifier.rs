@@ -0,0 +1,137 @@
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use image::{DynamicImage, imageops::FilterType};
+use rten::{Dimension, Model};
+use rten_tensor::{NdTensor, prelude::*};
+use serde::Deserialize;
+
+use crate::ml::{State, StateError, StateType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClassifierMode {
+    /// Keep the state from the pixel rules and report frames where the classifier disagrees.
+    Check,
+    /// Let the classifier decide which screen is shown, the pixel rules only read its details.
+    Replace,
+}
+
+#[derive(Debug)]
+pub enum ClassifierError {
+    IoError(PathBuf, std::io::Error),
+    LoadError(PathBuf, rten::LoadError),
+    RunError(rten::RunError),
+    UnsupportedInput(PathBuf),
+    NoLabels(PathBuf),
+    OutputMismatch(usize, usize),
+}
+impl std::fmt::Display for ClassifierError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(path, err) => write!(f, "failed to read {}: {err}", path.display()),
+            Self::LoadError(path, err) => write!(f, "failed to load {}: {err}", path.display()),
+            Self::RunError(err) => write!(f, "classifier failed: {err}"),
+            Self::UnsupportedInput(path) => write!(f, "{} must take a single 1x3xHxW image of fixed size", path.display()),
+            Self::NoLabels(path) => write!(f, "{} lists no labels", path.display()),
+            Self::OutputMismatch(outputs, labels) => write!(f, "model gives {outputs} scores but there are {labels} labels"),
+        }
+    }
+}
+impl std::error::Error for ClassifierError {}
+impl From<rten::RunError> for ClassifierError {
+    fn from(value: rten::RunError) -> Self {
+        Self::RunError(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Prediction<'a> {
+    pub label: &'a str,
+    pub confidence: f32,
+}
+
+/// A small CNN, trained offline, that tells which screen a frame shows.
+///
+/// The model takes the frame scaled to its fixed input size as a 1x3xHxW tensor with
+/// channels in 0..1 and returns one logit per label. Labels are [`StateType::name`]s, one
+/// per line in a `.labels` file next to the model, in output order.
+pub struct ScreenClassifier {
+    model: Model,
+    labels: Vec<String>,
+    size: (u32, u32),
+}
+impl ScreenClassifier {
+    pub fn load(path:&Path) -> Result<Self, ClassifierError> {
+        let model = Model::load_file(path).map_err(|err|ClassifierError::LoadError(path.to_owned(), err))?;
+        let shape = model.input_ids().first()
+        .and_then(|input|model.node_info(*input))
+        .and_then(|info|info.shape())
+        .unwrap_or_default();
+        let size = match shape.as_slice() {
+            [_, Dimension::Fixed(3), Dimension::Fixed(height), Dimension::Fixed(width)] => (*width as u32, *height as u32),
+            _ => return Err(ClassifierError::UnsupportedInput(path.to_owned())),
+        };
+        let labels_path = path.with_extension("labels");
+        let labels = std::fs::read_to_string(&labels_path)
+        .map_err(|err|ClassifierError::IoError(labels_path.clone(), err))?
+        .lines()
+        .map(str::trim)
+        .filter(|line|!line.is_empty())
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+        if labels.is_empty() {
+            return Err(ClassifierError::NoLabels(labels_path));
+        }
+        Ok(Self { model, labels, size })
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// The most likely label for `image` and its softmax probability.
+    pub fn predict(&self, image:&DynamicImage) -> Result<Prediction<'_>, ClassifierError> {
+        let (width, height) = self.size;
+        let small = image.resize_exact(width, height, FilterType::Triangle).to_rgb8();
+        let input = NdTensor::from_fn([1, 3, height as usize, width as usize], |[_, channel, y, x]|{
+            small.get_pixel(x as u32, y as u32)[channel] as f32 / 255.0
+        });
+        let output = self.model.run_one(input.into(), None)?;
+        let logits = output.into_tensor::<f32>().map(|tensor|tensor.to_vec()).unwrap_or_default();
+        if logits.len() != self.labels.len() {
+            return Err(ClassifierError::OutputMismatch(logits.len(), self.labels.len()));
+        }
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let total = logits.iter().map(|logit|(logit - max).exp()).sum::<f32>();
+        let (best, logit) = logits.iter().enumerate().max_by(|a, b|a.1.total_cmp(b.1)).unwrap();
+        Ok(Prediction { label: &self.labels[best], confidence: (logit - max).exp() / total })
+    }
+
+    /// Combines `detected`, the result of the pixel rules on `image`, with the classifier.
+    ///
+    /// Predictions below `min_confidence` are ignored, and in replace mode the rule result is
+    /// only overridden by labels that name a state without details. Returns the state to use
+    /// and whether the two disagreed.
+    pub fn reconcile(&self, image:&DynamicImage, detected:Result<State, StateError>, old_state:&State, mode:ClassifierMode, min_confidence:f32) -> (Result<State, StateError>, bool) {
+        let prediction = match self.predict(image) {
+            Ok(prediction) if prediction.confidence >= min_confidence => prediction,
+            Ok(_) => return (detected, false),
+            Err(err) => {
+                println!("{err}");
+                return (detected, false);
+            },
+        };
+        let rules = detected.as_ref().map(|state|state.state_type.name()).unwrap_or("unknown");
+        if prediction.label == rules {
+            return (detected, false);
+        }
+        println!("Classifier says {} ({:.2}), pixel rules say {rules}", prediction.label, prediction.confidence);
+        let state = match (mode, StateType::from_name(prediction.label)) {
+            (ClassifierMode::Replace, Some(state_type)) => Ok(Into::<State>::into(state_type).merge(old_state.clone())),
+            // Screens with details the classifier cannot read are left to the rules.
+            _ => detected,
+        };
+        (state, true)
+    }
+}
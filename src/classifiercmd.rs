@@ -0,0 +1,126 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}};
+
+use clap::Subcommand;
+use image::DynamicImage;
+
+use crate::{Opt, Services, atlas::Atlas, classifier::ClassifierError, game::ENDOR_LAYOUT, ml::{self, BitmapWebp, State}};
+
+const EXTENSIONS:[&str; 4] = ["png", "jpg", "jpeg", "webp"];
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum ClassifierCommand {
+    Dataset {
+        #[clap(required = true)]
+        images: Vec<PathBuf>,
+        #[clap(long, default_value = "dataset")]
+        out: PathBuf,
+    },
+    Predict {
+        #[clap(required = true)]
+        images: Vec<PathBuf>,
+    },
+}
+
+#[derive(Debug)]
+pub enum ClassifierCommandError {
+    ImageError(PathBuf, image::ImageError),
+    IoError(PathBuf, std::io::Error),
+    ClassifierError(ClassifierError),
+    NoModel,
+}
+impl std::fmt::Display for ClassifierCommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ImageError(path, err) => write!(f, "failed to load {}: {err}", path.display()),
+            Self::IoError(path, err) => write!(f, "io error on {}: {err}", path.display()),
+            Self::ClassifierError(err) => write!(f, "{err}"),
+            Self::NoModel => write!(f, "no classifier model, pass --classifier-model"),
+        }
+    }
+}
+impl std::error::Error for ClassifierCommandError {}
+impl From<ClassifierError> for ClassifierCommandError {
+    fn from(value: ClassifierError) -> Self {
+        Self::ClassifierError(value)
+    }
+}
+
+/// Image files among `paths`, descending one level into directories.
+fn image_files(paths:&[PathBuf]) -> Result<Vec<PathBuf>, ClassifierCommandError> {
+    let is_image = |path:&Path|path.extension()
+    .and_then(|extension|extension.to_str())
+    .is_some_and(|extension|EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()));
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let entries = std::fs::read_dir(path).map_err(|err|ClassifierCommandError::IoError(path.clone(), err))?;
+            let mut found = entries
+            .filter_map(|entry|entry.ok().map(|entry|entry.path()))
+            .filter(|path|is_image(path))
+            .collect::<Vec<_>>();
+            found.sort();
+            files.append(&mut found);
+        }
+        else {
+            files.push(path.clone());
+        }
+    }
+    Ok(files)
+}
+
+/// The state name the pixel rules give `image`, `None` when it matches no screen.
+fn detect(opt:&Opt, services:&Services, image:DynamicImage) -> Option<&'static str> {
+    let divisor = (ENDOR_LAYOUT.screen.0 / image.width().max(1)).max(1);
+    let bitmap = BitmapWebp::from_image(image, divisor, opt, services);
+    ml::get_state(State::default(), &bitmap, &mut Atlas::default()).ok().map(|state|state.state_type.name())
+}
+
+fn unused_path(dir:&Path, name:&str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    (1..).map(|n|dir.join(format!("{stem}-{n}.{extension}"))).find(|path|!path.exists()).unwrap()
+}
+
+pub fn run(opt:&Opt, services:&Services, command:&ClassifierCommand) -> Result<String, ClassifierCommandError> {
+    match command {
+        ClassifierCommand::Dataset { images, out } => {
+            let mut counts = BTreeMap::new();
+            let mut unknown = 0;
+            for path in image_files(images)? {
+                let image = image::open(&path).map_err(|err|ClassifierCommandError::ImageError(path.clone(), err))?;
+                let Some(label) = detect(opt, services, image) else {
+                    println!("{}: unknown", path.display());
+                    unknown += 1;
+                    continue;
+                };
+                let dir = out.join(label);
+                std::fs::create_dir_all(&dir).map_err(|err|ClassifierCommandError::IoError(dir.clone(), err))?;
+                let name = path.file_name().and_then(|name|name.to_str()).unwrap_or("frame.png");
+                let target = unused_path(&dir, name);
+                std::fs::copy(&path, &target).map_err(|err|ClassifierCommandError::IoError(target.clone(), err))?;
+                println!("{}: {label}", path.display());
+                *counts.entry(label).or_insert(0) += 1;
+            }
+            let summary = counts.iter().map(|(label, count)|format!("{label} {count}")).collect::<Vec<_>>().join(", ");
+            Ok(format!("Labelled {} screenshots into {}, {unknown} matched no screen\n{summary}", counts.values().sum::<usize>(), out.display()))
+        },
+        ClassifierCommand::Predict { images } => {
+            let classifier = services.classifier.as_ref().ok_or(ClassifierCommandError::NoModel)?;
+            let mut agreed = 0;
+            let files = image_files(images)?;
+            for path in &files {
+                let image = image::open(path).map_err(|err|ClassifierCommandError::ImageError(path.clone(), err))?;
+                let prediction = classifier.predict(&image)?;
+                let rules = detect(opt, services, image).unwrap_or("unknown");
+                if prediction.label == rules {
+                    agreed += 1;
+                }
+                println!("{}: classifier {} ({:.2}), pixel rules {rules}", path.display(), prediction.label, prediction.confidence);
+            }
+            Ok(format!("Classifier agreed with the pixel rules on {agreed} of {} screenshots", files.len()))
+        },
+    }
+}
@@ -0,0 +1,307 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use clap::{ArgMatches, parser::ValueSource};
+use serde::Deserialize;
+
+use crate::{Opt, classifier::ClassifierMode, fight::Rotation, macros::Macro, party::{MAX_PARTY_SIZE, Member}, policy::{DescendWhen, Mode, Policy, RetreatOn}, schedule::Window, screencap::CaptureBackend, strategy::StrategyKind};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(PathBuf, std::io::Error),
+    TomlError(PathBuf, toml::de::Error),
+}
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(path, err) => write!(f, "failed to read {}: {err}", path.display()),
+            Self::TomlError(path, err) => write!(f, "failed to parse {}: {err}", path.display()),
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    bind: Option<String>,
+    port: Option<u16>,
+    ws_port: Option<u16>,
+    token: Option<String>,
+    health_stale_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    backend: Option<CaptureBackend>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    package: Option<String>,
+    activity: Option<String>,
+    foreground_check_after: Option<u32>,
+    keep_awake: Option<bool>,
+    unlock_pin: Option<String>,
+    unlock_pattern: Option<Vec<(u32, u32)>>,
+    mode: Option<Mode>,
+    extra_screens: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThresholdConfig {
+    unknown_state_alert: Option<u32>,
+    unknown_recovery_ticks: Option<u32>,
+    max_actions_per_minute: Option<u32>,
+    max_fights_per_hour: Option<u32>,
+    max_runtime_minutes: Option<u64>,
+    handoff_quiet_secs: Option<u64>,
+    adb_timeout_ms: Option<u64>,
+    adb_retries: Option<u32>,
+    tick_default_ms: Option<u64>,
+    tick_max_backoff_ms: Option<u64>,
+    action_log_size: Option<usize>,
+    state_backups: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    retreat_on: Option<RetreatOn>,
+    retreat_below_pct: Option<f32>,
+    heal_below_pct: Option<f32>,
+    prioritize_chests: Option<bool>,
+    max_floor: Option<u32>,
+    descend_when: Option<DescendWhen>,
+    descend_explored_pct: Option<f32>,
+    seek_chests: Option<bool>,
+    max_ticks_per_target: Option<u32>,
+    use_potions: Option<bool>,
+    gold_target: Option<u32>,
+    rotations: Option<Vec<Rotation>>,
+    flee_ratio: Option<f32>,
+    boss_rotations: Option<Vec<Rotation>>,
+    boss_full_health: Option<bool>,
+    fight_watchdog_ticks: Option<u32>,
+    stuck_ticks: Option<u32>,
+    dungeon_energy_cost: Option<u32>,
+    strategy: Option<StrategyKind>,
+    macros: Option<Vec<Macro>>,
+    daily_macros: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartyConfig {
+    size: Option<u8>,
+    slot_y: Option<u32>,
+    slot_spacing: Option<u32>,
+    dead_below_pct: Option<f32>,
+    members: Option<Vec<Member>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    tap_jitter: Option<u32>,
+    delay_min_ms: Option<u64>,
+    delay_max_ms: Option<u64>,
+    idle_chance: Option<f64>,
+    idle_max_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    windows: Option<Vec<Window>>,
+    break_every_minutes: Option<u64>,
+    break_minutes: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StopConfig {
+    after_minutes: Option<u64>,
+    deaths: Option<u32>,
+    gold: Option<u32>,
+    floor: Option<u32>,
+    errors: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathConfig {
+    storage: Option<String>,
+    atlas: Option<PathBuf>,
+    journal: Option<PathBuf>,
+    glyphs: Option<PathBuf>,
+    buttons: Option<PathBuf>,
+    logs: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    url: Option<String>,
+    token: Option<String>,
+    interval_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClassifierConfig {
+    model: Option<PathBuf>,
+    mode: Option<ClassifierMode>,
+    min_confidence: Option<f32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    telegram_token: Option<String>,
+    telegram_chat_id: Option<String>,
+    discord_webhook: Option<String>,
+    screenshot: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    device: Option<String>,
+    game: GameConfig,
+    server: ServerConfig,
+    capture: CaptureConfig,
+    thresholds: ThresholdConfig,
+    policy: PolicyConfig,
+    event: PolicyConfig,
+    party: PartyConfig,
+    input: InputConfig,
+    schedule: ScheduleConfig,
+    stop: StopConfig,
+    ticks: BTreeMap<String, u64>,
+    paths: PathConfig,
+    sync: SyncConfig,
+    classifier: ClassifierConfig,
+    notify: NotifyConfig,
+}
+impl Config {
+    pub fn load(opt:&Opt, matches:&ArgMatches) -> Result<Self, ConfigError> {
+        let path = &opt.config;
+        match std::fs::read_to_string(path) {
+            Ok(data) => toml::from_str(&data).map_err(|err|ConfigError::TomlError(path.clone(), err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && !from_cli(matches, "config") => Ok(Config::default()),
+            Err(err) => Err(ConfigError::IoError(path.clone(), err)),
+        }
+    }
+
+    pub fn apply(self, opt:&mut Opt, matches:&ArgMatches) {
+        set(matches, "device", &mut opt.device, self.device);
+        set(matches, "game_package", &mut opt.game_package, self.game.package.map(Some));
+        set(matches, "game_activity", &mut opt.game_activity, self.game.activity.map(Some));
+        set(matches, "foreground_check_after", &mut opt.foreground_check_after, self.game.foreground_check_after);
+        set(matches, "keep_awake", &mut opt.keep_awake, self.game.keep_awake);
+        set(matches, "unlock_pin", &mut opt.unlock_pin, self.game.unlock_pin.map(Some));
+        set(matches, "unlock_pattern", &mut opt.unlock_pattern, self.game.unlock_pattern);
+        set(matches, "extra_screens", &mut opt.extra_screens, self.game.extra_screens);
+        set(matches, "bind", &mut opt.bind, self.server.bind);
+        set(matches, "port", &mut opt.port, self.server.port);
+        set(matches, "ws_port", &mut opt.ws_port, self.server.ws_port);
+        set(matches, "token", &mut opt.token, self.server.token.map(Some));
+        set(matches, "health_stale_secs", &mut opt.health_stale_secs, self.server.health_stale_secs);
+        set(matches, "capture_backend", &mut opt.capture_backend, self.capture.backend);
+        set(matches, "unknown_state_alert", &mut opt.unknown_state_alert, self.thresholds.unknown_state_alert);
+        set(matches, "unknown_recovery_ticks", &mut opt.unknown_recovery_ticks, self.thresholds.unknown_recovery_ticks);
+        set(matches, "max_actions_per_minute", &mut opt.max_actions_per_minute, self.thresholds.max_actions_per_minute);
+        set(matches, "max_fights_per_hour", &mut opt.max_fights_per_hour, self.thresholds.max_fights_per_hour);
+        set(matches, "max_runtime_minutes", &mut opt.max_runtime_minutes, self.thresholds.max_runtime_minutes);
+        set(matches, "handoff_quiet_secs", &mut opt.handoff_quiet_secs, self.thresholds.handoff_quiet_secs);
+        set(matches, "adb_timeout_ms", &mut opt.adb_timeout_ms, self.thresholds.adb_timeout_ms);
+        set(matches, "adb_retries", &mut opt.adb_retries, self.thresholds.adb_retries);
+        set(matches, "tick_default_ms", &mut opt.tick_default_ms, self.thresholds.tick_default_ms);
+        set(matches, "tick_max_backoff_ms", &mut opt.tick_max_backoff_ms, self.thresholds.tick_max_backoff_ms);
+        set(matches, "action_log_size", &mut opt.action_log_size, self.thresholds.action_log_size);
+        set(matches, "state_backups", &mut opt.state_backups, self.thresholds.state_backups);
+        set(matches, "mode", &mut opt.policy.mode, self.game.mode);
+        self.policy.apply(matches, &mut opt.policy);
+        if opt.policy.mode == Mode::Event {
+            self.event.apply(matches, &mut opt.policy);
+        }
+        set(matches, "party_size", &mut opt.party.size, self.party.size.map(|size|size.clamp(1, MAX_PARTY_SIZE as u8)));
+        set(matches, "party_slot_y", &mut opt.party.slot_y, self.party.slot_y);
+        set(matches, "party_slot_spacing", &mut opt.party.slot_spacing, self.party.slot_spacing);
+        set(matches, "party_dead_below_pct", &mut opt.party.dead_below_pct, self.party.dead_below_pct);
+        set(matches, "party_members", &mut opt.party.members, self.party.members);
+        set(matches, "tap_jitter", &mut opt.humanize.tap_jitter, self.input.tap_jitter);
+        set(matches, "action_delay_min_ms", &mut opt.humanize.delay_min_ms, self.input.delay_min_ms);
+        set(matches, "action_delay_max_ms", &mut opt.humanize.delay_max_ms, self.input.delay_max_ms);
+        set(matches, "idle_chance", &mut opt.humanize.idle_chance, self.input.idle_chance.map(|chance|chance.clamp(0.0, 1.0)));
+        set(matches, "idle_max_ms", &mut opt.humanize.idle_max_ms, self.input.idle_max_ms);
+        set(matches, "run_windows", &mut opt.schedule.windows, self.schedule.windows);
+        set(matches, "break_every_minutes", &mut opt.schedule.break_every_minutes, self.schedule.break_every_minutes);
+        set(matches, "break_minutes", &mut opt.schedule.break_minutes, self.schedule.break_minutes);
+        set(matches, "stop_after_minutes", &mut opt.stop.after_minutes, self.stop.after_minutes.map(Some));
+        set(matches, "stop_after_deaths", &mut opt.stop.deaths, self.stop.deaths.map(Some));
+        set(matches, "stop_after_gold", &mut opt.stop.gold, self.stop.gold.map(Some));
+        set(matches, "stop_at_floor", &mut opt.stop.floor, self.stop.floor.map(Some));
+        set(matches, "stop_after_errors", &mut opt.stop.errors, self.stop.errors.map(Some));
+        set(matches, "storage", &mut opt.storage, self.paths.storage);
+        set(matches, "atlas", &mut opt.atlas, self.paths.atlas);
+        set(matches, "journal", &mut opt.journal, self.paths.journal);
+        set(matches, "glyph_dir", &mut opt.glyph_dir, self.paths.glyphs);
+        set(matches, "button_dir", &mut opt.button_dir, self.paths.buttons);
+        set(matches, "log_dir", &mut opt.log_dir, self.paths.logs);
+        set(matches, "atlas_sync_url", &mut opt.atlas_sync_url, self.sync.url.map(Some));
+        set(matches, "atlas_sync_token", &mut opt.atlas_sync_token, self.sync.token.map(Some));
+        set(matches, "atlas_sync_secs", &mut opt.atlas_sync_secs, self.sync.interval_secs);
+        set(matches, "classifier_model", &mut opt.classifier_model, self.classifier.model.map(Some));
+        set(matches, "classifier_mode", &mut opt.classifier_mode, self.classifier.mode);
+        set(matches, "classifier_min_confidence", &mut opt.classifier_min_confidence, self.classifier.min_confidence.map(|confidence|confidence.clamp(0.0, 1.0)));
+        set(matches, "telegram_token", &mut opt.telegram_token, self.notify.telegram_token.map(Some));
+        set(matches, "telegram_chat_id", &mut opt.telegram_chat_id, self.notify.telegram_chat_id.map(Some));
+        set(matches, "discord_webhook", &mut opt.discord_webhook, self.notify.discord_webhook.map(Some));
+        set(matches, "notify_screenshot", &mut opt.notify_screenshot, self.notify.screenshot);
+        // Flags given on the command line are applied after the file so they win.
+        let mut ticks = self.ticks.into_iter().collect::<Vec<_>>();
+        ticks.append(&mut opt.tick_intervals);
+        opt.tick_intervals = ticks;
+    }
+}
+
+impl PolicyConfig {
+    fn apply(self, matches:&ArgMatches, policy:&mut Policy) {
+        set(matches, "retreat_on", &mut policy.retreat_on, self.retreat_on);
+        set(matches, "retreat_below_pct", &mut policy.retreat_below_pct, self.retreat_below_pct.map(Some));
+        set(matches, "heal_below_pct", &mut policy.heal_below_pct, self.heal_below_pct.map(Some));
+        set(matches, "prioritize_chests", &mut policy.prioritize_chests, self.prioritize_chests);
+        set(matches, "max_floor", &mut policy.max_floor, self.max_floor.map(Some));
+        set(matches, "descend_when", &mut policy.descend_when, self.descend_when);
+        set(matches, "descend_explored_pct", &mut policy.descend_explored_pct, self.descend_explored_pct);
+        set(matches, "seek_chests", &mut policy.seek_chests, self.seek_chests);
+        set(matches, "max_ticks_per_target", &mut policy.max_ticks_per_target, self.max_ticks_per_target);
+        set(matches, "use_potions", &mut policy.use_potions, self.use_potions);
+        set(matches, "gold_target", &mut policy.gold_target, self.gold_target.map(Some));
+        set(matches, "rotations", &mut policy.rotations, self.rotations);
+        set(matches, "flee_ratio", &mut policy.flee_ratio, self.flee_ratio.map(Some));
+        set(matches, "boss_rotations", &mut policy.boss_rotations, self.boss_rotations);
+        set(matches, "boss_full_health", &mut policy.boss_full_health, self.boss_full_health);
+        set(matches, "fight_watchdog_ticks", &mut policy.fight_watchdog_ticks, self.fight_watchdog_ticks);
+        set(matches, "stuck_ticks", &mut policy.stuck_ticks, self.stuck_ticks);
+        set(matches, "dungeon_energy_cost", &mut policy.dungeon_energy_cost, self.dungeon_energy_cost.map(Some));
+        set(matches, "strategy", &mut policy.strategy, self.strategy);
+        set(matches, "macros", &mut policy.macros, self.macros);
+        set(matches, "daily_macros", &mut policy.daily_macros, self.daily_macros);
+    }
+}
+
+fn set<T>(matches:&ArgMatches, id:&str, field:&mut T, value:Option<T>) {
+    if let Some(value) = value
+        && !from_cli(matches, id) {
+        *field = value;
+    }
+}
+
+fn from_cli(matches:&ArgMatches, id:&str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
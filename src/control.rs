@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{ml::{Action, Coords, Dungeon, MoveDirection, State, TileEdit}, schedule::{ScheduleOverride, ScheduleStatus}};
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum ManualAction {
+    Move(MoveDirection),
+    Action(Action),
+}
+impl ManualAction {
+    pub fn resolve(&self, state:&State) -> Option<Action> {
+        match self {
+            ManualAction::Move(direction) => state.dungeon.manual_move(*direction),
+            ManualAction::Action(action) => Some(*action),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TileCommand {
+    Edit(TileEdit),
+    Clear(Option<String>, Coords),
+}
+impl TileCommand {
+    pub fn apply(&self, dungeon:&mut Dungeon) -> bool {
+        match self {
+            TileCommand::Edit(edit) => dungeon.edit_tile(edit.clone()).is_some(),
+            TileCommand::Clear(floor, position) => dungeon.clear_tile(floor.clone(), *position),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Control {
+    shutdown: watch::Sender<bool>,
+    paused: AtomicBool,
+    step: AtomicBool,
+    save: AtomicBool,
+    action: Mutex<Option<ManualAction>>,
+    tile_commands: Mutex<Vec<TileCommand>>,
+    schedule_override: Mutex<ScheduleOverride>,
+    schedule: Mutex<ScheduleStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlStatus {
+    pub paused: bool,
+    pub shutdown: bool,
+    pub schedule: ScheduleStatus,
+}
+
+impl Control {
+    pub fn request_shutdown(&self) -> bool {
+        self.shutdown.send_replace(true)
+    }
+    pub fn is_shutdown(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+    pub async fn shutdown_requested(&self) {
+        let _ = self.shutdown.subscribe().wait_for(|shutdown|*shutdown).await;
+    }
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+    pub fn resume(&self) {
+        self.step.store(false, Ordering::SeqCst);
+        self.paused.store(false, Ordering::SeqCst);
+    }
+    pub fn step(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+        self.step.store(true, Ordering::SeqCst);
+    }
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+    /// Returns true if the loop may run a tick now, consuming a pending step while paused.
+    pub fn should_tick(&self) -> bool {
+        !self.is_paused() || self.step.swap(false, Ordering::SeqCst)
+    }
+    pub fn queue_action(&self, action:ManualAction) {
+        *self.action.lock() = Some(action);
+    }
+    pub fn take_action(&self) -> Option<ManualAction> {
+        self.action.lock().take()
+    }
+    pub fn queue_tile_command(&self, command:TileCommand) {
+        self.tile_commands.lock().push(command);
+    }
+    pub fn take_tile_commands(&self) -> Vec<TileCommand> {
+        std::mem::take(&mut *self.tile_commands.lock())
+    }
+    pub fn request_save(&self) {
+        self.save.store(true, Ordering::SeqCst);
+    }
+    pub fn take_save_request(&self) -> bool {
+        self.save.swap(false, Ordering::SeqCst)
+    }
+    pub fn override_schedule(&self, mode:ScheduleOverride) {
+        *self.schedule_override.lock() = mode;
+    }
+    pub fn schedule_override(&self) -> ScheduleOverride {
+        *self.schedule_override.lock()
+    }
+    pub fn set_schedule(&self, status:ScheduleStatus) {
+        *self.schedule.lock() = status;
+    }
+    pub fn schedule(&self) -> ScheduleStatus {
+        *self.schedule.lock()
+    }
+    pub fn status(&self) -> ControlStatus {
+        ControlStatus {
+            paused: self.is_paused(),
+            shutdown: self.is_shutdown(),
+            schedule: self.schedule(),
+        }
+    }
+}
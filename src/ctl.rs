@@ -0,0 +1,79 @@
+use std::path::{Path, PathBuf};
+
+use clap::Subcommand;
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum CtlCommand {
+    Status,
+    Pause,
+    Resume,
+    Step,
+    Macro {
+        name: String,
+    },
+    Action {
+        json: String,
+    },
+    Screenshot {
+        #[clap(long, short, default_value = "screenshot.png")]
+        out: PathBuf,
+    },
+}
+
+#[derive(Debug)]
+pub enum CtlError {
+    HttpError(ureq::Error),
+    IoError(std::io::Error),
+}
+impl std::fmt::Display for CtlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HttpError(err) => write!(f, "request failed: {err}"),
+            Self::IoError(err) => write!(f, "io error: {err}"),
+        }
+    }
+}
+impl std::error::Error for CtlError {}
+impl From<ureq::Error> for CtlError {
+    fn from(value: ureq::Error) -> Self {
+        Self::HttpError(value)
+    }
+}
+impl From<std::io::Error> for CtlError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+fn screenshot(url:&str, authorization:Option<&str>, out:&Path) -> Result<String, CtlError> {
+    let mut request = ureq::get(format!("{url}/screenshot"));
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    let png = request.call()?.body_mut().read_to_vec()?;
+    std::fs::write(out, &png)?;
+    Ok(format!("Saved screenshot to {}", out.display()))
+}
+
+pub fn run(url:&str, token:Option<&str>, command:&CtlCommand) -> Result<String, CtlError> {
+    let url = url.trim_end_matches('/');
+    let authorization = token.map(|token|format!("Bearer {token}"));
+    let path = match command {
+        CtlCommand::Status => "/control/status".to_owned(),
+        CtlCommand::Pause => "/control/pause".to_owned(),
+        CtlCommand::Resume => "/control/resume".to_owned(),
+        CtlCommand::Step => "/control/step".to_owned(),
+        CtlCommand::Macro { name } => format!("/control/macro/{name}"),
+        CtlCommand::Action { .. } => "/control/action".to_owned(),
+        CtlCommand::Screenshot { out } => return screenshot(url, authorization.as_deref(), out),
+    };
+    let mut request = ureq::post(format!("{url}{path}"));
+    if let Some(authorization) = &authorization {
+        request = request.header("Authorization", authorization);
+    }
+    let mut response = match command {
+        CtlCommand::Action { json } => request.header("Content-Type", "application/json").send(json.as_str())?,
+        _ => request.send_empty()?,
+    };
+    Ok(response.body_mut().read_to_string()?)
+}
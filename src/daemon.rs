@@ -0,0 +1,143 @@
+use std::{fs::OpenOptions, os::unix::net::UnixDatagram, path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant}};
+
+use serde::Serialize;
+
+use crate::timing::now_ms;
+
+pub fn redirect_output(log_dir:&Path) -> std::io::Result<PathBuf> {
+    use std::os::fd::AsRawFd;
+    std::fs::create_dir_all(log_dir)?;
+    let path = log_dir.join("endorbot.log");
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(path)
+}
+
+pub struct Systemd {
+    socket: Option<(UnixDatagram, String)>,
+    watchdog: Option<Duration>,
+    last_watchdog: Option<Instant>,
+}
+impl Systemd {
+    pub fn from_env() -> Self {
+        let socket = std::env::var("NOTIFY_SOCKET").ok()
+        .and_then(|path|UnixDatagram::unbound().ok().map(|socket|(socket, path)));
+        let watchdog = std::env::var("WATCHDOG_USEC").ok()
+        .filter(|_|std::env::var("WATCHDOG_PID").ok().is_none_or(|pid|pid == std::process::id().to_string()))
+        .and_then(|usec|usec.parse().ok())
+        .map(Duration::from_micros);
+        Self {
+            socket,
+            watchdog,
+            last_watchdog: None,
+        }
+    }
+
+    fn notify(&self, message:&str) {
+        let Some((socket, path)) = &self.socket else {
+            return;
+        };
+        let result = match path.strip_prefix('@') {
+            Some(name) => send_abstract(socket, name, message),
+            None => socket.send_to(message.as_bytes(), path).map(|_|()),
+        };
+        if let Err(err) = result {
+            println!("Failed to notify systemd: {err}");
+        }
+    }
+
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    pub fn status(&self, status:&str) {
+        self.notify(&format!("STATUS={status}"));
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    pub fn watchdog(&mut self) {
+        let Some(interval) = self.watchdog else {
+            return;
+        };
+        if self.last_watchdog.is_some_and(|last|last.elapsed() < interval / 2) {
+            return;
+        }
+        self.last_watchdog = Some(Instant::now());
+        self.notify("WATCHDOG=1");
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn send_abstract(socket:&UnixDatagram, name:&str, message:&str) -> std::io::Result<()> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(message.as_bytes(), &addr).map(|_|())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn send_abstract(_socket:&UnixDatagram, _name:&str, _message:&str) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "abstract sockets are only supported on Linux"))
+}
+
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    started_ms: AtomicU64,
+    beat_ms: AtomicU64,
+    tick_ms: AtomicU64,
+    restarts: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    pub healthy: bool,
+    pub uptime_secs: u64,
+    pub last_beat_secs: Option<u64>,
+    pub last_tick_secs: Option<u64>,
+    pub restarts: u64,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        let heartbeat = Self::default();
+        heartbeat.started_ms.store(now_ms(), Ordering::Relaxed);
+        heartbeat
+    }
+
+    pub fn beat(&self) {
+        self.beat_ms.store(now_ms(), Ordering::Relaxed);
+    }
+
+    pub fn ticked(&self) {
+        self.tick_ms.store(now_ms(), Ordering::Relaxed);
+    }
+
+    pub fn restarted(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn health(&self, stale:Duration) -> Health {
+        let now = now_ms();
+        let since = |at:&AtomicU64|match at.load(Ordering::Relaxed) {
+            0 => None,
+            at => Some(now.saturating_sub(at) / 1000),
+        };
+        let last_beat_secs = since(&self.beat_ms);
+        Health {
+            healthy: last_beat_secs.is_some_and(|secs|secs <= stale.as_secs()),
+            uptime_secs: since(&self.started_ms).unwrap_or_default(),
+            last_beat_secs,
+            last_tick_secs: since(&self.tick_ms),
+            restarts: self.restarts.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::{collections::VecDeque, process::{Command, Output, Stdio}, time::{Duration, Instant}};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::{Opt, runtime, timing::now_ms};
+
+const RETRY_BACKOFF:Duration = Duration::from_millis(250);
+const MAX_BACKOFF_SHIFT:u32 = 4;
+const ADB_LOG_SIZE:usize = 500;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AdbEntry {
+    timestamp: u64,
+    command: String,
+    attempt: u32,
+    duration_ms: u64,
+    status: Option<i32>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct AdbLog {
+    entries: Mutex<VecDeque<AdbEntry>>,
+}
+impl AdbLog {
+    fn push(&self, entry:AdbEntry) {
+        let mut entries = self.entries.lock();
+        if entries.len() >= ADB_LOG_SIZE {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    pub fn entries(&self) -> Vec<AdbEntry> {
+        self.entries.lock().iter().cloned().collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum DeviceError {
+    IoError(std::io::Error),
+    Timeout(Duration),
+    Failed(Option<i32>),
+}
+impl std::fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(err) => write!(f, "io error: {err}"),
+            Self::Timeout(timeout) => write!(f, "timed out after {}ms", timeout.as_millis()),
+            Self::Failed(Some(code)) => write!(f, "exited with status {code}"),
+            Self::Failed(None) => write!(f, "terminated by signal"),
+        }
+    }
+}
+impl std::error::Error for DeviceError {}
+
+impl From<std::io::Error> for DeviceError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+fn describe(command:&Command) -> String {
+    std::iter::once(command.get_program()).chain(command.get_args()).map(|arg|arg.to_string_lossy()).collect::<Vec<_>>().join(" ")
+}
+
+async fn run_once(command:&mut tokio::process::Command, timeout:Option<Duration>) -> Result<Output, DeviceError> {
+    let child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::null()).kill_on_drop(true).spawn()?;
+    let output = tokio::select! {
+        output = child.wait_with_output() => output?,
+        _ = tokio::time::sleep(timeout.unwrap_or_default()), if timeout.is_some() => {
+            return Err(DeviceError::Timeout(timeout.unwrap_or_default()));
+        },
+    };
+    if !output.status.success() {
+        return Err(DeviceError::Failed(output.status.code()));
+    }
+    Ok(output)
+}
+
+pub async fn run_async(command:&mut tokio::process::Command, opt:&Opt, log:&AdbLog) -> Result<Output, DeviceError> {
+    let timeout = (opt.adb_timeout_ms > 0).then(||Duration::from_millis(opt.adb_timeout_ms));
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let result = run_once(command, timeout).await;
+        let entry = AdbEntry {
+            timestamp: now_ms(),
+            command: describe(command.as_std()),
+            attempt,
+            duration_ms: started.elapsed().as_millis() as u64,
+            status: match &result {
+                Ok(output) => output.status.code(),
+                Err(DeviceError::Failed(code)) => *code,
+                Err(_) => None,
+            },
+            error: result.as_ref().err().map(|err|err.to_string()),
+        };
+        if opt.debug {
+            println!("adb {} ({}ms): {}", entry.command, entry.duration_ms, entry.error.as_deref().unwrap_or("ok"));
+        }
+        log.push(entry);
+        match result {
+            Ok(output) => return Ok(output),
+            Err(err) if attempt >= opt.adb_retries => return Err(err),
+            Err(err) => {
+                let backoff = RETRY_BACKOFF * (1 << attempt.min(MAX_BACKOFF_SHIFT));
+                println!("{} failed: {err}, retrying in {}ms", describe(command.as_std()), backoff.as_millis());
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            },
+        }
+    }
+}
+
+pub fn run(command:&mut Command, opt:&Opt, log:&AdbLog) -> Result<Output, DeviceError> {
+    let mut async_command = tokio::process::Command::new(command.get_program());
+    async_command.args(command.get_args());
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => async_command.env(key, value),
+            None => async_command.env_remove(key),
+        };
+    }
+    if let Some(dir) = command.get_current_dir() {
+        async_command.current_dir(dir);
+    }
+    runtime::block_on(run_async(&mut async_command, opt, log))
+}
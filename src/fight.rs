@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ml::Health;
+
+pub const SKILL_SLOTS:usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rotation {
+    pub character: u8,
+    pub skills: Vec<u8>,
+}
+
+pub fn parse_rotation(value:&str) -> Result<Rotation, String> {
+    let (character, skills) = value.split_once('=').ok_or_else(||format!("expected <character>=<skill>,<skill>..., got {value}"))?;
+    let character = character.trim().parse::<u8>().map_err(|err|format!("invalid character {character}: {err}"))?;
+    let skills = skills.split(',')
+    .map(|skill|skill.trim().parse::<u8>().map_err(|err|format!("invalid skill {skill}: {err}")))
+    .collect::<Result<Vec<_>, _>>()?;
+    if let Some(skill) = skills.iter().find(|skill|**skill as usize >= SKILL_SLOTS) {
+        return Err(format!("skill {skill} out of range, the skill bar has {SKILL_SLOTS} slots"));
+    }
+    Ok(Rotation { character, skills })
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SkillBar {
+    pub active_character: Option<u8>,
+    pub ready: [bool; SKILL_SLOTS],
+}
+impl SkillBar {
+    pub fn next_skill(&self, rotations:&[Rotation]) -> Option<u8> {
+        let character = self.active_character?;
+        rotations.iter()
+        .filter(|rotation|rotation.character == character)
+        .flat_map(|rotation|rotation.skills.iter())
+        .find(|skill|self.ready[**skill as usize])
+        .copied()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FightWatch {
+    ticks: u32,
+    stalled_ticks: u32,
+    enemy_health: Option<Health>,
+}
+impl FightWatch {
+    pub fn observe(&mut self, enemy_health:Option<Health>) {
+        let Some(health) = enemy_health else {
+            *self = Self::default();
+            return;
+        };
+        self.ticks += 1;
+        if self.enemy_health == Some(health) {
+            self.stalled_ticks += 1;
+        }
+        else {
+            self.stalled_ticks = 0;
+        }
+        self.enemy_health = Some(health);
+    }
+
+    pub fn escalation(&self, watchdog_ticks:u32) -> Option<u32> {
+        if watchdog_ticks == 0 || self.stalled_ticks < watchdog_ticks || !self.stalled_ticks.is_multiple_of(watchdog_ticks) {
+            return None;
+        }
+        Some(self.stalled_ticks / watchdog_ticks - 1)
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct FloorId {
+    pub prefix: String,
+    pub number: u32,
+}
+impl FloorId {
+    pub fn parse(text:&str) -> Option<Self> {
+        let text = text.chars().filter(|c|!c.is_whitespace()).collect::<String>().to_ascii_uppercase();
+        let split = text.find(|c:char|c.is_ascii_digit())?;
+        let (prefix, number) = text.split_at(split);
+        if !prefix.chars().all(|c|c.is_ascii_uppercase()) {
+            return None;
+        }
+        Some(Self {
+            prefix: prefix.to_owned(),
+            number: number.parse().ok()?,
+        })
+    }
+
+    pub fn next(&self) -> Self {
+        Self {
+            prefix: self.prefix.clone(),
+            number: self.number + 1,
+        }
+    }
+
+    pub fn previous(&self) -> Self {
+        Self {
+            prefix: self.prefix.clone(),
+            number: self.number.saturating_sub(1).max(1),
+        }
+    }
+}
+impl Display for FloorId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.prefix, self.number)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FloorChanged {
+    pub from: String,
+    pub to: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn floor(prefix:&str, number:u32) -> FloorId {
+        FloorId { prefix: prefix.to_owned(), number }
+    }
+
+    #[test]
+    fn parses_floor_labels() {
+        assert_eq!(FloorId::parse("B12"), Some(floor("B", 12)));
+        assert_eq!(FloorId::parse(" b 3 "), Some(floor("B", 3)));
+        assert_eq!(FloorId::parse("EX7"), Some(floor("EX", 7)));
+        assert_eq!(FloorId::parse("12"), Some(floor("", 12)));
+        assert_eq!(FloorId::parse("B12").unwrap().to_string(), "B12");
+    }
+
+    #[test]
+    fn rejects_malformed_labels() {
+        assert_eq!(FloorId::parse(""), None);
+        assert_eq!(FloorId::parse("B"), None);
+        assert_eq!(FloorId::parse("B-2"), None);
+        assert_eq!(FloorId::parse("B1A"), None);
+        assert_eq!(FloorId::parse("B99999999999"), None);
+    }
+
+    #[test]
+    fn steps_between_floors() {
+        assert_eq!(floor("B", 3).next(), floor("B", 4));
+        assert_eq!(floor("B", 3).previous(), floor("B", 2));
+        assert_eq!(floor("B", 1).previous(), floor("B", 1));
+        assert_eq!(floor("B", 0).previous(), floor("B", 1));
+    }
+}
@@ -0,0 +1,51 @@
+use std::{io::Cursor, sync::Arc};
+
+use image::{DynamicImage, ImageFormat, codecs::jpeg::JpegEncoder};
+use tokio::sync::watch;
+
+type Frame = Option<(u64, Arc<DynamicImage>)>;
+
+#[derive(Default)]
+pub struct LatestFrame {
+    frame: watch::Sender<Frame>,
+}
+impl LatestFrame {
+    pub fn publish(&self, image:DynamicImage) {
+        self.frame.send_modify(|frame|{
+            let sequence = frame.as_ref().map_or(0, |(sequence, _)|sequence + 1);
+            *frame = Some((sequence, Arc::new(image)));
+        });
+    }
+    pub fn latest(&self) -> Frame {
+        self.frame.borrow().clone()
+    }
+    /// Sees every frame published from now on, the current one counts as unseen.
+    pub fn subscribe(&self) -> watch::Receiver<Frame> {
+        let mut receiver = self.frame.subscribe();
+        receiver.mark_changed();
+        receiver
+    }
+}
+
+pub fn encode_png(image:&DynamicImage) -> image::ImageResult<Vec<u8>> {
+    let mut out = Cursor::new(Vec::new());
+    image.write_to(&mut out, ImageFormat::Png)?;
+    Ok(out.into_inner())
+}
+
+pub fn encode_jpeg(image:&DynamicImage, quality:u8) -> image::ImageResult<Vec<u8>> {
+    let mut out = Vec::new();
+    JpegEncoder::new_with_quality(&mut out, quality).encode_image(&image.to_rgb8())?;
+    Ok(out)
+}
+
+pub const MJPEG_BOUNDARY:&str = "endorbotframe";
+
+/// One part of the `multipart/x-mixed-replace` stream served on `/live`.
+pub fn mjpeg_part(image:&DynamicImage) -> image::ImageResult<Vec<u8>> {
+    let jpeg = encode_jpeg(image, 70)?;
+    let mut part = format!("--{MJPEG_BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n", jpeg.len()).into_bytes();
+    part.extend_from_slice(&jpeg);
+    part.extend_from_slice(b"\r\n");
+    Ok(part)
+}
@@ -0,0 +1,70 @@
+use image::DynamicImage;
+use serde::Serialize;
+
+use crate::{Opt, Services, atlas::Atlas, ml::{self, Action, BitmapWebp, Coords, State, StateError}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Layout {
+    pub screen: (u32, u32),
+    pub map_origin: (u32, u32),
+    pub map_tile: (u32, u32),
+    pub map_tiles: (u32, u32),
+}
+
+pub const ENDOR_LAYOUT:Layout = Layout {
+    screen: (1080, 2408),
+    map_origin: (536, 536),
+    map_tile: (60, 60),
+    map_tiles: (7, 7),
+};
+
+/// Everything the bot needs to know about a particular game.
+///
+/// Capture, pathfinding, the atlas, the web interface and the tick loop only go through
+/// this trait, so supporting another game means implementing it next to [`Endor`] and
+/// passing it to [`Bot::with_adapter`](crate::Bot::with_adapter).
+pub trait GameAdapter: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Screen resolution and where the minimap tiles are drawn.
+    fn layout(&self) -> &Layout;
+
+    /// Reads the state shown on `image`, continuing from `old_state`.
+    fn detect(&self, old_state:State, image:&BitmapWebp, atlas:&mut Atlas) -> Result<State, StateError>;
+
+    /// Updates `state` with the expected effect of `action`, returning the new position if it moves the party.
+    fn apply(&self, state:&mut State, action:&Action) -> Option<Coords>;
+
+    /// Performs `action` on the device. `frame` is the latest detected frame, used to locate buttons.
+    fn execute(&self, device:&str, opt:&Opt, services:&Services, action:&Action, frame:Option<&DynamicImage>);
+
+    /// Whether the game has focus, `None` when it cannot be told.
+    fn in_foreground(&self, device:&str, opt:&Opt, services:&Services) -> Option<bool>;
+}
+
+pub struct Endor;
+impl GameAdapter for Endor {
+    fn name(&self) -> &'static str {
+        "endor"
+    }
+
+    fn layout(&self) -> &Layout {
+        &ENDOR_LAYOUT
+    }
+
+    fn detect(&self, old_state:State, image:&BitmapWebp, atlas:&mut Atlas) -> Result<State, StateError> {
+        ml::get_state(old_state, image, atlas)
+    }
+
+    fn apply(&self, state:&mut State, action:&Action) -> Option<Coords> {
+        ml::apply_action(state, action)
+    }
+
+    fn execute(&self, device:&str, opt:&Opt, services:&Services, action:&Action, frame:Option<&DynamicImage>) {
+        ml::execute_action(device, opt, services, action, frame)
+    }
+
+    fn in_foreground(&self, device:&str, opt:&Opt, services:&Services) -> Option<bool> {
+        ml::game_in_foreground(device, opt, services)
+    }
+}
@@ -0,0 +1,162 @@
+use std::{io::Write, path::{Path, PathBuf}};
+
+use clap::Subcommand;
+use image::{DynamicImage, GenericImageView, GrayImage, Luma};
+
+use crate::{Opt, Services, glyphs::{self, GlyphError, GlyphTemplate, Mask, Region}};
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum GlyphCommand {
+    Capture {
+        image: PathBuf,
+        #[clap(long, value_parser = parse_region)]
+        region: Region,
+        #[clap(long)]
+        labels: Option<String>,
+        #[clap(long)]
+        out: Option<PathBuf>,
+        #[clap(long)]
+        save_dir: Option<PathBuf>,
+    },
+    Read {
+        image: PathBuf,
+        #[clap(long, value_parser = parse_region)]
+        region: Region,
+    },
+}
+
+pub fn parse_region(value:&str) -> Result<Region, String> {
+    let parts = value.split(',')
+    .map(|part|part.trim().parse::<u32>().map_err(|err|format!("invalid number {part} in {value}: {err}")))
+    .collect::<Result<Vec<_>, _>>()?;
+    let [x, y, width, height] = parts[..] else {
+        return Err(format!("expected x,y,w,h, got {value}"));
+    };
+    Ok(Region { x, y, width, height })
+}
+
+#[derive(Debug)]
+pub enum GlyphCommandError {
+    ImageError(PathBuf, image::ImageError),
+    OutOfBounds(Region, u32, u32),
+    LabelMismatch(usize, usize),
+    GlyphError(GlyphError),
+    IoError(PathBuf, std::io::Error),
+}
+impl std::fmt::Display for GlyphCommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ImageError(path, err) => write!(f, "failed to load {}: {err}", path.display()),
+            Self::OutOfBounds(region, width, height) => write!(f, "region {region:?} is outside the {width}x{height} image"),
+            Self::LabelMismatch(labels, glyphs) => write!(f, "{labels} labels given but {glyphs} glyphs found"),
+            Self::GlyphError(err) => write!(f, "{err}"),
+            Self::IoError(path, err) => write!(f, "failed to write {}: {err}", path.display()),
+        }
+    }
+}
+impl std::error::Error for GlyphCommandError {}
+impl From<GlyphError> for GlyphCommandError {
+    fn from(value: GlyphError) -> Self {
+        Self::GlyphError(value)
+    }
+}
+
+fn load_region(path:&PathBuf, region:Region) -> Result<DynamicImage, GlyphCommandError> {
+    let loaded = image::open(path).map_err(|err|GlyphCommandError::ImageError(path.clone(), err))?;
+    let (width, height) = loaded.dimensions();
+    if region.x + region.width > width || region.y + region.height > height {
+        return Err(GlyphCommandError::OutOfBounds(region, width, height));
+    }
+    Ok(loaded)
+}
+
+fn rgb(image:&DynamicImage) -> impl Fn(u32, u32) -> [u8; 3] + '_ {
+    |x, y|{
+        let pixel = image.get_pixel(x, y);
+        [pixel[0], pixel[1], pixel[2]]
+    }
+}
+
+fn save_masks(dir:&Path, masks:&[Mask]) -> Result<(), GlyphCommandError> {
+    std::fs::create_dir_all(dir).map_err(|err|GlyphCommandError::IoError(dir.to_owned(), err))?;
+    for (i, mask) in masks.iter().enumerate() {
+        let image = GrayImage::from_fn(mask.width as u32, mask.height as u32, |x, y|Luma([if mask.get(x as usize, y as usize) { 255 } else { 0 }]));
+        let path = dir.join(format!("glyph-{i}.png"));
+        image.save(&path).map_err(|err|GlyphCommandError::ImageError(path.clone(), err))?;
+    }
+    println!("Saved {} glyphs to {}", masks.len(), dir.display());
+    Ok(())
+}
+
+fn prompt_labels(services:&Services, masks:&[Mask]) -> Result<Vec<Option<char>>, GlyphCommandError> {
+    let stdin = std::io::stdin();
+    let mut labels = Vec::with_capacity(masks.len());
+    for (i, mask) in masks.iter().enumerate() {
+        println!("Glyph {} of {} ({}x{})", i + 1, masks.len(), mask.width, mask.height);
+        for row in GlyphTemplate::from_mask(glyphs::UNKNOWN, mask).rows {
+            println!("    {row}");
+        }
+        let guess = services.glyphs.recognize(mask);
+        match guess {
+            Some((label, score)) => print!("Label (enter keeps {label:?} matched at {score:.2}, - skips): "),
+            None => print!("Label (enter or - skips): "),
+        }
+        std::io::stdout().flush().ok();
+        let mut line = String::new();
+        stdin.read_line(&mut line).map_err(|err|GlyphCommandError::IoError(PathBuf::from("stdin"), err))?;
+        let line = line.trim();
+        labels.push(match line {
+            "-" => None,
+            "" => guess.map(|(label, _)|label),
+            line => line.chars().next(),
+        });
+    }
+    Ok(labels)
+}
+
+pub fn run(opt:&Opt, services:&Services, command:&GlyphCommand) -> Result<(), GlyphCommandError> {
+    match command {
+        GlyphCommand::Read { image, region } => {
+            let loaded = load_region(image, *region)?;
+            println!("{}", services.glyphs.read(&rgb(&loaded), *region));
+            Ok(())
+        },
+        GlyphCommand::Capture { image, region, labels, out, save_dir } => {
+            let loaded = load_region(image, *region)?;
+            let masks = glyphs::segment(&rgb(&loaded), *region);
+            if let Some(save_dir) = save_dir {
+                save_masks(save_dir, &masks)?;
+            }
+            let labels = match labels {
+                Some(labels) => {
+                    let labels = labels.chars().filter(|c|!c.is_whitespace()).map(Some).collect::<Vec<_>>();
+                    if labels.len() != masks.len() {
+                        return Err(GlyphCommandError::LabelMismatch(labels.len(), masks.len()));
+                    }
+                    labels
+                },
+                None => prompt_labels(services, &masks)?,
+            };
+            let out = out.clone().unwrap_or_else(||opt.glyph_dir.join("glyphs.json"));
+            let mut templates = if out.exists() {
+                glyphs::load_templates(&out)?
+            }
+            else {
+                Vec::new()
+            };
+            let mut captured = 0;
+            for (label, mask) in labels.iter().zip(&masks) {
+                if let Some(label) = label {
+                    templates.push(GlyphTemplate::from_mask(*label, mask));
+                    captured += 1;
+                }
+            }
+            if let Some(parent) = out.parent() {
+                std::fs::create_dir_all(parent).map_err(|err|GlyphCommandError::IoError(out.clone(), err))?;
+            }
+            std::fs::write(&out, serde_json::to_string_pretty(&templates).unwrap()).map_err(|err|GlyphCommandError::IoError(out.clone(), err))?;
+            println!("Captured {captured} of {} glyphs into {}", masks.len(), out.display());
+            Ok(())
+        },
+    }
+}
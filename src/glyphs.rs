@@ -0,0 +1,217 @@
+use std::{cell::RefCell, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+const INK_LUMA:f32 = 128.0;
+const MIN_SCORE:f32 = 0.8;
+const MAX_ASPECT_RATIO:f32 = 1.6;
+pub const UNKNOWN:char = '?';
+
+thread_local! {
+    static INK:RefCell<Vec<bool>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+impl Region {
+    /// A `width` by `height` box centred on `x`,`y`.
+    pub const fn around(x:u32, y:u32, width:u32, height:u32) -> Self {
+        Self { x: x.saturating_sub(width / 2), y: y.saturating_sub(height / 2), width, height }
+    }
+
+    pub fn centre(&self) -> (u32, u32) {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mask {
+    pub width: usize,
+    pub height: usize,
+    bits: Vec<bool>,
+}
+impl Mask {
+    pub fn get(&self, x:usize, y:usize) -> bool {
+        self.bits[y * self.width + x]
+    }
+
+    fn sample(&self, width:usize, height:usize) -> Vec<f32> {
+        let mut out = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let bit = self.get(x * self.width / width, y * self.height / height);
+                out.push(if bit { 1.0 } else { 0.0 });
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GlyphTemplate {
+    pub label: char,
+    pub rows: Vec<String>,
+}
+impl GlyphTemplate {
+    pub fn from_mask(label:char, mask:&Mask) -> Self {
+        Self {
+            label,
+            rows: (0..mask.height).map(|y|(0..mask.width).map(|x|if mask.get(x, y) { '#' } else { '.' }).collect()).collect(),
+        }
+    }
+
+    fn to_glyph(&self) -> Result<Glyph, String> {
+        let width = self.rows.first().map(|row|row.chars().count()).unwrap_or_default();
+        if width == 0 || self.rows.iter().any(|row|row.chars().count() != width) {
+            return Err(format!("glyph {:?} has ragged or empty rows", self.label));
+        }
+        Ok(Glyph {
+            label: self.label,
+            mask: Mask {
+                width,
+                height: self.rows.len(),
+                bits: self.rows.iter().flat_map(|row|row.chars().map(|c|c == '#')).collect(),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Glyph {
+    label: char,
+    mask: Mask,
+}
+
+#[derive(Debug)]
+pub enum GlyphError {
+    IoError(PathBuf, std::io::Error),
+    JsonError(PathBuf, serde_json::Error),
+    InvalidGlyph(PathBuf, String),
+}
+impl std::fmt::Display for GlyphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(path, err) => write!(f, "failed to read {}: {err}", path.display()),
+            Self::JsonError(path, err) => write!(f, "failed to parse {}: {err}", path.display()),
+            Self::InvalidGlyph(path, err) => write!(f, "invalid glyph in {}: {err}", path.display()),
+        }
+    }
+}
+impl std::error::Error for GlyphError {}
+
+pub fn load_templates(path:&Path) -> Result<Vec<GlyphTemplate>, GlyphError> {
+    let data = std::fs::read_to_string(path).map_err(|err|GlyphError::IoError(path.to_owned(), err))?;
+    serde_json::from_str(&data).map_err(|err|GlyphError::JsonError(path.to_owned(), err))
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct GlyphSet {
+    glyphs: Vec<Glyph>,
+}
+impl GlyphSet {
+    pub fn load(dir:&Path) -> Result<Self, GlyphError> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(GlyphError::IoError(dir.to_owned(), err)),
+        };
+        let mut paths = entries.filter_map(|entry|entry.ok().map(|entry|entry.path()))
+        .filter(|path|path.extension().is_some_and(|ext|ext == "json"))
+        .collect::<Vec<_>>();
+        paths.sort();
+        let mut glyphs = Vec::new();
+        for path in paths {
+            for template in load_templates(&path)? {
+                glyphs.push(template.to_glyph().map_err(|err|GlyphError::InvalidGlyph(path.clone(), err))?);
+            }
+        }
+        Ok(Self { glyphs })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.glyphs.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.glyphs.len()
+    }
+
+    pub fn recognize(&self, mask:&Mask) -> Option<(char, f32)> {
+        self.glyphs.iter()
+        .filter(|glyph|{
+            let ratio = glyph.mask.width.max(mask.width) as f32 / glyph.mask.width.min(mask.width).max(1) as f32;
+            ratio <= MAX_ASPECT_RATIO
+        })
+        .map(|glyph|(glyph.label, correlation(&mask.sample(glyph.mask.width, glyph.mask.height), &glyph.mask.sample(glyph.mask.width, glyph.mask.height))))
+        .max_by(|a, b|a.1.total_cmp(&b.1))
+        .filter(|(_, score)|*score >= MIN_SCORE)
+    }
+
+    pub fn read(&self, pixel:&dyn Fn(u32, u32) -> [u8; 3], region:Region) -> String {
+        segment(pixel, region).iter()
+        .map(|mask|self.recognize(mask).map(|(label, _)|label).unwrap_or(UNKNOWN))
+        .collect()
+    }
+}
+
+fn is_ink(color:[u8; 3]) -> bool {
+    0.299 * color[0] as f32 + 0.587 * color[1] as f32 + 0.114 * color[2] as f32 >= INK_LUMA
+}
+
+pub fn segment(pixel:&dyn Fn(u32, u32) -> [u8; 3], region:Region) -> Vec<Mask> {
+    let width = region.width as usize;
+    let height = region.height as usize;
+    INK.with_borrow_mut(|ink|{
+        ink.clear();
+        ink.extend((0..height).flat_map(|y|(0..width).map(move|x|(x, y))).map(|(x, y)|is_ink(pixel(region.x + x as u32, region.y + y as u32))));
+        segment_ink(ink, width, height)
+    })
+}
+
+fn segment_ink(ink:&[bool], width:usize, height:usize) -> Vec<Mask> {
+    let column_has_ink = |x:usize|(0..height).any(|y|ink[y * width + x]);
+    let row_has_ink = |y:usize|(0..width).any(|x|ink[y * width + x]);
+    let (Some(top), Some(bottom)) = ((0..height).find(|y|row_has_ink(*y)), (0..height).rfind(|y|row_has_ink(*y))) else {
+        return Vec::new();
+    };
+    let mut masks = Vec::new();
+    let mut x = 0;
+    while x < width {
+        if !column_has_ink(x) {
+            x += 1;
+            continue;
+        }
+        let start = x;
+        while x < width && column_has_ink(x) {
+            x += 1;
+        }
+        let bits = (top..=bottom).flat_map(|y|(start..x).map(move|x|(x, y))).map(|(x, y)|ink[y * width + x]).collect();
+        masks.push(Mask {
+            width: x - start,
+            height: bottom - top + 1,
+            bits,
+        });
+    }
+    masks
+}
+
+fn correlation(a:&[f32], b:&[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (a, b) in a.iter().zip(b) {
+        cov += (a - mean_a) * (b - mean_b);
+        var_a += (a - mean_a) * (a - mean_a);
+        var_b += (b - mean_b) * (b - mean_b);
+    }
+    if var_a == 0.0 || var_b == 0.0 {
+        return if var_a == var_b && mean_a == mean_b { 1.0 } else { 0.0 };
+    }
+    cov / (var_a * var_b).sqrt()
+}
@@ -0,0 +1,75 @@
+use std::{io::{BufRead, BufReader}, process::{Child, Stdio}, sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}}, time::Duration};
+
+use parking_lot::Mutex;
+
+use crate::{Opt, control::Control, ml::shell_command, timing::now_ms};
+
+const RESTART_DELAY:Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct Handoff {
+    last_touch: AtomicU64,
+    active: AtomicBool,
+    child: Mutex<Option<Child>>,
+}
+impl Handoff {
+    pub fn spawn(device:String, opt:Opt, control:Arc<Control>) -> Arc<Self> {
+        let handoff = Arc::new(Self::default());
+        let watcher = handoff.clone();
+        std::thread::spawn(move||{
+            while !control.is_shutdown() {
+                watcher.watch(&device, &opt);
+                if control.is_shutdown() {
+                    break;
+                }
+                std::thread::sleep(RESTART_DELAY);
+            }
+        });
+        handoff
+    }
+
+    fn watch(&self, device:&str, opt:&Opt) {
+        let mut child = match shell_command(device, opt, &["getevent", "-ql"]).stdout(Stdio::piped()).stderr(Stdio::null()).spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                println!("Failed to watch input events: {err}");
+                return;
+            },
+        };
+        let Some(stdout) = child.stdout.take() else {
+            return;
+        };
+        *self.child.lock() = Some(child);
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.contains("EV_ABS") || line.contains("EV_KEY") {
+                self.last_touch.store(now_ms(), Ordering::SeqCst);
+            }
+        }
+        if let Some(mut child) = self.child.lock().take() {
+            let _ = child.wait();
+        }
+    }
+
+    pub fn manual_input(&self, quiet:Duration) -> bool {
+        let last_touch = self.last_touch.load(Ordering::SeqCst);
+        let active = last_touch > 0 && now_ms().saturating_sub(last_touch) < quiet.as_millis() as u64;
+        let was_active = self.active.swap(active, Ordering::SeqCst);
+        if active && !was_active {
+            println!("Manual input detected, pausing until {}s without touches", quiet.as_secs());
+        }
+        else if !active && was_active {
+            println!("No manual input for {}s, resuming", quiet.as_secs());
+        }
+        active
+    }
+
+    pub fn stop(&self) {
+        if let Some(mut child) = self.child.lock().take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
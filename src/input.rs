@@ -0,0 +1,195 @@
+use std::{fmt::Write, process::Command, time::Duration};
+
+use clap::Args;
+use rand::Rng;
+
+use crate::{Opt, Services, device, game::ENDOR_LAYOUT, glyphs::Region};
+
+#[derive(Args, Debug, Clone)]
+pub struct Humanize {
+    #[clap(id = "tap_jitter", long = "tap-jitter", default_value_t = 0)]
+    pub tap_jitter: u32,
+    #[clap(id = "action_delay_min_ms", long = "action-delay-min-ms", default_value_t = 0)]
+    pub delay_min_ms: u64,
+    #[clap(id = "action_delay_max_ms", long = "action-delay-max-ms", default_value_t = 0)]
+    pub delay_max_ms: u64,
+    #[clap(id = "idle_chance", long = "idle-chance", default_value_t = 0.0)]
+    pub idle_chance: f64,
+    #[clap(id = "idle_max_ms", long = "idle-max-ms", default_value_t = 5000)]
+    pub idle_max_ms: u64,
+}
+impl Humanize {
+    /// Picks where to tap `button`: its centre moved by up to `tap_jitter` pixels, kept inside the button and on the screen.
+    fn jitter(&self, button:Region) -> (u32, u32) {
+        let (x, y) = button.centre();
+        if self.tap_jitter == 0 {
+            return (x, y);
+        }
+        let radius = self.tap_jitter as i64;
+        let mut rng = rand::rng();
+        let x = x as i64 + rng.random_range(-radius..=radius);
+        let y = y as i64 + rng.random_range(-radius..=radius);
+        (clamp(x, button.x, button.width, ENDOR_LAYOUT.screen.0), clamp(y, button.y, button.height, ENDOR_LAYOUT.screen.1))
+    }
+
+    fn spread(&self) -> u64 {
+        self.delay_max_ms.saturating_sub(self.delay_min_ms)
+    }
+
+    fn delay(&self) -> Duration {
+        let extra = match self.spread() {
+            0 => 0,
+            spread => rand::rng().random_range(0..=spread),
+        };
+        Duration::from_millis(self.delay_min_ms + extra)
+    }
+
+    fn idle(&self) -> Option<Duration> {
+        if self.idle_chance <= 0.0 || self.idle_max_ms == 0 {
+            return None;
+        }
+        let mut rng = rand::rng();
+        if !rng.random_bool(self.idle_chance.min(1.0)) {
+            return None;
+        }
+        Some(Duration::from_millis(rng.random_range(self.idle_max_ms / 2..=self.idle_max_ms)))
+    }
+
+    pub fn pause(&self) {
+        if let Some(idle) = self.idle() {
+            println!("Idling for {}ms", idle.as_millis());
+            std::thread::sleep(idle);
+        }
+        let delay = self.delay();
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+fn clamp(value:i64, start:u32, length:u32, screen:u32) -> u32 {
+    let last = screen.saturating_sub(1);
+    let low = start.min(last);
+    let high = (start + length.max(1) - 1).min(last);
+    value.clamp(low as i64, high as i64) as u32
+}
+
+pub fn tap_at(device:&str, opt:&Opt, services:&Services, x:u32, y:u32) {
+    let (x, y) = (x.to_string(), y.to_string());
+    let mut command = if opt.local {
+        Command::new("input")
+    }
+    else {
+        let mut command = Command::new("adb");
+        command.arg("-s").arg(device).arg("shell").arg("input");
+        command
+    };
+    if let Err(err) = device::run(command.arg("tap").arg(&x).arg(&y), opt, &services.adb_log) {
+        println!("Failed to tap {x},{y}: {err}");
+    }
+}
+
+pub fn adb_tap(device:&str, opt:&Opt, services:&Services, button:Region) {
+    let (x, y) = opt.humanize.jitter(button);
+    tap_at(device, opt, services, x, y);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Tap(Region),
+    Sleep(Duration),
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TapSequence {
+    steps: Vec<Step>,
+}
+impl TapSequence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tap(mut self, button:Region) -> Self {
+        self.steps.push(Step::Tap(button));
+        self
+    }
+
+    pub fn sleep(mut self, ms:u64) -> Self {
+        self.steps.push(Step::Sleep(Duration::from_millis(ms)));
+        self
+    }
+
+    fn script(&self, humanize:&Humanize) -> String {
+        let mut script = String::new();
+        for step in &self.steps {
+            if !script.is_empty() {
+                script.push_str("; ");
+            }
+            let _ = match step {
+                Step::Tap(button) => {
+                    let (x, y) = humanize.jitter(*button);
+                    write!(script, "input tap {x} {y}")
+                },
+                Step::Sleep(duration) => {
+                    let extra = match humanize.spread() {
+                        0 => 0,
+                        spread => rand::rng().random_range(0..=spread),
+                    };
+                    write!(script, "sleep {:.3}", (*duration + Duration::from_millis(extra)).as_secs_f32())
+                },
+            };
+        }
+        script
+    }
+
+    pub fn run(&self, device:&str, opt:&Opt, services:&Services) {
+        let script = self.script(&opt.humanize);
+        let mut command = if opt.local {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(&script);
+            command
+        }
+        else {
+            let mut command = Command::new("adb");
+            command.arg("-s").arg(device).arg("shell").arg(&script);
+            command
+        };
+        if let Err(err) = device::run(&mut command, opt, &services.adb_log) {
+            println!("Failed to run tap sequence {script}: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn humanize(tap_jitter:u32) -> Humanize {
+        Humanize { tap_jitter, delay_min_ms: 0, delay_max_ms: 0, idle_chance: 0.0, idle_max_ms: 0 }
+    }
+
+    #[test]
+    fn jitter_stays_inside_the_button() {
+        let button = Region::around(540, 1440, 40, 20);
+        for _ in 0..500 {
+            let (x, y) = humanize(100).jitter(button);
+            assert!((button.x..button.x + button.width).contains(&x), "{x}");
+            assert!((button.y..button.y + button.height).contains(&y), "{y}");
+        }
+    }
+
+    #[test]
+    fn jitter_stays_on_the_screen() {
+        let (width, height) = ENDOR_LAYOUT.screen;
+        let button = Region::around(width - 4, height - 4, 64, 64);
+        for _ in 0..500 {
+            let (x, y) = humanize(100).jitter(button);
+            assert!(x < width && y < height, "{x},{y}");
+        }
+    }
+
+    #[test]
+    fn no_jitter_taps_the_centre() {
+        assert_eq!(humanize(0).jitter(Region::around(100, 200, 50, 30)), (100, 200));
+    }
+}
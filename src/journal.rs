@@ -0,0 +1,180 @@
+use std::{collections::{BTreeMap, BTreeSet}, io::Write, path::PathBuf, time::{Instant, SystemTime, UNIX_EPOCH}};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::ml::{Action, State, StateType};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub started: u64,
+    pub duration_secs: f64,
+    pub floors_reached: usize,
+    pub deepest_floor: String,
+    pub tiles_explored: usize,
+    pub fights: u64,
+    pub chests: u64,
+    pub deaths: u64,
+    pub outcome: String,
+}
+
+struct CurrentRun {
+    started: u64,
+    started_at: Instant,
+    floors: BTreeSet<String>,
+    deepest_floor: String,
+    explored_at_start: usize,
+    explored: usize,
+    fights: u64,
+    chests: u64,
+    deaths: u64,
+}
+impl CurrentRun {
+    fn new(state:&State) -> Self {
+        let explored = state.dungeon.explored_tiles();
+        Self {
+            started: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            started_at: Instant::now(),
+            floors: BTreeSet::new(),
+            deepest_floor: String::new(),
+            explored_at_start: explored,
+            explored,
+            fights: 0,
+            chests: 0,
+            deaths: 0,
+        }
+    }
+
+    fn finish(self, outcome:&str) -> RunRecord {
+        RunRecord {
+            started: self.started,
+            duration_secs: self.started_at.elapsed().as_secs_f64(),
+            floors_reached: self.floors.len(),
+            deepest_floor: self.deepest_floor,
+            tiles_explored: self.explored.saturating_sub(self.explored_at_start),
+            fights: self.fights,
+            chests: self.chests,
+            deaths: self.deaths,
+            outcome: outcome.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct OutcomeStats {
+    pub runs: u64,
+    pub avg_duration_secs: f64,
+    pub avg_tiles_explored: f64,
+    pub avg_fights: f64,
+    pub avg_chests: f64,
+    pub avg_deaths: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub runs: u64,
+    pub total_duration_secs: f64,
+    pub total_tiles_explored: usize,
+    pub total_fights: u64,
+    pub total_chests: u64,
+    pub total_deaths: u64,
+    pub deepest_floors: BTreeMap<String, u64>,
+    pub outcomes: BTreeMap<String, OutcomeStats>,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    current: Mutex<Option<CurrentRun>>,
+}
+impl Journal {
+    pub fn new(path:PathBuf) -> Self {
+        Self {
+            path,
+            current: Mutex::new(None),
+        }
+    }
+
+    pub fn record(&self, previous:&State, state:&State, action:&Action, last_action:&Action) {
+        let mut current = self.current.lock();
+        if let Action::GotoDungeon = action
+            && current.is_none() {
+            *current = Some(CurrentRun::new(state));
+        }
+        let Some(run) = current.as_mut() else {
+            return;
+        };
+        if let StateType::Dungeon = state.state_type {
+            if run.floors.insert(state.dungeon.floor_name().to_owned()) {
+                run.deepest_floor = state.dungeon.floor_name().to_owned();
+            }
+            run.explored = state.dungeon.explored_tiles();
+            run.deaths += state.dungeon.dead_characters().saturating_sub(previous.dungeon.dead_characters()) as u64;
+        }
+        match action {
+            action if action.is_attack() && !last_action.is_attack() => run.fights += 1,
+            Action::OpenChest | Action::OpenChestMagical => run.chests += 1,
+            _ => {},
+        }
+        let outcome = match action {
+            Action::ReturnToTown(_, _) => "ReturnToTown",
+            Action::Resurrect => "Resurrect",
+            _ => return,
+        };
+        let record = current.take().unwrap().finish(outcome);
+        drop(current);
+        self.append(&record);
+    }
+
+    pub fn finish(&self, outcome:&str) {
+        let Some(run) = self.current.lock().take() else {
+            return;
+        };
+        self.append(&run.finish(outcome));
+    }
+
+    fn append(&self, record:&RunRecord) {
+        let result = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)
+        .and_then(|mut file|writeln!(file, "{}", serde_json::to_string(record).unwrap()));
+        if let Err(err) = result {
+            println!("Failed to write journal {}: {err}", self.path.display());
+        }
+    }
+
+    pub fn records(&self) -> Vec<RunRecord> {
+        let Ok(data) = std::fs::read_to_string(&self.path) else {
+            return Vec::new();
+        };
+        data.lines().filter_map(|line|serde_json::from_str(line).ok()).collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        for record in self.records() {
+            stats.runs += 1;
+            stats.total_duration_secs += record.duration_secs;
+            stats.total_tiles_explored += record.tiles_explored;
+            stats.total_fights += record.fights;
+            stats.total_chests += record.chests;
+            stats.total_deaths += record.deaths;
+            if !record.deepest_floor.is_empty() {
+                *stats.deepest_floors.entry(record.deepest_floor.clone()).or_default() += 1;
+            }
+            let outcome = stats.outcomes.entry(record.outcome.clone()).or_default();
+            outcome.runs += 1;
+            outcome.avg_duration_secs += record.duration_secs;
+            outcome.avg_tiles_explored += record.tiles_explored as f64;
+            outcome.avg_fights += record.fights as f64;
+            outcome.avg_chests += record.chests as f64;
+            outcome.avg_deaths += record.deaths as f64;
+        }
+        for outcome in stats.outcomes.values_mut() {
+            let runs = outcome.runs as f64;
+            outcome.avg_duration_secs /= runs;
+            outcome.avg_tiles_explored /= runs;
+            outcome.avg_fights /= runs;
+            outcome.avg_chests /= runs;
+            outcome.avg_deaths /= runs;
+        }
+        stats
+    }
+}
@@ -0,0 +1,306 @@
+//! Core of endorbot: screen capture, state detection, decisions and device input,
+//! usable without the command line front end.
+//!
+//! [`Bot`] ties these together one frame at a time. Frames come from
+//! [`pipeline::spawn_capture`] or from [`pipeline::CapturedFrame::from_image`] when
+//! replaying screenshots, and settings come from [`Opt`], usually parsed with clap
+//! and merged with [`config::Config`]. Loaded templates and shared logs travel
+//! separately in [`Services`].
+
+use std::{collections::VecDeque, path::PathBuf, sync::Arc};
+
+use clap::{ArgAction, Parser, Subcommand};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::{buttoncmd::ButtonCommand, buttons::ButtonBank, classifier::{ClassifierMode, ScreenClassifier}, classifiercmd::ClassifierCommand, ctl::CtlCommand, device::AdbLog, glyphcmd::GlyphCommand, glyphs::GlyphSet, input::Humanize, mapcmd::MapCommand, ml::{Action, Coords, State, StateType}, ocr::OcrCache, party::PartyLayout, policy::Policy, schedule::Schedule, screencap::CaptureBackend, stop::StopConditions, trace::DecisionTrace};
+
+pub mod atlas;
+pub mod atlassync;
+pub mod bot;
+pub mod buttoncmd;
+pub mod buttons;
+pub mod calibrate;
+pub mod classifier;
+pub mod classifiercmd;
+pub mod config;
+pub mod control;
+pub mod ctl;
+pub mod daemon;
+pub mod daily;
+pub mod device;
+pub mod fight;
+pub mod floor;
+pub mod frames;
+pub mod game;
+pub mod glyphcmd;
+pub mod glyphs;
+pub mod handoff;
+pub mod input;
+pub mod journal;
+pub mod macros;
+pub mod mapcmd;
+pub mod mapview;
+pub mod metrics;
+pub mod ml;
+pub mod movement;
+pub mod notifier;
+pub mod ocr;
+pub mod par;
+pub mod party;
+pub mod pipeline;
+pub mod planner;
+pub mod policy;
+pub mod power;
+pub mod recorder;
+pub mod resources;
+pub mod runtime;
+pub mod schedule;
+pub mod screencap;
+pub mod server;
+pub mod stop;
+pub mod storage;
+pub mod strategy;
+pub mod template;
+pub mod throttle;
+pub mod tick;
+pub mod tiles;
+pub mod timing;
+pub mod trace;
+pub mod transfer;
+pub mod ws;
+
+pub use bot::{Bot, Decision, TickError};
+pub use game::GameAdapter;
+
+#[derive(Parser, Clone)]
+pub struct Opt {
+    #[clap(long, action, default_value_t = false)]
+    pub step: bool,
+    #[clap(long, action, default_value_t = false)]
+    pub no_action: bool,
+    #[clap(long, action, default_value_t = false)]
+    pub local: bool,
+    #[clap(long, action, default_value_t = false)]
+    pub timing: bool,
+    #[clap(long, action, default_value_t = false)]
+    pub screencap: bool,
+    #[clap(long, action, default_value_t = false)]
+    pub debug: bool,
+    #[clap(long)]
+    pub test: Option<PathBuf>,
+    #[clap(long, default_value = "endorbot.toml")]
+    pub config: PathBuf,
+    #[clap(long, default_value = "RF8W101PHWF")]
+    pub device: String,
+    #[clap(long, default_value = "0.0.0.0")]
+    pub bind: String,
+    #[clap(long, default_value_t = 8080)]
+    pub port: u16,
+    #[clap(long, default_value_t = 8081)]
+    pub ws_port: u16,
+    #[clap(long)]
+    pub token: Option<String>,
+    #[clap(long, default_value_t = 120)]
+    pub health_stale_secs: u64,
+    #[clap(long, value_enum, default_value_t = CaptureBackend::Webp)]
+    pub capture_backend: CaptureBackend,
+    #[clap(flatten)]
+    pub policy: Policy,
+    #[clap(flatten)]
+    pub party: PartyLayout,
+    #[clap(flatten)]
+    pub humanize: Humanize,
+    #[clap(flatten)]
+    pub schedule: Schedule,
+    #[clap(flatten)]
+    pub stop: StopConditions,
+    #[clap(long = "tick", value_parser = tick::parse_interval)]
+    pub tick_intervals: Vec<(String, u64)>,
+    #[clap(long, default_value_t = 150)]
+    pub tick_default_ms: u64,
+    #[clap(long, default_value_t = 3000)]
+    pub tick_max_backoff_ms: u64,
+    #[clap(long, default_value_t = 200)]
+    pub action_log_size: usize,
+    #[clap(long, default_value = "file://state")]
+    pub storage: String,
+    #[clap(long, default_value_t = 5)]
+    pub state_backups: usize,
+    #[clap(long, default_value = "atlas.json")]
+    pub atlas: PathBuf,
+    #[clap(long, default_value = "runs.jsonl")]
+    pub journal: PathBuf,
+    #[clap(long)]
+    pub atlas_sync_url: Option<String>,
+    #[clap(long)]
+    pub atlas_sync_token: Option<String>,
+    #[clap(long, default_value_t = 300)]
+    pub atlas_sync_secs: u64,
+    #[clap(long, default_value = "assets/glyphs")]
+    pub glyph_dir: PathBuf,
+    #[clap(long, default_value = "assets/buttons")]
+    pub button_dir: PathBuf,
+    #[clap(long)]
+    pub classifier_model: Option<PathBuf>,
+    #[clap(long, value_enum, default_value_t = ClassifierMode::Check)]
+    pub classifier_mode: ClassifierMode,
+    #[clap(long, default_value_t = 0.9)]
+    pub classifier_min_confidence: f32,
+    #[clap(long, action, default_value_t = false)]
+    pub daemon: bool,
+    #[clap(long, default_value = "logs")]
+    pub log_dir: PathBuf,
+    #[clap(long)]
+    pub telegram_token: Option<String>,
+    #[clap(long)]
+    pub telegram_chat_id: Option<String>,
+    #[clap(long)]
+    pub discord_webhook: Option<String>,
+    #[clap(long, action, default_value_t = false)]
+    pub notify_screenshot: bool,
+    #[clap(long, default_value_t = 10)]
+    pub unknown_state_alert: u32,
+    #[clap(long, default_value_t = 5)]
+    pub unknown_recovery_ticks: u32,
+    #[clap(long, default_value_t = 300)]
+    pub max_actions_per_minute: u32,
+    #[clap(long, default_value_t = 0)]
+    pub max_fights_per_hour: u32,
+    #[clap(long, default_value_t = 0)]
+    pub max_runtime_minutes: u64,
+    #[clap(long, default_value_t = 30)]
+    pub handoff_quiet_secs: u64,
+    #[clap(long, default_value_t = 10000)]
+    pub adb_timeout_ms: u64,
+    #[clap(long, default_value_t = 2)]
+    pub adb_retries: u32,
+    #[clap(long)]
+    pub game_package: Option<String>,
+    #[clap(long)]
+    pub game_activity: Option<String>,
+    #[clap(long, default_value_t = 3)]
+    pub foreground_check_after: u32,
+    #[clap(long, action = ArgAction::Set, default_value_t = true)]
+    pub keep_awake: bool,
+    #[clap(long, value_parser = power::parse_pin)]
+    pub unlock_pin: Option<String>,
+    #[clap(long = "unlock-point", value_parser = power::parse_point)]
+    pub unlock_pattern: Vec<(u32, u32)>,
+    /// Recognise the temple and resurrection screens, boss banners, the heal button, the energy counter
+    /// and the event badge. Their probes are not yet checked against screenshots.
+    #[clap(long)]
+    pub extra_screens: bool,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum Command {
+    Ctl {
+        #[clap(long, default_value = "http://127.0.0.1:8080")]
+        url: String,
+        #[clap(long)]
+        token: Option<String>,
+        #[clap(subcommand)]
+        command: CtlCommand,
+    },
+    Map {
+        #[clap(subcommand)]
+        command: MapCommand,
+    },
+    Glyphs {
+        #[clap(subcommand)]
+        command: GlyphCommand,
+    },
+    Buttons {
+        #[clap(subcommand)]
+        command: ButtonCommand,
+    },
+    Classifier {
+        #[clap(subcommand)]
+        command: ClassifierCommand,
+    },
+    RecordMacro {
+        out: PathBuf,
+    },
+    Calibrate {
+        #[clap(long, default_value = "layout.json")]
+        out: PathBuf,
+        #[clap(long, default_value = "caps")]
+        reference_dir: PathBuf,
+        #[clap(long, default_value = "calibration")]
+        save_dir: PathBuf,
+        #[clap(long)]
+        images: Option<PathBuf>,
+    },
+}
+
+/// Templates and models loaded at startup and the caches and logs shared by every thread.
+///
+/// Kept apart from [`Opt`] so settings stay plain data; cloning only copies the handles.
+#[derive(Clone, Default)]
+pub struct Services {
+    pub glyphs: Arc<GlyphSet>,
+    pub buttons: Arc<ButtonBank>,
+    pub classifier: Option<Arc<ScreenClassifier>>,
+    pub ocr_cache: Arc<OcrCache>,
+    pub adb_log: Arc<AdbLog>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    pub timestamp: u64,
+    pub state_type: StateType,
+    pub action: String,
+    pub position: Option<Coords>,
+    pub trace: Option<DecisionTrace>,
+}
+
+pub struct ActionLog {
+    entries: Mutex<VecDeque<LogEntry>>,
+    capacity: usize,
+    broadcaster: Arc<ws::Broadcaster>,
+    pending_trace: Mutex<Option<DecisionTrace>>,
+    last_trace: Mutex<Option<DecisionTrace>>,
+}
+impl ActionLog {
+    pub fn new(capacity:usize, broadcaster:Arc<ws::Broadcaster>) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            broadcaster,
+            pending_trace: Mutex::new(None),
+            last_trace: Mutex::new(None),
+        }
+    }
+    pub fn record_trace(&self, trace:DecisionTrace) {
+        *self.last_trace.lock() = Some(trace.clone());
+        *self.pending_trace.lock() = Some(trace);
+    }
+    pub fn last_trace(&self) -> Option<DecisionTrace> {
+        self.last_trace.lock().clone()
+    }
+    /// An entry for `action` decided on `state`, with the pending trace. Nothing is recorded
+    /// until it is passed to [`push`](ActionLog::push).
+    pub fn entry(&self, state:&State, action:&Action) -> LogEntry {
+        LogEntry {
+            timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            state_type: state.state_type.clone(),
+            action: action.to_string(),
+            position: state.get_position(),
+            trace: self.pending_trace.lock().take(),
+        }
+    }
+    pub fn push(&self, entry:LogEntry) {
+        self.broadcaster.publish_log(&entry);
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+    pub fn entries(&self) -> Vec<LogEntry> {
+        self.entries.lock().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,160 @@
+use std::{collections::BTreeMap, fmt::Display, path::Path};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::{glyphs::Region, input::TapSequence};
+
+/// Recorded taps have no known button around them, so jitter only moves them within this box.
+const TAP_BOX:(u32, u32) = (24, 24);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MacroStep {
+    Tap(u32, u32),
+    Wait(u64),
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Segment {
+    expect: Option<String>,
+    steps: Vec<MacroStep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Macro {
+    pub name: String,
+    segments: Vec<Segment>,
+}
+impl Macro {
+    pub fn new(name:&str) -> Self {
+        Self {
+            name: name.to_owned(),
+            segments: vec![Segment::default()],
+        }
+    }
+
+    pub fn parse(name:&str, text:&str) -> Result<Self, String> {
+        let mut script = Self::new(name);
+        for (row, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message:&str|format!("{name}:{}: {message}: {line}", row + 1);
+            let mut words = line.split_whitespace();
+            let command = words.next();
+            let mut number = ||words.next().ok_or_else(||error("missing argument"))?.parse::<u64>().map_err(|err|error(&err.to_string()));
+            match command {
+                Some("tap") => {
+                    let (x, y) = (number()?, number()?);
+                    script.push(MacroStep::Tap(x as u32, y as u32));
+                },
+                Some("wait") => {
+                    script.push(MacroStep::Wait(number()?));
+                },
+                Some("expect") => match line.split_whitespace().nth(1) {
+                    Some(state) => script.expect(state),
+                    None => return Err(error("missing state name")),
+                },
+                _ => return Err(error("expected tap <x> <y>, wait <ms> or expect <state>")),
+            }
+        }
+        Ok(script)
+    }
+
+    pub fn load(path:&Path) -> Result<Self, String> {
+        let name = path.file_stem().map(|stem|stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let text = std::fs::read_to_string(path).map_err(|err|format!("failed to read macro {}: {err}", path.display()))?;
+        Self::parse(&name, &text)
+    }
+
+    pub fn push(&mut self, step:MacroStep) {
+        if let Some(segment) = self.segments.last_mut() {
+            segment.steps.push(step);
+        }
+    }
+
+    pub fn expect(&mut self, state:&str) {
+        self.segments.push(Segment { expect: Some(state.to_owned()), steps: Vec::new() });
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.iter().all(|segment|segment.steps.is_empty())
+    }
+
+    pub fn expects(&self, segment:u8) -> Option<&str> {
+        self.segments.get(segment as usize)?.expect.as_deref()
+    }
+
+    pub fn sequence(&self, segment:u8) -> TapSequence {
+        self.segments.get(segment as usize).map(|segment|segment.steps.iter().fold(TapSequence::new(), |sequence, step|match *step {
+            MacroStep::Tap(x, y) => sequence.tap(Region::around(x, y, TAP_BOX.0, TAP_BOX.1)),
+            MacroStep::Wait(ms) => sequence.sleep(ms),
+        })).unwrap_or_default()
+    }
+}
+impl Display for Macro {
+    fn fmt(&self, f:&mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for segment in &self.segments {
+            if let Some(expect) = &segment.expect {
+                writeln!(f, "expect {expect}")?;
+            }
+            for step in &segment.steps {
+                match step {
+                    MacroStep::Tap(x, y) => writeln!(f, "tap {x} {y}")?,
+                    MacroStep::Wait(ms) => writeln!(f, "wait {ms}")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+impl TryFrom<String> for Macro {
+    type Error = String;
+
+    fn try_from(value:String) -> Result<Self, Self::Error> {
+        Self::load(Path::new(&value))
+    }
+}
+
+pub fn load_macro(value:&str) -> Result<Macro, String> {
+    Macro::load(Path::new(value))
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MacroProgress {
+    running: Option<(u8, u8)>,
+    ran: BTreeMap<u8, NaiveDate>,
+}
+impl MacroProgress {
+    pub fn running(&self) -> Option<(u8, u8)> {
+        self.running
+    }
+
+    pub fn start(&mut self, index:u8) {
+        self.running = Some((index, 0));
+    }
+
+    pub fn advance(&mut self, index:u8, segment:u8) {
+        self.running = Some((index, segment + 1));
+    }
+
+    pub fn finish(&mut self, today:NaiveDate) {
+        if let Some((index, _)) = self.running.take() {
+            self.ran.insert(index, today);
+        }
+    }
+
+    pub fn abort(&mut self) {
+        self.running = None;
+    }
+
+    pub fn due(&self, index:u8, today:NaiveDate) -> bool {
+        self.ran.get(&index).is_none_or(|ran|*ran < today)
+    }
+}
@@ -1,37 +1,144 @@
-use std::{collections::{HashMap, HashSet}, convert::Infallible, io::Write, path::PathBuf, sync::Arc};
+use std::{collections::BTreeMap, io::Write, panic::AssertUnwindSafe, sync::Arc, time::Instant};
 
-use astra::{Body, Request, ResponseBuilder};
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 use fast_image_resize::{PixelType, ResizeAlg, ResizeOptions};
 use image::{DynamicImage, GenericImageView, RgbaImage, codecs::webp::WebPEncoder};
 use ravif::{Encoder, Img};
 use rgb::FromSlice;
+use parking_lot::Mutex;
 use rkyv::rancor::Panic;
 
-use crate::{ml::{Action, Bitmap, State}, screencap::screencap};
-
-mod screencap;
-mod ml;
-
-#[derive(Parser, Clone)]
-struct Opt {
-    #[clap(long, action, default_value_t = false)]
-    step: bool,
-    #[clap(long, action, default_value_t = false)]
-    no_action: bool,
-    #[clap(long, action, default_value_t = false)]
-    local: bool,
-    #[clap(long, action, default_value_t = false)]
-    screencap: bool,
-    #[clap(long, action, default_value_t = false)]
-    debug: bool,
-    #[clap(long)]
-    test: Option<PathBuf>,
-}
+use endorbot_core::{ActionLog, Bot, Command, Opt, Services, TickError, atlas::Atlas, atlassync::AtlasSync, buttoncmd, buttons::ButtonBank, calibrate, classifier::ScreenClassifier, classifiercmd, config::Config, ctl, daemon::{self, Heartbeat, Systemd}, glyphcmd, glyphs::GlyphSet, handoff::Handoff, journal::Journal, mapcmd, ml::{Action, State, StateType}, notifier::Notifier, pipeline, power, recorder, schedule::Scheduler, screencap::{self, screencap}, server, stop::{PARK_TIMEOUT, StopReason, StopWatch}, storage::Storage, throttle::{Throttle, Throttled}, tick::TickRate, timing, transfer, ws};
+
 //  1080x2408
 fn main() {
-    let device = "RF8W101PHWF";
-    let opt = Opt::parse();
+    let matches = Opt::command().get_matches();
+    let mut opt = Opt::from_arg_matches(&matches).unwrap_or_else(|err|err.exit());
+    match Config::load(&opt, &matches) {
+        Ok(config) => config.apply(&mut opt, &matches),
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        },
+    }
+    if !opt.extra_screens && opt.policy.visits_extra_screens() {
+        println!("Boss handling, the energy check and event mode need extra_screens to read their screens");
+    }
+    let mut services = Services::default();
+    match GlyphSet::load(&opt.glyph_dir) {
+        Ok(glyphs) => {
+            if !glyphs.is_empty() {
+                println!("Loaded {} glyph templates from {}", glyphs.len(), opt.glyph_dir.display());
+            }
+            services.glyphs = Arc::new(glyphs);
+        },
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        },
+    }
+    match ButtonBank::load(&opt.button_dir) {
+        Ok(buttons) => {
+            if !buttons.is_empty() {
+                println!("Loaded {} button templates from {}", buttons.len(), opt.button_dir.display());
+            }
+            services.buttons = Arc::new(buttons);
+        },
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        },
+    }
+    if let Some(model) = &opt.classifier_model {
+        match ScreenClassifier::load(model) {
+            Ok(classifier) => {
+                println!("Loaded screen classifier with {} labels from {}", classifier.labels().len(), model.display());
+                services.classifier = Some(Arc::new(classifier));
+            },
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            },
+        }
+    }
+    let device = opt.device.as_str();
+
+    if let Some(Command::Buttons { command }) = &opt.command {
+        match buttoncmd::run(&opt, &services, command) {
+            Ok(response) => println!("{response}"),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    if let Some(Command::Classifier { command }) = &opt.command {
+        match classifiercmd::run(&opt, &services, command) {
+            Ok(response) => println!("{response}"),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    if let Some(Command::Glyphs { command }) = &opt.command {
+        if let Err(err) = glyphcmd::run(&opt, &services, command) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::RecordMacro { out }) = &opt.command {
+        match recorder::run(&opt, &services, out) {
+            Ok(response) => println!("{response}"),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    if let Some(Command::Calibrate { out, reference_dir, save_dir, images }) = &opt.command {
+        match calibrate::run(&opt, &services, out, reference_dir, save_dir, images.as_deref()) {
+            Ok(response) => println!("{response}"),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    if let Some(Command::Ctl { url, token, command }) = &opt.command {
+        match ctl::run(url, token.as_deref().or(opt.token.as_deref()), command) {
+            Ok(response) => println!("{response}"),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    if let Some(Command::Map { command }) = &opt.command {
+        let result = Storage::open(&opt.storage, &opt.atlas, opt.state_backups)
+        .map_err(mapcmd::MapError::from)
+        .and_then(|mut storage|mapcmd::run(&mut storage, command));
+        match result {
+            Ok(response) => println!("{response}"),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
 
     if let Some(test) = &opt.test {
         if opt.local {
@@ -62,6 +169,7 @@ fn main() {
                 Ok(())
             }
 
+            #[allow(dead_code)]
             fn write_avif_to_stdout(img: &DynamicImage) {
                 let (w, h) = img.dimensions();
                 let img = Img::new(img.as_rgba8().unwrap().as_rgba(), w as usize, h as usize);
@@ -75,7 +183,7 @@ fn main() {
                 std::io::stderr().lock().write_all(&data).unwrap();
             }
 
-            let image = screencap::screencap(device, &opt).unwrap();
+            let image = screencap::screencap(device, &opt, &services).unwrap();
             //write_avif_to_stdout(&image);
             write_webp_to_stdout(&image).unwrap();
             //let mut stdout = std::io::stdout().lock();
@@ -83,7 +191,7 @@ fn main() {
         }
         else {
             let image = screencap::load_png_from_file(test.to_path_buf()).unwrap();
-            let bitmap = screencap::bitmap_from_image(&image, &opt).unwrap();
+            let _bitmap = screencap::bitmap_from_image(&image, &opt).unwrap();
             /*match ml::get_state(State::default(), &bitmap) {
                 Ok(state) => {
                     println!("{state:?}");
@@ -98,11 +206,10 @@ fn main() {
 
     if opt.screencap {
         if true {
-            let webp = screencap(device, &opt).unwrap();
+            let webp = screencap(device, &opt, &services).unwrap();
 
             fn write_webp_to_stdout(img: &DynamicImage) -> image::ImageResult<()> {
-                let stdout = std::io::stdout();
-                let mut out = stdout.lock();
+                let mut out = Vec::new();
 
                 let w = img.width() / 2;
                 let h = img.height() / 2;
@@ -123,270 +230,366 @@ fn main() {
                     image::ExtendedColorType::Rgba8
                 )?;
 
-                out.flush().ok();
+                transfer::write_framed(&mut std::io::stdout().lock(), &out)?;
                 Ok(())
             }
             write_webp_to_stdout(&webp).unwrap();
 
         }
         else {
-            let bitmap = screencap::screencap_bitmap(device, &opt).unwrap();
+            let bitmap = screencap::screencap_bitmap(device, &opt, &services).unwrap();
             let b = rkyv::to_bytes::<Panic>(&bitmap).unwrap();
             //println!("{}", b.len());
-            std::io::stdout().write_all(&b).unwrap();
+            transfer::write_framed(&mut std::io::stdout().lock(), &b).unwrap();
         }
         return;
     }
 
-    let old_state = std::sync::Arc::new(parking_lot::Mutex::new(if let Ok(state) = std::fs::read_to_string("state") {
-        serde_json::from_str(&state).unwrap_or(State::default())
+    if opt.daemon {
+        match daemon::redirect_output(&opt.log_dir) {
+            Ok(path) => println!("Daemon started, logging to {}", path.display()),
+            Err(err) => {
+                eprintln!("Failed to log to {}: {err}", opt.log_dir.display());
+                std::process::exit(1);
+            },
+        }
     }
-    else {
-        State::default()
-    }));
-
-    let http_state = old_state.clone();
-
-    std::thread::spawn(move|| {
-        astra::Server::bind("0.0.0.0:8080").serve(move|req:Request,info| {
-            if req.uri().path() == "/data" {
-                let j = {
-                    let guard = http_state.try_lock_for(std::time::Duration::from_millis(5000)).unwrap();
-                    serde_json::to_string(&*guard).unwrap()
-                };
-                ResponseBuilder::new()
-                .header("Content-Type", "application/json")
-                .body(Body::new(j))
-                .unwrap()
-            }
-            else {
-                ResponseBuilder::new()
-                .header("Content-Type", "text/html")
-                .body(Body::new(r#"
-                <!DOCTYPE html>
-                <html>
-                <head>
-                <title>Endorbot</title>
-                <style>
-                #map {
-                    display: flex;
-                    flex-direction: column;
-                }
-                .row {
-                    display: flex;
-                }
-                .tile {
-                    position: relative;
-                    width: 16px;
-                    height: 16px;
-                    border: 1px solid #f1f1f1;
-                }
-                .tile[explored] {
-                    background-color: #bfbfbf;
-                    border: 1px solid #000;
-                }
-                .tile[north-passable] {
-                    border-top: 1px solid transparent;
-                }
-                .tile[south-passable] {
-                    border-bottom: 1px solid transparent;
-                }
-                .tile[east-passable] {
-                    border-right: 1px solid transparent;
-                }
-                .tile[west-passable] {
-                    border-left: 1px solid transparent;
-                }
-                .tile[current]:after {
-                    content: 'x';
-                    position: absolute;
-                    left: 0;
-                    top: 0;
-                    width: 100%;
-                    height: 100%;
-                    text-align: center;
-                    font-size: 0.8em;
-                }
-                </style>
-                <script>
-                var map_size = {x: 0, y: 0};
-                var map_rows = [];
-
-                function update_map(map, state) {
-                    var dungeon = state.dungeon;
-                    var current_tile = document.querySelector('.tile[current]');
-                    for(const tile of dungeon.tiles) {
-                        if(tile.position.y >= map_size.y) {
-                            for(var y = map_size.y; y <= tile.position.y; ++y) {
-                                var row = document.createElement('div');
-                                row.className = 'row';
-                                var cols = [];
-                                for(var x = 0; x < map_size.x; ++x) {
-                                    var col = document.createElement('div');
-                                    col.className = 'tile';
-                                    row.appendChild(col);
-                                    cols.push(col);
-                                }
-                                map.appendChild(row);
-                                map_rows.push(cols);
-                            }
-                            map_size.y = tile.position.y + 1;
-                        }
-                        if(tile.position.x >= map_size.x) {
-                            for(var y = 0; y < map_size.y; ++y) {
-                                for(var x = map_size.x; x <= tile.position.x; ++x) {
-                                    var col = document.createElement('div');
-                                    col.className = 'tile';
-                                    map.children[y].appendChild(col);
-                                    map_rows[y].push(col);
-                                }
-                            }
-                            map_size.x = tile.position.x + 1;
-                        }
-                        var e = map_rows[tile.position.y][tile.position.x];
-                        if(tile.north_passable)
-                            e.setAttribute('north-passable', '');
-                        if(tile.south_passable)
-                            e.setAttribute('south-passable', '');
-                        if(tile.east_passable)
-                            e.setAttribute('east-passable', '');
-                        if(tile.west_passable)
-                            e.setAttribute('west-passable', '');
-                        e.setAttribute('explored', '');
-                        if(tile.position.x == dungeon.info.coordinates.x && tile.position.y == dungeon.info.coordinates.y) {
-                            if(current_tile)
-                                current_tile.removeAttribute('current');
-                            e.setAttribute('current', '');
-                        }
-                    }
-                    setTimeout(refresh_data, 1000);
-                }
+    let mut systemd = Systemd::from_env();
+    let heartbeat = Arc::new(Heartbeat::new());
 
-                function refresh_data() {
-                    var request = new XMLHttpRequest();
-                    request.open("GET", "/data");
-                    request.onreadystatechange = function () {
-                        if (this.readyState == 4) {
-                            if(this.status == 200) {
-                                var map = document.getElementById('map');
-                                update_map(map, JSON.parse(this.responseText));
-                                //console.log(this.responseText);
-                                //document.getElementById("container")
-                                //.innerHTML = this.responseText;
-                            }
-                            else
-                                console.info(this.status);
-                        }
-                    }
-                    request.send();
-                }
+    let mut storage = Storage::open(&opt.storage, &opt.atlas, opt.state_backups).expect("Failed to open storage");
+    let state = match storage.load_state() {
+        Ok(state) => state.unwrap_or_default(),
+        Err(err) => {
+            println!("Failed to load state, refusing to overwrite it: {err}");
+            return;
+        },
+    };
+    let mut atlas = storage.load_atlas().unwrap_or_else(|err|{
+        println!("Failed to load atlas: {err}");
+        Atlas::default()
+    });
+    let mut atlas_sync = AtlasSync::new(&opt);
+    if let Some(atlas_sync) = &mut atlas_sync {
+        sync_atlas(atlas_sync, &mut atlas);
+    }
+    let tile_stats = Arc::new(Mutex::new(atlas.tile_stats()));
 
-                refresh_data();
-                </script>
-                </head>
-                <body>
-                    <div id="map"></div>
-                </body>
-                </html>
-                "#))
-                .unwrap()
+    let broadcaster = Arc::new(ws::Broadcaster::default());
+    let log = Arc::new(ActionLog::new(opt.action_log_size, broadcaster.clone()));
+    println!("Using strategy {}", opt.policy.strategy);
+    let mut bot = Bot::new(opt.clone(), services.clone(), state, atlas, log.clone());
+    let control = bot.control();
+    {
+        let control = control.clone();
+        ctrlc::set_handler(move||{
+            if control.request_shutdown() {
+                println!("Forced exit");
+                std::process::exit(130);
             }
-        }).unwrap();
+            println!("Shutting down after the current tick (press Ctrl-C again to force)");
+        }).expect("Failed to set Ctrl-C handler");
+    }
+
+    let snapshots = bot.subscribe();
+    let frames = bot.frames();
+    let metrics = bot.metrics();
+    let notifier = Notifier::new(&opt, frames.clone());
+    let journal = Arc::new(Journal::new(opt.journal.clone()));
+    server::spawn(&format!("{}:{}", opt.bind, opt.port), server::Context {
+        ws_port: opt.ws_port,
+        token: opt.token.clone(),
+        state: snapshots.clone(),
+        control: control.clone(),
+        frames: frames.clone(),
+        log: log.clone(),
+        metrics: metrics.clone(),
+        journal: journal.clone(),
+        tile_stats: tile_stats.clone(),
+        heartbeat: heartbeat.clone(),
+        opt: opt.clone(),
+        services: services.clone(),
     });
+    ws::spawn(&format!("{}:{}", opt.bind, opt.ws_port), snapshots, broadcaster.clone(), control.clone(), opt.token.clone());
 
     let step = opt.step;
 
-    let main_state = old_state.clone();
-    let mut last_action = Action::CloseAd;
+    let mut summary = RunSummary::new();
+    let mut stop_watch = StopWatch::new(&opt.stop);
+    let mut stopping:Option<(StopReason, Instant)> = None;
+    let mut atlas_saved = Instant::now();
+    let mut tick_rate = TickRate::new(&opt.tick_intervals, opt.tick_default_ms, opt.tick_max_backoff_ms);
+    let mut unknown_states = 0;
+    let mut disconnected = false;
+    let _stay_awake = (opt.keep_awake && !opt.no_action).then(||power::StayAwake::enable(&opt.device, &opt, &services));
+    let capture = pipeline::spawn_capture(opt.device.clone(), opt.clone(), services.clone(), control.clone(), metrics.clone());
+    let mut throttle = Throttle::new(&opt);
+    let mut scheduler = Scheduler::new(&opt.schedule);
+    let mut scheduled = true;
+    let handoff = (opt.handoff_quiet_secs > 0 && !opt.no_action).then(||Handoff::spawn(opt.device.clone(), opt.clone(), control.clone()));
+    let handoff_quiet = std::time::Duration::from_secs(opt.handoff_quiet_secs);
+    let mut not_before = Instant::now();
+    systemd.ready();
+    systemd.status("Running");
     loop {
-        let snapshot = {
-            let guard = main_state.lock();
-            guard.clone()
+        heartbeat.beat();
+        systemd.watchdog();
+        if control.is_shutdown() {
+            break;
+        }
+        if let Some(atlas_sync) = &mut atlas_sync
+            && atlas_sync.poll(bot.atlas_mut()) {
+            *tile_stats.lock() = bot.atlas().tile_stats();
+        }
+        if control.take_save_request() {
+            if let Some(previous) = bot.apply_tile_commands() {
+                broadcaster.publish(&previous, &bot.state());
+            }
+            save_state(&mut storage, &bot.state());
+        }
+        if !control.should_tick() {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            continue;
+        }
+        let schedule = scheduler.poll(control.schedule_override());
+        control.set_schedule(schedule);
+        if schedule.state.runs() != scheduled {
+            scheduled = schedule.state.runs();
+            notifier.notify(&format!("Schedule {}", schedule.state));
+            if !scheduled {
+                journal.finish("Scheduled stop");
+            }
+        }
+        if !scheduled {
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            continue;
+        }
+        if handoff.as_ref().is_some_and(|handoff|handoff.manual_input(handoff_quiet)) {
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            continue;
+        }
+        let Some(frame) = capture.take_after(not_before, &control) else {
+            break;
         };
-        let (state, action) = run(&opt, device, snapshot, last_action);
-        last_action = action;
-        match action {
-            Action::CloseAd => {
-                std::thread::sleep(std::time::Duration::from_millis(200));
-            },
-            Action::TeleportToCity => {
-                std::thread::sleep(std::time::Duration::from_millis(200));
-            },
-            Action::CancelTeleportToCity => {
-            },
-            Action::GotoTown => {
-                std::thread::sleep(std::time::Duration::from_millis(200));
-            },
-            Action::GotoDungeon => {
-                std::thread::sleep(std::time::Duration::from_millis(200));
-            },
-            Action::GoDown => {
-                std::thread::sleep(std::time::Duration::from_millis(200));
+        let mut stop = false;
+        let result = if opt.daemon {
+            match std::panic::catch_unwind(AssertUnwindSafe(||bot.tick(frame))) {
+                Ok(result) => result,
+                Err(_) => {
+                    heartbeat.restarted();
+                    notifier.notify("Tick panicked, restarting the loop");
+                    bot.reset();
+                    not_before = Instant::now() + std::time::Duration::from_secs(5);
+                    continue;
+                },
             }
-            Action::FindFight(_move_direction, _target_tile) => {
-            },
-            Action::Fight => {
-                std::thread::sleep(std::time::Duration::from_millis(300));
-            //  break;
-            },
-            Action::OpenChest => {
-            },
-            Action::OpenChestMagical => {
+        }
+        else {
+            bot.tick(frame)
+        };
+        let mut decision = match result {
+            Ok(decision) => decision,
+            Err(err) => {
+                match err {
+                    TickError::DeviceDisconnected(err) => {
+                        if !disconnected {
+                            notifier.notify(&format!("Device disconnected, screen capture failed: {err}"));
+                        }
+                        disconnected = true;
+                    },
+                    TickError::ScreenOff => {
+                        disconnected = false;
+                        if !opt.no_action && power::screen_on(&opt.device, &opt, &services) != Some(true) {
+                            bot.wait();
+                            power::wake(&opt.device, &opt, &services);
+                        }
+                    },
+                    TickError::UnknownState => {
+                        disconnected = false;
+                        unknown_states += 1;
+                        if unknown_states == opt.unknown_state_alert {
+                            notifier.notify(&format!("{unknown_states} unknown states in a row"));
+                        }
+                        if unknown_states >= opt.foreground_check_after
+                            && bot.adapter().in_foreground(&opt.device, &opt, &services) == Some(false) {
+                            notifier.notify("Game is not in the foreground, relaunching");
+                            bot.execute(Action::LaunchGame);
+                            unknown_states = 0;
+                            not_before = bot.wait() + tick_rate.interval(&Action::LaunchGame);
+                            continue;
+                        }
+                        if opt.unknown_recovery_ticks > 0 && !opt.no_action
+                            && unknown_states.is_multiple_of(opt.unknown_recovery_ticks) {
+                            let action = if (unknown_states / opt.unknown_recovery_ticks) % 2 == 1 {
+                                Action::CloseAd
+                            }
+                            else {
+                                Action::Back
+                            };
+                            println!("{unknown_states} unknown states in a row, trying {action}");
+                            bot.execute(action);
+                        }
+                    },
+                }
+                if let Some(reason) = stop_watch.error() {
+                    stopping = Some((reason, Instant::now()));
+                    break;
+                }
+                if step {
+                    break;
+                }
+                not_before = Instant::now() + std::time::Duration::from_millis(if disconnected { 5000 } else { 500 });
+                continue;
             },
-            Action::ReturnToTown(_on_city_tile, _move_direction) => {
+        };
+        let action = decision.action;
+        let last_action = bot.last_action();
+        if !opt.no_action {
+            match throttle.permit(&action, &last_action) {
+                Ok(()) => bot.execute(action),
+                Err(throttled @ Throttled::Runtime(_)) => {
+                    notifier.notify(&throttled.to_string());
+                    break;
+                },
+                Err(throttled @ (Throttled::Actions(wait) | Throttled::Fights(wait))) => {
+                    println!("{throttled}, skipping {action}");
+                    not_before = Instant::now() + wait;
+                    continue;
+                },
+            }
+        }
+        if disconnected {
+            notifier.notify("Device reconnected");
+        }
+        disconnected = false;
+        unknown_states = 0;
+        tick_rate.observe_frame(decision.fingerprint);
+        let previous = decision.previous.clone();
+        let state = &mut decision.state;
+        journal.record(&previous, state, &action, &last_action);
+        if bot.atlas_mut().observe(&previous, state, &action, &last_action) {
+            *tile_stats.lock() = bot.atlas().tile_stats();
+        }
+        summary.record(&action);
+        if state.dungeon.in_boss_fight() && !previous.dungeon.in_boss_fight() {
+            notifier.notify(&format!("Boss reached on {}", state.dungeon.floor_name()));
+        }
+        match action {
+            Action::CancelResurrect => {
+                notifier.notify("Cannot afford resurrection, need manual resurrection");
+                stop = true;
             },
-            Action::Resurrect => {
-                println!("Need manual resurrection");
-                break;
+            Action::Resurrect if !opt.extra_screens => {
+                notifier.notify("Need manual resurrection");
+                stop = true;
             },
+            _ => {},
         }
-        let snapshot = {
-            let mut guard = main_state.lock();
-            *guard = state;
-            guard.clone()
-        };
-        std::fs::write("state", serde_json::to_string(&snapshot).unwrap()).unwrap();
-        if step {
+        stop_watch.observe(&previous, state);
+        if stopping.is_none()
+            && let Some(reason) = stop_watch.check(state) {
+            notifier.notify(&format!("Stop condition reached, {reason}, returning to town"));
+            stopping = Some((reason, Instant::now()));
+        }
+        if let Some((reason, since)) = &stopping {
+            state.parking = true;
+            if !reason.needs_parking() || matches!(state.state_type, StateType::City(_)) {
+                stop = true;
+            }
+            else if since.elapsed() >= PARK_TIMEOUT {
+                println!("Could not reach town within {}s, stopping anyway", PARK_TIMEOUT.as_secs());
+                stop = true;
+            }
+        }
+        let snapshot = bot.commit(decision);
+        heartbeat.ticked();
+        save_state(&mut storage, &snapshot);
+        if let Err(err) = storage.record_action(&snapshot, &action) {
+            println!("Failed to record action: {err}");
+        }
+        if bot.atlas().is_dirty() && atlas_saved.elapsed() >= std::time::Duration::from_secs(10) {
+            save_atlas(&mut storage, bot.atlas_mut());
+            atlas_saved = Instant::now();
+        }
+        broadcaster.publish(&previous, &snapshot);
+        if let Some(change) = snapshot.dungeon.floor_changed() {
+            broadcaster.publish_floor_changed(change);
+        }
+        if opt.timing {
+            println!("Timing: {}", timing::tick_summary());
+        }
+        if step || stop || control.is_shutdown() {
             break;
         }
-        std::thread::sleep(std::time::Duration::from_millis(150));
+        not_before = bot.wait() + tick_rate.interval(&action);
+    }
+
+    systemd.stopping();
+    bot.wait();
+    control.request_shutdown();
+    if let Some(handoff) = &handoff {
+        handoff.stop();
+    }
+    journal.finish(&stopping.as_ref().map(|(reason, _)|format!("Stopped, {reason}")).unwrap_or_else(||"Shutdown".to_owned()));
+    let snapshot = bot.state();
+    save_state(&mut storage, &snapshot);
+    if let Some(atlas_sync) = &mut atlas_sync {
+        sync_atlas(atlas_sync, bot.atlas_mut());
+    }
+    if bot.atlas().is_dirty() {
+        save_atlas(&mut storage, bot.atlas_mut());
     }
+    summary.print(&snapshot);
+    if let Some((reason, _)) = &stopping {
+        notifier.notify(&stop_watch.summary(&snapshot, reason));
+    }
+    notifier.flush();
 }
 
-fn run(opt:&Opt, device:&str, old_state:State, last_action:Action) -> (State, Action) {
-    //let img = screencap::screencap(device, &opt).unwrap();
-    let img = screencap::screencap_webp(device, &opt).unwrap();
-    //println!("{:?} {:?}", img.get_info(), img.get_has_dead_characters());
-    //img.save_with_format("cap.png", image::ImageFormat::Png).unwrap();
-    let old_position = old_state.get_position();
-    let mut state = ml::get_state(old_state, &img).unwrap();
-    //println!("{:?}", state);
-    let action = ml::determine_action(&state, last_action, old_position);
-    if let Some(pos) = state.get_position() {
-        println!("position = {:?}", pos);
+fn sync_atlas(atlas_sync:&mut AtlasSync, atlas:&mut Atlas) {
+    match atlas_sync.sync(atlas) {
+        Ok(changed) => println!("Atlas synced, {changed} floors updated"),
+        Err(err) => println!("Atlas sync failed: {err}"),
     }
-    else {
-        println!("position = none");
+}
+
+fn save_state(storage:&mut Storage, state:&State) {
+    if let Err(err) = storage.save_state(state) {
+        println!("Failed to save state: {err}");
     }
-    match action {
-        Action::CloseAd => println!("CloseAd"),
-        Action::CancelTeleportToCity => println!("CancelTeleportToCity"),
-        Action::TeleportToCity => println!("TeleportToCity"),
-        Action::GotoTown => println!("GotoTown"),
-        Action::GotoDungeon => println!("GotoDungeon"),
-        Action::GoDown => println!("GoDown"),
-        Action::FindFight(move_direction, (tile, ticks_same_target)) => println!("FindFight {move_direction:?} target = {:?} ticks = {ticks_same_target}", tile.get_position()),
-        Action::Fight => println!("Fight"),
-        Action::OpenChest => println!("OpenChest"),
-        Action::OpenChestMagical => println!("OpenChestMagical"),
-        Action::ReturnToTown(on_city_tile, move_direction) => println!("ReturnToTown {on_city_tile} {move_direction:?}"),
-        Action::Resurrect => println!("Resurrect"),
+}
+
+fn save_atlas(storage:&mut Storage, atlas:&mut Atlas) {
+    if let Err(err) = storage.save_atlas(atlas) {
+        println!("Failed to save atlas: {err}");
+    }
+}
+
+struct RunSummary {
+    started: Instant,
+    ticks: u64,
+    actions: BTreeMap<&'static str, u64>,
+}
+impl RunSummary {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            ticks: 0,
+            actions: BTreeMap::new(),
+        }
     }
-    //println!("{:?}", action);
-    if !opt.no_action {
-        if let Some(new_position) = ml::run_action(device, opt, &mut state, &action) {
-            state.set_position(new_position);
+    fn record(&mut self, action:&Action) {
+        self.ticks += 1;
+        *self.actions.entry(action.name()).or_default() += 1;
+    }
+    fn print(&self, state:&State) {
+        let elapsed = self.started.elapsed();
+        println!("Run summary");
+        println!("\tduration = {}s", elapsed.as_secs());
+        println!("\tticks = {}", self.ticks);
+        for (name, count) in &self.actions {
+            println!("\t{name} = {count}");
         }
+        println!("\tposition = {:?}", state.get_position());
     }
-    (state, action)
-}
\ No newline at end of file
+}
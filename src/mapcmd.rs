@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+
+use crate::{storage::{Storage, StorageError}, tiles::TileMap};
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum MapCommand {
+    Export {
+        #[clap(long)]
+        floor: String,
+        #[clap(long)]
+        out: Option<PathBuf>,
+    },
+    Import {
+        file: PathBuf,
+        #[clap(long)]
+        floor: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct MapFile {
+    floor: String,
+    tiles: TileMap,
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    UnknownFloor(String),
+    IoError(std::io::Error),
+    JsonError(serde_json::Error),
+    StorageError(StorageError),
+}
+impl std::fmt::Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownFloor(floor) => write!(f, "no map recorded for floor {floor}"),
+            Self::IoError(err) => write!(f, "io error: {err}"),
+            Self::JsonError(err) => write!(f, "json error: {err}"),
+            Self::StorageError(err) => write!(f, "storage error: {err}"),
+        }
+    }
+}
+impl std::error::Error for MapError {}
+impl From<std::io::Error> for MapError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+impl From<serde_json::Error> for MapError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::JsonError(value)
+    }
+}
+impl From<StorageError> for MapError {
+    fn from(value: StorageError) -> Self {
+        Self::StorageError(value)
+    }
+}
+
+pub fn run(storage:&mut Storage, command:&MapCommand) -> Result<String, MapError> {
+    match command {
+        MapCommand::Export { floor, out } => {
+            let atlas = storage.load_atlas()?;
+            let tiles = match atlas.floor(floor) {
+                Some(recorded) => recorded.tiles().iter().copied().collect::<TileMap>(),
+                None => {
+                    let state = storage.load_state()?.unwrap_or_default();
+                    let (tiles, _) = state.dungeon.floor_map(floor).ok_or_else(||MapError::UnknownFloor(floor.clone()))?;
+                    tiles.clone()
+                },
+            };
+            let data = serde_json::to_string_pretty(&MapFile { floor: floor.clone(), tiles })?;
+            match out {
+                Some(out) => {
+                    std::fs::write(out, data)?;
+                    Ok(format!("Exported {floor} to {}", out.display()))
+                },
+                None => Ok(data),
+            }
+        },
+        MapCommand::Import { file, floor } => {
+            let map = serde_json::from_str::<MapFile>(&std::fs::read_to_string(file)?)?;
+            let floor = floor.clone().unwrap_or(map.floor);
+            let mut atlas = storage.load_atlas()?;
+            atlas.replace(&floor, &map.tiles);
+            storage.save_atlas(&mut atlas)?;
+            if let Some(mut state) = storage.load_state()?
+                && state.dungeon.replace_floor(&floor, map.tiles.clone()) {
+                storage.save_state(&state)?;
+            }
+            Ok(format!("Imported {} tiles into {floor}", map.tiles.len()))
+        },
+    }
+}
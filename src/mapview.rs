@@ -0,0 +1,151 @@
+use std::fmt::Write;
+
+use image::{DynamicImage, Rgb, RgbImage};
+
+use crate::{frames, ml::{Coords, Tile}, tiles::TileMap};
+
+const CELL:u32 = 24;
+const WALL:u32 = 2;
+const MARKER:u32 = 6;
+
+const BACKGROUND:[u8; 3] = [24, 24, 28];
+const UNEXPLORED:[u8; 3] = [60, 60, 68];
+const EXPLORED:[u8; 3] = [190, 190, 200];
+const TRAP:[u8; 3] = [200, 90, 90];
+const WALL_COLOR:[u8; 3] = [10, 10, 10];
+const CITY:[u8; 3] = [60, 120, 230];
+const GO_DOWN:[u8; 3] = [60, 180, 80];
+const GO_UP:[u8; 3] = [60, 190, 190];
+const CHEST:[u8; 3] = [255, 193, 7];
+const POSITION:[u8; 3] = [230, 40, 40];
+
+struct Layout {
+    min: Coords,
+    width: u32,
+    height: u32,
+}
+impl Layout {
+    fn new(tiles:&TileMap, position:Option<Coords>) -> Self {
+        let positions = tiles.iter().map(|tile|tile.position).chain(position).collect::<Vec<_>>();
+        let min = Coords {
+            x: positions.iter().map(|pos|pos.x).min().unwrap_or(0),
+            y: positions.iter().map(|pos|pos.y).min().unwrap_or(0),
+        };
+        let max = Coords {
+            x: positions.iter().map(|pos|pos.x).max().unwrap_or(0),
+            y: positions.iter().map(|pos|pos.y).max().unwrap_or(0),
+        };
+        Self {
+            min,
+            width: (max.x - min.x + 1) * CELL,
+            height: (max.y - min.y + 1) * CELL,
+        }
+    }
+
+    fn origin(&self, position:Coords) -> (u32, u32) {
+        ((position.x - self.min.x) * CELL, (position.y - self.min.y) * CELL)
+    }
+}
+
+fn fill(tile:&Tile) -> [u8; 3] {
+    if tile.trap {
+        TRAP
+    }
+    else if tile.explored {
+        EXPLORED
+    }
+    else {
+        UNEXPLORED
+    }
+}
+
+fn marker(tile:&Tile) -> Option<[u8; 3]> {
+    if tile.is_city {
+        Some(CITY)
+    }
+    else if tile.is_go_down {
+        Some(GO_DOWN)
+    }
+    else if tile.is_go_up {
+        Some(GO_UP)
+    }
+    else if tile.has_chest {
+        Some(CHEST)
+    }
+    else {
+        None
+    }
+}
+
+fn walls(tile:&Tile) -> Vec<(u32, u32, u32, u32)> {
+    let mut out = Vec::new();
+    if !tile.north_passable {
+        out.push((0, 0, CELL, WALL));
+    }
+    if !tile.east_passable {
+        out.push((CELL - WALL, 0, WALL, CELL));
+    }
+    if !tile.south_passable {
+        out.push((0, CELL - WALL, CELL, WALL));
+    }
+    if !tile.west_passable {
+        out.push((0, 0, WALL, CELL));
+    }
+    out
+}
+
+fn fill_rect(image:&mut RgbImage, x:u32, y:u32, width:u32, height:u32, color:[u8; 3]) {
+    for py in y..(y + height).min(image.height()) {
+        for px in x..(x + width).min(image.width()) {
+            image.put_pixel(px, py, Rgb(color));
+        }
+    }
+}
+
+pub fn render_png(tiles:&TileMap, position:Option<Coords>) -> image::ImageResult<Vec<u8>> {
+    let layout = Layout::new(tiles, position);
+    let mut image = RgbImage::from_pixel(layout.width, layout.height, Rgb(BACKGROUND));
+    for tile in tiles.iter() {
+        let (x, y) = layout.origin(tile.position);
+        fill_rect(&mut image, x, y, CELL, CELL, fill(tile));
+        if let Some(color) = marker(tile) {
+            fill_rect(&mut image, x + (CELL - MARKER * 2) / 2, y + (CELL - MARKER * 2) / 2, MARKER * 2, MARKER * 2, color);
+        }
+        for (wx, wy, width, height) in walls(tile) {
+            fill_rect(&mut image, x + wx, y + wy, width, height, WALL_COLOR);
+        }
+    }
+    if let Some(position) = position {
+        let (x, y) = layout.origin(position);
+        fill_rect(&mut image, x + (CELL - MARKER) / 2, y + (CELL - MARKER) / 2, MARKER, MARKER, POSITION);
+    }
+    frames::encode_png(&DynamicImage::ImageRgb8(image))
+}
+
+fn rgb(color:[u8; 3]) -> String {
+    format!("rgb({},{},{})", color[0], color[1], color[2])
+}
+
+pub fn render_svg(tiles:&TileMap, position:Option<Coords>) -> String {
+    let layout = Layout::new(tiles, position);
+    let mut svg = String::new();
+    let _ = write!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}">"#, layout.width, layout.height);
+    let _ = write!(svg, r#"<rect width="{}" height="{}" fill="{}"/>"#, layout.width, layout.height, rgb(BACKGROUND));
+    for tile in tiles.sorted() {
+        let (x, y) = layout.origin(tile.position);
+        let _ = write!(svg, r#"<rect x="{x}" y="{y}" width="{CELL}" height="{CELL}" fill="{}"/>"#, rgb(fill(&tile)));
+        if let Some(color) = marker(&tile) {
+            let offset = (CELL - MARKER * 2) / 2;
+            let _ = write!(svg, r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{}"/>"#, x + offset, y + offset, MARKER * 2, MARKER * 2, rgb(color));
+        }
+        for (wx, wy, width, height) in walls(&tile) {
+            let _ = write!(svg, r#"<rect x="{}" y="{}" width="{width}" height="{height}" fill="{}"/>"#, x + wx, y + wy, rgb(WALL_COLOR));
+        }
+    }
+    if let Some(position) = position {
+        let (x, y) = layout.origin(position);
+        let _ = write!(svg, r#"<circle cx="{}" cy="{}" r="{}" fill="{}"/>"#, x + CELL / 2, y + CELL / 2, MARKER / 2 + 1, rgb(POSITION));
+    }
+    svg.push_str("</svg>");
+    svg
+}
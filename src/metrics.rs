@@ -0,0 +1,111 @@
+use std::{collections::BTreeMap, fmt::Write, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+
+use parking_lot::Mutex;
+
+use crate::timing;
+
+#[derive(Default)]
+struct Latency {
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+    last_micros: AtomicU64,
+}
+impl Latency {
+    fn observe(&self, duration:Duration) {
+        let micros = duration.as_micros() as u64;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.last_micros.store(micros, Ordering::Relaxed);
+    }
+
+    fn render(&self, out:&mut String, name:&str, help:&str) {
+        let seconds = |micros:u64|micros as f64 / 1_000_000.0;
+        writeln!(out, "# HELP endorbot_{name}_seconds {help}").unwrap();
+        writeln!(out, "# TYPE endorbot_{name}_seconds summary").unwrap();
+        writeln!(out, "endorbot_{name}_seconds_sum {}", seconds(self.sum_micros.load(Ordering::Relaxed))).unwrap();
+        writeln!(out, "endorbot_{name}_seconds_count {}", self.count.load(Ordering::Relaxed)).unwrap();
+        writeln!(out, "# HELP endorbot_last_{name}_seconds {help} (most recent tick)").unwrap();
+        writeln!(out, "# TYPE endorbot_last_{name}_seconds gauge").unwrap();
+        writeln!(out, "endorbot_last_{name}_seconds {}", seconds(self.last_micros.load(Ordering::Relaxed))).unwrap();
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    ticks: AtomicU64,
+    frames: Mutex<BTreeMap<&'static str, u64>>,
+    fights_started: AtomicU64,
+    chests_opened: AtomicU64,
+    deaths: AtomicU64,
+    unknown_states: AtomicU64,
+    duplicate_frames: AtomicU64,
+    classifier_disagreements: AtomicU64,
+    capture: Latency,
+    detection: Latency,
+}
+impl Metrics {
+    pub fn tick(&self) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn frame(&self, state_type:&'static str) {
+        *self.frames.lock().entry(state_type).or_default() += 1;
+    }
+
+    pub fn fight_started(&self) {
+        self.fights_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn chest_opened(&self) {
+        self.chests_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn deaths(&self, count:u64) {
+        self.deaths.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn unknown_state(&self) {
+        self.unknown_states.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn duplicate_frame(&self) {
+        self.duplicate_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn classifier_disagreement(&self) {
+        self.classifier_disagreements.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn capture(&self, duration:Duration) {
+        self.capture.observe(duration);
+    }
+
+    pub fn detection(&self, duration:Duration) {
+        self.detection.observe(duration);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counter = |out:&mut String, name:&str, help:&str, value:&AtomicU64| {
+            writeln!(out, "# HELP endorbot_{name}_total {help}").unwrap();
+            writeln!(out, "# TYPE endorbot_{name}_total counter").unwrap();
+            writeln!(out, "endorbot_{name}_total {}", value.load(Ordering::Relaxed)).unwrap();
+        };
+        counter(&mut out, "ticks", "Main loop ticks", &self.ticks);
+        writeln!(out, "# HELP endorbot_frames_total Captured frames by detected state type").unwrap();
+        writeln!(out, "# TYPE endorbot_frames_total counter").unwrap();
+        for (state_type, count) in self.frames.lock().iter() {
+            writeln!(out, "endorbot_frames_total{{state=\"{state_type}\"}} {count}").unwrap();
+        }
+        counter(&mut out, "fights_started", "Fights started", &self.fights_started);
+        counter(&mut out, "chests_opened", "Chests opened", &self.chests_opened);
+        counter(&mut out, "deaths", "Party members that died", &self.deaths);
+        counter(&mut out, "unknown_states", "Frames that matched no known screen", &self.unknown_states);
+        counter(&mut out, "duplicate_frames", "Frames identical to the previous one, analysis skipped", &self.duplicate_frames);
+        counter(&mut out, "classifier_disagreements", "Frames where the screen classifier and the pixel rules disagreed", &self.classifier_disagreements);
+        self.capture.render(&mut out, "capture", "Screen capture latency");
+        self.detection.render(&mut out, "detection", "State detection latency");
+        timing::render(&mut out);
+        out
+    }
+}
@@ -1,18 +1,16 @@
-use std::{char::ToLowercase, collections::{HashMap, HashSet}, io::Write, process::{Command, Stdio}};
+use std::{collections::{BTreeMap, HashSet}, process::{Command, Stdio}, time::Instant};
 
-use image::{DynamicImage, EncodableLayout, GenericImage, GenericImageView, Rgb, Rgba};
-use rand::{seq::{IndexedRandom, IteratorRandom}, thread_rng};
-use rten::Model;
+use image::{DynamicImage, GenericImageView, Rgb};
+use rand::seq::IndexedRandom;
 use serde::{Deserialize, Serialize};
 
-use crate::Opt;
+use crate::{Opt, Services, buttons::Button, game::ENDOR_LAYOUT, macros::MacroProgress, atlas::Atlas, daily, device, par, timing::{self, Phase}, input::{TapSequence, adb_tap}, floor::{FloorChanged, FloorId}, glyphs::Region, party::PartyLayout, tiles::TileMap, fight::{FightWatch, SkillBar}, movement::{MoveWatch, Recovery}, planner::{ExplorePlan, Route, RouteCache}, policy::{Mode, Policy}, strategy::Strategy, resources::{ResourceHistory, Resources}, trace::DecisionTrace};
 
 use BitmapWebp as BitmapImpl;
 
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, PartialEq)]
 pub struct Bitmap {
     pixels: Vec<(u16, u16, [u8;3])>,
-    has_dead_characters: bool,
     info: DungeonInfo,
 }
 impl Bitmap {
@@ -33,19 +31,14 @@ impl Bitmap {
             info: DungeonInfo {
                 floor: "".to_owned(),
                 coordinates: None,
+                size: None,
+                kind: DungeonKind::Main,
             },
-            has_dead_characters: false,
         }
     }
-    pub fn set_has_dead_characters(&mut self, has_dead_characters:bool) {
-        self.has_dead_characters = has_dead_characters;
-    }
     pub fn set_info(&mut self, info:DungeonInfo) {
         self.info = info;
     }
-    pub fn get_has_dead_characters(&self) -> bool {
-        self.has_dead_characters
-    }
     pub fn get_info(&self) -> &DungeonInfo {
         &self.info
     }
@@ -66,8 +59,8 @@ fn get_pixel(image:&BitmapImpl, bx:u32, by:u32, x:u32, y:u32, opt:&Opt) -> [u8;
 }
 
 fn find_text_char(x:u32, y:u32, image:&BitmapImpl, opt:&Opt) -> TextChar {
-    let clr = [230 as u8, 224, 233];
-    let gray = [29 as u8, 27, 32];
+    let clr = [230_u8, 224, 233];
+    let gray = [29_u8, 27, 32];
     /*if x == 292 {
         println!("{}x{} {}x{} {}x{} {}x{} {}x{} {}x{}", x,y+1, x-5, y+3, x-2, y+6, x+2,y+6,x+3,y+19,x-6,y+21);
         println!("{:?} {:?} {:?} {:?} {:?} {:?}", image.get_pixel(x, y + 1), image.get_pixel(x - 5, y + 3), image.get_pixel(x - 2, y + 6), image.get_pixel(x + 2, y + 6), image.get_pixel(x + 3, y + 19), image.get_pixel(x - 6, y + 21));
@@ -195,7 +188,129 @@ fn find_text_char(x:u32, y:u32, image:&BitmapImpl, opt:&Opt) -> TextChar {
     TextChar::Unknown
 }
 
-fn get_info(image:&BitmapImpl, opt:&Opt) -> DungeonInfo {
+const GLYPH_LEFT:u32 = 8;
+const GLYPH_TOP:u32 = 3;
+const TEXT_WIDTH:u32 = 300;
+const TEXT_HEIGHT:u32 = 32;
+const FLOOR_LABEL:(u32, u32, u32, u32) = (60, 1048, 150, 34);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OcrRegion {
+    Coordinates(u32),
+    FloorSize(u32),
+    FloorLabel,
+    Potions,
+    Gold,
+    Xp,
+    Level,
+    Energy,
+    ResurrectCost,
+    EnemyLevel,
+}
+impl OcrRegion {
+    fn anchor(&self) -> (u32, u32) {
+        match self {
+            OcrRegion::Coordinates(start) => (start + 20, 1052),
+            OcrRegion::FloorSize(start) => (start + 20, FLOOR_SIZE_Y as u32),
+            OcrRegion::FloorLabel => (FLOOR_LABEL.0, FLOOR_LABEL.1),
+            OcrRegion::Potions => (HEAL_BUTTON.0 + 32, HEAL_BUTTON.1 + 28),
+            OcrRegion::Gold => (GOLD_ICON.0 + 40, GOLD_ICON.1 - 12),
+            OcrRegion::Xp => XP_TEXT,
+            OcrRegion::Level => LEVEL_TEXT,
+            OcrRegion::Energy => ENERGY_TEXT,
+            OcrRegion::ResurrectCost => RESURRECT_COST,
+            OcrRegion::EnemyLevel => ENEMY_LEVEL,
+        }
+    }
+
+    fn crop(&self) -> Region {
+        if let OcrRegion::FloorLabel = self {
+            let (x, y, width, height) = FLOOR_LABEL;
+            return Region { x, y, width, height };
+        }
+        let (x, y) = self.anchor();
+        Region { x: x.saturating_sub(GLYPH_LEFT), y: y.saturating_sub(GLYPH_TOP), width: TEXT_WIDTH, height: TEXT_HEIGHT }
+    }
+}
+
+fn read_glyphs(region:OcrRegion, image:&BitmapImpl, services:&Services) -> String {
+    let pixel = |x:u32, y:u32|image.get_pixel(x as u16, y as u16);
+    let crop = region.crop();
+    services.ocr_cache.read(region, crop, &pixel, |cropped|services.glyphs.read(cropped, crop))
+}
+
+fn read_numbers_with_glyphs(region:OcrRegion, image:&BitmapImpl, opt:&Opt, services:&Services) -> Vec<u32> {
+    let text = read_glyphs(region, image, services);
+    if opt.debug {
+        println!("{region:?} = {text:?}");
+    }
+    text.trim_start_matches(|c:char|!c.is_ascii_digit())
+    .split(|c:char|!c.is_ascii_digit() && c != ',')
+    .next()
+    .unwrap_or_default()
+    .split(',')
+    .filter_map(|number|number.parse().ok())
+    .collect()
+}
+
+fn read_floor_label(image:&BitmapImpl, services:&Services) -> Option<String> {
+    if services.glyphs.is_empty() {
+        return None;
+    }
+    let text = read_glyphs(OcrRegion::FloorLabel, image, services);
+    FloorId::parse(&text).filter(|floor|!floor.prefix.is_empty()).map(|floor|floor.to_string())
+}
+
+fn read_numbers(region:OcrRegion, image:&BitmapImpl, opt:&Opt, services:&Services) -> Vec<u32> {
+    timing::time(Phase::Ocr, ||scan_numbers(region, image, opt, services))
+}
+
+fn scan_numbers(region:OcrRegion, image:&BitmapImpl, opt:&Opt, services:&Services) -> Vec<u32> {
+    if !services.glyphs.is_empty() {
+        return read_numbers_with_glyphs(region, image, opt, services);
+    }
+    let (mut x, y) = region.anchor();
+    let mut numbers = Vec::new();
+    let mut current_number = None;
+    loop {
+        match find_text_char(x, y, image, opt) {
+            TextChar::Digit(v) => {
+                if opt.debug {
+                    println!("{x}x{y} = {v}");
+                }
+                current_number = if let Some(n) = current_number {
+                    Some(n * 10 + v)
+                }
+                else {
+                    Some(v)
+                };
+            },
+            TextChar::Comma => {
+                if opt.debug {
+                    println!("{x}x{y} = ,");
+                }
+                x += 1;
+                if let Some(n) = current_number {
+                    numbers.push(n);
+                    current_number = None;
+                }
+            },
+            TextChar::Unknown => {
+                if opt.debug {
+                    println!("{x}x{y} = UNKNOWN");
+                }
+                if let Some(n) = current_number {
+                    numbers.push(n);
+                }
+                break;
+            }
+        }
+        x += 20;
+    }
+    numbers
+}
+
+fn get_info(image:&BitmapImpl, opt:&Opt, services:&Services) -> DungeonInfo {
     let clr = [230, 224, 233];
     for x in 220..378 {
         if image.get_pixel(x, 1051) == clr {
@@ -203,90 +318,161 @@ fn get_info(image:&BitmapImpl, opt:&Opt) -> DungeonInfo {
                 println!("Position start at {x}x1051");
             }
 
-            let mut x = x as u32 + 20;
-            let y = 1052;
-
-            let mut numbers = Vec::new();
-            let mut current_number = None;
-            loop {
-                match find_text_char(x, y, image, opt) {
-                    TextChar::Digit(v) => {
-                        if opt.debug {
-                            println!("{x}x{y} = {v}");
-                        }
-                        current_number = if let Some(n) = current_number {
-                            Some(n * 10 + v)
-                        }
-                        else {
-                            Some(v)
-                        };
-                    },
-                    TextChar::Comma => {
-                        if opt.debug {
-                            println!("{x}x{y} = ,");
-                        }
-                        x += 1;
-                        if let Some(n) = current_number {
-                            numbers.push(n);
-                            current_number = None;
-                        }
-                    },
-                    TextChar::Unknown => {
-                        if opt.debug {
-                            println!("{x}x{y} = UNKNOWN");
-                        }
-                        if let Some(n) = current_number {
-                            numbers.push(n);
-                            current_number = None;
-                        }
-                        break;
-                    }
-                }
-                x += 20;
-            }
+            let numbers = read_numbers(OcrRegion::Coordinates(x as u32), image, opt, services);
             if opt.debug {
                 println!("numbers = {numbers:?}");
             }
 
             return DungeonInfo {
-                floor: "D1".to_owned(),
+                floor: read_floor_label(image, services).unwrap_or_default(),
                 coordinates: if numbers.len() >= 2 {
                     Some(Coords{x: numbers[0], y: numbers[1]})
                 } else {None},
+                size: get_floor_size(image, opt, services),
+                kind: get_dungeon_kind(image),
             };
         }
     }
     DungeonInfo {
         floor: "".to_owned(),
         coordinates: None,
+        size: None,
+        kind: DungeonKind::Main,
+    }
+}
+
+fn get_dungeon_kind(image:&BitmapImpl) -> DungeonKind {
+    if image.extra_screens && pixel_color(image, EVENT_BADGE.into(), EVENT_PINK) {
+        DungeonKind::Event
+    }
+    else {
+        DungeonKind::Main
+    }
+}
+
+fn get_floor_size(image:&BitmapImpl, opt:&Opt, services:&Services) -> Option<Coords> {
+    let clr = [230, 224, 233];
+    let x = (220..378).find(|x|image.get_pixel(*x, FLOOR_SIZE_Y - 1) == clr)?;
+    let numbers = read_numbers(OcrRegion::FloorSize(x as u32), image, opt, services);
+    if opt.debug {
+        println!("floor size = {numbers:?}");
+    }
+    if numbers.len() >= 2 {
+        Some(Coords{x: numbers[0], y: numbers[1]})
+    }
+    else {
+        None
+    }
+}
+
+fn get_potions(image:&BitmapImpl, opt:&Opt, services:&Services) -> Option<u32> {
+    if !image.extra_screens || !pixel_color(image, HEAL_BUTTON.into(), HEAL_GREEN) {
+        return None;
     }
+    let numbers = read_numbers(OcrRegion::Potions, image, opt, services);
+    if opt.debug {
+        println!("potions = {numbers:?}");
+    }
+    numbers.first().copied()
+}
+
+fn read_hud_number(region:OcrRegion, image:&BitmapImpl, opt:&Opt, services:&Services) -> Option<u32> {
+    read_numbers(region, image, opt, services).into_iter().reduce(|value, n|value * 1000 + n)
+}
+
+fn get_resources(image:&BitmapImpl, opt:&Opt, services:&Services) -> Resources {
+    if !pixel_color(image, GOLD_ICON.into(), GOLD) {
+        return Resources::default();
+    }
+    let resources = Resources {
+        gold: read_hud_number(OcrRegion::Gold, image, opt, services),
+        xp: read_hud_number(OcrRegion::Xp, image, opt, services),
+        level: read_hud_number(OcrRegion::Level, image, opt, services),
+        energy: (opt.extra_screens && pixel_color(image, ENERGY_ICON.into(), ENERGY_BLUE)).then(||read_numbers(OcrRegion::Energy, image, opt, services).first().copied()).flatten(),
+    };
+    if opt.debug {
+        println!("resources = {resources:?}");
+    }
+    resources
+}
+
+fn get_resurrect_cost(image:&BitmapImpl, opt:&Opt, services:&Services) -> Option<u32> {
+    if !is_resurrect_dialog(image) {
+        return None;
+    }
+    read_hud_number(OcrRegion::ResurrectCost, image, opt, services)
+}
+
+fn is_resurrect_dialog(image:&BitmapImpl) -> bool {
+    image.extra_screens && pixels_same_color(image, [(155, 1000).into(), (911, 1000).into()].into_iter(), image::Rgb([43, 41, 48]))
+        && pixel_color(image, RESURRECT_CONFIRM.into(), RESURRECT_PURPLE)
+}
+
+fn get_temple_slot(image:&BitmapImpl) -> Option<u8> {
+    (0..4).find(|i|pixel_color(image, (TEMPLE_RESURRECT.0, TEMPLE_RESURRECT.1 + *i as u32 * 150).into(), RESURRECT_PURPLE))
 }
+
 pub struct BitmapWebp {
     image: DynamicImage,
     divisor: u32,
-    pub has_dead_characters: bool,
+    pub characters: Vec<Character>,
+    pub skills: SkillBar,
     pub info: DungeonInfo,
+    pub potions: Option<u32>,
+    pub resources: Resources,
+    pub resurrect_cost: Option<u32>,
+    pub enemy_level: Option<u32>,
+    pub extra_screens: bool,
 }
 impl BitmapWebp {
-    pub fn from_image(image:DynamicImage, divisor:u32, opt:&Opt) -> Self {
+    pub fn from_image(image:DynamicImage, divisor:u32, opt:&Opt, services:&Services) -> Self {
+        let started = Instant::now();
         let mut bmp = Self {
             image,
             divisor,
-            has_dead_characters: false,
+            characters: Vec::new(),
+            skills: SkillBar::default(),
             info: DungeonInfo {
                 floor: "".to_owned(),
                 coordinates: None,
-            }
+                size: None,
+                kind: DungeonKind::Main,
+            },
+            potions: None,
+            resources: Resources::default(),
+            resurrect_cost: None,
+            enemy_level: None,
+            extra_screens: opt.extra_screens,
         };
-        bmp.has_dead_characters = get_characters(&bmp).iter().find(|char|char.is_dead()).is_some();
-        bmp.info = get_info(&bmp, opt);
+        bmp.characters = get_characters(&bmp, &opt.party);
+        bmp.skills = get_skill_bar(&bmp, &opt.party);
+        bmp.info = get_info(&bmp, opt, services);
+        bmp.potions = get_potions(&bmp, opt, services);
+        bmp.resources = get_resources(&bmp, opt, services);
+        bmp.resurrect_cost = get_resurrect_cost(&bmp, opt, services);
+        bmp.enemy_level = get_enemy_level(&bmp, opt, services);
+        timing::observe(Phase::Bitmap, started.elapsed());
         bmp
     }
+    pub fn image(&self) -> &DynamicImage {
+        &self.image
+    }
+    pub fn into_image(self) -> DynamicImage {
+        self.image
+    }
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = std::hash::DefaultHasher::new();
+        std::hash::Hasher::write(&mut hasher, self.image.as_bytes());
+        std::hash::Hasher::finish(&hasher)
+    }
+    pub fn is_black(&self) -> bool {
+        BLACK_PROBES.iter().all(|(x, y)|self.get_pixel(*x, *y) == [0, 0, 0])
+    }
     pub fn get_pixel(&self, x:u16, y:u16) -> [u8; 3] {
         self.image.get_pixel((x as u32) / self.divisor, (y as u32) / self.divisor).0[0..3].try_into().unwrap()
     }
-    pub fn get_has_dead_characters(&self) -> bool {
-        self.has_dead_characters
+    pub fn has_dead_characters(&self) -> bool {
+        dead_slots(&self.characters).next().is_some()
     }
     pub fn get_info(&self) -> &DungeonInfo {
         &self.info
@@ -299,6 +485,19 @@ pub struct Coords {
     pub y: u32,
 }
 impl Coords {
+    pub fn checked_move(&self, direction:MoveDirection) -> Option<Self> {
+        match direction {
+            MoveDirection::North => self.y.checked_sub(1).map(|y|Self {x: self.x, y}),
+            MoveDirection::East => Some(Self {x: self.x + 1, y: self.y}),
+            MoveDirection::South => Some(Self {x: self.x, y: self.y + 1}),
+            MoveDirection::West => self.x.checked_sub(1).map(|x|Self {x, y: self.y}),
+        }
+    }
+
+    pub fn steps_to(&self, other:Coords) -> u32 {
+        self.x.abs_diff(other.x) + self.y.abs_diff(other.y)
+    }
+
     pub fn move_direction(&self, direction:MoveDirection) -> Self {
         match direction {
             MoveDirection::North => Self {x: self.x, y: self.y - 1},
@@ -329,27 +528,62 @@ pub enum StateError {
     UnknownState,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum StateType {
     Ad,
     Main,
     City(bool),
     Dungeon,
     TeleportToCity,
+    Temple(Option<u8>),
+    ResurrectConfirm { cost: Option<u32>, gold: Option<u32> },
+}
+impl StateType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StateType::Ad => "ad",
+            StateType::Main => "main",
+            StateType::City(_) => "city",
+            StateType::Dungeon => "dungeon",
+            StateType::TeleportToCity => "teleport_to_city",
+            StateType::Temple(_) => "temple",
+            StateType::ResurrectConfirm { .. } => "resurrect_confirm",
+        }
+    }
+
+    /// The state named `name`, for the states that carry nothing read from the screen.
+    pub fn from_name(name:&str) -> Option<StateType> {
+        match name {
+            "ad" => Some(StateType::Ad),
+            "main" => Some(StateType::Main),
+            "teleport_to_city" => Some(StateType::TeleportToCity),
+            _ => None,
+        }
+    }
 }
-impl Into<State> for StateType {
-    fn into(self) -> State {
+impl From<StateType> for State {
+    fn from(val: StateType) -> Self {
         State {
-            state_type: self,
+            state_type: val,
             dungeon: Dungeon::default(),
+            resources: ResourceHistory::default(),
+            macros: MacroProgress::default(),
+            fight: FightWatch::default(),
+            moves: MoveWatch::default(),
+            parking: false,
         }
     }
 }
-impl Into<State> for (StateType, Dungeon) {
-    fn into(self) -> State {
+impl From<(StateType, Dungeon)> for State {
+    fn from(val: (StateType, Dungeon)) -> Self {
         State {
-            state_type: self.0,
-            dungeon: self.1,
+            state_type: val.0,
+            dungeon: val.1,
+            resources: ResourceHistory::default(),
+            macros: MacroProgress::default(),
+            fight: FightWatch::default(),
+            moves: MoveWatch::default(),
+            parking: false,
         }
     }
 }
@@ -357,10 +591,20 @@ impl Into<State> for (StateType, Dungeon) {
 pub struct State {
     pub state_type: StateType,
     pub dungeon: Dungeon,
+    #[serde(default)]
+    pub resources: ResourceHistory,
+    #[serde(default)]
+    pub macros: MacroProgress,
+    #[serde(default)]
+    pub fight: FightWatch,
+    #[serde(default)]
+    pub moves: MoveWatch,
+    #[serde(skip)]
+    pub parking: bool,
 }
 impl Default for State {
     fn default() -> Self {
-        Self { state_type: StateType::Main, dungeon: Default::default() }
+        Self { state_type: StateType::Main, dungeon: Default::default(), resources: Default::default(), macros: Default::default(), fight: Default::default(), moves: Default::default(), parking: false }
     }
 }
 
@@ -369,11 +613,22 @@ impl State {
         self.dungeon.info.coordinates
     }
 
-    pub fn merge(&mut self, old:State) -> State {
-        let city_tile = self.dungeon.tiles.iter().find(|tile|tile.is_city).cloned();
-        let down_tile = self.dungeon.tiles.iter().find(|tile|tile.is_go_down).cloned();
+    pub fn merge(&mut self, mut old:State) -> State {
+        if let StateType::Dungeon = self.state_type {
+            if self.dungeon.info.kind != old.dungeon.info.kind {
+                println!("Switched to a {:?} dungeon, dropping the {:?} maps", self.dungeon.info.kind, old.dungeon.info.kind);
+                old.dungeon = Dungeon::default();
+            }
+        }
+        else {
+            self.dungeon.info.kind = old.dungeon.info.kind;
+        }
+        let floor_changed = changed_floor(&self.dungeon.info.floor, old.dungeon.floor_name())
+            .map(|floor|old.dungeon.change_floor(floor.to_string()));
+        let city_tile = self.dungeon.tiles.first(|tile|tile.is_city).cloned();
+        let down_tile = self.dungeon.tiles.first(|tile|tile.is_go_down).cloned();
         for mut tile in old.dungeon.tiles {
-            if let Some(new_tile) = self.dungeon.tiles.iter_mut().find(|v|v.position == tile.position) {
+            if let Some(new_tile) = self.dungeon.tiles.get_mut(&tile.position) {
                 if city_tile.is_none() {
                     new_tile.is_city = tile.is_city || new_tile.is_city;
                 }
@@ -381,6 +636,8 @@ impl State {
                     new_tile.is_go_down = tile.is_go_down || new_tile.is_go_down;
                 }
                 new_tile.visited = tile.visited || new_tile.visited;
+                new_tile.trap = tile.trap || new_tile.trap;
+                new_tile.is_go_up = tile.is_go_up || new_tile.is_go_up;
             }
             else {
                 tile.is_city = if city_tile.is_none() {
@@ -395,72 +652,279 @@ impl State {
                 else {
                     false
                 };
-                self.dungeon.tiles.push(tile);
+                self.dungeon.tiles.insert(tile);
             }
         }
+        if self.dungeon.floors.is_empty() {
+            self.dungeon.floors = old.dungeon.floors;
+        }
+        if !old.dungeon.info.floor.is_empty() {
+            self.dungeon.info.floor = old.dungeon.info.floor;
+        }
+        if self.dungeon.potions.is_none() {
+            self.dungeon.potions = old.dungeon.potions;
+        }
+        self.resources = old.resources;
+        self.macros = old.macros;
+        self.fight = old.fight;
+        self.moves = old.moves;
+        self.parking = old.parking;
+        self.dungeon.blocked = old.dungeon.blocked;
+        self.dungeon.apply_blocked();
+        self.dungeon.edits = old.dungeon.edits;
+        self.dungeon.apply_edits();
+        self.dungeon.plan = old.dungeon.plan;
+        self.dungeon.route = old.dungeon.route;
+        self.dungeon.floor_changed = floor_changed;
         self.clone()
     }
     
     pub fn set_position(&mut self, new_position: Coords) {
         self.dungeon.info.coordinates = Some(new_position);
     }
+
+    pub fn diff(&self, old:&State) -> Option<StateDiff> {
+        let old_tiles = &old.dungeon.tiles;
+        let tiles_reset = self.dungeon.tiles.iter().filter(|tile|old_tiles.contains(&tile.position)).count() != old_tiles.len();
+        let tiles = self.dungeon.tiles.sorted().into_iter().filter(|tile|{
+            tiles_reset || old_tiles.get(&tile.position).is_none_or(|old_tile|*old_tile != *tile)
+        }).collect::<Vec<_>>();
+        let floors = if self.dungeon.floors != old.dungeon.floors {
+            Some(self.dungeon.floors.clone())
+        }
+        else {
+            None
+        };
+        let characters = if self.dungeon.characters != old.dungeon.characters {
+            Some(self.dungeon.characters.clone())
+        }
+        else {
+            None
+        };
+        if !tiles_reset && tiles.is_empty() && characters.is_none() && floors.is_none()
+            && self.state_type == old.state_type
+            && self.dungeon.state == old.dungeon.state
+            && self.dungeon.info == old.dungeon.info
+            && self.dungeon.potions == old.dungeon.potions
+            && self.dungeon.navigation() == old.dungeon.navigation() {
+            return None;
+        }
+        Some(StateDiff {
+            party: self.party_status(),
+            state_type: self.state_type.clone(),
+            dungeon_state: self.dungeon.state.clone(),
+            info: self.dungeon.info.clone(),
+            characters,
+            tiles,
+            tiles_reset,
+            floors,
+            navigation: self.dungeon.navigation(),
+        })
+    }
+
+    pub fn party_status(&self) -> PartyStatus {
+        let dungeon = &self.dungeon;
+        let (dungeon_state, enemy) = match dungeon.state {
+            DungeonState::Idle(_) => ("Idle", None),
+            DungeonState::IdleChest => ("IdleChest", None),
+            DungeonState::IdleChestMagical => ("IdleChestMagical", None),
+            DungeonState::Fight(enemy) => ("Fight", Some(enemy)),
+            DungeonState::BossFight(enemy) => ("BossFight", Some(enemy)),
+        };
+        let retreat_reason = if let StateType::Dungeon = self.state_type {
+            dead_slots(&dungeon.characters).next().map(|slot|format!("{} is dead", dungeon.characters[slot].label(slot)))
+        }
+        else {
+            None
+        };
+        PartyStatus {
+            characters: dungeon.characters.iter().map(|character|character.health).collect(),
+            health_pct: dungeon.characters.iter().map(|character|character.health_pct).collect(),
+            names: dungeon.characters.iter().enumerate().map(|(slot, character)|character.label(slot)).collect(),
+            enemy: enemy.map(|enemy|enemy.health),
+            enemy_pct: enemy.and_then(|enemy|enemy.health_pct),
+            enemies: enemy.map(|enemy|enemy.panels().collect()).unwrap_or_default(),
+            dungeon_state,
+            retreat_reason,
+            potions: dungeon.potions,
+        }
+    }
+
+    pub fn view(&self) -> StateView<'_> {
+        StateView {
+            state: self,
+            party: self.party_status(),
+            exploration: self.dungeon.exploration_by_floor(),
+            navigation: self.dungeon.navigation(),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
-enum Health {
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PartyStatus {
+    characters: Vec<Health>,
+    names: Vec<String>,
+    health_pct: Vec<Option<f32>>,
+    enemy: Option<Health>,
+    enemy_pct: Option<f32>,
+    enemies: Vec<f32>,
+    dungeon_state: &'static str,
+    retreat_reason: Option<String>,
+    potions: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StateView<'a> {
+    #[serde(flatten)]
+    state: &'a State,
+    party: PartyStatus,
+    exploration: BTreeMap<String, f32>,
+    navigation: Option<Navigation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Navigation {
+    target: Coords,
+    path: Vec<Coords>,
+    next: Option<MoveDirection>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StateDiff {
+    party: PartyStatus,
+    state_type: StateType,
+    dungeon_state: DungeonState,
+    info: DungeonInfo,
+    characters: Option<Vec<Character>>,
+    tiles: Vec<Tile>,
+    tiles_reset: bool,
+    floors: Option<BTreeMap<String, TileMap>>,
+    navigation: Option<Navigation>,
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Copy, Clone, Serialize, Deserialize)]
+pub enum Health {
     Unknown,
     Dead,
     Low,
     Hurt,
     Healthy,
 }
+impl Health {
+    fn from_pct(pct:Option<f32>, dead_below:f32) -> Self {
+        match pct {
+            None => Health::Unknown,
+            Some(pct) if pct <= dead_below => Health::Dead,
+            Some(pct) if pct >= HEALTHY_PCT => Health::Healthy,
+            Some(pct) if pct >= HURT_PCT => Health::Hurt,
+            Some(_) => Health::Low,
+        }
+    }
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Character {
-    health: Health,
+    pub health: Health,
+    #[serde(default)]
+    pub health_pct: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
 }
 impl Default for Character {
     fn default() -> Self {
-        Self { health: Health::Unknown }
+        Self { health: Health::Unknown, health_pct: None, name: None, class: None }
     }
 }
 impl Character {
     pub fn is_dead(&self) -> bool {
-        if let Health::Dead = self.health {
-            true
-        }
-        else {
-            false
+        matches!(self.health, Health::Dead)
+    }
+
+    pub fn is_below(&self, pct:f32) -> bool {
+        self.health_pct.is_some_and(|health|health > 0.0 && health < pct)
+    }
+
+    pub fn label(&self, slot:usize) -> String {
+        match (&self.name, &self.class) {
+            (Some(name), Some(class)) => format!("{name} ({class})"),
+            (Some(name), None) => name.clone(),
+            _ => format!("Character {}", slot + 1),
         }
     }
 }
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Enemy {
     health: Health,
+    #[serde(default)]
+    health_pct: Option<f32>,
+    #[serde(default = "default_enemy_count")]
+    count: u8,
+    #[serde(default)]
+    level: Option<u32>,
+    #[serde(default)]
+    panels: [Option<f32>; ENEMY_SLOTS as usize],
+}
+fn default_enemy_count() -> u8 {
+    1
+}
+impl Enemy {
+    pub fn health_pct(&self) -> Option<f32> {
+        self.health_pct
+    }
+
+    pub fn panels(&self) -> impl Iterator<Item = f32> + '_ {
+        self.panels.iter().flatten().copied()
+    }
+
+    pub fn difficulty(&self) -> f32 {
+        self.level.unwrap_or(1) as f32 * self.count as f32
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, PartialEq)]
 pub struct DungeonInfo {
     pub floor: String,
     pub coordinates: Option<Coords>,
+    #[serde(default)]
+    pub size: Option<Coords>,
+    #[serde(default)]
+    pub kind: DungeonKind,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DungeonKind {
+    #[default]
+    Main,
+    Event,
 }
 
-const TILE_SIZE:(u32, u32) = (60, 60);
-const TILE_START:(u32, u32) = (536, 536);
-const TILE_COUNT:(u32, u32) = (7, 7);
+const TILE_SIZE:(u32, u32) = ENDOR_LAYOUT.map_tile;
+const TILE_START:(u32, u32) = ENDOR_LAYOUT.map_origin;
+const TILE_COUNT:(u32, u32) = ENDOR_LAYOUT.map_tiles;
+const MAP_MARGIN:u32 = 3;
+const TRAP_COST:u32 = 8;
+const FLOOR_SIZE_Y:u16 = 1100;
+const JUMP_CONFIRMATIONS:u32 = 3;
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Tile {
-    explored: bool,
-    trap: bool,
-    is_city: bool,
-    is_go_down: bool,
-    visited: bool,
-    position: Coords,
-    north_passable: bool,
-    east_passable: bool,
-    south_passable: bool,
-    west_passable: bool,
+    pub explored: bool,
+    pub trap: bool,
+    pub is_city: bool,
+    pub is_go_down: bool,
+    #[serde(default)]
+    pub is_go_up: bool,
+    #[serde(default)]
+    pub has_chest: bool,
+    pub visited: bool,
+    pub position: Coords,
+    pub north_passable: bool,
+    pub east_passable: bool,
+    pub south_passable: bool,
+    pub west_passable: bool,
 }
 
 impl Tile {
@@ -483,161 +947,162 @@ impl Tile {
     pub fn get_position(&self) -> Coords {
         self.position
     }
+
+    fn is_passable(&self, direction:MoveDirection) -> bool {
+        match direction {
+            MoveDirection::North => self.north_passable,
+            MoveDirection::East => self.east_passable,
+            MoveDirection::South => self.south_passable,
+            MoveDirection::West => self.west_passable,
+        }
+    }
 }
 
-fn get_tiles(info:&DungeonInfo, image:&BitmapImpl) -> Vec<Tile> {
+fn get_tiles(info:&DungeonInfo, image:&BitmapImpl) -> TileMap {
     let (x_base, y_base) = if let Some(coords) = info.coordinates {
         (coords.x as i32 - (TILE_COUNT.0 + 1 ) as i32 / 2, coords.y as i32 - (TILE_COUNT.1 + 1 ) as i32 / 2 + 1)
     }
     else {
         (0, 0)
     };
-    /*let (x_skip, y_skip, x_base, y_base) = if x_base < 0 || y_base < 0 {
-        println!("{} {}", if x_base < 0 {x_base.abs()as u32}else{0}, if y_base < 0{y_base.abs() as u32}else{0});
-        (if x_base < 0 {x_base.abs()as u32}else{0}, if y_base < 0{y_base.abs() as u32}else{0}, if x_base < 0{0}else{x_base}, if y_base < 0{0}else{y_base})
-//        panic!("{x_base}/{y_base} {info:?}");
+    let cells = (0..TILE_COUNT.0).flat_map(|x_count|(0..TILE_COUNT.1).map(move|y_count|(x_count, y_count))).collect::<Vec<_>>();
+    let mut tiles = TileMap::default();
+    for tile in par::filter_map(&cells, |&(x_count, y_count)|read_tile(image, (x_base, y_base), x_count, y_count)) {
+        tiles.insert(tile);
     }
-    else {
-        (0, 0, x_base, y_base)
-    };*/
-    //let (x_base, y_base) = (x_base as u32, y_base as u32);
-    let mut tiles = Vec::new();
-    for x_count in 0..TILE_COUNT.0 {
-        for y_count in 0..TILE_COUNT.1 {
-            if (x_base + x_count as i32) < 0 || (y_base + y_count as i32) < 0 {
-                continue;
-            }
-//            println!("{x_base} {x_count} x {y_base} {y_count}");
-            let x = TILE_START.0 + x_count * TILE_SIZE.0 + TILE_SIZE.0 / 2;
-            let y = TILE_START.1 + y_count * TILE_SIZE.1 + TILE_SIZE.1 / 2;
-
-            //panic!("{x}x{y} {x_base} + {x_count} {y_base} + {y_count}");
-
-            if pixel_color(image, (x, y).into(), TILE_UNEXPLORED) {
-                continue;
-                //println!("{}x{}", x_base + x_count, y_base + y_count);
-            }
-
-          //  println!("{x}x{y} {}x{}", (x_base + x_count as i32) as u32, (y_base + y_count as i32) as u32);
-
-            //println!("{x}x{} {}x{} {:?}", TILE_START.1 + y_count * TILE_SIZE.1 + TILE_SIZE.1 - 1, x_base + x_count, y_base + y_count, image.get_pixel(x, TILE_START.1 + y_count * TILE_SIZE.1 + TILE_SIZE.1 - 1));
-
-           // println!("{x}x{y} {:?}", image.get_pixel(x, y));
-
-            fn is_wall(image:&BitmapImpl, x:u32, y:u32) -> bool {
-                let color = image.get_pixel(x as u16, y as u16);
-                let color2 = image.get_pixel(x as u16, y as u16 + 1);
-                color.iter().all(|v|*v >= 125) || color2.iter().all(|v|*v >= 125)
-                || color.iter().all(|v|*v >= 40 && *v <= 64)
-                || color2.iter().all(|v|*v >= 40 && *v <= 64)
-            }
-
-            fn is_city(image:&BitmapImpl, x:u32, y:u32) -> bool {
-                let clr = [244u8, 67, 54];
-                let clr_faded = [165u8, 118, 66];
-                let color = image.get_pixel(x as u16, y as u16);
-                let color2 = image.get_pixel(x as u16 + 4, y as u16 + 8);
-                if (color == clr || color == clr_faded)  && color2 != clr && color2 != clr_faded  {
-                    //println!("{x}x{y}");
-                    true
-                }
-                else {
-                    false
-                }
-            }
-            fn is_go_down(image:&BitmapImpl, x:u32, y:u32) -> bool {
-                let clr = [244u8, 67, 54];
-                let clr_faded = [165u8, 118, 66];
-                let color = image.get_pixel(x as u16, y as u16);
-                let color2 = image.get_pixel(x as u16 + 4, y as u16 + 8);
-                let color3 = image.get_pixel(x as u16 + 5, y as u16);
-                let color4 = image.get_pixel(x as u16 - 5, y as u16);
-                //println!("{x}x{y} {color:?} {color2:?} {color3:?}");
-                if (color == clr || color == clr_faded)  && (color2 == clr || color2 == clr_faded) && color3 != clr && color3 == clr_faded && color4 == clr && color4 == clr_faded  {
-                    //println!("{x}x{y}");
-                    true
-                }
-                else {
-                    false
-                }
-            }
-
-            fn is_go_up(image:&BitmapImpl, x:u32, y:u32) -> bool {
-                let clr = [244u8, 67, 54];
-                let clr_faded = [165u8, 118, 66];
-                let color = image.get_pixel(x as u16, y as u16);
-                let color2 = image.get_pixel(x as u16 + 4, y as u16 + 8);
-                let color3 = image.get_pixel(x as u16 + 5, y as u16);
-                let color4 = image.get_pixel(x as u16 - 5, y as u16);
-                //println!("{x}x{y} {color:?} {color2:?} {color3:?}");
-                if (color == clr || color == clr_faded)  && color2 != clr && color2 != clr_faded && (color3 == clr || color3 == clr_faded) && (color4 == clr || color4 == clr_faded)  {
-                    //println!("{x}x{y}");
-                    true
-                }
-                else {
-                    false
-                }
-            }
-
-            let is_go_up = is_go_up(image, x-2, y);
-            let position = Coords{x: (x_base + x_count as i32) as u32, y: (y_base + y_count as i32) as u32};
-            let tile = Tile {
-                explored: !pixel_color(image, (x, y).into(), TILE_UNEXPLORED),
-                trap: false,
-                visited: false,
-                is_city: is_city(image, x-2, y),
-                is_go_down: position != (15, 15).into() && !is_go_up && is_go_down(image, x-2, y),
-                //is_city: pixel_color(image, (x-2, y).into(), Rgb([244, 67, 54])),
-                position: position,
-                north_passable: !is_wall(image, x, TILE_START.1 + y_count * TILE_SIZE.1 + 1),
-                east_passable: !is_wall(image, TILE_START.0 + x_count * TILE_SIZE.0 + TILE_SIZE.0 - 4, y),
-                south_passable: !is_wall(image, x, TILE_START.1 + y_count * TILE_SIZE.1 + TILE_SIZE.1 - 4),
-                west_passable: !is_wall(image, TILE_START.0 + x_count * TILE_SIZE.0 + 1, y),
-                //north_passable: !pixel_color(image, (x, TILE_START.1 + y_count * TILE_SIZE.1 + 1).into(), HEALTH_GREY) && !pixel_color(image, (x, TILE_START.1 + y_count * TILE_SIZE.1 + 1).into(), WHITE),
-                //east_passable: !pixel_color(image, (TILE_START.0 + x_count * TILE_SIZE.0 + TILE_SIZE.0 - 4, y).into(), HEALTH_GREY) && !pixel_color(image, (TILE_START.0 + x_count * TILE_SIZE.0 + TILE_SIZE.0 - 4, y).into(), WHITE),
-                //south_passable: !pixel_color(image, (x, TILE_START.1 + y_count * TILE_SIZE.1 + TILE_SIZE.1 - 4).into(), HEALTH_GREY) && !pixel_color(image, (x, TILE_START.1 + y_count * TILE_SIZE.1 + TILE_SIZE.1 - 4).into(), WHITE),
-                //west_passable: !pixel_color(image, (TILE_START.0 + x_count * TILE_SIZE.0 + 1, y).into(), HEALTH_GREY) && !pixel_color(image, (TILE_START.0 + x_count * TILE_SIZE.0 + 1, y).into(), WHITE),
-            };
+    tiles
+}
 
-            if tile.position.x == 18 && tile.position.y == 4 {
-               // println!("{tile:?} {}x{} {:?}", TILE_START.0 + x_count * TILE_SIZE.0 + 1, y, image.get_pixel((TILE_START.0 + x_count * TILE_SIZE.0 + 1) as u16, y as u16));
-            }
+fn read_tile(image:&BitmapImpl, (x_base, y_base):(i32, i32), x_count:u32, y_count:u32) -> Option<Tile> {
+    if (x_base + x_count as i32) < 0 || (y_base + y_count as i32) < 0 {
+        return None;
+    }
+    let x = TILE_START.0 + x_count * TILE_SIZE.0 + TILE_SIZE.0 / 2;
+    let y = TILE_START.1 + y_count * TILE_SIZE.1 + TILE_SIZE.1 / 2;
+
+    if pixel_color(image, (x, y).into(), TILE_UNEXPLORED) {
+        return None;
+    }
+
+    fn is_wall(image:&BitmapImpl, x:u32, y:u32) -> bool {
+        let color = image.get_pixel(x as u16, y as u16);
+        let color2 = image.get_pixel(x as u16, y as u16 + 1);
+        color.iter().all(|v|*v >= 125) || color2.iter().all(|v|*v >= 125)
+        || color.iter().all(|v|*v >= 40 && *v <= 64)
+        || color2.iter().all(|v|*v >= 40 && *v <= 64)
+    }
+
+    fn is_city(image:&BitmapImpl, x:u32, y:u32) -> bool {
+        let clr = [244u8, 67, 54];
+        let clr_faded = [165u8, 118, 66];
+        let color = image.get_pixel(x as u16, y as u16);
+        let color2 = image.get_pixel(x as u16 + 4, y as u16 + 8);
+        (color == clr || color == clr_faded) && color2 != clr && color2 != clr_faded
+    }
+    fn is_go_down(image:&BitmapImpl, x:u32, y:u32) -> bool {
+        let clr = [244u8, 67, 54];
+        let clr_faded = [165u8, 118, 66];
+        let color = image.get_pixel(x as u16, y as u16);
+        let color2 = image.get_pixel(x as u16 + 4, y as u16 + 8);
+        let color3 = image.get_pixel(x as u16 + 5, y as u16);
+        let color4 = image.get_pixel(x as u16 - 5, y as u16);
+        (color == clr || color == clr_faded) && (color2 == clr || color2 == clr_faded) && color3 != clr && color3 == clr_faded && color4 == clr && color4 == clr_faded
+    }
+
+    fn is_go_up(image:&BitmapImpl, x:u32, y:u32) -> bool {
+        let clr = [244u8, 67, 54];
+        let clr_faded = [165u8, 118, 66];
+        let color = image.get_pixel(x as u16, y as u16);
+        let color2 = image.get_pixel(x as u16 + 4, y as u16 + 8);
+        let color3 = image.get_pixel(x as u16 + 5, y as u16);
+        let color4 = image.get_pixel(x as u16 - 5, y as u16);
+        (color == clr || color == clr_faded) && color2 != clr && color2 != clr_faded && (color3 == clr || color3 == clr_faded) && (color4 == clr || color4 == clr_faded)
+    }
+
+    fn is_trap(image:&BitmapImpl, x:u32, y:u32) -> bool {
+        let clr = [156u8, 39, 176];
+        image.get_pixel(x as u16 - 6, y as u16 - 6) == clr
+            && image.get_pixel(x as u16 + 6, y as u16 + 6) == clr
+            && image.get_pixel(x as u16 + 6, y as u16 - 6) == clr
+    }
+
+    fn is_chest(image:&BitmapImpl, x:u32, y:u32) -> bool {
+        let clr = [255u8, 193, 7];
+        image.get_pixel(x as u16, y as u16 + 8) == clr
+            && image.get_pixel(x as u16 - 8, y as u16 + 8) == clr
+            && image.get_pixel(x as u16 + 8, y as u16 + 8) == clr
+    }
+
+    let is_go_up = is_go_up(image, x-2, y);
+    let position = Coords{x: (x_base + x_count as i32) as u32, y: (y_base + y_count as i32) as u32};
+    let tile = Tile {
+        explored: !pixel_color(image, (x, y).into(), TILE_UNEXPLORED),
+        trap: is_trap(image, x, y),
+        visited: false,
+        is_city: is_city(image, x-2, y),
+        is_go_down: position != (15, 15).into() && !is_go_up && is_go_down(image, x-2, y),
+        is_go_up,
+        has_chest: is_chest(image, x, y),
+        position,
+        north_passable: !is_wall(image, x, TILE_START.1 + y_count * TILE_SIZE.1 + 1),
+        east_passable: !is_wall(image, TILE_START.0 + x_count * TILE_SIZE.0 + TILE_SIZE.0 - 4, y),
+        south_passable: !is_wall(image, x, TILE_START.1 + y_count * TILE_SIZE.1 + TILE_SIZE.1 - 4),
+        west_passable: !is_wall(image, TILE_START.0 + x_count * TILE_SIZE.0 + 1, y),
+    };
 
-            if false && tile.position.x == 18 && tile.position.y == 4 {
-                println!("{tile:?}");
-                println!("west {}x{} {:?}", TILE_START.0 + x_count * TILE_SIZE.0 + 1, y, image.get_pixel((TILE_START.0 + x_count * TILE_SIZE.0 + 1) as u16, y as u16));
-                println!("east {}x{} {:?}", x, TILE_START.1 + y_count * TILE_SIZE.1 + 1, image.get_pixel(x as u16, (TILE_START.1 + y_count * TILE_SIZE.1 + 1) as u16));
-                println!("south {}x{} {:?}", TILE_START.0 as u16 + x_count as u16 * TILE_SIZE.0 as u16 + TILE_SIZE.0 as u16 - 4, y as u16, image.get_pixel(TILE_START.0 as u16 + x_count as u16 * TILE_SIZE.0 as u16 + TILE_SIZE.0 as u16 - 4, y as u16));
-            }
+    if pixel_color(image, (TILE_START.0 + x_count * TILE_SIZE.0 + 1, y).into(), TILE_UNEXPLORED) && !pixel_color(image, (x, y).into(), TILE_UNEXPLORED) {
+        return None;
+    }
 
-            if pixel_color(image, (TILE_START.0 + x_count * TILE_SIZE.0 + 1, y).into(), TILE_UNEXPLORED) && !pixel_color(image, (x, y).into(), TILE_UNEXPLORED) {
-                continue;
-            }
+    Some(tile)
+}
 
-            //println!("{x}x{y} = {}x{} n={} e={} s={} w={} ", tile.position.x, tile.position.y, tile.north_passable, tile.east_passable, tile.south_passable, tile.west_passable);
-            
-            if tile.position.x == 22 && tile.position.y == 14 {
-                if tile.north_passable {
-                    println!("{tile:?} {}x{}", x, TILE_START.1 + y_count * TILE_SIZE.1 + 1);
-                    panic!();
-                }
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TileEdit {
+    #[serde(default)]
+    pub floor: Option<String>,
+    pub position: Coords,
+    #[serde(default)]
+    pub north_passable: Option<bool>,
+    #[serde(default)]
+    pub east_passable: Option<bool>,
+    #[serde(default)]
+    pub south_passable: Option<bool>,
+    #[serde(default)]
+    pub west_passable: Option<bool>,
+    #[serde(default)]
+    pub is_city: Option<bool>,
+    #[serde(default)]
+    pub is_go_down: Option<bool>,
+}
+impl TileEdit {
+    fn apply(&self, tile:&mut Tile) {
+        let fields = [
+            (self.north_passable, &mut tile.north_passable),
+            (self.east_passable, &mut tile.east_passable),
+            (self.south_passable, &mut tile.south_passable),
+            (self.west_passable, &mut tile.west_passable),
+            (self.is_city, &mut tile.is_city),
+            (self.is_go_down, &mut tile.is_go_down),
+        ];
+        for (value, field) in fields {
+            if let Some(value) = value {
+                *field = value;
             }
-            //println!("{x}x{y} {tile:?}");
-
-            /*if 806 == x && 686 == y {
-                println!("west {}x{y} {:?}", TILE_START.0 + x_count * TILE_SIZE.0 + 1, image.get_pixel(TILE_START.0 + x_count * TILE_SIZE.0 + 1, y));
-                println!("east {}x{y} {:?}", TILE_START.0 + x_count * TILE_SIZE.0 + TILE_SIZE.0 - 1, image.get_pixel(TILE_START.0 + x_count * TILE_SIZE.0 + TILE_SIZE.0 - 1, y));
-
-                println!("south {x}x{} {:?}", TILE_START.1 + y_count * TILE_SIZE.1 + TILE_SIZE.1 - 4, image.get_pixel(x, TILE_START.1 + y_count * TILE_SIZE.1 + TILE_SIZE.1 - 4));
-            }*/
-
-            tiles.push(tile);
         }
     }
-   // std::process::exit(0);
-    tiles
+
+    fn combine(&mut self, newer:&TileEdit) {
+        self.north_passable = newer.north_passable.or(self.north_passable);
+        self.east_passable = newer.east_passable.or(self.east_passable);
+        self.south_passable = newer.south_passable.or(self.south_passable);
+        self.west_passable = newer.west_passable.or(self.west_passable);
+        self.is_city = newer.is_city.or(self.is_city);
+        self.is_go_down = newer.is_go_down.or(self.is_go_down);
+    }
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 enum RandomTarget {
     GoDown,
@@ -648,58 +1113,209 @@ enum RandomTarget {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Dungeon {
     state: DungeonState,
-    characters: [Character; 4],
+    characters: Vec<Character>,
     info: DungeonInfo,
-    tiles: Vec<Tile>,
+    tiles: TileMap,
+    #[serde(default)]
+    floors: BTreeMap<String, TileMap>,
+    #[serde(default)]
+    potions: Option<u32>,
+    #[serde(default)]
+    skills: SkillBar,
+    #[serde(default)]
+    blocked: HashSet<(Coords, MoveDirection)>,
+    #[serde(default)]
+    edits: Vec<TileEdit>,
+    #[serde(skip)]
+    jump: Option<(Coords, u32)>,
+    #[serde(skip)]
+    floor_changed: Option<FloorChanged>,
+    #[serde(skip)]
+    plan: Option<ExplorePlan>,
+    #[serde(skip)]
+    route: RouteCache,
 }
 impl Default for Dungeon {
     fn default() -> Self {
-        Self { state: DungeonState::Idle(false), characters: Default::default(), info: DungeonInfo {floor: "".to_owned(), coordinates: None}, tiles: Default::default() }
+        Self { state: DungeonState::Idle(false), characters: Default::default(), info: DungeonInfo {floor: "".to_owned(), coordinates: None, size: None, kind: DungeonKind::Main}, tiles: Default::default(), floors: Default::default(), potions: None, skills: Default::default(), blocked: HashSet::new(), edits: Vec::new(), jump: None, floor_changed: None, plan: None, route: Default::default() }
+    }
+}
+
+fn checked_coordinates(info:&DungeonInfo, old:&Dungeon) -> (Option<Coords>, Option<(Coords, u32)>) {
+    let previous = old.info.coordinates;
+    let (Some(read), Some(previous)) = (info.coordinates, previous) else {
+        return (info.coordinates.or(previous), None);
+    };
+    if let Some(size) = info.size.or(old.info.size)
+        && (read.x >= size.x || read.y >= size.y) {
+        println!("Ignoring coordinates {read:?} outside floor size {size:?}, staying at {previous:?}");
+        return (Some(previous), old.jump);
+    }
+    if read.steps_to(previous) <= 1 || changed_floor(&info.floor, old.floor_name()).is_some() {
+        return (Some(read), None);
+    }
+    let seen = match old.jump {
+        Some((position, seen)) if position == read => seen + 1,
+        _ => 1,
+    };
+    if seen >= JUMP_CONFIRMATIONS {
+        println!("Accepting jump from {previous:?} to {read:?} after {seen} readings");
+        return (Some(read), None);
+    }
+    println!("Ignoring coordinates {read:?}, more than one step from {previous:?}");
+    (Some(previous), Some((read, seen)))
+}
+
+fn next_floor(floor:&str) -> String {
+    match FloorId::parse(floor) {
+        Some(floor) => floor.next().to_string(),
+        None => format!("{floor}+1"),
+    }
+}
+fn previous_floor(floor:&str) -> String {
+    match FloorId::parse(floor) {
+        Some(floor) => floor.previous().to_string(),
+        None => floor.to_owned(),
     }
 }
+fn changed_floor(label:&str, current:&str) -> Option<FloorId> {
+    let floor = FloorId::parse(label)?;
+    (FloorId::parse(current).as_ref() != Some(&floor)).then_some(floor)
+}
 impl Dungeon {
-    fn has_low_character(&self) -> bool {
-        self.characters.iter().any(|v|v.health == Health::Low)
+    pub fn characters(&self) -> &[Character] {
+        &self.characters
     }
-    fn has_dead_character(&self) -> bool {
-        self.characters.iter().any(|v|v.health == Health::Dead)
+
+    pub fn floor_number(&self) -> Option<u32> {
+        FloorId::parse(self.floor_name()).map(|floor|floor.number)
     }
 
-    pub fn new(state:DungeonState, image:&BitmapImpl, old_position:Option<Coords>) -> Self {
-        let mut state = Self {
-            state,
-            characters: get_characters(image),
-            info: if let Some(p) = image.info.coordinates {
-                image.info.clone()
-            }
-            else {
-                DungeonInfo {
-                    floor: image.info.floor.to_owned(),
-                    coordinates: old_position,
-                }
-            },
-            tiles: get_tiles(&image.info, image),
-        };
-        if let Some(pos) = state.info.coordinates {
-            state.set_tile_visited(pos.x, pos.y);
+    pub fn floor_changed(&self) -> Option<&FloorChanged> {
+        self.floor_changed.as_ref()
+    }
+
+    pub fn explored_tiles(&self) -> usize {
+        self.tiles.iter().chain(self.floors.values().flat_map(|tiles|tiles.iter())).filter(|tile|tile.explored).count()
+    }
+
+    pub fn exploration(&self) -> f32 {
+        self.tiles.exploration()
+    }
+
+    pub fn navigation(&self) -> Option<Navigation> {
+        if !matches!(self.state, DungeonState::Idle(_)) {
+            return None;
         }
-        state
+        let position = self.info.coordinates?;
+        let route = self.route.lock();
+        let route = route.as_ref()?;
+        let path = route.remaining(position);
+        let next = match path.as_slice() {
+            [from, to, ..] => Some(self.get_tile(to.x, to.y).direction_from(self.get_tile(from.x, from.y))),
+            _ => None,
+        };
+        Some(Navigation {
+            target: route.goal(),
+            path,
+            next,
+        })
     }
 
-    fn get_current_tile(&self) -> Tile {
-        self.get_tile(self.info.coordinates.unwrap().x, self.info.coordinates.unwrap().y)
+    pub fn manual_move(&self, direction:MoveDirection) -> Option<Action> {
+        self.info.coordinates?;
+        Some(Action::FindFight(direction, (self.get_current_tile(), 0)))
     }
-    fn get_tile(&self, x:u32, y:u32) -> Tile {
-        for tile in &self.tiles {
-            if tile.position.x == x && tile.position.y == y {
-                return *tile
-            }
+
+    pub fn floor_map(&self, floor:&str) -> Option<(&TileMap, Option<Coords>)> {
+        if floor == self.floor_name() {
+            Some((&self.tiles, self.info.coordinates))
         }
-        Tile {
-            explored: false,
-            trap: false,
-            is_city: false,
-            is_go_down: false,
+        else {
+            self.floors.get(floor).map(|tiles|(tiles, None))
+        }
+    }
+
+    pub fn exploration_by_floor(&self) -> BTreeMap<String, f32> {
+        let mut exploration = self.floors.iter().map(|(floor, tiles)|(floor.clone(), tiles.exploration())).collect::<BTreeMap<_, _>>();
+        if !self.tiles.is_empty() {
+            exploration.insert(self.floor_name().to_owned(), self.exploration());
+        }
+        exploration
+    }
+
+    pub fn enemy_health(&self) -> Option<Health> {
+        match self.state {
+            DungeonState::Fight(enemy) | DungeonState::BossFight(enemy) => Some(enemy.health),
+            _ => None,
+        }
+    }
+
+    pub fn in_boss_fight(&self) -> bool {
+        matches!(self.state, DungeonState::BossFight(_))
+    }
+
+    pub fn potions(&self) -> Option<u32> {
+        self.potions
+    }
+
+    pub fn dead_characters(&self) -> usize {
+        dead_slots(&self.characters).count()
+    }
+
+    pub fn new(state:DungeonState, image:&BitmapImpl, old:&Dungeon) -> Self {
+        let (coordinates, jump) = checked_coordinates(&image.info, old);
+        let info = DungeonInfo {
+            floor: image.info.floor.to_owned(),
+            coordinates,
+            size: image.info.size,
+            kind: image.info.kind,
+        };
+        let mut state = Self {
+            state: state.clone(),
+            characters: image.characters.clone(),
+            tiles: if image.info.coordinates.is_some() && coordinates != image.info.coordinates {
+                TileMap::default()
+            }
+            else {
+                get_tiles(&image.info, image)
+            },
+            info,
+            floors: BTreeMap::new(),
+            potions: image.potions,
+            skills: if let DungeonState::Fight(_) | DungeonState::BossFight(_) = state {
+                image.skills
+            }
+            else {
+                SkillBar::default()
+            },
+            blocked: HashSet::new(),
+            edits: Vec::new(),
+            jump,
+            floor_changed: None,
+            plan: None,
+            route: Default::default(),
+        };
+        if let Some(pos) = state.info.coordinates {
+            state.set_tile_visited(pos.x, pos.y);
+        }
+        state
+    }
+
+    fn get_current_tile(&self) -> Tile {
+        self.get_tile(self.info.coordinates.unwrap().x, self.info.coordinates.unwrap().y)
+    }
+    fn get_tile(&self, x:u32, y:u32) -> Tile {
+        if let Some(tile) = self.tiles.get(&Coords { x, y }) {
+            return *tile;
+        }
+        Tile {
+            explored: false,
+            trap: false,
+            is_city: false,
+            is_go_down: false,
+            is_go_up: false,
+            has_chest: false,
             visited: false,
             position: Coords { x, y },
             north_passable: true,
@@ -710,21 +1326,39 @@ impl Dungeon {
     }
 
     fn get_city_tile(&self) -> Option<Tile> {
-        for tile in &self.tiles {
-            if tile.is_city {
-                return Some(*tile);
-            }
-        }
-        None
+        self.tiles.first(|tile|tile.is_city).copied()
     }
 
     fn get_go_down_tile(&self) -> Option<Tile> {
-        for tile in &self.tiles {
-            if tile.is_go_down {
-                return Some(*tile);
-            }
+        self.tiles.first(|tile|tile.is_go_down).copied()
+    }
+
+    pub fn visited_pct(&self) -> f32 {
+        let explored = self.tiles.iter().filter(|tile|tile.explored).count();
+        if explored == 0 {
+            return 0.0;
         }
-        None
+        self.tiles.iter().filter(|tile|tile.explored && tile.visited).count() as f32 * 100.0 / explored as f32
+    }
+
+    pub fn visible_chests(&self) -> usize {
+        self.tiles.iter().filter(|tile|tile.has_chest).count()
+    }
+
+    fn get_closest_chest_tile(&self) -> Option<Tile> {
+        let position = self.info.coordinates?;
+        let chests = self.tiles.iter().filter(|tile|tile.has_chest && tile.position != position).map(|tile|tile.position).collect::<HashSet<_>>();
+        if chests.is_empty() {
+            return None;
+        }
+        let bounds = self.bounds();
+        let plan = ExplorePlan::new(position, chests, |pos|self.successors(pos, bounds))?;
+        let target = plan.target();
+        Some(self.get_tile(target.x, target.y))
+    }
+
+    fn get_go_up_tile(&self) -> Option<Tile> {
+        self.tiles.first(|tile|tile.is_go_up).copied()
     }
 
     fn get_random_tile_from_current(&self, avoid_position:Option<Coords>, random_target:RandomTarget) -> Tile {
@@ -797,6 +1431,57 @@ impl Dungeon {
         *tiles.choose(&mut rand::rng()).unwrap()
     }
     
+    fn bounds(&self) -> (Coords, Coords) {
+        if let Some(size) = self.info.size {
+            return (Coords { x: 0, y: 0 }, Coords { x: size.x.saturating_sub(1), y: size.y.saturating_sub(1) });
+        }
+        let positions = self.tiles.iter().map(|tile|tile.position).chain(self.info.coordinates);
+        let (min, max) = positions.fold((Coords { x: u32::MAX, y: u32::MAX }, Coords { x: 0, y: 0 }), |(min, max), pos|{
+            (Coords { x: min.x.min(pos.x), y: min.y.min(pos.y) }, Coords { x: max.x.max(pos.x), y: max.y.max(pos.y) })
+        });
+        if min.x > max.x {
+            return (min, min);
+        }
+        (Coords { x: min.x.saturating_sub(MAP_MARGIN), y: min.y.saturating_sub(MAP_MARGIN) }, Coords { x: max.x + MAP_MARGIN, y: max.y + MAP_MARGIN })
+    }
+
+    fn successors(&self, pos:&Coords, (min, max):(Coords, Coords)) -> Vec<(Coords, u32)> {
+        let tile = self.get_tile(pos.x, pos.y);
+
+        let mut out = Vec::with_capacity(4);
+        let mut push = |pos:Coords|{
+            let cost = if self.tiles.get(&pos).is_some_and(|tile|tile.trap) {
+                TRAP_COST
+            }
+            else {
+                1
+            };
+            out.push((pos, cost));
+        };
+
+        // Norr: y - 1 (anpassa om ditt koordinatsystem är tvärtom)
+        if tile.north_passable && pos.y > min.y {
+            let n = Coords { x: pos.x, y: pos.y - 1 };
+                push(n);
+        }
+        // Öst: x + 1
+        if tile.east_passable && pos.x < max.x {
+            let e = Coords { x: pos.x + 1, y: pos.y };
+                push(e);
+        }
+        // Syd: y + 1
+        if tile.south_passable && pos.y < max.y {
+            let s = Coords { x: pos.x, y: pos.y + 1 };
+                push(s);
+        }
+        // Väst: x - 1
+        if tile.west_passable && pos.x > min.x {
+            let w = Coords { x: pos.x - 1, y: pos.y };
+                push(w);
+        }
+        out
+    }
+
     fn get_next_tile_to_goal(&self, current_tile:Tile, goal:Tile) -> Option<Tile> {
         use pathfinding::prelude::astar;
         fn manhattan(a: Coords, b: Coords) -> u32 {
@@ -805,186 +1490,302 @@ impl Dungeon {
         if current_tile.position == goal.position {
             return Some(current_tile);
         }
-        //let map: HashMap<Coords, &Tile> = self.tiles.iter().map(|t| (t.position, t)).collect();
-        let successors = |pos: &Coords| -> Vec<(Coords, u32)> {
-            let tile = self.get_tile(pos.x, pos.y);
-
-            let mut out = Vec::with_capacity(4);
-
-            // Norr: y - 1 (anpassa om ditt koordinatsystem är tvärtom)
-            if tile.north_passable && pos.y > 0 {
-                let n = Coords { x: pos.x, y: pos.y - 1 };
-                    out.push((n, 1));
-            }
-            // Öst: x + 1
-            if tile.east_passable && pos.x < 29 {
-                let e = Coords { x: pos.x + 1, y: pos.y };
-                    out.push((e, 1));
-            }
-            // Syd: y + 1
-            if tile.south_passable && pos.y < 29 {
-                let s = Coords { x: pos.x, y: pos.y + 1 };
-                    out.push((s, 1));
-            }
-            // Väst: x - 1
-            if tile.west_passable && pos.x > 0 {
-                let w = Coords { x: pos.x - 1, y: pos.y };
-                    out.push((w, 1));
-            }
-            out
-        };
-        if let Some((path, _cost)) = astar(&current_tile.position, successors, |p|manhattan(*p, goal.position), |p|*p == goal.position) {
-            let l = path.get(path.len()-2).unwrap();
-            //println!("{path:?} {:?}", self.get_tile(l.x, l.y));
+        let mut route = self.route.lock();
+        if let Some(route) = route.as_mut()
+            && let Some(pos) = route.next_step(current_tile.position, goal.position, |pos|self.get_tile(pos.x, pos.y)) {
+            return Some(self.get_tile(pos.x, pos.y));
+        }
+        let bounds = self.bounds();
+        if let Some((path, _cost)) = timing::time(Phase::Pathfinding, ||astar(&current_tile.position, |pos|self.successors(pos, bounds), |p|manhattan(*p, goal.position), |p|*p == goal.position)) {
+            //println!("{path:?}");
             //println!("{:?}", self.get_current_tile());
             let pos = path.get(1).unwrap();
-            Some(self.get_tile(pos.x, pos.y))
+            let next = self.get_tile(pos.x, pos.y);
+            *route = Some(Route::new(goal.position, path.iter().map(|pos|self.get_tile(pos.x, pos.y)).collect()));
+            Some(next)
         }
         else {
             None
         }
     }
 
-    fn get_closest_unvisited_tile(&self, current_tile:Tile) -> Option<Tile> {
-        use pathfinding::prelude::astar;
-        //let map: HashMap<Coords, &Tile> =
-            //self.tiles.iter().map(|t| (t.position, t)).collect();
-
-        let successors = |pos: &Coords| -> Vec<(Coords, u32)> {
-            //let Some(tile) = map.get(pos) else { return vec![]; };
-            let tile = self.get_tile(pos.x, pos.y);
-            let mut out = Vec::with_capacity(4);
-            if tile.north_passable && pos.y > 0 {
-                let n = Coords { x: pos.x, y: pos.y - 1 };
-                //if map.contains_key(&n) {
-                    out.push((n, 1));
-                //}
-            }
-            if tile.east_passable {
-                let e = Coords { x: pos.x + 1, y: pos.y };
-                //if map.contains_key(&e) {
-                    out.push((e, 1));
-                //}
-            }
-            if tile.south_passable {
-                let s = Coords { x: pos.x, y: pos.y + 1 };
-                //if map.contains_key(&s) {
-                    out.push((s, 1));
-                //}
-            }
-            if tile.west_passable && pos.x > 0 {
-                let w = Coords { x: pos.x - 1, y: pos.y };
-                //if map.contains_key(&w) {
-                    out.push((w, 1));
-                //}
-            }
+    fn frontier(&self, position:Coords) -> HashSet<Coords> {
+        self.tiles.iter()
+        .filter(|tile|tile.explored && tile.position != position && self.has_unexplored_neighbour(tile))
+        .map(|tile|tile.position)
+        .collect()
+    }
 
-            out
+    fn update_plan(&mut self) {
+        let Some(position) = self.info.coordinates else {
+            self.plan = None;
+            return;
         };
+        let frontier = self.frontier(position);
+        if let Some(plan) = self.plan.as_mut()
+            && plan.advance(&frontier, position) {
+            return;
+        }
+        let bounds = self.bounds();
+        self.plan = ExplorePlan::new(position, frontier, |pos|self.successors(pos, bounds));
+        if let Some(plan) = &self.plan {
+            println!("Planned path to frontier tile {:?} ({} steps)", plan.target(), plan.path().len() - 1);
+        }
+    }
 
-        let is_goal = |pos: &Coords| {
-            !self.get_tile(pos.x, pos.y).visited
-            //map.get(pos).map_or(false, |t| !t.explored)
-        };
+    fn get_unexplored_tile(&self, old_position: Option<Coords>) -> Tile {
+        if let Some(plan) = &self.plan {
+            let target = plan.target();
+            return self.get_tile(target.x, target.y);
+        }
+        println!("No reachable frontier tile");
+        self.get_random_tile_from_current(old_position, RandomTarget::Unexplored)
+    }
+    
+    fn has_unexplored_neighbour(&self, tile: &Tile) -> bool {
+        if tile.north_passable && tile.position.y > 0 && !self.get_tile(tile.position.x, tile.position.y - 1).explored {
+            return true;
+        }
+        if tile.south_passable && !self.get_tile(tile.position.x, tile.position.y + 1).explored {
+            return true;
+        }
+        if tile.east_passable && !self.get_tile(tile.position.x + 1, tile.position.y).explored {
+            return true;
+        }
+        if tile.west_passable && tile.position.x > 0 && !self.get_tile(tile.position.x - 1, tile.position.y).explored {
+            return true;
+        }
+        false
+    }
+    
+    pub fn floor_name(&self) -> &str {
+        if self.info.floor.is_empty() {
+            "D1"
+        }
+        else {
+            &self.info.floor
+        }
+    }
+
+    pub fn atlas_key(&self) -> String {
+        match self.info.kind {
+            DungeonKind::Main => self.floor_name().to_owned(),
+            DungeonKind::Event => format!("event/{}", self.floor_name()),
+        }
+    }
 
-        if let Some(result) = astar(
-            &current_tile.position,
-            successors,
-            |_| 0u32,
-            is_goal,
-        ) {
-            //println!("astar {result:?}");
-            if !result.0.is_empty() {
-                let pos = result.0.last().unwrap();
-                return Some(self.get_tile(pos.x, pos.y));
+    fn seed_from_atlas(&mut self, atlas:&Atlas) {
+        let Some(floor) = atlas.floor(&self.atlas_key()) else {
+            return;
+        };
+        let has_city = self.get_city_tile().is_some();
+        let has_go_down = self.get_go_down_tile().is_some();
+        for tile in floor.tiles() {
+            if self.tiles.contains(&tile.position) {
+                continue;
             }
+            let mut tile = *tile;
+            tile.visited = false;
+            tile.is_city = tile.is_city && !has_city;
+            tile.is_go_down = tile.is_go_down && !has_go_down;
+            self.tiles.insert(tile);
+        }
+    }
+
+    pub fn replace_floor(&mut self, floor:&str, tiles:TileMap) -> bool {
+        if floor == self.floor_name() {
+            self.tiles = tiles;
+            self.plan = None;
+            self.route.clear();
+            true
+        }
+        else if let Some(known) = self.floors.get_mut(floor) {
+            *known = tiles;
+            true
         }
         else {
-            println!("found no ununvisited tile");
+            false
         }
-        None
     }
-    
-    fn get_unexplored_tile(&self, old_position: Option<Coords>) -> Tile {
-        let me = self.get_current_tile();
-        if let Some(tile) = self.get_closest_unvisited_tile(me) {
-            return tile;
-        }
-        if me.west_passable && me.position.x > 0 {
-            let tile = self.get_tile(me.position.x - 1, me.position.y);
-            if !tile.explored {
-                return tile;
-            }
+
+    fn descend(&mut self) {
+        let next = next_floor(self.floor_name());
+        self.floor_changed = Some(self.change_floor(next));
+    }
+
+    fn change_floor(&mut self, floor:String) -> FloorChanged {
+        let from = self.floor_name().to_owned();
+        println!("Floor changed from {from} to {floor}");
+        let tiles = std::mem::take(&mut self.tiles);
+        self.floors.insert(from.clone(), tiles);
+        self.tiles = self.floors.remove(&floor).unwrap_or_default();
+        self.blocked.clear();
+        self.clear_visited();
+        self.info.floor = floor.clone();
+        self.info.coordinates = None;
+        self.jump = None;
+        self.plan = None;
+        self.route.clear();
+        FloorChanged { from, to: floor }
+    }
+
+    fn ascend_towards_city(&self) -> Option<Action> {
+        if self.get_city_tile().is_some() || self.floor_number().is_none_or(|floor|floor <= 1) {
+            return None;
         }
-        if me.east_passable {
-            let tile = self.get_tile(me.position.x + 1, me.position.y);
-            if !tile.explored {
-                return tile;
-            }
+        let go_up_tile = self.get_go_up_tile()?;
+        let current_tile = self.get_current_tile();
+        if go_up_tile.position == current_tile.position {
+            return Some(Action::GoUp);
         }
-        if me.north_passable && me.position.y > 0 {
-            let tile = self.get_tile(me.position.x, me.position.y - 1);
-            if !tile.explored {
-                return tile;
-            }
+        if let Some(next_tile) = self.get_next_tile_to_goal(current_tile, go_up_tile) {
+            println!("Stairs up {:?}", go_up_tile);
+            Some(Action::ReturnToTown(false, next_tile.direction_from(current_tile)))
         }
-        if me.south_passable {
-            let tile = self.get_tile(me.position.x, me.position.y + 1);
-            if !tile.explored {
-                return tile;
-            }
+        else {
+            println!("Found no path to stairs up");
+            let tile = self.get_random_tile_from_current(None, RandomTarget::City);
+            Some(Action::ReturnToTown(false, tile.direction_from(current_tile)))
         }
-        if let Some(tile) = self.tiles.iter().filter(|tile|self.has_unexplored_neighbour(tile)).choose(&mut rand::rng()) {
-            return *tile;
+    }
+
+    pub fn leave_floor(&self) -> Option<Action> {
+        let current_tile = self.get_current_tile();
+        let (target, arrive) = if self.floor_number().is_some_and(|floor|floor > 1) {
+            (self.get_go_up_tile()?, Action::GoUp)
+        }
+        else {
+            (self.get_city_tile()?, Action::ReturnToTown(true, MoveDirection::East))
+        };
+        if target.position == current_tile.position {
+            return Some(arrive);
+        }
+        let next_tile = self.get_next_tile_to_goal(current_tile, target)?;
+        Some(Action::FindFight(next_tile.direction_from(current_tile), (target, 1)))
+    }
+
+    fn ascend(&mut self) {
+        let previous = previous_floor(self.floor_name());
+        self.floor_changed = Some(self.change_floor(previous));
+    }
+
+    fn clear_visited(&mut self) {
+        for tile in self.tiles.iter_mut() {
+            tile.visited = false;
         }
-        self.get_random_tile_from_current(old_position, RandomTarget::Unexplored)
     }
     
-    fn has_unexplored_neighbour(&self, tile: &Tile) -> bool {
-        if tile.north_passable && tile.position.y > 0 {
-            if !self.get_tile(tile.position.x, tile.position.y - 1).explored {
-                return true;
+    fn took_damage_since(&self, old:&[Character]) -> bool {
+        self.characters.iter().zip(old.iter()).any(|(new, old)|{
+            match (new.health_pct, old.health_pct) {
+                (Some(new), Some(old)) => new + DAMAGE_NOISE_PCT < old,
+                _ => new.health != Health::Unknown && old.health != Health::Unknown && new.health < old.health,
             }
+        })
+    }
+
+    fn mark_trap(&mut self, position:Coords) {
+        let mut tile = self.get_tile(position.x, position.y);
+        if !tile.trap {
+            println!("Marking trap at {position:?}");
+            tile.trap = true;
+            self.tiles.insert(tile);
         }
-        if tile.south_passable {
-            if !self.get_tile(tile.position.x, tile.position.y + 1).explored {
-                return true;
-            }
+    }
+
+    fn block_edge(&mut self, position:Coords, direction:MoveDirection) {
+        println!("Marking {direction:?} of {position:?} impassable");
+        self.blocked.insert((position, direction));
+        if let Some(neighbour) = position.checked_move(direction) {
+            self.blocked.insert((neighbour, direction.opposite()));
         }
-        if tile.east_passable {
-            if !self.get_tile(tile.position.x + 1, tile.position.y).explored {
-                return true;
+        self.apply_blocked();
+        self.plan = None;
+        self.route.clear();
+    }
+
+    fn floor_tiles_mut(&mut self, floor:&str) -> Option<&mut TileMap> {
+        if floor == self.floor_name() {
+            Some(&mut self.tiles)
+        }
+        else {
+            self.floors.get_mut(floor)
+        }
+    }
+
+    pub fn edit_tile(&mut self, mut edit:TileEdit) -> Option<Tile> {
+        let floor = edit.floor.clone().unwrap_or_else(||self.floor_name().to_owned());
+        edit.floor = Some(floor.clone());
+        let tiles = self.floor_tiles_mut(&floor)?;
+        let tile = tiles.get_mut(&edit.position)?;
+        edit.apply(tile);
+        let tile = *tile;
+        match self.edits.iter_mut().find(|known|known.floor == edit.floor && known.position == edit.position) {
+            Some(known) => known.combine(&edit),
+            None => self.edits.push(edit),
+        }
+        self.plan = None;
+        self.route.clear();
+        Some(tile)
+    }
+
+    pub fn clear_tile(&mut self, floor:Option<String>, position:Coords) -> bool {
+        let floor = floor.unwrap_or_else(||self.floor_name().to_owned());
+        let Some(tiles) = self.floor_tiles_mut(&floor) else {
+            return false;
+        };
+        if tiles.remove(&position).is_none() {
+            return false;
+        }
+        self.edits.retain(|edit|edit.floor.as_deref() != Some(floor.as_str()) || edit.position != position);
+        self.plan = None;
+        self.route.clear();
+        true
+    }
+
+    fn apply_edits(&mut self) {
+        let floor = self.floor_name().to_owned();
+        for edit in self.edits.iter().filter(|edit|edit.floor.as_deref() == Some(floor.as_str())) {
+            if let Some(tile) = self.tiles.get_mut(&edit.position) {
+                edit.apply(tile);
             }
         }
-        if tile.west_passable && tile.position.x > 0 {
-            if !self.get_tile(tile.position.x - 1, tile.position.y).explored {
-                return true;
+    }
+
+    fn apply_blocked(&mut self) {
+        for (position, direction) in &self.blocked {
+            if let Some(tile) = self.tiles.get_mut(position) {
+                match direction {
+                    MoveDirection::North => tile.north_passable = false,
+                    MoveDirection::East => tile.east_passable = false,
+                    MoveDirection::South => tile.south_passable = false,
+                    MoveDirection::West => tile.west_passable = false,
+                }
             }
         }
-        false
     }
-    
-    fn clear_visited(&mut self) {
-        for tile in self.tiles.iter_mut() {
-            tile.visited = false;
+
+    fn clear_chest(&mut self) {
+        if let Some(position) = self.info.coordinates
+            && let Some(tile) = self.tiles.get_mut(&position) {
+            tile.has_chest = false;
         }
     }
-    
+
     fn set_tile_visited(&mut self, x: u32, y: u32) {
-        for tile in self.tiles.iter_mut() {
-            if tile.position.x == x && tile.position.y == y {
-                tile.visited = true;
-            }
+        if let Some(tile) = self.tiles.get_mut(&Coords { x, y }) {
+            tile.visited = true;
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DungeonState {
     Idle(bool),
     IdleChest,
     IdleChestMagical,
     Fight(Enemy),
+    BossFight(Enemy),
 }
 
 const WHITE:image::Rgb<u8> = image::Rgb([255, 255, 255]);
@@ -996,61 +1797,139 @@ const HEALTH_RED:image::Rgb<u8> = image::Rgb([244, 67, 54]);
 const HEALTH_RED_PLAYER:image::Rgb<u8> = image::Rgb([211, 47, 47]);
 const HEALTH_GREEN:image::Rgb<u8> = image::Rgb([56, 142, 60]);
 const HEALTH_ORANGE:image::Rgb<u8> = image::Rgb([245, 124, 0]);
-
+const CHARACTER_BAR:(u32, u32) = (147, 516);
+const ENEMY_SCAN:(u32, u32) = (12, 1068);
+const ENEMY_BAR_Y:u32 = 1471;
+const MIN_ENEMY_BAR:u32 = 200;
+const HEALTHY_PCT:f32 = 98.0;
+const HURT_PCT:f32 = 39.0;
+const DAMAGE_NOISE_PCT:f32 = 2.0;
+
+const GOLD:image::Rgb<u8> = image::Rgb([255, 193, 7]);
+const GOLD_ICON:(u32, u32) = (664, 96);
+const LEVEL_TEXT:(u32, u32) = (132, 84);
+const ENERGY_BLUE:image::Rgb<u8> = image::Rgb([3, 169, 244]);
+const ENERGY_ICON:(u32, u32) = (436, 96);
+const ENERGY_TEXT:(u32, u32) = (476, 84);
+const XP_TEXT:(u32, u32) = (300, 140);
+const RESURRECT_PURPLE:image::Rgb<u8> = image::Rgb([103, 80, 164]);
+const TEMPLE_TITLE:(u32, u32) = (96, 300);
+const TEMPLE_RESURRECT:(u32, u32) = (860, 620);
+const TEMPLE_CLOSE:(u32, u32) = (980, 300);
+const CITY_TEMPLE:(u32, u32) = (180, 1700);
+const RESURRECT_COST:(u32, u32) = (470, 1180);
+const RESURRECT_CONFIRM:(u32, u32) = (680, 1440);
+const RESURRECT_CANCEL:(u32, u32) = (331, 1440);
+const CITY_EVENT:(u32, u32) = (660, 1500);
+const EVENT_ENTER:(u32, u32) = (540, 1700);
+pub const EVENT_PINK:image::Rgb<u8> = image::Rgb([236, 64, 122]);
+pub const EVENT_BADGE:(u32, u32) = (1000, 1052);
+const SKILL_READY:image::Rgb<u8> = image::Rgb([208, 188, 255]);
+const SKILL_BAR:(u32, u32) = (160, 1620);
+const SKILL_SPACING:u32 = 190;
+const ACTIVE_YELLOW:image::Rgb<u8> = image::Rgb([255, 235, 59]);
+const ACTIVE_CHARACTER_X:u32 = 40;
+const ENEMY_SLOTS:u32 = 3;
+const ENEMY_MARKER:(u32, u32) = (600, 1380);
+const ENEMY_LEVEL:(u32, u32) = (560, 1420);
+const FLEE_BUTTON:(u32, u32) = (120, 1308);
+const DIALOG_OK:(u32, u32) = (540, 1440);
+const DIALOG_BUTTON:(u32, u32) = (280, 96);
+const CITY_BUTTON:(u32, u32) = (200, 120);
+const ICON_BUTTON:(u32, u32) = (64, 64);
+const SKILL_BUTTON:(u32, u32) = (150, 100);
+const MOVE_BUTTON:(u32, u32) = (120, 120);
+const ITEM_ROW:(u32, u32) = (400, 90);
+const TEMPLE_ROW:(u32, u32) = (160, 100);
+const BLACK_PROBES:[(u16, u16); 5] = [(540, 300), (200, 1200), (880, 1200), (540, 1200), (540, 2100)];
+const BOSS_RED:image::Rgb<u8> = image::Rgb([183, 28, 28]);
+const BOSS_BANNER:(u32, u32) = (440, 1340);
+const HEAL_GREEN:image::Rgb<u8> = image::Rgb([76, 175, 80]);
+const HEAL_BUTTON:(u32, u32) = (402, 1308);
 const IDLE_1:image::Rgb<u8> = image::Rgb([202, 196, 208]);
 
 const TILE_UNEXPLORED:image::Rgb<u8> = image::Rgb([29, 27, 32]);
 
-pub fn get_characters(image:&BitmapImpl) -> [Character; 4] {
-    std::array::from_fn(|i|{
-        let y = 560 + i as u32 * 120;
-        let health = if pixel_color(image, (514, y).into(), HEALTH_GREEN) {
-            Health::Healthy
+fn scan_health_bar(image:&BitmapImpl, (start, end):(u32, u32), y:u32, fill:&[Rgb<u8>]) -> Option<f32> {
+    let filled = (start..=end).rev().find(|x|pixel_either_color(image, (*x, y).into(), fill.iter().copied()));
+    match filled {
+        Some(x) => Some((x - start + 1) as f32 * 100.0 / (end - start + 1) as f32),
+        None if pixel_color(image, (start, y).into(), HEALTH_GREY) => Some(0.0),
+        None => None,
+    }
+}
+
+pub fn dead_slots(characters:&[Character]) -> impl Iterator<Item = usize> + '_ {
+    characters.iter().enumerate().filter(|(_, character)|character.is_dead()).map(|(slot, _)|slot)
+}
+
+pub fn get_characters(image:&BitmapImpl, party:&PartyLayout) -> Vec<Character> {
+    party.slots().map(|(slot, y)|{
+        let health_pct = scan_health_bar(image, CHARACTER_BAR, y, &[HEALTH_GREEN, HEALTH_ORANGE, HEALTH_RED_PLAYER]);
+        let member = party.member(slot);
+        Character {
+            health: Health::from_pct(health_pct, party.dead_below_pct),
+            health_pct,
+            name: member.map(|member|member.name.clone()),
+            class: member.and_then(|member|member.class.clone()),
         }
-        else if pixel_color(image, (291, y).into(), HEALTH_GREEN) {
-            Health::Hurt
+    }).collect()
+}
+
+fn get_enemy_level(image:&BitmapImpl, opt:&Opt, services:&Services) -> Option<u32> {
+    if get_enemy_panels(image).is_empty() {
+        return None;
+    }
+    read_hud_number(OcrRegion::EnemyLevel, image, opt, services)
+}
+
+fn get_skill_bar(image:&BitmapImpl, party:&PartyLayout) -> SkillBar {
+    SkillBar {
+        active_character: party.slots().find(|(_, y)|pixel_color(image, (ACTIVE_CHARACTER_X, *y).into(), ACTIVE_YELLOW)).map(|(slot, _)|slot as u8),
+        ready: std::array::from_fn(|i|pixel_color(image, (SKILL_BAR.0 + i as u32 * SKILL_SPACING, SKILL_BAR.1).into(), SKILL_READY)),
+    }
+}
+
+fn get_enemy_panels(image:&BitmapImpl) -> Vec<f32> {
+    let is_bar = |x:u32|pixel_either_color(image, (x, ENEMY_BAR_Y).into(), [HEALTH_RED, HEALTH_GREY].into_iter());
+    let mut panels = Vec::new();
+    let mut x = ENEMY_SCAN.0;
+    while x < ENEMY_SCAN.1 && panels.len() < ENEMY_SLOTS as usize {
+        if !is_bar(x) {
+            x += 1;
+            continue;
         }
-        else if pixel_either_color(image, (147, y).into(), [HEALTH_RED_PLAYER, HEALTH_GREEN, HEALTH_ORANGE].into_iter()) {
-            Health::Low
+        let start = x;
+        while x < ENEMY_SCAN.1 && is_bar(x) {
+            x += 1;
         }
-        else if pixel_color(image, (147, y).into(), HEALTH_GREY) {
-            Health::Dead
+        if x - start >= MIN_ENEMY_BAR {
+            panels.push(scan_health_bar(image, (start, x - 1), ENEMY_BAR_Y, &[HEALTH_RED]).unwrap_or_default());
         }
-        else {
-            Health::Unknown
-        };
-        Character { health }
-    })
+    }
+    panels
 }
 
 fn get_enemy(image:&BitmapImpl) -> Enemy {
-    let x = if pixel_either_color(image, (90, 1472).into(), [HEALTH_RED, HEALTH_GREY].into_iter()) {
-        89
+    let found = get_enemy_panels(image);
+    let mut panels = [None; ENEMY_SLOTS as usize];
+    for (panel, pct) in panels.iter_mut().zip(&found) {
+        *panel = Some(*pct);
     }
-    else {
-        0
-    };
+    let alive = found.iter().filter(|pct|**pct > 0.0).count();
+    let health_pct = (!found.is_empty()).then(||found.iter().sum::<f32>() / found.len() as f32);
+    let markers = (0..ENEMY_SLOTS).filter(|i|pixel_color(image, (ENEMY_MARKER.0 + i * 120, ENEMY_MARKER.1).into(), HEALTH_RED)).count();
 
     Enemy {
-        health: if pixel_color(image, (511 - x, 1471).into(), HEALTH_RED) {
-            Health::Healthy
-        }
-        else if pixel_color(image, (355 - x, 1471).into(), HEALTH_RED) {
-            Health::Hurt
-        }
-        else if pixel_color(image, (181 - x, 1471).into(), HEALTH_RED) {
-            Health::Low
-        }
-        else if pixel_color(image, (181 - x, 1471).into(), HEALTH_GREY) {
-            Health::Dead
-        }
-        else {
-            Health::Unknown
-        }
+        count: markers.max(alive).max(1) as u8,
+        level: image.enemy_level,
+        health: Health::from_pct(health_pct, 0.0),
+        health_pct,
+        panels,
     }
 }
 
-fn write_coord_to_file(x:u32, y: u32) {
+fn write_coord_to_file(_x:u32, _y: u32) {
     //let mut f = std::fs::OpenOptions::new().write(true).create(true).append(true).open("coords.txt").unwrap();
     //write!(f, "{x},{y}\n").unwrap();    
 }
@@ -1079,12 +1958,7 @@ fn pixel_color(image: &BitmapImpl, coords:Coords, color: Rgb<u8>) -> bool {
 fn pixel_color_tolerance(image: &BitmapImpl, coords:Coords, color: Rgb<u8>, tolerance:u8) -> bool {
     write_coord_to_file(coords.x, coords.y);
     fn diff(a:u8, b:u8) -> u8 {
-        if a > b {
-            a - b
-        }
-        else {
-            b - a
-        }
+        a.abs_diff(b)
     }
     //println!("{}x{} {:?} {:?}", coords.x, coords.y, color, image.get_pixel(coords.x, coords.y));
     let clr = image.get_pixel(coords.x as u16, coords.y as u16);
@@ -1096,76 +1970,244 @@ fn pixel_either_color(image: &BitmapImpl, coords:Coords, colors: impl Iterator<I
     colors.into_iter().any(|v|v.0 == color)
 }
 
-pub fn get_state(old_state:State, image:&BitmapImpl) -> Result<State, StateError> {
-    if pixels_same_color(&image, [(918, 138).into(), (949, 138).into(), (919, 168).into(), (949, 168).into()].into_iter(), image::Rgb([202, 196, 208])) {
+pub fn get_state(old_state:State, image:&BitmapImpl, atlas:&mut Atlas) -> Result<State, StateError> {
+    let old_characters = if let (StateType::Dungeon, DungeonState::Idle(_)) = (&old_state.state_type, &old_state.dungeon.state) {
+        Some(old_state.dungeon.characters.clone())
+    }
+    else {
+        None
+    };
+    let mut state = detect_state(old_state, image)?;
+    if let (StateType::Dungeon, DungeonState::Idle(_)) = (&state.state_type, &state.dungeon.state)
+        && let Some(old_characters) = old_characters
+        && state.dungeon.took_damage_since(&old_characters)
+        && let Some(position) = state.get_position() {
+        state.dungeon.mark_trap(position);
+    }
+    state.resources.record(&image.resources);
+    state.fight.observe(state.dungeon.enemy_health());
+    if let StateType::Dungeon = state.state_type {
+        state.dungeon.seed_from_atlas(atlas);
+        atlas.record(&state.dungeon.atlas_key(), &state.dungeon.tiles);
+        state.dungeon.update_plan();
+    }
+    Ok(state)
+}
+
+pub fn observe_movement(state:&mut State, last_action:&Action, frame_unchanged:bool, policy:&Policy) {
+    // An unchanged frame shows the last detected position, not the one predicted by the last move.
+    let position = if frame_unchanged {
+        state.moves.position()
+    }
+    else {
+        state.get_position()
+    };
+    state.moves.observe(last_action, position, state.dungeon.explored_tiles());
+    if let Some(Recovery::Block) = state.moves.recovery(policy.stuck_ticks)
+        && let (Some(position), Some(direction)) = (position, state.moves.direction()) {
+        state.dungeon.block_edge(position, direction);
+        state.moves = MoveWatch::default();
+    }
+}
+
+fn detect_state(old_state:State, image:&BitmapImpl) -> Result<State, StateError> {
+    if pixels_same_color(image, [(918, 138).into(), (949, 138).into(), (919, 168).into(), (949, 168).into()].into_iter(), image::Rgb([202, 196, 208])) {
         return Ok(Into::<State>::into(StateType::Ad).merge(old_state));
     }
-    if pixels_same_color(&image, [(911, 940).into(), (155, 940).into()].into_iter(), image::Rgb([43, 41, 48])) {
+    if is_resurrect_dialog(image) {
+        return Ok(Into::<State>::into(StateType::ResurrectConfirm { cost: image.resurrect_cost, gold: image.resources.gold }).merge(old_state));
+    }
+    if pixels_same_color(image, [(911, 940).into(), (155, 940).into()].into_iter(), image::Rgb([43, 41, 48])) {
         return Ok(Into::<State>::into(StateType::TeleportToCity).merge(old_state));
     }
-    if pixels_same_color(&image, [(918, 138).into(), (949, 138).into(), (919, 168).into(), (949, 168).into()].into_iter(), image::Rgb([202, 196, 208])) {
+    if image.extra_screens && pixels_same_color(image, [TEMPLE_TITLE.into(), TEMPLE_CLOSE.into()].into_iter(), GOLD) {
+        return Ok(Into::<State>::into(StateType::Temple(get_temple_slot(image))).merge(old_state));
+    }
+    if pixels_same_color(image, [(918, 138).into(), (949, 138).into(), (919, 168).into(), (949, 168).into()].into_iter(), image::Rgb([202, 196, 208])) {
         return Ok(Into::<State>::into(StateType::Ad).merge(old_state));
     }
-    if pixel_color_tolerance(&image, (466, 1116).into(), image::Rgb([185, 207, 220]), 5) && pixels_same_color(&image, [(690, 1306).into(), (717, 1326).into()].into_iter(), image::Rgb([56, 30, 114])) {
-        return Ok(Into::<State>::into((StateType::Dungeon, Dungeon::new(DungeonState::IdleChest, &image, old_state.get_position()))).merge(old_state));
+    if pixel_color_tolerance(image, (466, 1116).into(), image::Rgb([185, 207, 220]), 5) && pixels_same_color(image, [(690, 1306).into(), (717, 1326).into()].into_iter(), image::Rgb([56, 30, 114])) {
+        return Ok(Into::<State>::into((StateType::Dungeon, Dungeon::new(DungeonState::IdleChest, image, &old_state.dungeon))).merge(old_state));
     }
-    if pixel_color_tolerance(&image, (466, 1116).into(), image::Rgb([185, 207, 220]), 5) && pixel_color(&image, (714, 1308).into(), image::Rgb([105, 102, 108])) {
-        return Ok(Into::<State>::into((StateType::Dungeon, Dungeon::new(DungeonState::IdleChestMagical, &image, old_state.get_position()))).merge(old_state));
+    if pixel_color_tolerance(image, (466, 1116).into(), image::Rgb([185, 207, 220]), 5) && pixel_color(image, (714, 1308).into(), image::Rgb([105, 102, 108])) {
+        return Ok(Into::<State>::into((StateType::Dungeon, Dungeon::new(DungeonState::IdleChestMagical, image, &old_state.dungeon))).merge(old_state));
     }
-    if (image.get_info().coordinates.is_none() &&
-        (pixel_either_color(&image, (827, 1306).into(), [FIGHT, image::Rgb([192, 172, 241])].into_iter()) ||
-        pixel_either_color(&image, (827, 1260).into(), [FIGHT, image::Rgb([192, 172, 241])].into_iter())) &&
-        !pixel_color(&image, (671, 1309).into(), image::Rgb([56, 30, 114]))) {
-        return Ok(Into::<State>::into((StateType::Dungeon, Dungeon::new(DungeonState::Fight(get_enemy(&image)), &image, old_state.get_position()))).merge(old_state));
+    if image.get_info().coordinates.is_none() &&
+        (pixel_either_color(image, (827, 1306).into(), [FIGHT, image::Rgb([192, 172, 241])].into_iter()) ||
+        pixel_either_color(image, (827, 1260).into(), [FIGHT, image::Rgb([192, 172, 241])].into_iter())) &&
+        !pixel_color(image, (671, 1309).into(), image::Rgb([56, 30, 114])) {
+        let fight = if image.extra_screens && pixels_same_color(image, [BOSS_BANNER.into(), (BOSS_BANNER.0 + 200, BOSS_BANNER.1).into()].into_iter(), BOSS_RED) {
+            DungeonState::BossFight(get_enemy(image))
+        }
+        else {
+            DungeonState::Fight(get_enemy(image))
+        };
+        return Ok(Into::<State>::into((StateType::Dungeon, Dungeon::new(fight, image, &old_state.dungeon))).merge(old_state));
     }
-    if pixel_color(&image, (979, 1083).into(), IDLE_1) && pixel_color(&image, (1023, 1116).into(), IDLE_1) {
-        let on_city_tile = pixel_color(&image, (716, 1279).into(), FIGHT)
+    if pixel_color(image, (979, 1083).into(), IDLE_1) && pixel_color(image, (1023, 1116).into(), IDLE_1) {
+        let on_city_tile = pixel_color(image, (716, 1279).into(), FIGHT)
             && !pixels_same_color(image, [(642, 1201).into(), (608, 1307).into(), (609, 1329).into()].into_iter(), image::Rgb([56, 30, 114]));
-        return Ok(Into::<State>::into((StateType::Dungeon, Dungeon::new(DungeonState::Idle(on_city_tile), &image, old_state.get_position()))).merge(old_state));
+        return Ok(Into::<State>::into((StateType::Dungeon, Dungeon::new(DungeonState::Idle(on_city_tile), image, &old_state.dungeon))).merge(old_state));
     }
-    if pixels_color(&image, [(752, 1926, CITY_1).into(), (75, 1512, CITY_2).into()].into_iter()) {
-        return Ok(Into::<State>::into(StateType::City(image.get_has_dead_characters())).merge(old_state));
+    if pixels_color(image, [(752, 1926, CITY_1).into(), (75, 1512, CITY_2).into()].into_iter()) {
+        return Ok(Into::<State>::into(StateType::City(image.has_dead_characters())).merge(old_state));
     }
-    if pixels_same_color(&image, [(462, 1254).into(), (536, 1262).into(), (615, 1270).into()].into_iter(), WHITE) {
+    if pixels_same_color(image, [(462, 1254).into(), (536, 1262).into(), (615, 1270).into()].into_iter(), WHITE) {
         return Ok(Into::<State>::into(StateType::Main).merge(old_state));
     }
     Err(StateError::UnknownState)
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum MoveDirection {
     North,
     East,
     South,
     West,
 }
-#[derive(Debug, Copy, Clone)]
+impl MoveDirection {
+    pub fn opposite(&self) -> Self {
+        match self {
+            MoveDirection::North => MoveDirection::South,
+            MoveDirection::East => MoveDirection::West,
+            MoveDirection::South => MoveDirection::North,
+            MoveDirection::West => MoveDirection::East,
+        }
+    }
+
+    pub fn perpendicular(&self) -> [Self; 2] {
+        match self {
+            MoveDirection::North | MoveDirection::South => [MoveDirection::East, MoveDirection::West],
+            MoveDirection::East | MoveDirection::West => [MoveDirection::North, MoveDirection::South],
+        }
+    }
+}
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum Action {
     CloseAd, 
     GotoTown,
     GotoDungeon,
     GoDown,
+    GoUp,
 
     CancelTeleportToCity,
     TeleportToCity,
 
     FindFight(MoveDirection, (Tile, u32)),
     Fight,
+    UseSkill(u8),
+    Flee,
+    DismissDialog,
+    Back,
+    Redetect,
     OpenChest,
     OpenChestMagical,
 
+    UseHealingItem(u8),
     ReturnToTown(bool, MoveDirection),
     Resurrect,
+    ResurrectCharacter(u8),
+    ConfirmResurrect,
+    CancelResurrect,
+    LeaveTemple,
+    StartMacro(u8),
+    RunMacro(u8, u8),
+    FinishMacro,
+    AbortMacro,
+    LaunchGame,
+    Rest,
+}
+
+impl Action {
+    pub fn is_attack(&self) -> bool {
+        matches!(self, Action::Fight | Action::UseSkill(_))
+    }
+
+    pub fn move_direction(&self) -> Option<MoveDirection> {
+        match self {
+            Action::FindFight(direction, _) | Action::ReturnToTown(false, direction) => Some(*direction),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::CloseAd => "CloseAd",
+            Action::GotoTown => "GotoTown",
+            Action::GotoDungeon => "GotoDungeon",
+            Action::GoDown => "GoDown",
+            Action::GoUp => "GoUp",
+            Action::CancelTeleportToCity => "CancelTeleportToCity",
+            Action::TeleportToCity => "TeleportToCity",
+            Action::FindFight(_, _) => "FindFight",
+            Action::Fight => "Fight",
+            Action::UseSkill(_) => "UseSkill",
+            Action::Flee => "Flee",
+            Action::DismissDialog => "DismissDialog",
+            Action::Back => "Back",
+            Action::Redetect => "Redetect",
+            Action::OpenChest => "OpenChest",
+            Action::OpenChestMagical => "OpenChestMagical",
+            Action::UseHealingItem(_) => "UseHealingItem",
+            Action::ReturnToTown(_, _) => "ReturnToTown",
+            Action::Resurrect => "Resurrect",
+            Action::ResurrectCharacter(_) => "ResurrectCharacter",
+            Action::ConfirmResurrect => "ConfirmResurrect",
+            Action::CancelResurrect => "CancelResurrect",
+            Action::LeaveTemple => "LeaveTemple",
+            Action::StartMacro(_) => "StartMacro",
+            Action::RunMacro(..) => "RunMacro",
+            Action::FinishMacro => "FinishMacro",
+            Action::AbortMacro => "AbortMacro",
+            Action::LaunchGame => "LaunchGame",
+            Action::Rest => "Rest",
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::FindFight(move_direction, (tile, ticks_same_target)) => write!(f, "FindFight {move_direction:?} target = {:?} ticks = {ticks_same_target}", tile.get_position()),
+            Action::UseHealingItem(slot) => write!(f, "UseHealingItem {}", slot + 1),
+            Action::ResurrectCharacter(slot) => write!(f, "ResurrectCharacter {}", slot + 1),
+            Action::StartMacro(index) => write!(f, "StartMacro {index}"),
+            Action::RunMacro(index, segment) => write!(f, "RunMacro {index} segment {segment}"),
+            Action::UseSkill(slot) => write!(f, "UseSkill {}", slot + 1),
+            Action::ReturnToTown(on_city_tile, move_direction) => write!(f, "ReturnToTown {on_city_tile} {move_direction:?}"),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
 }
 
-pub fn determine_action(state:&State, last_action:Action, old_position:Option<Coords>) -> Action {
+pub fn determine_action<S:Strategy + ?Sized>(state:&State, last_action:Action, old_position:Option<Coords>, strategy:&S, trace:&mut DecisionTrace) -> Action {
+    let policy = strategy.policy();
    // println!("{state:?}");
+    if let Some((index, segment)) = state.macros.running() {
+        trace.step("macro running", &[("index", &index), ("segment", &segment)]);
+        return match policy.macros.get(index as usize) {
+            Some(script) if (segment as usize) < script.len() => match script.expects(segment) {
+                Some(expected) if expected != state.state_type.name() => {
+                    println!("Macro {} expected {expected} but found {}, aborting", script.name, state.state_type.name());
+                    Action::AbortMacro
+                },
+                _ => Action::RunMacro(index, segment),
+            },
+            Some(_) => Action::FinishMacro,
+            None => Action::AbortMacro,
+        };
+    }
     match state.state_type {
         StateType::Ad => {
-            Action::CloseAd
+            if let Action::CloseAd = last_action {
+                Action::Back
+            }
+            else {
+                Action::CloseAd
+            }
         },
         StateType::TeleportToCity => {
-            if state.dungeon.has_dead_character() {
+            if policy.should_retreat(&state.dungeon) || policy.wants_town(state) {
                 Action::TeleportToCity
             }
             else {
@@ -1176,21 +2218,92 @@ pub fn determine_action(state:&State, last_action:Action, old_position:Option<Co
             Action::GotoTown
         },
         StateType::City(has_dead_characters) => {
-            if has_dead_characters {
+            if state.parking {
+                trace.step("parked for stop condition", &[]);
+                Action::GotoTown
+            }
+            else if has_dead_characters {
+                trace.step("dead characters", &[]);
                 Action::Resurrect
             }
+            else if let Some(index) = policy.macro_due(&state.macros, daily::today()) {
+                trace.step("daily macro due", &[("macro", &policy.macros[index as usize].name)]);
+                Action::StartMacro(index)
+            }
+            else if policy.gold_target_reached(state.resources.current()) {
+                trace.step("gold target reached", &[("gold", &state.resources.current().gold), ("gold_target", &policy.gold_target)]);
+                println!("Gold target reached, staying in town");
+                Action::GotoTown
+            }
+            else if policy.lacks_energy(state.resources.current()) {
+                trace.step("lacks energy", &[("energy", &state.resources.current().energy), ("dungeon_energy_cost", &policy.dungeon_energy_cost)]);
+                println!("Not enough energy for the dungeon, resting");
+                Action::Rest
+            }
             else {
+                trace.step("enter dungeon", &[("mode", &policy.mode)]);
                 Action::GotoDungeon
             }
         },
+        StateType::Temple(dead_slot) => {
+            if let Some(slot) = dead_slot {
+                Action::ResurrectCharacter(slot)
+            }
+            else {
+                Action::LeaveTemple
+            }
+        },
+        StateType::ResurrectConfirm { cost, gold } => {
+            trace.step("resurrection cost", &[("cost", &cost), ("gold", &gold)]);
+            match (cost, gold) {
+                (Some(cost), Some(gold)) if cost <= gold => Action::ConfirmResurrect,
+                (Some(cost), Some(gold)) => {
+                    println!("Resurrection costs {cost} gold but only {gold} available");
+                    Action::CancelResurrect
+                },
+                _ => {
+                    println!("Could not read resurrection cost ({cost:?}) or gold ({gold:?})");
+                    Action::CancelResurrect
+                },
+            }
+        },
         StateType::Dungeon => {
             let dungeon = &state.dungeon;
-            match dungeon.state {
+            let dungeon_state = match dungeon.state.clone() {
+                DungeonState::IdleChest | DungeonState::IdleChestMagical if !strategy.open_chests(dungeon) => DungeonState::Idle(false),
+                dungeon_state => dungeon_state,
+            };
+            match dungeon_state {
                 DungeonState::Idle(on_city_tile) => {
-                    if dungeon.has_dead_character() {
+                    let stuck = state.moves.recovery(policy.stuck_ticks).zip(state.moves.direction());
+                    if let Some((recovery, direction)) = stuck
+                        && recovery != Recovery::Block {
+                        trace.step("stuck", &[("direction", &direction), ("recovery", &recovery)]);
+                        println!("Position unchanged while moving {direction:?}, recovery {recovery:?}");
+                        match recovery {
+                            Recovery::Recapture => Action::Redetect,
+                            Recovery::Jiggle => {
+                                let current = dungeon.get_current_tile();
+                                let direction = direction.perpendicular().into_iter()
+                                .find(|direction|current.is_passable(*direction))
+                                .unwrap_or(direction.perpendicular()[0]);
+                                Action::FindFight(direction, (current, 0))
+                            },
+                            _ => last_action,
+                        }
+                    }
+                    else if let Some(slot) = policy.should_heal(dungeon) {
+                        trace.step("heal", &[("slot", &slot), ("potions", &dungeon.potions()), ("characters", &dungeon.characters())]);
+                        Action::UseHealingItem(slot)
+                    }
+                    else if policy.should_retreat(dungeon) || policy.wants_town(state) {
+                        trace.step("retreat", &[("should_retreat", &policy.should_retreat(dungeon)), ("wants_town", &policy.wants_town(state)), ("on_city_tile", &on_city_tile), ("city_tile", &dungeon.get_city_tile().map(|tile|tile.position))]);
                         if on_city_tile {
                             Action::ReturnToTown(true, MoveDirection::East)
                         }
+                        else if let Some(action) = dungeon.ascend_towards_city() {
+                            action
+                        }
                         else if let Some(city_tile) = dungeon.get_city_tile() {
                             if let Some(next_tile) = dungeon.get_next_tile_to_goal(dungeon.get_current_tile(), city_tile) {
                                 println!("This tile {:?}", dungeon.get_current_tile());
@@ -1215,27 +2328,34 @@ pub fn determine_action(state:&State, last_action:Action, old_position:Option<Co
                     }
                     else {
                         println!("{:?}", dungeon.get_current_tile());
-                        if let Some(go_down_tile) = dungeon.get_go_down_tile() {
-                            if go_down_tile.position == dungeon.get_current_tile().position {
-                                return Action::GoDown;
-                            }
+                        let may_descend = strategy.may_descend(dungeon);
+                        trace.step("explore", &[("may_descend", &may_descend), ("floor", &dungeon.floor_number()), ("exploration", &dungeon.exploration()), ("descend_when", &policy.descend_when), ("stairs", &dungeon.get_go_down_tile().map(|tile|tile.position)), ("current", &dungeon.get_current_tile().position)]);
+                        if may_descend
+                            && let Some(go_down_tile) = dungeon.get_go_down_tile()
+                            && go_down_tile.position == dungeon.get_current_tile().position {
+                            trace.step("on stairs", &[]);
+                            return Action::GoDown;
                         }
                         let (tile, ticks_same_target) = if let Action::FindFight(_move_direction, (target_tile, ticks_same_target)) = last_action {
                             if target_tile.position == dungeon.get_current_tile().position {
+                                trace.step("target reached, new unexplored target", &[]);
                                 println!("looking for unexplored tile");
                                 (dungeon.get_unexplored_tile(old_position), 1)
                             }
                             else {
+                                trace.step("keep last target", &[("target", &target_tile.position), ("ticks_same_target", &ticks_same_target)]);
                                 println!("using last target tile");
                                 (target_tile, ticks_same_target + 1)
                             }
                         }
                         else {
+                            trace.step("new unexplored target", &[("last_action", &last_action.name())]);
                             println!("looking for unexplored tile");
                             (dungeon.get_unexplored_tile(old_position), 1)
                         };
 
-                        let (tile, ticks_same_target) = if ticks_same_target > 30 {
+                        let (tile, ticks_same_target) = if ticks_same_target > policy.max_ticks_per_target {
+                            trace.step("target abandoned", &[("ticks_same_target", &ticks_same_target), ("max_ticks_per_target", &policy.max_ticks_per_target)]);
                             println!("Too many ticks spent on moving to target");
                             (dungeon.get_unexplored_tile(old_position), 1)
                         }
@@ -1243,8 +2363,10 @@ pub fn determine_action(state:&State, last_action:Action, old_position:Option<Co
                             (tile, ticks_same_target)
                         };
 
-                        let (tile, ticks_same_target) = if let Some(go_down_tile) = dungeon.get_go_down_tile() {
+                        let (tile, ticks_same_target) = if may_descend
+                            && let Some(go_down_tile) = dungeon.get_go_down_tile() {
                             if go_down_tile.position != tile.position {
+                                trace.step("head for stairs", &[("stairs", &go_down_tile.position)]);
                                 (go_down_tile, 1)
                             }
                             else {
@@ -1255,10 +2377,27 @@ pub fn determine_action(state:&State, last_action:Action, old_position:Option<Co
                             (tile, ticks_same_target)
                         };
 
+                        let (tile, ticks_same_target) = if strategy.seek_chests(dungeon)
+                            && let Some(chest_tile) = dungeon.get_closest_chest_tile() {
+                            if chest_tile.position != tile.position {
+                                trace.step("head for chest", &[("chest", &chest_tile.position)]);
+                                println!("Heading for chest at {:?}", chest_tile.position);
+                                (chest_tile, 1)
+                            }
+                            else {
+                                (tile, ticks_same_target)
+                            }
+                        }
+                        else {
+                            (tile, ticks_same_target)
+                        };
+
                         if let Some(next_tile) = dungeon.get_next_tile_to_goal(dungeon.get_current_tile(), tile) {
+                            trace.step("path to target", &[("target", &tile.position), ("next", &next_tile.position)]);
                             Action::FindFight(next_tile.direction_from(dungeon.get_current_tile()), (tile, ticks_same_target))
                         }
                         else {
+                            trace.step("no path to target", &[("target", &tile.position)]);
                             println!("Found no path to {:?}", tile);
                             let tile = dungeon.get_random_tile_from_current(None, RandomTarget::Unexplored);
                             Action::FindFight(tile.direction_from(dungeon.get_current_tile()), (tile, 0))
@@ -1271,9 +2410,36 @@ pub fn determine_action(state:&State, last_action:Action, old_position:Option<Co
                 DungeonState::IdleChestMagical => {
                     Action::OpenChestMagical
                 },
-                DungeonState::Fight(_enemy) => {
-                    if false && dungeon.has_low_character() || dungeon.has_dead_character() {
-                        if let Some(city_tile) = dungeon.get_city_tile() {
+                DungeonState::Fight(enemy) | DungeonState::BossFight(enemy) => {
+                    let boss = matches!(dungeon_state, DungeonState::BossFight(_));
+                    trace.step("fight", &[("boss", &boss), ("enemy", &enemy), ("characters", &dungeon.characters())]);
+                    if let Some(step) = state.fight.escalation(policy.fight_watchdog_ticks) {
+                        trace.step("fight watchdog", &[("step", &step)]);
+                        println!("Enemy health unchanged for too long, escalation step {step}");
+                        match step % 3 {
+                            0 => Action::DismissDialog,
+                            1 => Action::Back,
+                            _ => Action::Redetect,
+                        }
+                    }
+                    else if let Some(slot) = policy.should_heal(dungeon) {
+                        Action::UseHealingItem(slot)
+                    }
+                    else if boss && !last_action.is_attack()
+                        && let Some(slot) = policy.boss_needs_healing(dungeon) {
+                        if dungeon.potions().is_some_and(|potions|potions > 0) {
+                            Action::UseHealingItem(slot)
+                        }
+                        else {
+                            println!("Party not at full health, not engaging boss");
+                            Action::Flee
+                        }
+                    }
+                    else if policy.should_retreat(dungeon) {
+                        if let Some(action) = dungeon.ascend_towards_city() {
+                            action
+                        }
+                        else if let Some(city_tile) = dungeon.get_city_tile() {
                             if let Some(next_tile) = dungeon.get_next_tile_to_goal(dungeon.get_current_tile(), city_tile) {
                                 println!("This tile {:?}", dungeon.get_current_tile());
                                 println!("City tile {:?}", city_tile);
@@ -1296,6 +2462,15 @@ pub fn determine_action(state:&State, last_action:Action, old_position:Option<Co
                             Action::ReturnToTown(false, tile.direction_from(dungeon.get_current_tile()))
                         }
                     }
+                    else if policy.should_flee(dungeon, &enemy) {
+                        trace.step("flee", &[("flee_ratio", &policy.flee_ratio), ("difficulty", &enemy.difficulty())]);
+                        println!("Fleeing from {enemy:?}");
+                        Action::Flee
+                    }
+                    else if let Some(skill) = dungeon.skills.next_skill(policy.rotations(boss)) {
+                        trace.step("skill rotation", &[("skills", &dungeon.skills)]);
+                        Action::UseSkill(skill)
+                    }
                     else {
                         Action::Fight
                     }
@@ -1305,98 +2480,387 @@ pub fn determine_action(state:&State, last_action:Action, old_position:Option<Co
     }
 }
 
-pub fn run_action(device:&str, opt:&Opt, state:&mut State, action:&Action) -> Option<Coords> {
+pub fn apply_action(state:&mut State, action:&Action) -> Option<Coords> {
+    match action {
+        Action::GotoDungeon => {
+            state.dungeon.clear_visited();
+        },
+        Action::StartMacro(index) => {
+            state.macros.start(*index);
+        },
+        Action::RunMacro(index, segment) => {
+            state.macros.advance(*index, *segment);
+        },
+        Action::FinishMacro => {
+            state.macros.finish(daily::today());
+        },
+        Action::AbortMacro => {
+            state.macros.abort();
+        },
+        Action::OpenChest | Action::OpenChestMagical => {
+            state.dungeon.clear_chest();
+        },
+        Action::GoDown => {
+            state.dungeon.descend();
+        },
+        Action::GoUp => {
+            state.dungeon.ascend();
+        },
+        Action::FindFight(move_direction, _target_tile) => {
+            return Some(state.get_position().unwrap().move_direction(*move_direction));
+        },
+        Action::UseHealingItem(_) => {
+            state.dungeon.potions = state.dungeon.potions.map(|potions|potions.saturating_sub(1));
+        },
+        Action::ReturnToTown(false, move_direction) => {
+            return Some(state.get_position().unwrap().move_direction(*move_direction));
+        },
+        _ => {},
+    }
+    None
+}
+
+pub fn execute_action(device:&str, opt:&Opt, services:&Services, action:&Action, frame:Option<&DynamicImage>) {
+    let tap_button = |button:Button|{
+        adb_tap(device, opt, services, tap_box(services.buttons.position(button, frame), button.size()));
+    };
     match action {
         Action::CloseAd => {
-            adb_tap(device, opt, 935, 153);
+            adb_tap(device, opt, services, tap_box((935, 153), ICON_BUTTON));
         },
         Action::GotoTown => {
 
         },
-        Action::GotoDungeon => {
-            state.dungeon.clear_visited();
-            adb_tap(device, opt, 890, 1928);
+        Action::GotoDungeon => match opt.policy.mode {
+            Mode::Main => adb_tap(device, opt, services, tap_box((890, 1928), CITY_BUTTON)),
+            Mode::Event => TapSequence::new().tap(tap_box(CITY_EVENT, CITY_BUTTON)).sleep(400).tap(tap_box(EVENT_ENTER, CITY_BUTTON)).run(device, opt, services),
         },
         Action::CancelTeleportToCity => {
-            adb_tap(device, opt, 331, 1440);
+            adb_tap(device, opt, services, tap_box((331, 1440), DIALOG_BUTTON));
         },
         Action::TeleportToCity => {
-            adb_tap(device, opt, 680, 1440);
+            tap_button(Button::Confirm);
         },
-        Action::GoDown => {
-            state.dungeon.tiles = Vec::new();
-            adb_tap(device, opt, 715, 1316);
+        Action::GoDown | Action::GoUp => {
+            tap_button(Button::Stairs);
         },
         Action::FindFight(move_direction, _target_tile) => {
-            adb_move(device, opt, move_direction);
-            return Some(state.get_position().unwrap().move_direction(*move_direction));
+            adb_move(device, opt, services, move_direction);
         },
         Action::Fight => {
-            adb_tap(device, opt, 711, 1308);
+            tap_button(Button::Fight);
+        },
+        Action::Flee => {
+            adb_tap(device, opt, services, tap_box(FLEE_BUTTON, ICON_BUTTON));
+        },
+        Action::DismissDialog => {
+            adb_tap(device, opt, services, tap_box(DIALOG_OK, DIALOG_BUTTON));
+        },
+        Action::Back => {
+            adb_key(device, opt, services, "KEYCODE_BACK");
+        },
+        Action::Redetect => {
+
+        },
+        Action::UseSkill(slot) => {
+            adb_tap(device, opt, services, tap_box((SKILL_BAR.0 + *slot as u32 * SKILL_SPACING, SKILL_BAR.1), SKILL_BUTTON));
         },
         Action::OpenChest => {
-            adb_tap(device, opt, 798, 1312);
+            tap_button(Button::Chest);
         },
         Action::OpenChestMagical => {
-            adb_tap(device, opt, 738, 1181);
-            std::thread::sleep(std::time::Duration::from_millis(200));
-            adb_tap(device, opt, 738, 1336);
+            TapSequence::new().tap(tap_box((738, 1181), ICON_BUTTON)).sleep(200).tap(tap_box((738, 1336), ICON_BUTTON)).run(device, opt, services);
+        },
+        Action::UseHealingItem(slot) => {
+            TapSequence::new().tap(tap_box(HEAL_BUTTON, ICON_BUTTON)).sleep(200).tap(tap_box((330, 560 + *slot as u32 * 120), ITEM_ROW)).run(device, opt, services);
         },
         Action::ReturnToTown(on_city_tile, move_direction) => {
             if *on_city_tile {
-                adb_tap(device, opt, 715, 1316);
+                tap_button(Button::Stairs);
             }
             else {
-                adb_move(device, opt, move_direction);
-                return Some(state.get_position().unwrap().move_direction(*move_direction));
+                adb_move(device, opt, services, move_direction);
             }
         },
         Action::Resurrect => {
+            if opt.extra_screens {
+                adb_tap(device, opt, services, tap_box(CITY_TEMPLE, CITY_BUTTON));
+            }
+        },
+        Action::ResurrectCharacter(slot) => {
+            adb_tap(device, opt, services, tap_box((TEMPLE_RESURRECT.0, TEMPLE_RESURRECT.1 + *slot as u32 * 150), TEMPLE_ROW));
+        },
+        Action::ConfirmResurrect => {
+            tap_button(Button::Confirm);
+        },
+        Action::CancelResurrect => {
+            adb_tap(device, opt, services, tap_box(RESURRECT_CANCEL, DIALOG_BUTTON));
+        },
+        Action::StartMacro(_) | Action::FinishMacro | Action::AbortMacro => {
 
         },
+        Action::RunMacro(index, segment) => {
+            if let Some(script) = opt.policy.macros.get(*index as usize) {
+                script.sequence(*segment).run(device, opt, services);
+            }
+        },
+        Action::LeaveTemple => {
+            adb_tap(device, opt, services, tap_box(TEMPLE_CLOSE, ICON_BUTTON));
+        },
+        Action::Rest => {
+            adb_key(device, opt, services, "KEYCODE_SLEEP");
+        },
+        Action::LaunchGame => {
+            let Some(package) = &opt.game_package else {
+                println!("No game package configured, cannot relaunch");
+                return;
+            };
+            let component = match &opt.game_activity {
+                Some(activity) => format!("{package}/{activity}"),
+                None => package.clone(),
+            };
+            if opt.game_activity.is_some() {
+                adb_shell(device, opt, services, &["am", "start", "-n", &component]);
+            }
+            else {
+                adb_shell(device, opt, services, &["monkey", "-p", &component, "-c", "android.intent.category.LAUNCHER", "1"]);
+            }
+        },
     }
-    None
 }
 
-fn adb_move(device:&str, opt:&Opt, move_direction:&MoveDirection) {
+fn adb_move(device:&str, opt:&Opt, services:&Services, move_direction:&MoveDirection) {
     match move_direction {
-        MoveDirection::North => adb_tap(device, opt, 774, 2085),
-        MoveDirection::East => adb_tap(device, opt, 953, 2277),
-        MoveDirection::South => adb_tap(device, opt, 774, 2264),
-        MoveDirection::West => adb_tap(device, opt, 575, 2277),
+        MoveDirection::North => adb_tap(device, opt, services, tap_box((774, 2085), MOVE_BUTTON)),
+        MoveDirection::East => adb_tap(device, opt, services, tap_box((953, 2277), MOVE_BUTTON)),
+        MoveDirection::South => adb_tap(device, opt, services, tap_box((774, 2264), MOVE_BUTTON)),
+        MoveDirection::West => adb_tap(device, opt, services, tap_box((575, 2277), MOVE_BUTTON)),
     }
 }
 
-/*fn adb_input(device:&str, opt:&Opt, key:&str) {
-    let _ = if opt.local {
-        Command::new("input").arg("keyevent").arg(key)
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .stdout(Stdio::null())
-        .spawn().unwrap().wait().unwrap();
+fn tap_box(centre:(u32, u32), size:(u32, u32)) -> Region {
+    Region::around(centre.0, centre.1, size.0, size.1)
+}
+
+pub fn adb_key(device:&str, opt:&Opt, services:&Services, keycode:&str) {
+    adb_shell(device, opt, services, &["input", "keyevent", keycode]);
+}
+
+pub fn shell_command(device:&str, opt:&Opt, args:&[&str]) -> Command {
+    let mut command = if opt.local {
+        Command::new(args[0])
     }
     else {
-        Command::new("adb").arg("-s").arg(device).arg("shell").arg("input").arg("keyevent").arg(key)
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .stdout(Stdio::null())
-        .spawn().unwrap().wait().unwrap();
+        let mut command = Command::new("adb");
+        command.arg("-s").arg(device).arg("shell").arg(args[0]);
+        command
     };
-}*/
+    command.args(&args[1..]).stdin(Stdio::null());
+    command
+}
 
-fn adb_tap(device:&str, opt:&Opt, x:u32, y:u32) {
-    let _ = if opt.local {
-        Command::new("input").arg("tap").arg(x.to_string()).arg(y.to_string())
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .stdout(Stdio::null())
-        .spawn().unwrap().wait().unwrap();
+pub fn adb_shell(device:&str, opt:&Opt, services:&Services, args:&[&str]) {
+    if let Err(err) = device::run(&mut shell_command(device, opt, args), opt, &services.adb_log) {
+        println!("Failed to run {}: {err}", args.join(" "));
     }
-    else {
-        Command::new("adb").arg("-s").arg(device).arg("shell").arg("input").arg("tap").arg(x.to_string()).arg(y.to_string())
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .stdout(Stdio::null())
-        .spawn().unwrap().wait().unwrap();
-    };
-}
\ No newline at end of file
+}
+
+pub fn game_in_foreground(device:&str, opt:&Opt, services:&Services) -> Option<bool> {
+    let package = opt.game_package.as_ref()?;
+    let output = device::run(&mut shell_command(device, opt, &["dumpsys", "window"]), opt, &services.adb_log).ok()?;
+    let dump = String::from_utf8_lossy(&output.stdout);
+    let focus = dump.lines().filter(|line|line.contains("mCurrentFocus") || line.contains("mFocusedApp")).collect::<Vec<_>>();
+    if focus.is_empty() {
+        return None;
+    }
+    Some(focus.iter().any(|line|line.contains(package.as_str())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Instant};
+
+    use clap::Parser;
+
+    use super::*;
+
+    const FLOOR:u32 = 49;
+    const ROUNDS:u32 = 20;
+
+    fn open_tile(x:u32, y:u32) -> Tile {
+        Tile {
+            explored: true,
+            trap: false,
+            is_city: false,
+            is_go_down: false,
+            is_go_up: false,
+            has_chest: false,
+            visited: true,
+            position: Coords { x, y },
+            north_passable: y > 0,
+            east_passable: x + 1 < FLOOR,
+            south_passable: y + 1 < FLOOR,
+            west_passable: x > 0,
+        }
+    }
+
+    fn mapped_floor() -> Dungeon {
+        let mut dungeon = Dungeon::default();
+        dungeon.info.coordinates = Some(Coords { x: 0, y: 0 });
+        dungeon.info.size = Some(Coords { x: FLOOR, y: FLOOR });
+        dungeon.tiles = (0..FLOOR).flat_map(|y|(0..FLOOR).map(move|x|open_tile(x, y))).collect();
+        dungeon
+    }
+
+    #[test]
+    fn marked_tiles_are_found_in_scan_order() {
+        let mut dungeon = mapped_floor();
+        for (x, y) in [(40, 2), (3, 20), (1, 2), (30, 45)] {
+            let tile = dungeon.tiles.get_mut(&Coords { x, y }).unwrap();
+            tile.is_city = true;
+            tile.is_go_down = true;
+            tile.is_go_up = true;
+        }
+        for _ in 0..10 {
+            let expected = Some(Coords { x: 1, y: 2 });
+            assert_eq!(dungeon.get_city_tile().map(|tile|tile.position), expected);
+            assert_eq!(dungeon.get_go_down_tile().map(|tile|tile.position), expected);
+            assert_eq!(dungeon.get_go_up_tile().map(|tile|tile.position), expected);
+            dungeon.tiles = dungeon.tiles.clone().into_iter().collect();
+        }
+    }
+
+    #[test]
+    fn explores_fully_mapped_floor() {
+        let mut dungeon = mapped_floor();
+        let (start, goal) = (open_tile(0, 0), open_tile(FLOOR - 1, FLOOR - 1));
+        let next = dungeon.get_next_tile_to_goal(start, goal).unwrap();
+        assert!(matches!((next.position.x, next.position.y), (1, 0) | (0, 1)));
+        dungeon.update_plan();
+        assert!(dungeon.plan.is_none());
+        assert_eq!(dungeon.tiles.exploration(), 100.0);
+    }
+
+    /// Timings on a fully mapped 49x49 floor, run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn benchmark_fully_mapped_floor() {
+        let mut dungeon = mapped_floor();
+        let (start, goal) = (open_tile(0, 0), open_tile(FLOOR - 1, FLOOR - 1));
+
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            dungeon.route.clear();
+            dungeon.get_next_tile_to_goal(start, goal).unwrap();
+        }
+        let astar = started.elapsed() / ROUNDS;
+
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            dungeon.plan = None;
+            dungeon.update_plan();
+        }
+        let plan = started.elapsed() / ROUNDS;
+
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            dungeon.tiles.exploration();
+        }
+        let exploration = started.elapsed() / ROUNDS;
+
+        println!("{FLOOR}x{FLOOR} floor: a* corner to corner {astar:?}, frontier plan {plan:?}, exploration {exploration:?}");
+    }
+
+    fn detect_reference_state(name:&str, opt:&Opt) -> Option<State> {
+        let image = image::open(Path::new("caps").join(name)).unwrap();
+        let divisor = (ENDOR_LAYOUT.screen.0 / image.width().max(1)).max(1);
+        let bitmap = BitmapWebp::from_image(image, divisor, opt, &Services::default());
+        detect_state(State::default(), &bitmap).ok()
+    }
+
+    fn detect_reference(name:&str, opt:&Opt) -> Option<&'static str> {
+        detect_reference_state(name, opt).map(|state|state.state_type.name())
+    }
+
+    #[test]
+    fn detects_reference_screens() {
+        let opt = Opt::parse_from(["endorbot"]);
+        assert!(!opt.extra_screens);
+        for (name, expected) in [("main.png", "main"), ("city.png", "city"), ("city-ad.png", "ad"), ("dungeon.png", "dungeon"), ("fight.png", "dungeon"), ("chest.png", "dungeon"), ("here13.png", "teleport_to_city")] {
+            assert_eq!(detect_reference(name, &opt), Some(expected), "{name}");
+        }
+    }
+
+    /// The newer screen probes must not claim any of the reference screenshots.
+    #[test]
+    fn extra_screens_leave_reference_screens_alone() {
+        let opt = Opt::parse_from(["endorbot"]);
+        let extra = Opt::parse_from(["endorbot", "--extra-screens"]);
+        let mut names = std::fs::read_dir("caps").unwrap()
+        .filter_map(|entry|entry.ok()?.file_name().into_string().ok())
+        .filter(|name|name.ends_with(".png"))
+        .collect::<Vec<_>>();
+        names.sort();
+        assert!(!names.is_empty());
+        for name in names {
+            assert_eq!(detect_reference(&name, &extra), detect_reference(&name, &opt), "{name}");
+        }
+    }
+
+    #[test]
+    fn reference_fights_are_not_boss_fights() {
+        let extra = Opt::parse_from(["endorbot", "--extra-screens"]);
+        assert!(matches!(detect_reference_state("fight.png", &extra).unwrap().dungeon.state, DungeonState::Fight(_)));
+        for entry in std::fs::read_dir("caps").unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            if !name.ends_with(".png") {
+                continue;
+            }
+            if let Some(state) = detect_reference_state(&name, &extra) {
+                assert!(!matches!(state.dungeon.state, DungeonState::BossFight(_)), "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn reference_screens_show_no_heal_button() {
+        let extra = Opt::parse_from(["endorbot", "--extra-screens"]);
+        for entry in std::fs::read_dir("caps").unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            if !name.ends_with(".png") {
+                continue;
+            }
+            let image = image::open(Path::new("caps").join(&name)).unwrap();
+            let divisor = (ENDOR_LAYOUT.screen.0 / image.width().max(1)).max(1);
+            assert_eq!(BitmapWebp::from_image(image, divisor, &extra, &Services::default()).potions, None, "{name}");
+        }
+    }
+
+    #[test]
+    fn reference_screens_show_no_energy_counter() {
+        let extra = Opt::parse_from(["endorbot", "--extra-screens"]);
+        for entry in std::fs::read_dir("caps").unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            if !name.ends_with(".png") {
+                continue;
+            }
+            let image = image::open(Path::new("caps").join(&name)).unwrap();
+            let divisor = (ENDOR_LAYOUT.screen.0 / image.width().max(1)).max(1);
+            assert_eq!(BitmapWebp::from_image(image, divisor, &extra, &Services::default()).resources.energy, None, "{name}");
+        }
+    }
+
+    #[test]
+    fn reference_screens_show_no_event_badge() {
+        let extra = Opt::parse_from(["endorbot", "--extra-screens"]);
+        for entry in std::fs::read_dir("caps").unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            if !name.ends_with(".png") {
+                continue;
+            }
+            let image = image::open(Path::new("caps").join(&name)).unwrap();
+            let divisor = (ENDOR_LAYOUT.screen.0 / image.width().max(1)).max(1);
+            assert_eq!(BitmapWebp::from_image(image, divisor, &extra, &Services::default()).get_info().kind, DungeonKind::Main, "{name}");
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ml::{Action, Coords, MoveDirection};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    Retap,
+    Recapture,
+    Jiggle,
+    Block,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MoveWatch {
+    position: Option<Coords>,
+    explored: usize,
+    direction: Option<MoveDirection>,
+    stalled_ticks: u32,
+}
+impl MoveWatch {
+    pub fn position(&self) -> Option<Coords> {
+        self.position
+    }
+
+    pub fn direction(&self) -> Option<MoveDirection> {
+        self.direction
+    }
+
+    pub fn observe(&mut self, last_action:&Action, position:Option<Coords>, explored:usize) {
+        let direction = match last_action {
+            Action::Redetect => self.direction,
+            action => action.move_direction(),
+        };
+        let Some(direction) = direction else {
+            *self = Self::default();
+            return;
+        };
+        if position.is_some() && self.position == position && self.explored == explored {
+            self.stalled_ticks += 1;
+        }
+        else {
+            *self = Self {
+                position,
+                explored,
+                direction: Some(direction),
+                stalled_ticks: 0,
+            };
+        }
+    }
+
+    pub fn recovery(&self, stuck_ticks:u32) -> Option<Recovery> {
+        if stuck_ticks == 0 || self.stalled_ticks < stuck_ticks || !self.stalled_ticks.is_multiple_of(stuck_ticks) {
+            return None;
+        }
+        Some(match self.stalled_ticks / stuck_ticks {
+            1 => Recovery::Retap,
+            2 => Recovery::Recapture,
+            3 => Recovery::Jiggle,
+            _ => Recovery::Block,
+        })
+    }
+}
@@ -0,0 +1,100 @@
+use std::{sync::Arc, thread::JoinHandle};
+
+use parking_lot::Mutex;
+use ureq::unversioned::multipart::{Form, Part};
+
+use crate::{Opt, frames::{self, LatestFrame}};
+
+#[derive(Debug, Clone)]
+enum Target {
+    Telegram {
+        token: String,
+        chat_id: String,
+    },
+    Discord {
+        webhook: String,
+    },
+}
+impl Target {
+    fn send(&self, message:&str, screenshot:Option<&[u8]>) -> Result<(), ureq::Error> {
+        match (self, screenshot) {
+            (Target::Telegram { token, chat_id }, Some(png)) => {
+                let form = Form::new()
+                .text("chat_id", chat_id)
+                .text("caption", message)
+                .part("photo", Part::bytes(png).file_name("screenshot.png").mime_str("image/png")?);
+                ureq::post(format!("https://api.telegram.org/bot{token}/sendPhoto")).send(form)?;
+            },
+            (Target::Telegram { token, chat_id }, None) => {
+                let form = Form::new()
+                .text("chat_id", chat_id)
+                .text("text", message);
+                ureq::post(format!("https://api.telegram.org/bot{token}/sendMessage")).send(form)?;
+            },
+            (Target::Discord { webhook }, screenshot) => {
+                let mut form = Form::new()
+                .text("content", message);
+                if let Some(png) = screenshot {
+                    form = form.part("file", Part::bytes(png).file_name("screenshot.png").mime_str("image/png")?);
+                }
+                ureq::post(webhook).send(form)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+pub struct Notifier {
+    targets: Vec<Target>,
+    attach_screenshot: bool,
+    frames: Arc<LatestFrame>,
+    pending: Mutex<Vec<JoinHandle<()>>>,
+}
+impl Notifier {
+    pub fn new(opt:&Opt, frames:Arc<LatestFrame>) -> Self {
+        let mut targets = Vec::new();
+        if let (Some(token), Some(chat_id)) = (&opt.telegram_token, &opt.telegram_chat_id) {
+            targets.push(Target::Telegram { token: token.clone(), chat_id: chat_id.clone() });
+        }
+        if let Some(webhook) = &opt.discord_webhook {
+            targets.push(Target::Discord { webhook: webhook.clone() });
+        }
+        Self {
+            targets,
+            attach_screenshot: opt.notify_screenshot,
+            frames,
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn notify(&self, message:&str) {
+        println!("Notify: {message}");
+        if self.targets.is_empty() {
+            return;
+        }
+        let screenshot = if self.attach_screenshot {
+            self.frames.latest().and_then(|(_, image)|frames::encode_png(&image).ok())
+        }
+        else {
+            None
+        };
+        let targets = self.targets.clone();
+        let message = format!("endorbot: {message}");
+        let handle = std::thread::spawn(move||{
+            for target in targets {
+                if let Err(err) = target.send(&message, screenshot.as_deref()) {
+                    println!("Failed to send notification: {err}");
+                }
+            }
+        });
+        let mut pending = self.pending.lock();
+        pending.retain(|handle|!handle.is_finished());
+        pending.push(handle);
+    }
+
+    pub fn flush(&self) {
+        for handle in self.pending.lock().drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::{cell::RefCell, collections::HashMap, hash::{DefaultHasher, Hash, Hasher}};
+
+use parking_lot::Mutex;
+
+use crate::{glyphs::Region, ml::OcrRegion};
+
+thread_local! {
+    static CROP:RefCell<Vec<[u8; 3]>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug, Default)]
+pub struct OcrCache {
+    entries: Mutex<HashMap<OcrRegion, (u64, String)>>,
+}
+impl OcrCache {
+    pub fn read(&self, region:OcrRegion, crop:Region, pixel:&dyn Fn(u32, u32) -> [u8; 3], read:impl FnOnce(&dyn Fn(u32, u32) -> [u8; 3]) -> String) -> String {
+        CROP.with_borrow_mut(|buffer|{
+            sample_crop(crop, pixel, buffer);
+            let hash = crop_hash(buffer);
+            if let Some((cached, text)) = self.entries.lock().get(&region)
+                && *cached == hash {
+                return text.clone();
+            }
+            let cropped = |x:u32, y:u32|buffer[((y - crop.y) * crop.width + x - crop.x) as usize];
+            let text = read(&cropped);
+            self.entries.lock().insert(region, (hash, text.clone()));
+            text
+        })
+    }
+}
+
+fn sample_crop(crop:Region, pixel:&dyn Fn(u32, u32) -> [u8; 3], buffer:&mut Vec<[u8; 3]>) {
+    buffer.clear();
+    for y in crop.y..crop.y + crop.height {
+        for x in crop.x..crop.x + crop.width {
+            buffer.push(pixel(x, y));
+        }
+    }
+}
+
+fn crop_hash(buffer:&[[u8; 3]]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    buffer.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,22 @@
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+#[cfg(feature = "rayon")]
+pub fn map<T:Sync, R:Send>(items:&[T], f:impl Fn(&T) -> R + Sync + Send) -> Vec<R> {
+    items.par_iter().map(f).collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+pub fn map<T, R>(items:&[T], f:impl Fn(&T) -> R) -> Vec<R> {
+    items.iter().map(f).collect()
+}
+
+#[cfg(feature = "rayon")]
+pub fn filter_map<T:Sync, R:Send>(items:&[T], f:impl Fn(&T) -> Option<R> + Sync + Send) -> Vec<R> {
+    items.par_iter().filter_map(f).collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+pub fn filter_map<T, R>(items:&[T], f:impl Fn(&T) -> Option<R>) -> Vec<R> {
+    items.iter().filter_map(f).collect()
+}
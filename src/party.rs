@@ -0,0 +1,46 @@
+use clap::Args;
+use serde::Deserialize;
+
+pub const MAX_PARTY_SIZE:usize = 6;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Member {
+    pub name: String,
+    #[serde(default)]
+    pub class: Option<String>,
+}
+
+pub fn parse_member(value:&str) -> Result<Member, String> {
+    let (name, class) = match value.split_once(':') {
+        Some((name, class)) => (name.trim(), Some(class.trim().to_owned())),
+        None => (value.trim(), None),
+    };
+    if name.is_empty() {
+        return Err(format!("expected <name>[:<class>], got {value}"));
+    }
+    Ok(Member { name: name.to_owned(), class })
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct PartyLayout {
+    #[clap(id = "party_size", long = "party-size", default_value_t = 4, value_parser = clap::value_parser!(u8).range(1..=MAX_PARTY_SIZE as i64))]
+    pub size: u8,
+    #[clap(id = "party_slot_y", long = "party-slot-y", default_value_t = 560)]
+    pub slot_y: u32,
+    #[clap(id = "party_slot_spacing", long = "party-slot-spacing", default_value_t = 120)]
+    pub slot_spacing: u32,
+    #[clap(id = "party_dead_below_pct", long = "dead-below-pct", default_value_t = 0.0)]
+    pub dead_below_pct: f32,
+    #[clap(id = "party_members", long = "member", value_parser = parse_member)]
+    pub members: Vec<Member>,
+}
+impl PartyLayout {
+    pub fn slots(&self) -> impl Iterator<Item = (usize, u32)> + '_ {
+        (0..self.size as usize).map(|slot|(slot, self.slot_y + slot as u32 * self.slot_spacing))
+    }
+
+    pub fn member(&self, slot:usize) -> Option<&Member> {
+        self.members.get(slot)
+    }
+}
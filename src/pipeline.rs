@@ -0,0 +1,126 @@
+use std::{sync::{Arc, mpsc::{Receiver, sync_channel}}, time::{Duration, Instant}};
+
+use image::DynamicImage;
+use parking_lot::{Condvar, Mutex};
+
+use tokio::sync::mpsc;
+
+use crate::{Opt, Services, control::Control, frames::LatestFrame, game::GameAdapter, metrics::Metrics, ml::{Action, BitmapWebp}, runtime, screencap::{self, ScreencapError}, timing::{self, Phase}};
+
+pub struct CapturedFrame {
+    pub started: Instant,
+    pub image: Result<BitmapWebp, ScreencapError>,
+}
+impl CapturedFrame {
+    pub fn from_image(image:DynamicImage, opt:&Opt, services:&Services) -> Self {
+        Self {
+            started: Instant::now(),
+            image: Ok(BitmapWebp::from_image(image, 1, opt, services)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct FrameSlot {
+    frame: Mutex<Option<CapturedFrame>>,
+    updated: Condvar,
+}
+impl FrameSlot {
+    fn put(&self, frame:CapturedFrame) {
+        *self.frame.lock() = Some(frame);
+        self.updated.notify_all();
+    }
+
+    pub fn take_after(&self, not_before:Instant, control:&Control) -> Option<CapturedFrame> {
+        let mut frame = self.frame.lock();
+        loop {
+            if control.is_shutdown() {
+                return None;
+            }
+            if frame.as_ref().is_some_and(|frame|frame.started >= not_before) {
+                return frame.take();
+            }
+            self.updated.wait_for(&mut frame, Duration::from_millis(200));
+        }
+    }
+}
+
+pub fn spawn_capture(device:String, opt:Opt, services:Services, control:Arc<Control>, metrics:Arc<Metrics>) -> Arc<FrameSlot> {
+    let slot = Arc::new(FrameSlot::default());
+    {
+        let slot = slot.clone();
+        runtime::get().spawn(async move {
+            while !control.is_shutdown() {
+                let started = Instant::now();
+                let image = tokio::select! {
+                    image = screencap::capture(&device, &opt, &services) => image,
+                    _ = control.shutdown_requested() => break,
+                };
+                metrics.capture(started.elapsed());
+                timing::observe(Phase::Capture, started.elapsed());
+                let failed = image.is_err();
+                slot.put(CapturedFrame { started, image });
+                let pause = if failed {
+                    Duration::from_secs(1)
+                }
+                else if control.is_paused() {
+                    Duration::from_millis(500)
+                }
+                else {
+                    continue;
+                };
+                tokio::select! {
+                    _ = tokio::time::sleep(pause) => {},
+                    _ = control.shutdown_requested() => break,
+                }
+            }
+        });
+    }
+    slot
+}
+
+pub struct Executor {
+    actions: mpsc::Sender<Action>,
+    done: Receiver<Instant>,
+    pending: bool,
+}
+impl Executor {
+    pub fn spawn(device:String, opt:Opt, services:Services, adapter:Arc<dyn GameAdapter>, frames:Arc<LatestFrame>) -> Self {
+        let (actions, mut action_receiver) = mpsc::channel::<Action>(1);
+        let (done_sender, done) = sync_channel(1);
+        let context = Arc::new((device, opt, services, adapter, frames));
+        runtime::get().spawn(async move {
+            while let Some(action) = action_receiver.recv().await {
+                let context = context.clone();
+                let _ = tokio::task::spawn_blocking(move||{
+                    let (device, opt, services, adapter, frames) = &*context;
+                    opt.humanize.pause();
+                    let frame = frames.latest().map(|(_, image)|image);
+                    timing::time(Phase::Action, ||adapter.execute(device, opt, services, &action, frame.as_deref()));
+                }).await;
+                if done_sender.send(Instant::now()).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            actions,
+            done,
+            pending: false,
+        }
+    }
+
+    pub fn submit(&mut self, action:Action) {
+        self.wait();
+        self.actions.blocking_send(action).unwrap();
+        self.pending = true;
+    }
+
+    pub fn wait(&mut self) -> Instant {
+        if !self.pending {
+            return Instant::now();
+        }
+        self.pending = false;
+        self.done.recv().unwrap_or_else(|_|Instant::now())
+    }
+}
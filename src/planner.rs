@@ -0,0 +1,99 @@
+use std::collections::HashSet;
+
+use parking_lot::{Mutex, MutexGuard};
+use pathfinding::prelude::dijkstra;
+
+use crate::{ml::{Coords, Tile}, timing::{self, Phase}};
+
+#[derive(Debug, Clone)]
+pub struct ExplorePlan {
+    frontier: HashSet<Coords>,
+    path: Vec<Coords>,
+}
+impl ExplorePlan {
+    pub fn new(start:Coords, frontier:HashSet<Coords>, successors:impl FnMut(&Coords) -> Vec<(Coords, u32)>) -> Option<Self> {
+        let (path, _cost) = timing::time(Phase::Pathfinding, ||dijkstra(&start, successors, |pos|frontier.contains(pos)))?;
+        Some(Self {
+            frontier,
+            path,
+        })
+    }
+
+    pub fn target(&self) -> Coords {
+        *self.path.last().unwrap()
+    }
+
+    pub fn path(&self) -> &[Coords] {
+        &self.path
+    }
+
+    pub fn advance(&mut self, frontier:&HashSet<Coords>, position:Coords) -> bool {
+        if self.frontier != *frontier {
+            return false;
+        }
+        let Some(index) = self.path.iter().position(|pos|*pos == position) else {
+            return false;
+        };
+        self.path.drain(..index);
+        self.path.len() > 1
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    goal: Coords,
+    path: Vec<Tile>,
+}
+impl Route {
+    pub fn new(goal:Coords, path:Vec<Tile>) -> Self {
+        Self {
+            goal,
+            path,
+        }
+    }
+
+    pub fn goal(&self) -> Coords {
+        self.goal
+    }
+
+    pub fn remaining(&self, position:Coords) -> Vec<Coords> {
+        let start = self.path.iter().position(|tile|tile.position == position).unwrap_or(0);
+        self.path[start..].iter().map(|tile|tile.position).collect()
+    }
+
+    pub fn next_step(&mut self, position:Coords, goal:Coords, tile_at:impl Fn(&Coords) -> Tile) -> Option<Coords> {
+        if self.goal != goal
+            || self.path.iter().any(|tile|!same_layout(&tile_at(&tile.position), tile)) {
+            return None;
+        }
+        let index = self.path.iter().position(|tile|tile.position == position)?;
+        self.path.drain(..index);
+        self.path.get(1).map(|tile|tile.position)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RouteCache(Mutex<Option<Route>>);
+impl RouteCache {
+    pub fn lock(&self) -> MutexGuard<'_, Option<Route>> {
+        self.0.lock()
+    }
+
+    pub fn clear(&self) {
+        *self.0.lock() = None;
+    }
+}
+impl Clone for RouteCache {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(self.0.lock().clone()))
+    }
+}
+
+fn same_layout(a:&Tile, b:&Tile) -> bool {
+    a.explored == b.explored
+        && a.trap == b.trap
+        && a.north_passable == b.north_passable
+        && a.east_passable == b.east_passable
+        && a.south_passable == b.south_passable
+        && a.west_passable == b.west_passable
+}
@@ -0,0 +1,170 @@
+use clap::{ArgAction, Args, ValueEnum};
+use serde::Deserialize;
+
+use chrono::NaiveDate;
+
+use crate::{fight::{self, Rotation}, ml::{Dungeon, Enemy, Health, State}, resources::Resources, macros::{self, Macro, MacroProgress}, strategy::{self, StrategyKind}};
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetreatOn {
+    Dead,
+    Low,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DescendWhen {
+    Immediately,
+    Explored,
+    Chests,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Main,
+    Event,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Policy {
+    #[clap(long, value_enum, default_value_t = RetreatOn::Dead)]
+    pub retreat_on: RetreatOn,
+    #[clap(long)]
+    pub retreat_below_pct: Option<f32>,
+    #[clap(long)]
+    pub heal_below_pct: Option<f32>,
+    #[clap(long, action = ArgAction::Set, default_value_t = true)]
+    pub prioritize_chests: bool,
+    #[clap(long)]
+    pub max_floor: Option<u32>,
+    #[clap(long, value_enum, default_value_t = DescendWhen::Immediately)]
+    pub descend_when: DescendWhen,
+    #[clap(long, default_value_t = 80.0)]
+    pub descend_explored_pct: f32,
+    #[clap(long)]
+    pub seek_chests: bool,
+    #[clap(long, default_value_t = 30)]
+    pub max_ticks_per_target: u32,
+    #[clap(long, action = ArgAction::Set, default_value_t = true)]
+    pub use_potions: bool,
+    #[clap(long)]
+    pub gold_target: Option<u32>,
+    #[clap(long)]
+    pub dungeon_energy_cost: Option<u32>,
+    #[clap(long = "macro", value_parser = macros::load_macro)]
+    pub macros: Vec<Macro>,
+    #[clap(long = "daily-macro")]
+    pub daily_macros: Vec<String>,
+    #[clap(long, value_parser = strategy::parse_strategy, default_value = "explore")]
+    pub strategy: StrategyKind,
+    #[clap(long, value_enum, default_value_t = Mode::Main)]
+    pub mode: Mode,
+    #[clap(long = "rotation", value_parser = fight::parse_rotation)]
+    pub rotations: Vec<Rotation>,
+    #[clap(long)]
+    pub flee_ratio: Option<f32>,
+    #[clap(long = "boss-rotation", value_parser = fight::parse_rotation)]
+    pub boss_rotations: Vec<Rotation>,
+    #[clap(long)]
+    pub boss_full_health: bool,
+    #[clap(long, default_value_t = 40)]
+    pub fight_watchdog_ticks: u32,
+    #[clap(long, default_value_t = 5)]
+    pub stuck_ticks: u32,
+}
+impl Policy {
+    pub fn should_retreat(&self, dungeon:&Dungeon) -> bool {
+        dungeon.characters().iter().any(|character|match self.retreat_on {
+            RetreatOn::Dead => character.health == Health::Dead,
+            RetreatOn::Low => matches!(character.health, Health::Dead | Health::Low),
+        } || self.retreat_below_pct.is_some_and(|pct|character.is_below(pct)))
+    }
+
+    pub fn should_flee(&self, dungeon:&Dungeon, enemy:&Enemy) -> bool {
+        let Some(flee_ratio) = self.flee_ratio else {
+            return false;
+        };
+        let party = dungeon.characters().iter().map(|character|match (character.health_pct, character.health) {
+            (Some(pct), _) => pct / 100.0,
+            (None, Health::Healthy) => 1.0,
+            (None, Health::Hurt) => 0.66,
+            (None, Health::Low) => 0.33,
+            (None, Health::Dead | Health::Unknown) => 0.0,
+        }).sum::<f32>();
+        let remaining = enemy.health_pct().map(|pct|pct / 100.0).unwrap_or(1.0);
+        enemy.difficulty() * remaining / party.max(0.1) > flee_ratio
+    }
+
+    pub fn boss_needs_healing(&self, dungeon:&Dungeon) -> Option<u8> {
+        if !self.boss_full_health {
+            return None;
+        }
+        dungeon.characters().iter().position(|character|matches!(character.health, Health::Hurt | Health::Low)).map(|slot|slot as u8)
+    }
+
+    pub fn rotations(&self, boss:bool) -> &[Rotation] {
+        if boss && !self.boss_rotations.is_empty() {
+            &self.boss_rotations
+        }
+        else {
+            &self.rotations
+        }
+    }
+
+    pub fn should_heal(&self, dungeon:&Dungeon) -> Option<u8> {
+        if !self.use_potions || dungeon.potions().is_none_or(|potions|potions == 0) {
+            return None;
+        }
+        dungeon.characters().iter().position(|character|match self.heal_below_pct {
+            Some(pct) => character.is_below(pct),
+            None => character.health == Health::Low,
+        }).map(|slot|slot as u8)
+    }
+
+    pub fn gold_target_reached(&self, resources:&Resources) -> bool {
+        matches!((self.gold_target, resources.gold), (Some(target), Some(gold)) if gold >= target)
+    }
+
+    pub fn lacks_energy(&self, resources:&Resources) -> bool {
+        matches!((self.dungeon_energy_cost, resources.energy), (Some(cost), Some(energy)) if energy < cost)
+    }
+
+    pub fn wants_town(&self, state:&State) -> bool {
+        state.parking
+            || self.gold_target_reached(state.resources.current())
+    }
+
+    pub fn macro_index(&self, name:&str) -> Option<u8> {
+        self.macros.iter().position(|script|script.name == name).map(|index|index as u8)
+    }
+
+    pub fn macro_due(&self, progress:&MacroProgress, today:NaiveDate) -> Option<u8> {
+        self.daily_macros.iter()
+        .filter_map(|name|self.macro_index(name))
+        .find(|index|progress.due(*index, today))
+    }
+
+    /// Whether any enabled feature opens a screen only recognised with `extra_screens`.
+    pub fn visits_extra_screens(&self) -> bool {
+        !self.boss_rotations.is_empty() || self.boss_full_health || self.dungeon_energy_cost.is_some()
+            || self.mode == Mode::Event
+    }
+
+    pub fn below_max_floor(&self, dungeon:&Dungeon) -> bool {
+        match (self.max_floor, dungeon.floor_number()) {
+            (Some(max_floor), Some(floor)) => floor < max_floor,
+            _ => true,
+        }
+    }
+
+    pub fn may_descend(&self, dungeon:&Dungeon) -> bool {
+        let chests_left = self.seek_chests && dungeon.visible_chests() > 0;
+        self.below_max_floor(dungeon) && !chests_left && match self.descend_when {
+            DescendWhen::Immediately => true,
+            DescendWhen::Explored => dungeon.exploration() >= self.descend_explored_pct,
+            DescendWhen::Chests => dungeon.exploration() >= 100.0 && dungeon.visible_chests() == 0,
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{Opt, Services, device, ml::{adb_key, adb_shell, shell_command}};
+
+const UNLOCK_SWIPE:(u32, u32, u32, u32) = (540, 1900, 540, 700);
+
+fn shell_output(device:&str, opt:&Opt, services:&Services, args:&[&str]) -> Option<String> {
+    let output = device::run(&mut shell_command(device, opt, args), opt, &services.adb_log).ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+pub fn screen_on(device:&str, opt:&Opt, services:&Services) -> Option<bool> {
+    let dump = shell_output(device, opt, services, &["dumpsys", "power"])?;
+    dump.lines().map(str::trim).find_map(|line|{
+        line.strip_prefix("mWakefulness=").map(|state|state == "Awake")
+        .or_else(||line.strip_prefix("Display Power: state=").map(|state|state == "ON"))
+    })
+}
+
+pub fn parse_pin(value:&str) -> Result<String, String> {
+    if value.is_empty() || !value.chars().all(|c|c.is_ascii_digit()) {
+        return Err(format!("expected a numeric PIN, got {value}"));
+    }
+    Ok(value.to_owned())
+}
+
+pub fn parse_point(value:&str) -> Result<(u32, u32), String> {
+    let (x, y) = value.split_once(',').ok_or_else(||format!("expected <x>,<y>, got {value}"))?;
+    let x = x.trim().parse::<u32>().map_err(|err|format!("invalid x {x}: {err}"))?;
+    let y = y.trim().parse::<u32>().map_err(|err|format!("invalid y {y}: {err}"))?;
+    Ok((x, y))
+}
+
+pub fn locked(device:&str, opt:&Opt, services:&Services) -> Option<bool> {
+    let dump = shell_output(device, opt, services, &["dumpsys", "window", "policy"])?;
+    dump.lines().map(str::trim).find_map(|line|{
+        ["mShowingLockscreen=", "isKeyguardShowing=", "showing="].iter()
+        .find_map(|key|line.strip_prefix(key))
+        .map(|value|value.starts_with("true"))
+    })
+}
+
+fn unlock(device:&str, opt:&Opt, services:&Services) {
+    if let Some(pin) = &opt.unlock_pin {
+        println!("Entering unlock PIN");
+        for digit in pin.chars() {
+            adb_key(device, opt, services, &format!("KEYCODE_{digit}"));
+        }
+        adb_key(device, opt, services, "KEYCODE_ENTER");
+    }
+    else if let Some(((first, rest), last)) = opt.unlock_pattern.split_first().zip(opt.unlock_pattern.last()) {
+        println!("Drawing unlock pattern");
+        adb_shell(device, opt, services, &["input", "motionevent", "DOWN", &first.0.to_string(), &first.1.to_string()]);
+        for (x, y) in rest {
+            adb_shell(device, opt, services, &["input", "motionevent", "MOVE", &x.to_string(), &y.to_string()]);
+        }
+        adb_shell(device, opt, services, &["input", "motionevent", "UP", &last.0.to_string(), &last.1.to_string()]);
+    }
+}
+
+pub fn wake(device:&str, opt:&Opt, services:&Services) {
+    println!("Waking the screen");
+    adb_key(device, opt, services, "KEYCODE_WAKEUP");
+    let (x1, y1, x2, y2) = UNLOCK_SWIPE;
+    adb_shell(device, opt, services, &["input", "swipe", &x1.to_string(), &y1.to_string(), &x2.to_string(), &y2.to_string()]);
+    if (opt.unlock_pin.is_some() || !opt.unlock_pattern.is_empty()) && locked(device, opt, services) != Some(false) {
+        unlock(device, opt, services);
+    }
+}
+
+pub struct StayAwake {
+    device: String,
+    opt: Opt,
+    services: Services,
+    previous: Option<String>,
+}
+impl StayAwake {
+    pub fn enable(device:&str, opt:&Opt, services:&Services) -> Self {
+        let previous = shell_output(device, opt, services, &["settings", "get", "global", "stay_on_while_plugged_in"]);
+        adb_shell(device, opt, services, &["svc", "power", "stayon", "usb"]);
+        Self {
+            device: device.to_owned(),
+            opt: opt.clone(),
+            services: services.clone(),
+            previous,
+        }
+    }
+}
+impl Drop for StayAwake {
+    fn drop(&mut self) {
+        match self.previous.as_deref() {
+            Some(previous) => adb_shell(&self.device, &self.opt, &self.services, &["settings", "put", "global", "stay_on_while_plugged_in", previous]),
+            None => adb_shell(&self.device, &self.opt, &self.services, &["svc", "power", "stayon", "false"]),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{io::{BufRead, BufReader}, path::Path, process::{Command, Stdio}, sync::mpsc};
+
+use crate::{Opt, Services, device::{self, DeviceError}, macros::{Macro, MacroStep}};
+
+#[derive(Debug)]
+pub enum RecordError {
+    IoError(std::io::Error),
+    DeviceError(DeviceError),
+    NoTouchscreen,
+    UnknownScreenSize,
+}
+impl std::fmt::Display for RecordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(err) => write!(f, "io error: {err}"),
+            Self::DeviceError(err) => write!(f, "device error: {err}"),
+            Self::NoTouchscreen => write!(f, "no input device reports touch positions"),
+            Self::UnknownScreenSize => write!(f, "could not read the screen size from wm size"),
+        }
+    }
+}
+impl std::error::Error for RecordError {}
+impl From<std::io::Error> for RecordError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+impl From<DeviceError> for RecordError {
+    fn from(value: DeviceError) -> Self {
+        Self::DeviceError(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Event<'a> {
+    time: f64,
+    code: &'a str,
+    value: &'a str,
+}
+
+fn parse_event(line:&str) -> Option<Event<'_>> {
+    let (time, rest) = line.trim_start().strip_prefix('[')?.split_once(']')?;
+    let (_device, rest) = rest.split_once(": ")?;
+    let mut words = rest.split_whitespace();
+    let _kind = words.next()?;
+    Some(Event { time: time.trim().parse().ok()?, code: words.next()?, value: words.next()? })
+}
+
+fn parse_axis_max(description:&str, axis:&str) -> Option<u32> {
+    description.lines()
+    .find(|line|line.split_whitespace().any(|word|word == axis))
+    .and_then(|line|line.split(',').find_map(|part|part.trim().strip_prefix("max ")))
+    .and_then(|max|max.trim().parse().ok())
+}
+
+fn parse_screen_size(output:&str) -> Option<(u32, u32)> {
+    let size = output.lines().filter_map(|line|line.split_once(": ")).next_back()?.1;
+    let (width, height) = size.trim().split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+fn shell(opt:&Opt, script:&str) -> Command {
+    if opt.local {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+    else {
+        let mut command = Command::new("adb");
+        command.arg("-s").arg(&opt.device).arg("shell").arg(script);
+        command
+    }
+}
+
+enum Message {
+    Line(String),
+    Stop,
+}
+
+pub fn run(opt:&Opt, services:&Services, out:&Path) -> Result<String, RecordError> {
+    let description = String::from_utf8_lossy(&device::run(&mut shell(opt, "getevent -lp"), opt, &services.adb_log)?.stdout).into_owned();
+    let (max_x, max_y) = parse_axis_max(&description, "ABS_MT_POSITION_X").zip(parse_axis_max(&description, "ABS_MT_POSITION_Y"))
+    .ok_or(RecordError::NoTouchscreen)?;
+    let size = String::from_utf8_lossy(&device::run(&mut shell(opt, "wm size"), opt, &services.adb_log)?.stdout).into_owned();
+    let (width, height) = parse_screen_size(&size).ok_or(RecordError::UnknownScreenSize)?;
+
+    let mut child = shell(opt, "getevent -lt").stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn()?;
+    let stdout = child.stdout.take().ok_or(RecordError::NoTouchscreen)?;
+    let (sender, receiver) = mpsc::channel();
+    let lines = sender.clone();
+    std::thread::spawn(move||{
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if lines.send(Message::Line(line)).is_err() {
+                break;
+            }
+        }
+        let _ = lines.send(Message::Stop);
+    });
+    std::thread::spawn(move||{
+        let _ = std::io::stdin().lines().next();
+        let _ = sender.send(Message::Stop);
+    });
+    println!("Recording taps on {width}x{height}, press enter to stop");
+
+    let name = out.file_stem().map(|stem|stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut script = Macro::new(&name);
+    let (mut x, mut y) = (0, 0);
+    let mut last_tap:Option<f64> = None;
+    let mut taps = 0;
+    while let Ok(Message::Line(line)) = receiver.recv() {
+        let Some(event) = parse_event(&line) else {
+            continue;
+        };
+        let value = u32::from_str_radix(event.value, 16).ok();
+        match (event.code, event.value) {
+            ("ABS_MT_POSITION_X", _) => x = value.unwrap_or(x),
+            ("ABS_MT_POSITION_Y", _) => y = value.unwrap_or(y),
+            ("BTN_TOUCH", "UP") => {
+                if let Some(last_tap) = last_tap {
+                    script.push(MacroStep::Wait(((event.time - last_tap) * 1000.0).max(0.0) as u64));
+                }
+                let (tap_x, tap_y) = (x * width / (max_x + 1), y * height / (max_y + 1));
+                println!("tap {tap_x} {tap_y}");
+                script.push(MacroStep::Tap(tap_x, tap_y));
+                last_tap = Some(event.time);
+                taps += 1;
+            },
+            _ => {},
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    std::fs::write(out, script.to_string())?;
+    Ok(format!("Recorded {taps} taps into {}", out.display()))
+}
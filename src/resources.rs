@@ -0,0 +1,64 @@
+use std::{collections::VecDeque, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+
+const HISTORY_LEN:usize = 1000;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Resources {
+    pub gold: Option<u32>,
+    pub xp: Option<u32>,
+    pub level: Option<u32>,
+    #[serde(default)]
+    pub energy: Option<u32>,
+}
+impl Resources {
+    pub fn is_empty(&self) -> bool {
+        self.gold.is_none() && self.xp.is_none() && self.level.is_none() && self.energy.is_none()
+    }
+
+    fn merge(&self, old:&Resources) -> Self {
+        Self {
+            gold: self.gold.or(old.gold),
+            xp: self.xp.or(old.xp),
+            level: self.level.or(old.level),
+            energy: self.energy.or(old.energy),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ResourceSample {
+    pub time: u64,
+    #[serde(flatten)]
+    pub resources: Resources,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ResourceHistory {
+    current: Resources,
+    samples: VecDeque<ResourceSample>,
+}
+impl ResourceHistory {
+    pub fn current(&self) -> &Resources {
+        &self.current
+    }
+
+    pub fn record(&mut self, reading:&Resources) {
+        if reading.is_empty() {
+            return;
+        }
+        let current = reading.merge(&self.current);
+        if current == self.current {
+            return;
+        }
+        self.current = current;
+        if self.samples.len() >= HISTORY_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ResourceSample {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            resources: current,
+        });
+    }
+}
@@ -0,0 +1,20 @@
+use std::{future::Future, sync::OnceLock};
+
+use tokio::runtime::Runtime;
+
+static RUNTIME:OnceLock<Runtime> = OnceLock::new();
+
+pub fn get() -> &'static Runtime {
+    RUNTIME.get_or_init(||{
+        tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("endorbot-io")
+        .enable_all()
+        .build()
+        .expect("Failed to start the I/O runtime")
+    })
+}
+
+pub fn block_on<F:Future>(future:F) -> F::Output {
+    get().block_on(future)
+}
@@ -0,0 +1,221 @@
+use std::{fmt::Display, time::{Duration, Instant}};
+
+use chrono::{Local, NaiveTime};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+impl Window {
+    pub fn contains(&self, time:NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        }
+        else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+impl TryFrom<String> for Window {
+    type Error = String;
+
+    fn try_from(value:String) -> Result<Self, Self::Error> {
+        parse_window(&value)
+    }
+}
+impl From<Window> for String {
+    fn from(window:Window) -> Self {
+        window.to_string()
+    }
+}
+impl Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
+
+pub fn parse_window(value:&str) -> Result<Window, String> {
+    let parse = |time:&str|NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|err|format!("invalid time {time}: {err}"));
+    let Some((start, end)) = value.split_once('-') else {
+        return Err(format!("expected HH:MM-HH:MM, got {value}"));
+    };
+    Ok(Window { start: parse(start)?, end: parse(end)? })
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct Schedule {
+    #[clap(id = "run_windows", long = "run-window", value_parser = parse_window)]
+    pub windows: Vec<Window>,
+    #[clap(id = "break_every_minutes", long = "break-every-minutes", default_value_t = 0)]
+    pub break_every_minutes: u64,
+    #[clap(id = "break_minutes", long = "break-minutes", default_value_t = 10)]
+    pub break_minutes: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleOverride {
+    #[default]
+    Auto,
+    Run,
+    Stop,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ScheduleState {
+    #[default]
+    Running,
+    OutsideWindow,
+    Break { remaining_secs: u64 },
+    Stopped,
+}
+impl ScheduleState {
+    pub fn runs(&self) -> bool {
+        matches!(self, ScheduleState::Running)
+    }
+}
+impl Display for ScheduleState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScheduleState::Running => write!(f, "running"),
+            ScheduleState::OutsideWindow => write!(f, "outside of the run windows"),
+            ScheduleState::Break { remaining_secs } => write!(f, "on a break for {} minutes", remaining_secs.div_ceil(60)),
+            ScheduleState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleStatus {
+    #[serde(flatten)]
+    pub state: ScheduleState,
+    #[serde(rename = "override")]
+    pub mode: ScheduleOverride,
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    schedule: Schedule,
+    active_since: Option<Instant>,
+    break_until: Option<Instant>,
+}
+impl Scheduler {
+    pub fn new(schedule:&Schedule) -> Self {
+        Self {
+            schedule: schedule.clone(),
+            active_since: None,
+            break_until: None,
+        }
+    }
+
+    fn in_window(&self) -> bool {
+        let now = Local::now().time();
+        self.schedule.windows.is_empty() || self.schedule.windows.iter().any(|window|window.contains(now))
+    }
+
+    fn take_break(&mut self, now:Instant) -> Option<Duration> {
+        if let Some(until) = self.break_until {
+            if now < until {
+                return Some(until - now);
+            }
+            self.break_until = None;
+            self.active_since = Some(now);
+        }
+        let active_since = *self.active_since.get_or_insert(now);
+        if self.schedule.break_every_minutes == 0
+            || now.duration_since(active_since) < Duration::from_secs(self.schedule.break_every_minutes * 60) {
+            return None;
+        }
+        let length = Duration::from_secs(self.schedule.break_minutes * 60);
+        self.break_until = Some(now + length);
+        self.active_since = None;
+        Some(length)
+    }
+
+    pub fn poll(&mut self, mode:ScheduleOverride) -> ScheduleStatus {
+        let now = Instant::now();
+        let state = match mode {
+            ScheduleOverride::Run => ScheduleState::Running,
+            ScheduleOverride::Stop => ScheduleState::Stopped,
+            ScheduleOverride::Auto if !self.in_window() => ScheduleState::OutsideWindow,
+            ScheduleOverride::Auto => match self.take_break(now) {
+                Some(remaining) => ScheduleState::Break { remaining_secs: remaining.as_secs() },
+                None => ScheduleState::Running,
+            },
+        };
+        if !state.runs() && !matches!(state, ScheduleState::Break { .. }) {
+            self.active_since = None;
+            self.break_until = None;
+        }
+        ScheduleStatus { state, mode }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour:u32, minute:u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn scheduler(break_every_minutes:u64, break_minutes:u64) -> Scheduler {
+        Scheduler::new(&Schedule { windows: Vec::new(), break_every_minutes, break_minutes })
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let window = parse_window("09:00-17:30").unwrap();
+        assert!(window.contains(time(9, 0)));
+        assert!(window.contains(time(17, 29)));
+        assert!(!window.contains(time(17, 30)));
+        assert!(!window.contains(time(8, 59)));
+        assert_eq!(window.to_string(), "09:00-17:30");
+    }
+
+    #[test]
+    fn window_across_midnight() {
+        let window = parse_window("22:00 - 06:00").unwrap();
+        assert!(window.contains(time(22, 0)));
+        assert!(window.contains(time(23, 59)));
+        assert!(window.contains(time(0, 0)));
+        assert!(window.contains(time(5, 59)));
+        assert!(!window.contains(time(6, 0)));
+        assert!(!window.contains(time(12, 0)));
+    }
+
+    #[test]
+    fn rejects_malformed_windows() {
+        assert!(parse_window("09:00").is_err());
+        assert!(parse_window("9-17").is_err());
+        assert!(parse_window("25:00-26:00").is_err());
+    }
+
+    #[test]
+    fn breaks_after_active_time() {
+        let mut scheduler = scheduler(60, 10);
+        let start = Instant::now();
+        assert_eq!(scheduler.take_break(start), None);
+        assert_eq!(scheduler.take_break(start + Duration::from_secs(59 * 60)), None);
+        assert_eq!(scheduler.take_break(start + Duration::from_secs(60 * 60)), Some(Duration::from_secs(10 * 60)));
+        assert_eq!(scheduler.take_break(start + Duration::from_secs(65 * 60)), Some(Duration::from_secs(5 * 60)));
+
+        let resumed = start + Duration::from_secs(70 * 60);
+        assert_eq!(scheduler.take_break(resumed), None);
+        assert_eq!(scheduler.take_break(resumed + Duration::from_secs(59 * 60)), None);
+        assert!(scheduler.take_break(resumed + Duration::from_secs(60 * 60)).is_some());
+    }
+
+    #[test]
+    fn never_breaks_when_disabled() {
+        let mut scheduler = scheduler(0, 10);
+        let start = Instant::now();
+        assert_eq!(scheduler.take_break(start), None);
+        assert_eq!(scheduler.take_break(start + Duration::from_secs(24 * 60 * 60)), None);
+    }
+}
@@ -1,15 +1,29 @@
-use std::{fs::File, io::{BufReader, Read, Write}, path::PathBuf, process::{Command, Stdio}};
+use std::{fs::File, io::{BufReader, Read}, path::PathBuf, process::Command};
 
+use clap::ValueEnum;
 use image::{DynamicImage, GenericImageView, ImageError, RgbaImage};
+use serde::Deserialize;
 
-use crate::{Opt, ml::{self, Bitmap, BitmapWebp, Coords, DungeonInfo}};
+use crate::{Opt, Services, device::{self, DeviceError}, par, runtime, transfer::{self, TransferError}, ml::{Bitmap, BitmapWebp, Coords, DungeonInfo, DungeonKind, EVENT_BADGE, EVENT_PINK}};
 
 #[derive(Debug)]
 pub enum LoadBitmapError {
     ImageError(ImageError),
     IoError(std::io::Error),
+    InvalidRawHeader(usize),
 }
 
+impl std::fmt::Display for LoadBitmapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ImageError(err) => write!(f, "image error: {err}"),
+            Self::IoError(err) => write!(f, "io error: {err}"),
+            Self::InvalidRawHeader(len) => write!(f, "unrecognized raw screencap header ({len} bytes of data)"),
+        }
+    }
+}
+impl std::error::Error for LoadBitmapError {}
+
 impl From<std::io::Error> for LoadBitmapError {
     fn from(value: std::io::Error) -> Self {
         Self::IoError(value)
@@ -21,20 +35,19 @@ impl From<ImageError> for LoadBitmapError {
     }
 }
 
+const PNG_MAGIC:&[u8] = b"\x89PNG\r\n\x1a\n";
+
 pub fn load_bitmap(input: &[u8]) -> Result<DynamicImage, LoadBitmapError> {
+    if input.starts_with(PNG_MAGIC) {
+        return image::load_from_memory_with_format(input, image::ImageFormat::Png).map_err(|err|err.into());
+    }
     match image::load_from_memory_with_format(input, image::ImageFormat::Bmp) {
         Ok(image) => {
             Ok(image)
         },
         Err(err) => {
             match err {
-                image::ImageError::Decoding(_) => {
-                    let width = u32::from_le_bytes(input[..4].try_into().unwrap());
-                    let height = u32::from_le_bytes(input[4..8].try_into().unwrap());
-                    let image_buffer = RgbaImage::from_raw(width, height, input[16..].to_vec()).expect("Invalid bitmap data");
-                    let image:DynamicImage = image_buffer.into();
-                    Ok(image)
-                },
+                image::ImageError::Decoding(_) => load_raw(input),
                 _ => {
                     Err(LoadBitmapError::ImageError(err))
                 }
@@ -43,6 +56,45 @@ pub fn load_bitmap(input: &[u8]) -> Result<DynamicImage, LoadBitmapError> {
     }
 }
 
+const RAW_HEADER_SIZES:[usize; 2] = [16, 12];
+const MAX_RAW_DIMENSION:u32 = 16384;
+const PIXEL_FORMAT_RGBA_8888:u32 = 1;
+const PIXEL_FORMAT_RGBX_8888:u32 = 2;
+const PIXEL_FORMAT_BGRA_8888:u32 = 5;
+
+fn raw_word(input:&[u8], index:usize) -> Option<u32> {
+    input.get(index * 4..index * 4 + 4).map(|word|u32::from_le_bytes(word.try_into().unwrap()))
+}
+
+// Older releases write width, height and format; newer ones append a colorspace word.
+fn raw_header_size(input:&[u8]) -> Option<(usize, u32, u32, u32)> {
+    let (width, height, format) = (raw_word(input, 0)?, raw_word(input, 1)?, raw_word(input, 2)?);
+    if width == 0 || height == 0 || width > MAX_RAW_DIMENSION || height > MAX_RAW_DIMENSION {
+        return None;
+    }
+    if !matches!(format, PIXEL_FORMAT_RGBA_8888 | PIXEL_FORMAT_RGBX_8888 | PIXEL_FORMAT_BGRA_8888) {
+        return None;
+    }
+    let pixels = width as usize * height as usize * 4;
+    RAW_HEADER_SIZES.into_iter()
+    .find(|header|input.len() == header + pixels)
+    .or_else(||RAW_HEADER_SIZES.into_iter().find(|header|input.len() > header + pixels))
+    .map(|header|(header, width, height, format))
+}
+
+fn load_raw(input:&[u8]) -> Result<DynamicImage, LoadBitmapError> {
+    let (header, width, height, format) = raw_header_size(input).ok_or(LoadBitmapError::InvalidRawHeader(input.len()))?;
+    let mut pixels = input[header..header + width as usize * height as usize * 4].to_vec();
+    match format {
+        PIXEL_FORMAT_BGRA_8888 => pixels.chunks_exact_mut(4).for_each(|pixel|pixel.swap(0, 2)),
+        PIXEL_FORMAT_RGBX_8888 => pixels.chunks_exact_mut(4).for_each(|pixel|pixel[3] = 255),
+        _ => {},
+    }
+    let image_buffer = RgbaImage::from_raw(width, height, pixels).ok_or(LoadBitmapError::InvalidRawHeader(input.len()))?;
+    Ok(image_buffer.into())
+}
+
+#[allow(dead_code)]
 pub fn load_bitmap_from_file(path: PathBuf) -> Result<DynamicImage, LoadBitmapError> {
     let mut buf = Vec::new();
     File::open(path)?.read_to_end(&mut buf)?;
@@ -57,13 +109,38 @@ pub fn load_png_from_file(path: PathBuf) -> Result<DynamicImage, LoadBitmapError
 pub enum ScreencapError {
     LoadBitmapError(LoadBitmapError),
     IoError(std::io::Error),
+    Device(DeviceError),
+    Transfer(TransferError),
     Failed,
 }
+impl std::fmt::Display for ScreencapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LoadBitmapError(err) => write!(f, "failed to load bitmap: {err}"),
+            Self::IoError(err) => write!(f, "io error: {err}"),
+            Self::Device(err) => write!(f, "device error: {err}"),
+            Self::Transfer(err) => write!(f, "transfer error: {err}"),
+            Self::Failed => write!(f, "screencap failed"),
+        }
+    }
+}
+impl std::error::Error for ScreencapError {}
+
 impl From<std::io::Error> for ScreencapError {
     fn from(value: std::io::Error) -> Self {
         Self::IoError(value)
     }
 }
+impl From<TransferError> for ScreencapError {
+    fn from(value: TransferError) -> Self {
+        Self::Transfer(value)
+    }
+}
+impl From<DeviceError> for ScreencapError {
+    fn from(value: DeviceError) -> Self {
+        Self::Device(value)
+    }
+}
 impl From<LoadBitmapError> for ScreencapError {
     fn from(value: LoadBitmapError) -> Self {
         Self::LoadBitmapError(value)
@@ -256,7 +333,6 @@ fn get_info(image:&DynamicImage, opt:&Opt) -> DungeonInfo {
                         }
                         if let Some(n) = current_number {
                             numbers.push(n);
-                            current_number = None;
                         }
                         break;
                     }
@@ -272,96 +348,129 @@ fn get_info(image:&DynamicImage, opt:&Opt) -> DungeonInfo {
                 coordinates: if numbers.len() >= 2 {
                     Some(Coords{x: numbers[0], y: numbers[1]})
                 } else {None},
+                size: None,
+                kind: if opt.extra_screens && image.get_pixel(EVENT_BADGE.0, EVENT_BADGE.1).0[0..3] == EVENT_PINK.0 {
+                    DungeonKind::Event
+                }
+                else {
+                    DungeonKind::Main
+                },
             };
         }
     }
     DungeonInfo {
         floor: "".to_owned(),
         coordinates: None,
+        size: None,
+        kind: DungeonKind::Main,
     }
 }
 
+const PROBES:&[(u16, u16)] = &[(918u16,138u16),(714,1308),(569,566),(559,566),(569,566),(559,566),(629,566),(619,566),(629,566),(619,566),(629,626),(619,626),(629,626),(619,626),(629,686),(619,686),(629,686),(619,686),(629,746),(619,746),(629,746),(619,746),(629,806),(619,806),(629,806),(619,806),(629,866),(619,866),(629,866),(619,866),(689,566),(679,566),(689,566),(679,566),(689,626),(679,626),(689,626),(679,626),(689,686),(679,686),(689,686),(679,686),(689,866),(679,866),(689,866),(679,866),(749,566),(739,566),(749,566),(739,566),(749,626),(739,626),(749,626),(739,626),(749,866),(739,866),(749,866),(739,866),(809,566),(799,566),(809,566),(799,566),(869,566),(859,566),(869,566),(859,566),(929,566),(919,566),(929,566),(919,566),(809,926),(799,926),(809,926),(799,926),(869,926),(859,926),(869,926),(859,926),(929,926),(919,926),(929,926),(919,926),(809,626),(799,626),(809,626),(799,626),(869,626),(859,626),(869,626),(859,626),(869,686),(859,686),(869,686),(859,686),(929,626),(919,626),(929,626),(919,626),(929,686),(919,686),(929,686),(919,686),(809,806),(799,806),(809,806),(799,806),(809,866),(799,866),(809,866),(799,866),(869,746),(859,746),(869,746),(859,746),(869,806),(859,806),(869,806),(859,806),(869,866),(859,866),(869,866),(859,866),(929,746),(919,746),(929,746),(919,746),(929,806),(919,806),(929,806),(919,806),(929,866),(919,866),(929,866),(919,866),(911,940),(155,940),(749,686),(739,686),(749,686),(739,686),(749,746),(739,746),(749,746),(739,746),(749,806),(739,806),(749,806),(739,806),(809,686),(799,686),(809,686),(799,686),(809,746),(799,746),(809,746),(799,746),(560,930),(620,930),(680,930),(740,930),(800,930),(860,930),(920,930),(560,570),(560,630),(560,690),(560,750),(560,810),(560,870),(620,570),(620,630),(620,690),(620,750),(620,810),(620,870),(680,570),(680,630),(680,690),(680,750),(680,810),(680,870),(740,570),(740,630),(740,690),(740,750),(740,810),(740,870),(800,570),(800,630),(800,690),(800,750),(800,810),(800,870),(860,570),(860,630),(860,690),(860,750),(860,810),(860,870),(920,570),(920,630),(920,690),(920,750),(920,810),(920,870),(928,574),(928,634),(928,694),(928,754),(928,814),(928,874),(928,934),(568,574),(568,634),(568,694),(568,754),(568,814),(568,874),(568,934),(628,574),(628,634),(628,694),(628,754),(628,814),(628,874),(628,934),(688,574),(688,634),(688,694),(688,754),(688,814),(688,874),(688,934),(748,574),(748,634),(748,694),(748,754),(748,814),(748,874),(748,934),(808,574),(808,634),(808,694),(808,754),(808,814),(808,874),(808,934),(868,574),(868,634),(868,694),(868,754),(868,814),(868,874),(868,934),(642, 1201),(608, 1307),(609, 1329),(952,927),(926,953),(897,927),(592,927),(566,953),(537,927),(652,927),(626,953),(597,927),(712,927),(686,953),(657,927),(772,927),(746,953),(717,927),(832,927),(806,953),(777,927),(892,927),(866,953),(837,927),(592,867),(566,893),(537,867),(652,867),(626,893),(597,867),(712,867),(686,893),(657,867),(772,867),(746,893),(717,867),(832,867),(806,893),(777,867),(892,867),(866,893),(837,867),(952,867),(926,893),(897,867),(892,627),(866,653),(837,627),(892,687),(866,713),(837,687),(892,747),(866,773),(837,747),(892,807),(866,833),(837,807),(926,538),(952,567),(926,593),(897,567),(952,627),(926,653),(897,627),(952,687),(926,713),(897,687),(952,747),(926,773),(897,747),(952,807),(926,833),(897,807),(592,567),(566,593),(537,567),(592,627),(566,653),(537,627),(592,687),(566,713),(537,687),(592,747),(566,773),(537,747),(592,807),(566,833),(537,807),(652,567),(626,593),(597,567),(652,627),(626,653),(597,627),(652,687),(626,713),(597,687),(652,747),(626,773),(597,747),(652,807),(626,833),(597,807),(712,567),(686,593),(657,567),(712,627),(686,653),(657,627),(712,687),(686,713),(657,687),(712,747),(686,773),(657,747),(712,807),(686,833),(657,807),(772,567),(746,593),(717,567),(772,627),(746,653),(717,627),(772,687),(746,713),(717,687),(772,747),(746,773),(717,747),(772,807),(746,833),(717,807),(832,567),(806,593),(777,567),(832,627),(806,653),(777,627),(832,687),(806,713),(777,687),(832,747),(806,773),(777,747),(832,807),(806,833),(777,807),(866,538),(892,567),(866,593),(837,567),(566,898),(626,898),(686,898),(746,898),(806,898),(866,898),(926,898),(866,538),(566,838),(626,838),(686,838),(746,598),(746,658),(746,718),(746,778),(746,838),(806,538),(806,598),(806,658),(806,718),(806,778),(806,838),(866,598),(866,658),(866,718),(866,778),(866,838),(926,598),(926,658),(926,718),(926,778),(926,838),(566,538),(566,598),(566,658),(566,718),(566,778),(626,538),(626,598),(626,658),(626,718),(626,778),(686,538),(686,598),(686,658),(686,718),(686,778),(746,538),(147,680), (147,800), (75,1512), (147,920),(466,1116),(827,1306),(147,560),(671,1309),(90,1472),(511,1471),(511-89,1471),(514,560),(291,560),(514,680),(514,800),(514,920),(566,566),(564,566),(566,537),(566,538),(592,566),(566,592),(537,566),(566,626),(564,626),(566,597),(592,626),(566,652),(537,626),(566,686),(566,746),(566,806),(564,806),(566,777),(592,806),(566,832),(537,806),(566,866),(566,926),(626,566),(624,566),(626,537),(652,566),(626,592),(597,566),(626,626),(624,626),(626,597),(652,626),(626,652),(597,626),(626,686),(626,746),(626,806),(624,806),(626,777),(652,806),(626,832),(597,806),(626,866),(626,926),(686,566),(684,566),(686,537),(712,566),(686,592),(657,566),(686,626),(684,626),(686,597),(712,626),(686,652),(657,626),(686,686),(686,746),(686,806),(684,806),(686,777),(712,806),(686,832),(657,806),(686,866),(686,926),(746,566),(744,566),(746,537),(772,566),(746,592),(717,566),(746,626),(746,686),(746,746),(746,806),(744,806),(746,777),(772,806),(746,832),(717,806),(746,866),(746,926),(806,566),(804,566),(806,537),(832,566),(806,592),(777,566),(806,626),(804,626),(806,597),(832,626),(806,652),(777,626),(806,686),(804,686),(806,657),(832,686),(806,712),(777,686),(806,746),(804,746),(806,717),(832,746),(806,772),(777,746),(806,806),(804,806),(806,777),(832,806),(806,832),(777,806),(806,866),(806,926),(866,566),(864,566),(866,537),(892,566),(866,592),(837,566),(866,626),(864,626),(866,597),(892,626),(866,652),(837,626),(866,686),(864,686),(866,657),(892,686),(866,712),(837,686),(866,746),(864,746),(866,717),(892,746),(866,772),(837,746),(866,806),(864,806),(866,777),(892,806),(866,832),(837,806),(866,866),(866,926),(926,566),(924,566),(926,537),(952,566),(926,592),(897,566),(926,626),(924,626),(926,597),(952,626),(926,652),(897,626),(926,686),(924,686),(926,657),(952,686),(926,712),(897,686),(926,746),(924,746),(926,717),(952,746),(926,772),(897,746),(926,806),(924,806),(926,777),(952,806),(926,832),(897,806),(926,866),(926,926),(355,1471),(355-89,1471),(181,1471),(181-89,1471),(291,920),(827,1260),(979,1083),(1023,1116),(716,1279),(564,686),(566,657),(592,686),(566,712),(537,686),(564,866),(566,837),(592,866),(566,892),(537,866),(624,686),(626,657),(652,686),(626,712),(597,686),(624,866),(626,837),(652,866),(626,892),(597,866),(684,686),(686,657),(712,686),(686,712),(657,686),(684,866),(686,837),(712,866),(686,892),(657,866),(744,626),(746,597),(772,626),(746,652),(717,626),(744,866),(746,837),(772,866),(746,892),(717,866),(804,866),(806,837),(832,866),(806,892),(777,866),(864,866),(866,837),(892,866),(866,892),(837,866),(924,866),(926,837),(952,866),(926,892),(897,866),(564,746),(566,717),(592,746),(566,772),(537,746),(564,926),(566,897),(592,926),(566,952),(537,926),(624,746),(626,717),(652,746),(626,772),(597,746),(624,926),(626,897),(652,926),(626,952),(597,926),(684,746),(686,717),(712,746),(686,772),(657,746),(684,926),(686,897),(712,926),(686,952),(657,926),(744,686),(746,657),(772,686),(746,712),(717,686),(744,926),(746,897),(772,926),(746,952),(717,926),(804,926),(806,897),(832,926),(806,952),(777,926),(864,926),(866,897),(892,926),(866,952),(837,926),(924,926),(926,897),(952,926),(926,952),(897,926),(690,1306),(422,1471),(744,746),(746,717),(772,746),(746,772),(717,746),(291,680),(717,1326),(291,800),(949,138),(919,168),(949,168),(752,1926),(462,1254)];
+
 pub fn bitmap_from_image(image:&DynamicImage, opt:&Opt) -> Option<Bitmap> {
     let mut bitmap = Bitmap::with_capacity(100);
-    for (x, y) in [(918u16,138u16),(714,1308),(569,566),(559,566),(569,566),(559,566),(629,566),(619,566),(629,566),(619,566),(629,626),(619,626),(629,626),(619,626),(629,686),(619,686),(629,686),(619,686),(629,746),(619,746),(629,746),(619,746),(629,806),(619,806),(629,806),(619,806),(629,866),(619,866),(629,866),(619,866),(689,566),(679,566),(689,566),(679,566),(689,626),(679,626),(689,626),(679,626),(689,686),(679,686),(689,686),(679,686),(689,866),(679,866),(689,866),(679,866),(749,566),(739,566),(749,566),(739,566),(749,626),(739,626),(749,626),(739,626),(749,866),(739,866),(749,866),(739,866),(809,566),(799,566),(809,566),(799,566),(869,566),(859,566),(869,566),(859,566),(929,566),(919,566),(929,566),(919,566),(809,926),(799,926),(809,926),(799,926),(869,926),(859,926),(869,926),(859,926),(929,926),(919,926),(929,926),(919,926),(809,626),(799,626),(809,626),(799,626),(869,626),(859,626),(869,626),(859,626),(869,686),(859,686),(869,686),(859,686),(929,626),(919,626),(929,626),(919,626),(929,686),(919,686),(929,686),(919,686),(809,806),(799,806),(809,806),(799,806),(809,866),(799,866),(809,866),(799,866),(869,746),(859,746),(869,746),(859,746),(869,806),(859,806),(869,806),(859,806),(869,866),(859,866),(869,866),(859,866),(929,746),(919,746),(929,746),(919,746),(929,806),(919,806),(929,806),(919,806),(929,866),(919,866),(929,866),(919,866),(911,940),(155,940),(749,686),(739,686),(749,686),(739,686),(749,746),(739,746),(749,746),(739,746),(749,806),(739,806),(749,806),(739,806),(809,686),(799,686),(809,686),(799,686),(809,746),(799,746),(809,746),(799,746),(560,930),(620,930),(680,930),(740,930),(800,930),(860,930),(920,930),(560,570),(560,630),(560,690),(560,750),(560,810),(560,870),(620,570),(620,630),(620,690),(620,750),(620,810),(620,870),(680,570),(680,630),(680,690),(680,750),(680,810),(680,870),(740,570),(740,630),(740,690),(740,750),(740,810),(740,870),(800,570),(800,630),(800,690),(800,750),(800,810),(800,870),(860,570),(860,630),(860,690),(860,750),(860,810),(860,870),(920,570),(920,630),(920,690),(920,750),(920,810),(920,870),(928,574),(928,634),(928,694),(928,754),(928,814),(928,874),(928,934),(568,574),(568,634),(568,694),(568,754),(568,814),(568,874),(568,934),(628,574),(628,634),(628,694),(628,754),(628,814),(628,874),(628,934),(688,574),(688,634),(688,694),(688,754),(688,814),(688,874),(688,934),(748,574),(748,634),(748,694),(748,754),(748,814),(748,874),(748,934),(808,574),(808,634),(808,694),(808,754),(808,814),(808,874),(808,934),(868,574),(868,634),(868,694),(868,754),(868,814),(868,874),(868,934),(642, 1201),(608, 1307),(609, 1329),(952,927),(926,953),(897,927),(592,927),(566,953),(537,927),(652,927),(626,953),(597,927),(712,927),(686,953),(657,927),(772,927),(746,953),(717,927),(832,927),(806,953),(777,927),(892,927),(866,953),(837,927),(592,867),(566,893),(537,867),(652,867),(626,893),(597,867),(712,867),(686,893),(657,867),(772,867),(746,893),(717,867),(832,867),(806,893),(777,867),(892,867),(866,893),(837,867),(952,867),(926,893),(897,867),(892,627),(866,653),(837,627),(892,687),(866,713),(837,687),(892,747),(866,773),(837,747),(892,807),(866,833),(837,807),(926,538),(952,567),(926,593),(897,567),(952,627),(926,653),(897,627),(952,687),(926,713),(897,687),(952,747),(926,773),(897,747),(952,807),(926,833),(897,807),(592,567),(566,593),(537,567),(592,627),(566,653),(537,627),(592,687),(566,713),(537,687),(592,747),(566,773),(537,747),(592,807),(566,833),(537,807),(652,567),(626,593),(597,567),(652,627),(626,653),(597,627),(652,687),(626,713),(597,687),(652,747),(626,773),(597,747),(652,807),(626,833),(597,807),(712,567),(686,593),(657,567),(712,627),(686,653),(657,627),(712,687),(686,713),(657,687),(712,747),(686,773),(657,747),(712,807),(686,833),(657,807),(772,567),(746,593),(717,567),(772,627),(746,653),(717,627),(772,687),(746,713),(717,687),(772,747),(746,773),(717,747),(772,807),(746,833),(717,807),(832,567),(806,593),(777,567),(832,627),(806,653),(777,627),(832,687),(806,713),(777,687),(832,747),(806,773),(777,747),(832,807),(806,833),(777,807),(866,538),(892,567),(866,593),(837,567),(566,898),(626,898),(686,898),(746,898),(806,898),(866,898),(926,898),(866,538),(566,838),(626,838),(686,838),(746,598),(746,658),(746,718),(746,778),(746,838),(806,538),(806,598),(806,658),(806,718),(806,778),(806,838),(866,598),(866,658),(866,718),(866,778),(866,838),(926,598),(926,658),(926,718),(926,778),(926,838),(566,538),(566,598),(566,658),(566,718),(566,778),(626,538),(626,598),(626,658),(626,718),(626,778),(686,538),(686,598),(686,658),(686,718),(686,778),(746,538),(147,680), (147,800), (75,1512), (147,920),(466,1116),(827,1306),(147,560),(671,1309),(90,1472),(511,1471),(511-89,1471),(514,560),(291,560),(514,680),(514,800),(514,920),(566,566),(564,566),(566,537),(566,538),(592,566),(566,592),(537,566),(566,626),(564,626),(566,597),(592,626),(566,652),(537,626),(566,686),(566,746),(566,806),(564,806),(566,777),(592,806),(566,832),(537,806),(566,866),(566,926),(626,566),(624,566),(626,537),(652,566),(626,592),(597,566),(626,626),(624,626),(626,597),(652,626),(626,652),(597,626),(626,686),(626,746),(626,806),(624,806),(626,777),(652,806),(626,832),(597,806),(626,866),(626,926),(686,566),(684,566),(686,537),(712,566),(686,592),(657,566),(686,626),(684,626),(686,597),(712,626),(686,652),(657,626),(686,686),(686,746),(686,806),(684,806),(686,777),(712,806),(686,832),(657,806),(686,866),(686,926),(746,566),(744,566),(746,537),(772,566),(746,592),(717,566),(746,626),(746,686),(746,746),(746,806),(744,806),(746,777),(772,806),(746,832),(717,806),(746,866),(746,926),(806,566),(804,566),(806,537),(832,566),(806,592),(777,566),(806,626),(804,626),(806,597),(832,626),(806,652),(777,626),(806,686),(804,686),(806,657),(832,686),(806,712),(777,686),(806,746),(804,746),(806,717),(832,746),(806,772),(777,746),(806,806),(804,806),(806,777),(832,806),(806,832),(777,806),(806,866),(806,926),(866,566),(864,566),(866,537),(892,566),(866,592),(837,566),(866,626),(864,626),(866,597),(892,626),(866,652),(837,626),(866,686),(864,686),(866,657),(892,686),(866,712),(837,686),(866,746),(864,746),(866,717),(892,746),(866,772),(837,746),(866,806),(864,806),(866,777),(892,806),(866,832),(837,806),(866,866),(866,926),(926,566),(924,566),(926,537),(952,566),(926,592),(897,566),(926,626),(924,626),(926,597),(952,626),(926,652),(897,626),(926,686),(924,686),(926,657),(952,686),(926,712),(897,686),(926,746),(924,746),(926,717),(952,746),(926,772),(897,746),(926,806),(924,806),(926,777),(952,806),(926,832),(897,806),(926,866),(926,926),(355,1471),(355-89,1471),(181,1471),(181-89,1471),(291,920),(827,1260),(979,1083),(1023,1116),(716,1279),(564,686),(566,657),(592,686),(566,712),(537,686),(564,866),(566,837),(592,866),(566,892),(537,866),(624,686),(626,657),(652,686),(626,712),(597,686),(624,866),(626,837),(652,866),(626,892),(597,866),(684,686),(686,657),(712,686),(686,712),(657,686),(684,866),(686,837),(712,866),(686,892),(657,866),(744,626),(746,597),(772,626),(746,652),(717,626),(744,866),(746,837),(772,866),(746,892),(717,866),(804,866),(806,837),(832,866),(806,892),(777,866),(864,866),(866,837),(892,866),(866,892),(837,866),(924,866),(926,837),(952,866),(926,892),(897,866),(564,746),(566,717),(592,746),(566,772),(537,746),(564,926),(566,897),(592,926),(566,952),(537,926),(624,746),(626,717),(652,746),(626,772),(597,746),(624,926),(626,897),(652,926),(626,952),(597,926),(684,746),(686,717),(712,746),(686,772),(657,746),(684,926),(686,897),(712,926),(686,952),(657,926),(744,686),(746,657),(772,686),(746,712),(717,686),(744,926),(746,897),(772,926),(746,952),(717,926),(804,926),(806,897),(832,926),(806,952),(777,926),(864,926),(866,897),(892,926),(866,952),(837,926),(924,926),(926,897),(952,926),(926,952),(897,926),(690,1306),(422,1471),(744,746),(746,717),(772,746),(746,772),(717,746),(291,680),(717,1326),(291,800),(949,138),(919,168),(949,168),(752,1926),(462,1254)] {
-        bitmap.set_pixel(x, y, image.get_pixel(x as u32, y as u32).0[0..3].try_into().unwrap());
+    let samples = par::map(PROBES, |&(x, y)|(x, y, image.get_pixel(x as u32, y as u32).0));
+    for (x, y, pixel) in samples {
+        bitmap.set_pixel(x, y, pixel[0..3].try_into().unwrap());
     }
     
-    bitmap.set_info(get_info(&image, opt));
-    //bitmap.set_has_dead_characters(ml::get_characters(&bitmap).iter().find(|char|char.is_dead()).is_some());
+    bitmap.set_info(get_info(image, opt));
     
     if opt.debug {
-        println!("{:?}", bitmap.get_has_dead_characters());
         println!("{:?}", bitmap.get_info());
     }
-    return Some(bitmap);
+    Some(bitmap)
 }
 
-pub fn screencap_bitmap(device:&str, opt:&Opt) -> Option<Bitmap> {
+pub fn screencap_bitmap(device:&str, opt:&Opt, services:&Services) -> Option<Bitmap> {
     if opt.local {
-        let image = screencap(device, &opt).unwrap();
-        return bitmap_from_image(&image, opt);
+        let image = screencap(device, opt, services).unwrap();
+        bitmap_from_image(&image, opt)
     }
     else {
-        let output = Command::new("adb").arg("-s").arg(device).arg("exec-out").arg("sh").arg("-c").arg("cd /data/local/tmp/ && ./endorbot --local --screencap")
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped())
-        .spawn().unwrap().wait_with_output().unwrap();
-        if output.status.success() {
-            return Some(rkyv::from_bytes::<Bitmap, rkyv::rancor::Error>(&output.stdout).unwrap());
-        }
+        let payload = helper_screencap(device, opt, services).ok()?;
+        rkyv::from_bytes::<Bitmap, rkyv::rancor::Error>(&payload).ok()
     }
-    None
 }
 
-pub fn screencap_webp(device:&str, opt:&Opt) -> Option<BitmapWebp> {
-    let output = Command::new("adb").arg("-s").arg(device).arg("exec-out").arg("sh").arg("-c").arg("cd /data/local/tmp/ && ./endorbot --local --screencap")
-    .stdin(Stdio::null())
-    .stderr(Stdio::null())
-    .stdout(Stdio::piped())
-    .spawn().unwrap().wait_with_output().unwrap();
-    if output.status.success() {
-        return Some(BitmapWebp::from_image(image::load_from_memory_with_format(&output.stdout, image::ImageFormat::WebP).unwrap(), 2, opt));
-        //return Some(rkyv::from_bytes::<Bitmap, rkyv::rancor::Error>(&output.stdout).unwrap());
-    }
-    None
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureBackend {
+    Webp,
+    Raw,
+    Png,
+}
+
+pub async fn capture(device:&str, opt:&Opt, services:&Services) -> Result<BitmapWebp, ScreencapError> {
+    let payload = match opt.capture_backend {
+        CaptureBackend::Webp => helper_payload(device, opt, services).await?,
+        CaptureBackend::Raw => device::run_async(&mut screencap_command(device, opt, &[]), opt, &services.adb_log).await?.stdout,
+        CaptureBackend::Png => device::run_async(&mut screencap_command(device, opt, &["-p"]), opt, &services.adb_log).await?.stdout,
+    };
+    tokio::task::block_in_place(||match opt.capture_backend {
+        CaptureBackend::Webp => image::load_from_memory_with_format(&payload, image::ImageFormat::WebP)
+            .map(|image|BitmapWebp::from_image(image, 2, opt, services))
+            .map_err(|_|ScreencapError::Failed),
+        CaptureBackend::Raw | CaptureBackend::Png => load_bitmap(&payload)
+            .map(|image|BitmapWebp::from_image(image, 1, opt, services))
+            .map_err(|err|err.into()),
+    })
 }
 
-pub fn screencap(device:&str, opt:&Opt) -> Result<DynamicImage, ScreencapError> {
+fn screencap_command(device:&str, opt:&Opt, args:&[&str]) -> tokio::process::Command {
     if opt.local {
-        //screencap_framebuffer(device, opt)
-        let output = Command::new("screencap")
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped())
-        .spawn()?.wait_with_output()?;
-        if output.status.success() {
-            return load_bitmap(&output.stdout).map_err(|err|err.into());
-        }
+        let mut command = tokio::process::Command::new("screencap");
+        command.args(args);
+        command
     }
     else {
-        let output = Command::new("adb").arg("-s").arg(device).arg("exec-out").arg("screencap")
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped())
-        .spawn()?.wait_with_output()?;
-        if output.status.success() {
-            return load_bitmap(&output.stdout).map_err(|err|err.into());
+        let mut command = tokio::process::Command::new("adb");
+        command.arg("-s").arg(device).arg("exec-out").arg("screencap").args(args);
+        command
+    }
+}
+
+fn helper_screencap(device:&str, opt:&Opt, services:&Services) -> Result<Vec<u8>, ScreencapError> {
+    runtime::block_on(helper_payload(device, opt, services))
+}
+
+async fn helper_payload(device:&str, opt:&Opt, services:&Services) -> Result<Vec<u8>, ScreencapError> {
+    let mut attempt = 0;
+    loop {
+        let output = device::run_async(tokio::process::Command::new("adb").arg("-s").arg(device).arg("exec-out").arg("sh").arg("-c").arg("cd /data/local/tmp/ && ./endorbot --local --screencap"), opt, &services.adb_log).await?;
+        match transfer::read_framed(&output.stdout) {
+            Ok(payload) => return Ok(payload.to_vec()),
+            Err(err) if attempt >= opt.adb_retries => return Err(err.into()),
+            Err(err) => {
+                println!("Corrupted frame from screencap helper: {err}, requesting it again");
+                attempt += 1;
+            },
         }
     }
-    Err(ScreencapError::Failed)
 }
 
-pub fn screencap_framebuffer(device:&str, opt:&Opt) -> Result<DynamicImage, ScreencapError> {
-    fn read_fb0_rgba(data:&Vec<u8>) -> Result<DynamicImage, ScreencapError> {
+pub fn screencap(device:&str, opt:&Opt, services:&Services) -> Result<DynamicImage, ScreencapError> {
+    if opt.local {
+        //screencap_framebuffer(device, opt, services)
+        let output = device::run(&mut Command::new("screencap"), opt, &services.adb_log)?;
+        load_bitmap(&output.stdout).map_err(|err|err.into())
+    }
+    else {
+        let output = device::run(Command::new("adb").arg("-s").arg(device).arg("exec-out").arg("screencap"), opt, &services.adb_log)?;
+        load_bitmap(&output.stdout).map_err(|err|err.into())
+    }
+}
+
+#[allow(dead_code)]
+pub fn screencap_framebuffer(device:&str, opt:&Opt, services:&Services) -> Result<DynamicImage, ScreencapError> {
+    fn read_fb0_rgba(data:&[u8]) -> Result<DynamicImage, ScreencapError> {
         let width = 1080usize;
         let height = 2408usize;
         let stride_pixels = 1088usize;
         let bpp = 4usize; // RGBA_8888
         let stride_bytes = stride_pixels * bpp;
         let row_bytes = width * bpp;
-        let expected = stride_bytes * height;
 
         let mut pixels = Vec::with_capacity(row_bytes * height);
         for y in 0..height {
@@ -378,17 +487,79 @@ pub fn screencap_framebuffer(device:&str, opt:&Opt) -> Result<DynamicImage, Scre
 
     if opt.local {
         let output = std::fs::read("/dev/graphics/fb0")?;
-        return read_fb0_rgba(&output).map_err(|err|err.into())
+        read_fb0_rgba(&output)
     }
     else {
-        let output = Command::new("adb").arg("-s").arg(device).arg("exec-out").arg("su").arg("-c").arg("cat").arg("/dev/graphics/fb0")
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped())
-        .spawn()?.wait_with_output()?;
-        if output.status.success() {
-            return read_fb0_rgba(&output.stdout).map_err(|err|err.into())
+        let output = device::run(Command::new("adb").arg("-s").arg(device).arg("exec-out").arg("su").arg("-c").arg("cat").arg("/dev/graphics/fb0"), opt, &services.adb_log)?;
+        read_fb0_rgba(&output.stdout)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIXELS:[u8; 8] = [10, 20, 30, 40, 50, 60, 70, 80];
+
+    fn raw(header:&[u8], pixels:&[u8]) -> Vec<u8> {
+        [header, pixels].concat()
+    }
+
+    #[test]
+    fn loads_header_with_colorspace() {
+        // 2x1, RGBA_8888, colorspace sRGB
+        let header = [2, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0];
+        let image = load_raw(&raw(&header, &PIXELS)).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (2, 1));
+        assert_eq!(image.as_raw().as_slice(), &PIXELS);
+    }
+
+    #[test]
+    fn loads_legacy_header() {
+        // 1x2, RGBA_8888, no colorspace word
+        let header = [1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0];
+        let image = load_raw(&raw(&header, &PIXELS)).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (1, 2));
+        assert_eq!(image.as_raw().as_slice(), &PIXELS);
+    }
+
+    #[test]
+    fn ignores_trailing_padding() {
+        let header = [2, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0];
+        let image = load_raw(&raw(&header, &[&PIXELS[..], &[0; 5]].concat())).unwrap().to_rgba8();
+        assert_eq!(image.as_raw().as_slice(), &PIXELS);
+    }
+
+    #[test]
+    fn forces_rgbx_alpha() {
+        let header = [2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0];
+        let image = load_raw(&raw(&header, &PIXELS)).unwrap().to_rgba8();
+        assert_eq!(image.as_raw().as_slice(), &[10, 20, 30, 255, 50, 60, 70, 255]);
+    }
+
+    #[test]
+    fn swaps_bgra_channels() {
+        let header = [2, 0, 0, 0, 1, 0, 0, 0, 5, 0, 0, 0];
+        let image = load_raw(&raw(&header, &PIXELS)).unwrap().to_rgba8();
+        assert_eq!(image.as_raw().as_slice(), &[30, 20, 10, 40, 70, 60, 50, 80]);
+    }
+
+    #[test]
+    fn rejects_broken_headers() {
+        let truncated = raw(&[0x38, 0x04, 0, 0, 0x68, 0x09, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0], &PIXELS);
+        let huge = raw(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 1, 0, 0, 0], &PIXELS);
+        let empty = raw(&[0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0], &PIXELS);
+        let unknown_format = raw(&[2, 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0], &PIXELS);
+        let short = [2, 0, 0, 0, 1, 0, 0];
+        for input in [&truncated[..], &huge, &empty, &unknown_format, &short, &[]] {
+            assert!(matches!(load_raw(input), Err(LoadBitmapError::InvalidRawHeader(len)) if len == input.len()));
+            assert!(load_bitmap(input).is_err());
         }
-    };
-    Err(ScreencapError::Failed)
-}
\ No newline at end of file
+    }
+
+    #[test]
+    fn falls_back_to_raw_after_bmp() {
+        let header = [2, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0];
+        let image = load_bitmap(&raw(&header, &PIXELS)).unwrap().to_rgba8();
+        assert_eq!(image.as_raw().as_slice(), &PIXELS);
+    }
+}
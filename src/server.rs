@@ -0,0 +1,409 @@
+use std::{collections::BTreeMap, convert::Infallible, pin::Pin, sync::Arc, task::{Context as TaskContext, Poll}};
+
+use bytes::Bytes;
+use http_body::Frame;
+use http_body_util::{BodyExt, Full, Limited, combinators::BoxBody};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use parking_lot::Mutex;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{net::TcpListener, sync::{mpsc, watch}};
+
+use crate::{ActionLog, Opt, Services, atlas::TileStats, control::{Control, ManualAction, TileCommand}, daemon::Heartbeat, frames::{self, LatestFrame}, input, journal::Journal, mapview, metrics::Metrics, ml::{Action, Coords, State, StateView, TileEdit}, runtime, schedule::{ScheduleOverride, ScheduleStatus}};
+
+type Body = BoxBody<Bytes, std::io::Error>;
+type Request = http::Request<Bytes>;
+type Response = http::Response<Body>;
+
+pub struct Context {
+    pub ws_port: u16,
+    pub token: Option<String>,
+    pub state: watch::Receiver<Arc<State>>,
+    pub control: Arc<Control>,
+    pub frames: Arc<LatestFrame>,
+    pub log: Arc<ActionLog>,
+    pub metrics: Arc<Metrics>,
+    pub journal: Arc<Journal>,
+    pub tile_stats: Arc<Mutex<BTreeMap<String, Vec<TileStats>>>>,
+    pub heartbeat: Arc<Heartbeat>,
+    pub opt: Opt,
+    pub services: Services,
+}
+
+#[derive(Serialize)]
+struct DataView<'a> {
+    #[serde(flatten)]
+    state: StateView<'a>,
+    schedule: ScheduleStatus,
+    tile_stats: BTreeMap<String, Vec<TileStats>>,
+}
+
+pub fn spawn(addr:&str, context:Context) {
+    let addr = addr.to_owned();
+    let context = Arc::new(context);
+    runtime::get().spawn(async move {
+        let listener = match TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                println!("Failed to bind web server to {addr}: {err}");
+                return;
+            },
+        };
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        println!("Failed to accept connection: {err}");
+                        continue;
+                    },
+                },
+                _ = context.control.shutdown_requested() => break,
+            };
+            let context = context.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move|req|serve(req, context.clone()));
+                let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+            });
+        }
+    });
+}
+
+async fn serve(req:http::Request<Incoming>, context:Arc<Context>) -> Result<Response, Infallible> {
+    let (parts, body) = req.into_parts();
+    let body = match Limited::new(body, MAX_BODY).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(err) => return Ok(bad_request(format!("Failed to read body: {err}"))),
+    };
+    let req = Request::from_parts(parts, body);
+    Ok(handle(req, context).await)
+}
+
+/// Runs image encoding and device input off the I/O threads.
+async fn blocking(work:impl FnOnce() -> Response + Send + 'static) -> Response {
+    tokio::task::spawn_blocking(work).await.unwrap_or_else(|_|status_response(500, "Internal error"))
+}
+
+fn full(data:impl Into<Bytes>) -> Body {
+    Full::new(data.into()).map_err(|never|match never {}).boxed()
+}
+
+struct ChannelBody {
+    parts: mpsc::Receiver<std::io::Result<Bytes>>,
+}
+impl http_body::Body for ChannelBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(mut self:Pin<&mut Self>, cx:&mut TaskContext<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        self.parts.poll_recv(cx).map(|part|part.map(|part|part.map(Frame::data)))
+    }
+}
+
+/// Streams every new frame as MJPEG until the client goes away or the bot shuts down.
+fn live_body(frames:&LatestFrame, control:Arc<Control>) -> Body {
+    let mut updates = frames.subscribe();
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                changed = updates.changed() => if changed.is_err() {
+                    break;
+                },
+                _ = sender.closed() => break,
+                _ = control.shutdown_requested() => break,
+            }
+            let Some((_, image)) = updates.borrow_and_update().clone() else {
+                continue;
+            };
+            let part = tokio::task::spawn_blocking(move||frames::mjpeg_part(&image)).await
+            .map_err(std::io::Error::other)
+            .and_then(|part|part.map(Bytes::from).map_err(std::io::Error::other));
+            let failed = part.is_err();
+            if sender.send(part).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    ChannelBody { parts: receiver }.boxed()
+}
+
+fn json_response(value:&impl Serialize) -> Response {
+    http::Response::builder()
+    .header("Content-Type", "application/json")
+    .body(full(serde_json::to_string(value).unwrap()))
+    .unwrap()
+}
+
+fn status_response(status:u16, message:&'static str) -> Response {
+    http::Response::builder()
+    .status(status)
+    .body(full(message))
+    .unwrap()
+}
+
+pub fn token_matches(expected:&str, given:&str) -> bool {
+    expected.len() == given.len() && expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)|diff | (a ^ b)) == 0
+}
+
+pub fn query_token(query:Option<&str>) -> Option<&str> {
+    query?.split('&').find_map(|pair|pair.strip_prefix("token="))
+}
+
+fn authorized(req:&Request, token:&str) -> bool {
+    let bearer = req.headers().get("Authorization")
+    .and_then(|value|value.to_str().ok())
+    .and_then(|value|value.strip_prefix("Bearer "));
+    bearer.or_else(||query_token(req.uri().query())).is_some_and(|given|token_matches(token, given))
+}
+
+async fn map_response(file:&str, context:&Context) -> Response {
+    let (floor, svg) = if let Some(floor) = file.strip_suffix(".svg") {
+        (floor, true)
+    }
+    else if let Some(floor) = file.strip_suffix(".png") {
+        (floor, false)
+    }
+    else {
+        return status_response(404, "Not found");
+    };
+    let snapshot = context.state.borrow().clone();
+    let floor = floor.to_owned();
+    blocking(move||{
+        let Some((tiles, position)) = snapshot.dungeon.floor_map(&floor) else {
+            return status_response(404, "Unknown floor");
+        };
+        if svg {
+            return http::Response::builder()
+            .header("Content-Type", "image/svg+xml")
+            .header("Cache-Control", "no-store")
+            .body(full(mapview::render_svg(tiles, position)))
+            .unwrap();
+        }
+        match mapview::render_png(tiles, position) {
+            Ok(png) => {
+                http::Response::builder()
+                .header("Content-Type", "image/png")
+                .header("Cache-Control", "no-store")
+                .body(full(png))
+                .unwrap()
+            },
+            Err(err) => {
+                println!("Failed to encode map: {err}");
+                status_response(500, "Failed to encode map")
+            },
+        }
+    }).await
+}
+
+const MAX_BODY:usize = 64 * 1024;
+
+fn read_json<T:DeserializeOwned>(req:&Request) -> Result<T, String> {
+    serde_json::from_slice(req.body()).map_err(|err|format!("Invalid request: {err}"))
+}
+
+fn bad_request(message:String) -> Response {
+    http::Response::builder()
+    .status(400)
+    .body(full(message))
+    .unwrap()
+}
+
+#[derive(serde::Deserialize)]
+struct Tap {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+fn tap_response(req:&Request, context:&Context) -> Response {
+    let tap = match read_json::<Tap>(req) {
+        Ok(tap) => tap,
+        Err(message) => return bad_request(message),
+    };
+    if context.opt.no_action {
+        return status_response(409, "Actions are disabled");
+    }
+    let Some((_, image)) = context.frames.latest() else {
+        return status_response(404, "No frame captured yet");
+    };
+    if tap.width <= 0.0 || tap.height <= 0.0 || !(0.0..=tap.width).contains(&tap.x) || !(0.0..=tap.height).contains(&tap.y) {
+        return bad_request(format!("Tap at {}x{} outside {}x{}", tap.x, tap.y, tap.width, tap.height));
+    }
+    let x = ((tap.x / tap.width * image.width() as f32) as u32).min(image.width() - 1);
+    let y = ((tap.y / tap.height * image.height() as f32) as u32).min(image.height() - 1);
+    println!("Manual tap at {x}x{y}");
+    input::tap_at(&context.opt.device, &context.opt, &context.services, x, y);
+    json_response(&Coords { x, y })
+}
+
+#[derive(serde::Deserialize)]
+struct ClearTile {
+    #[serde(default)]
+    floor: Option<String>,
+    position: Coords,
+}
+
+fn tiles_response(command:&str, req:Request, context:&Context) -> Response {
+    if req.method() != "POST" {
+        return status_response(405, "Method not allowed");
+    }
+    let response = match command {
+        "edit" => {
+            let edit = match read_json::<TileEdit>(&req) {
+                Ok(edit) => edit,
+                Err(message) => return bad_request(message),
+            };
+            let mut dungeon = context.state.borrow().dungeon.clone();
+            match dungeon.edit_tile(edit.clone()) {
+                Some(tile) => {
+                    context.control.queue_tile_command(TileCommand::Edit(edit));
+                    json_response(&tile)
+                },
+                None => return status_response(404, "Unknown tile"),
+            }
+        },
+        "clear" => {
+            let clear = match read_json::<ClearTile>(&req) {
+                Ok(clear) => clear,
+                Err(message) => return bad_request(message),
+            };
+            let mut dungeon = context.state.borrow().dungeon.clone();
+            if !dungeon.clear_tile(clear.floor.clone(), clear.position) {
+                return status_response(404, "Unknown tile");
+            }
+            context.control.queue_tile_command(TileCommand::Clear(clear.floor, clear.position));
+            json_response(&clear.position)
+        },
+        _ => return status_response(404, "Not found"),
+    };
+    context.control.request_save();
+    response
+}
+
+async fn handle(req:Request, context:Arc<Context>) -> Response {
+    let control = &context.control;
+    if control.is_shutdown() {
+        return status_response(503, "Shutting down");
+    }
+    let path = req.uri().path().to_owned();
+    if path != "/" && path != "/health"
+        && let Some(token) = &context.token
+        && !authorized(&req, token) {
+        return http::Response::builder()
+        .status(401)
+        .header("WWW-Authenticate", "Bearer")
+        .body(full("Unauthorized"))
+        .unwrap();
+    }
+    if let Some(command) = path.strip_prefix("/control/") {
+        if req.method() != "POST" {
+            return status_response(405, "Method not allowed");
+        }
+        match command {
+            "pause" => control.pause(),
+            "resume" => control.resume(),
+            "step" => control.step(),
+            "status" => {},
+            "tap" => {
+                let context = context.clone();
+                return blocking(move||tap_response(&req, &context)).await;
+            },
+            "action" => match read_json::<ManualAction>(&req) {
+                Ok(action) => control.queue_action(action),
+                Err(message) => return bad_request(message),
+            },
+            "schedule" => match read_json::<ScheduleOverride>(&req) {
+                Ok(mode) => control.override_schedule(mode),
+                Err(message) => return bad_request(message),
+            },
+            command if command.starts_with("macro/") => match context.opt.policy.macro_index(&command["macro/".len()..]) {
+                Some(index) => control.queue_action(ManualAction::Action(Action::StartMacro(index))),
+                None => return status_response(404, "Unknown macro"),
+            },
+            _ => return status_response(404, "Not found"),
+        }
+        return json_response(&control.status());
+    }
+    if let Some(command) = path.strip_prefix("/tiles/") {
+        return tiles_response(command, req, &context);
+    }
+    if let Some(file) = path.strip_prefix("/map/") {
+        return map_response(file, &context).await;
+    }
+    match path.as_str() {
+        "/data" => {
+            let snapshot = context.state.borrow().clone();
+            json_response(&DataView { state: snapshot.view(), schedule: control.schedule(), tile_stats: context.tile_stats.lock().clone() })
+        },
+        "/log" => {
+            json_response(&context.log.entries())
+        },
+        "/trace" => match context.log.last_trace() {
+            Some(trace) => json_response(&trace),
+            None => status_response(404, "No decision traced yet"),
+        },
+        "/trace.dot" => match context.log.last_trace() {
+            Some(trace) => http::Response::builder()
+            .header("Content-Type", "text/vnd.graphviz")
+            .body(full(trace.to_dot()))
+            .unwrap(),
+            None => status_response(404, "No decision traced yet"),
+        },
+        "/adb-log" => {
+            json_response(&context.services.adb_log.entries())
+        },
+        "/stats" => {
+            json_response(&context.journal.stats())
+        },
+        "/runs" => {
+            json_response(&context.journal.records())
+        },
+        "/health" => {
+            let health = context.heartbeat.health(std::time::Duration::from_secs(context.opt.health_stale_secs));
+            http::Response::builder()
+            .status(if health.healthy { 200 } else { 503 })
+            .header("Content-Type", "application/json")
+            .body(full(serde_json::to_string(&health).unwrap()))
+            .unwrap()
+        },
+        "/metrics" => {
+            http::Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(full(context.metrics.render()))
+            .unwrap()
+        },
+        "/screenshot" | "/cap.png" => {
+            let Some((_, image)) = context.frames.latest() else {
+                return status_response(404, "No frame captured yet");
+            };
+            blocking(move||match frames::encode_png(&image) {
+                Ok(png) => {
+                    http::Response::builder()
+                    .header("Content-Type", "image/png")
+                    .header("Cache-Control", "no-store")
+                    .body(full(png))
+                    .unwrap()
+                },
+                Err(err) => {
+                    println!("Failed to encode screenshot: {err}");
+                    status_response(500, "Failed to encode screenshot")
+                },
+            }).await
+        },
+        "/live" => {
+            http::Response::builder()
+            .header("Content-Type", format!("multipart/x-mixed-replace; boundary={}", frames::MJPEG_BOUNDARY))
+            .header("Cache-Control", "no-store")
+            .body(live_body(&context.frames, control.clone()))
+            .unwrap()
+        },
+        _ => {
+            http::Response::builder()
+            .header("Content-Type", "text/html")
+            .body(full(include_str!("index.html").replace("{{ws_port}}", &context.ws_port.to_string())))
+            .unwrap()
+        },
+    }
+}
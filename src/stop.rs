@@ -0,0 +1,114 @@
+use std::{fmt::Display, time::{Duration, Instant}};
+
+use clap::Args;
+
+use crate::ml::State;
+
+pub const PARK_TIMEOUT:Duration = Duration::from_secs(600);
+
+#[derive(Args, Debug, Clone)]
+pub struct StopConditions {
+    #[clap(id = "stop_after_minutes", long = "stop-after-minutes")]
+    pub after_minutes: Option<u64>,
+    #[clap(id = "stop_after_deaths", long = "stop-after-deaths")]
+    pub deaths: Option<u32>,
+    #[clap(id = "stop_after_gold", long = "stop-after-gold")]
+    pub gold: Option<u32>,
+    #[clap(id = "stop_at_floor", long = "stop-at-floor")]
+    pub floor: Option<u32>,
+    #[clap(id = "stop_after_errors", long = "stop-after-errors")]
+    pub errors: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Duration(Duration),
+    Deaths(u32),
+    Gold(u32),
+    Floor(u32),
+    Errors(u32),
+}
+impl StopReason {
+    pub fn needs_parking(&self) -> bool {
+        !matches!(self, StopReason::Errors(_))
+    }
+}
+impl Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::Duration(duration) => write!(f, "ran for {} minutes", duration.as_secs() / 60),
+            StopReason::Deaths(deaths) => write!(f, "{deaths} character deaths"),
+            StopReason::Gold(gold) => write!(f, "earned {gold} gold"),
+            StopReason::Floor(floor) => write!(f, "reached floor {floor}"),
+            StopReason::Errors(errors) => write!(f, "{errors} failed ticks in a row"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct StopWatch {
+    conditions: StopConditions,
+    started: Instant,
+    starting_gold: Option<u32>,
+    deaths: u32,
+    errors: u32,
+}
+impl StopWatch {
+    pub fn new(conditions:&StopConditions) -> Self {
+        Self {
+            conditions: conditions.clone(),
+            started: Instant::now(),
+            starting_gold: None,
+            deaths: 0,
+            errors: 0,
+        }
+    }
+
+    pub fn error(&mut self) -> Option<StopReason> {
+        self.errors += 1;
+        self.conditions.errors.filter(|limit|self.errors >= *limit).map(StopReason::Errors)
+    }
+
+    pub fn observe(&mut self, previous:&State, state:&State) {
+        self.errors = 0;
+        self.deaths += state.dungeon.dead_characters().saturating_sub(previous.dungeon.dead_characters()) as u32;
+        if self.starting_gold.is_none() {
+            self.starting_gold = state.resources.current().gold;
+        }
+    }
+
+    pub fn earned(&self, state:&State) -> Option<u32> {
+        Some(state.resources.current().gold?.saturating_sub(self.starting_gold?))
+    }
+
+    pub fn check(&self, state:&State) -> Option<StopReason> {
+        let elapsed = self.started.elapsed();
+        if let Some(minutes) = self.conditions.after_minutes
+            && elapsed.as_secs() >= minutes * 60 {
+            return Some(StopReason::Duration(elapsed));
+        }
+        if let Some(limit) = self.conditions.deaths
+            && self.deaths >= limit {
+            return Some(StopReason::Deaths(self.deaths));
+        }
+        if let Some(target) = self.conditions.gold
+            && let Some(earned) = self.earned(state)
+            && earned >= target {
+            return Some(StopReason::Gold(earned));
+        }
+        if let Some(target) = self.conditions.floor
+            && let Some(floor) = state.dungeon.floor_number()
+            && floor >= target {
+            return Some(StopReason::Floor(floor));
+        }
+        None
+    }
+
+    pub fn summary(&self, state:&State, reason:&StopReason) -> String {
+        format!("Run stopped, {reason}. Runtime {} minutes, {} deaths, {} gold earned, last floor {}",
+            self.started.elapsed().as_secs() / 60,
+            self.deaths,
+            self.earned(state).map(|gold|gold.to_string()).unwrap_or_else(||"unknown".to_owned()),
+            state.dungeon.floor_name())
+    }
+}
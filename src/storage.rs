@@ -0,0 +1,389 @@
+use std::{ffi::OsString, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use serde::Serialize;
+
+use crate::{atlas::Atlas, ml::{Action, State}};
+
+pub const STATE_VERSION:u64 = 1;
+const BACKUP_INTERVAL:Duration = Duration::from_secs(300);
+
+#[derive(Serialize)]
+struct VersionedState<'a> {
+    version: u64,
+    state: &'a State,
+}
+
+fn encode_state(state:&State) -> Result<String, StorageError> {
+    Ok(serde_json::to_string(&VersionedState { version: STATE_VERSION, state })?)
+}
+
+fn decode_state(data:&str) -> Result<State, StorageError> {
+    let mut value = serde_json::from_str::<serde_json::Value>(data)?;
+    let version = value.get("version").and_then(|version|version.as_u64());
+    match version {
+        Some(version) => {
+            let state = value.get_mut("state").map(serde_json::Value::take).unwrap_or_default();
+            migrate_state(version, state)
+        },
+        None => migrate_state(0, value),
+    }
+}
+
+fn migrate_state(version:u64, state:serde_json::Value) -> Result<State, StorageError> {
+    match version {
+        // Version 0 is the bare State written before the file was versioned, same layout as version 1.
+        0 | 1 => Ok(serde_json::from_value(state)?),
+        _ => Err(StorageError::UnsupportedVersion(version)),
+    }
+}
+
+fn suffixed(path:&Path, suffix:&str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    name.into()
+}
+
+fn write_atomic(path:&Path, data:&str) -> std::io::Result<()> {
+    let tmp = suffixed(path, ".tmp");
+    {
+        use std::io::Write;
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(data.as_bytes())?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp, path)
+}
+
+fn rotate_backups(path:&Path, backups:usize) -> std::io::Result<()> {
+    if backups == 0 || !path.exists() {
+        return Ok(());
+    }
+    for i in (1..backups).rev() {
+        let from = suffixed(path, &format!(".{i}"));
+        if from.exists() {
+            std::fs::rename(&from, suffixed(path, &format!(".{}", i + 1)))?;
+        }
+    }
+    std::fs::copy(path, suffixed(path, ".1"))?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    UnsupportedScheme(String),
+    UnsupportedVersion(u64),
+    IoError(std::io::Error),
+    JsonError(serde_json::Error),
+    #[cfg(feature = "sqlite")]
+    SqliteError(rusqlite::Error),
+}
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedScheme(scheme) => write!(f, "unsupported storage scheme {scheme}"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported state version {version}, newer than {STATE_VERSION}"),
+            Self::IoError(err) => write!(f, "io error: {err}"),
+            Self::JsonError(err) => write!(f, "json error: {err}"),
+            #[cfg(feature = "sqlite")]
+            Self::SqliteError(err) => write!(f, "sqlite error: {err}"),
+        }
+    }
+}
+impl std::error::Error for StorageError {}
+impl From<std::io::Error> for StorageError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+impl From<serde_json::Error> for StorageError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::JsonError(value)
+    }
+}
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for StorageError {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::SqliteError(value)
+    }
+}
+
+pub enum Storage {
+    File {
+        state: PathBuf,
+        atlas: PathBuf,
+        backups: usize,
+        last_backup: Option<Instant>,
+    },
+    #[cfg(feature = "sqlite")]
+    Sqlite(sqlite::SqliteStorage),
+}
+impl Storage {
+    pub fn open(url:&str, atlas:&Path, backups:usize) -> Result<Self, StorageError> {
+        let file = |path:&str|Storage::File { state: path.into(), atlas: atlas.to_owned(), backups, last_backup: None };
+        match url.split_once("://") {
+            None => Ok(file(url)),
+            Some(("file", path)) => Ok(file(path)),
+            #[cfg(feature = "sqlite")]
+            Some(("sqlite", path)) => Ok(Storage::Sqlite(sqlite::SqliteStorage::open(Path::new(path))?)),
+            Some((scheme, _)) => Err(StorageError::UnsupportedScheme(scheme.to_owned())),
+        }
+    }
+
+    pub fn load_state(&self) -> Result<Option<State>, StorageError> {
+        match self {
+            Storage::File { state, backups, .. } => {
+                let candidates = std::iter::once(state.clone()).chain((1..=*backups).map(|i|suffixed(state, &format!(".{i}"))));
+                let mut first_error = None;
+                for path in candidates {
+                    let data = match std::fs::read_to_string(&path) {
+                        Ok(data) => data,
+                        Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                        Err(err) => {
+                            println!("Failed to read {}: {err}", path.display());
+                            first_error.get_or_insert(err.into());
+                            continue;
+                        },
+                    };
+                    match decode_state(&data) {
+                        Ok(loaded) => {
+                            if &path != state {
+                                println!("Recovered state from backup {}", path.display());
+                            }
+                            return Ok(Some(loaded));
+                        },
+                        Err(err) => {
+                            println!("Failed to load {}: {err}", path.display());
+                            first_error.get_or_insert(err);
+                        },
+                    }
+                }
+                match first_error {
+                    Some(err) => Err(err),
+                    None => Ok(None),
+                }
+            },
+            #[cfg(feature = "sqlite")]
+            Storage::Sqlite(storage) => storage.load_state(),
+        }
+    }
+
+    pub fn save_state(&mut self, state:&State) -> Result<(), StorageError> {
+        match self {
+            Storage::File { state: path, backups, last_backup, .. } => {
+                if last_backup.is_none_or(|last_backup|last_backup.elapsed() >= BACKUP_INTERVAL) {
+                    rotate_backups(path, *backups)?;
+                    *last_backup = Some(Instant::now());
+                }
+                write_atomic(path, &encode_state(state)?)?;
+                Ok(())
+            },
+            #[cfg(feature = "sqlite")]
+            Storage::Sqlite(storage) => storage.save_state(state),
+        }
+    }
+
+    pub fn load_atlas(&self) -> Result<Atlas, StorageError> {
+        match self {
+            Storage::File { atlas, .. } => Ok(Atlas::load(atlas)),
+            #[cfg(feature = "sqlite")]
+            Storage::Sqlite(storage) => storage.load_atlas(),
+        }
+    }
+
+    pub fn save_atlas(&mut self, atlas:&mut Atlas) -> Result<(), StorageError> {
+        match self {
+            Storage::File { atlas: path, .. } => Ok(atlas.save(path)?),
+            #[cfg(feature = "sqlite")]
+            Storage::Sqlite(storage) => storage.save_atlas(atlas),
+        }
+    }
+
+    pub fn record_action(&mut self, state:&State, action:&Action) -> Result<(), StorageError> {
+        match self {
+            Storage::File { .. } => {
+                let _ = (state, action);
+                Ok(())
+            },
+            #[cfg(feature = "sqlite")]
+            Storage::Sqlite(storage) => storage.record_action(state, action),
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::{collections::BTreeMap, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+    use rusqlite::{Connection, OptionalExtension, params};
+
+    use crate::{atlas::{Atlas, AtlasFloor}, ml::{Action, State}};
+
+    use super::{StorageError, decode_state, encode_state};
+
+    const SNAPSHOTS_KEPT:i64 = 100;
+
+    pub struct SqliteStorage {
+        connection: Connection,
+    }
+    impl SqliteStorage {
+        pub fn open(path:&Path) -> Result<Self, StorageError> {
+            let connection = Connection::open(path)?;
+            connection.execute_batch("
+                PRAGMA journal_mode = WAL;
+                PRAGMA synchronous = NORMAL;
+                CREATE TABLE IF NOT EXISTS state_snapshots (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    timestamp INTEGER NOT NULL,
+                    state TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS atlas_floors (
+                    floor TEXT PRIMARY KEY,
+                    data TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS actions (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    timestamp INTEGER NOT NULL,
+                    state_type TEXT NOT NULL,
+                    action TEXT NOT NULL,
+                    floor TEXT,
+                    x INTEGER,
+                    y INTEGER
+                );
+            ")?;
+            Ok(Self { connection })
+        }
+
+        pub fn load_state(&self) -> Result<Option<State>, StorageError> {
+            let data = self.connection.query_row(
+                "SELECT state FROM state_snapshots ORDER BY id DESC LIMIT 1",
+                [],
+                |row|row.get::<_, String>(0),
+            ).optional()?;
+            data.map(|data|decode_state(&data)).transpose()
+        }
+
+        pub fn save_state(&mut self, state:&State) -> Result<(), StorageError> {
+            let data = encode_state(state)?;
+            let transaction = self.connection.transaction()?;
+            transaction.execute("INSERT INTO state_snapshots (timestamp, state) VALUES (?1, ?2)", params![timestamp(), data])?;
+            let id = transaction.last_insert_rowid();
+            transaction.execute("DELETE FROM state_snapshots WHERE id <= ?1", params![id - SNAPSHOTS_KEPT])?;
+            transaction.commit()?;
+            Ok(())
+        }
+
+        pub fn load_atlas(&self) -> Result<Atlas, StorageError> {
+            let mut statement = self.connection.prepare("SELECT floor, data FROM atlas_floors")?;
+            let rows = statement.query_map([], |row|Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+            let mut floors = BTreeMap::new();
+            for row in rows {
+                let (floor, data) = row?;
+                floors.insert(floor, serde_json::from_str::<AtlasFloor>(&data)?);
+            }
+            Ok(Atlas::from_floors(floors))
+        }
+
+        pub fn save_atlas(&mut self, atlas:&mut Atlas) -> Result<(), StorageError> {
+            let transaction = self.connection.transaction()?;
+            for (floor, tiles) in atlas.floors() {
+                transaction.execute(
+                    "INSERT INTO atlas_floors (floor, data) VALUES (?1, ?2) ON CONFLICT(floor) DO UPDATE SET data = excluded.data",
+                    params![floor, serde_json::to_string(tiles)?],
+                )?;
+            }
+            transaction.commit()?;
+            atlas.mark_saved();
+            Ok(())
+        }
+
+        pub fn record_action(&mut self, state:&State, action:&Action) -> Result<(), StorageError> {
+            let position = state.get_position();
+            self.connection.execute(
+                "INSERT INTO actions (timestamp, state_type, action, floor, x, y) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    timestamp(),
+                    state.state_type.name(),
+                    action.to_string(),
+                    state.dungeon.floor_name(),
+                    position.map(|pos|pos.x),
+                    position.map(|pos|pos.y),
+                ],
+            )?;
+            Ok(())
+        }
+    }
+
+    fn timestamp() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ml::StateType;
+
+    fn city() -> State {
+        State { state_type: StateType::City(false), ..State::default() }
+    }
+
+    fn scratch(name:&str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("endorbot-storage-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn decodes_current_version() {
+        let state = decode_state(&encode_state(&city()).unwrap()).unwrap();
+        assert_eq!(state.state_type, StateType::City(false));
+    }
+
+    #[test]
+    fn decodes_unversioned_state() {
+        let state = decode_state(&serde_json::to_string(&city()).unwrap()).unwrap();
+        assert_eq!(state.state_type, StateType::City(false));
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let state = serde_json::to_value(city()).unwrap();
+        let data = serde_json::json!({ "version": STATE_VERSION + 1, "state": state }).to_string();
+        assert!(matches!(decode_state(&data), Err(StorageError::UnsupportedVersion(2))));
+        assert!(matches!(decode_state("{"), Err(StorageError::JsonError(_))));
+    }
+
+    #[test]
+    fn falls_back_to_backups() {
+        let dir = scratch("backups");
+        let path = dir.join("state.json");
+        let storage = Storage::open(path.to_str().unwrap(), &dir.join("atlas.json"), 2).unwrap();
+        assert!(storage.load_state().unwrap().is_none());
+
+        std::fs::write(&path, "{").unwrap();
+        std::fs::write(suffixed(&path, ".1"), "{\"version\": 9, \"state\": {}}").unwrap();
+        std::fs::write(suffixed(&path, ".2"), encode_state(&city()).unwrap()).unwrap();
+        assert_eq!(storage.load_state().unwrap().unwrap().state_type, StateType::City(false));
+
+        std::fs::remove_file(suffixed(&path, ".2")).unwrap();
+        assert!(matches!(storage.load_state(), Err(StorageError::JsonError(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_backups_on_save() {
+        let dir = scratch("rotate");
+        let path = dir.join("state.json");
+        let mut storage = Storage::open(path.to_str().unwrap(), &dir.join("atlas.json"), 2).unwrap();
+        storage.save_state(&State::default()).unwrap();
+        assert!(!suffixed(&path, ".1").exists());
+        if let Storage::File { last_backup, .. } = &mut storage {
+            *last_backup = None;
+        }
+        storage.save_state(&city()).unwrap();
+        assert!(suffixed(&path, ".1").exists());
+        assert_eq!(storage.load_state().unwrap().unwrap().state_type, StateType::City(false));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt::Display;
+
+use serde::Deserialize;
+
+use crate::{ml::{self, Action, Coords, Dungeon, State}, policy::Policy, trace::DecisionTrace};
+
+/// Decides what to do on each frame.
+///
+/// This is the pluggable decision policy, named `Strategy` because [`Policy`] already holds the
+/// tunable settings. [`ml::determine_action`] handles every screen and asks the hooks below where
+/// to go while exploring, so a new strategy overrides the hooks or [`decide`](Strategy::decide).
+pub trait Strategy {
+    /// The settings the shared decision logic reads.
+    fn policy(&self) -> &Policy;
+
+    /// Whether to head for the stairs and take them down.
+    fn may_descend(&self, dungeon:&Dungeon) -> bool {
+        self.policy().may_descend(dungeon)
+    }
+
+    /// Whether to walk to the closest visible chest.
+    fn seek_chests(&self, _dungeon:&Dungeon) -> bool {
+        self.policy().seek_chests
+    }
+
+    /// Whether to open a chest the party stands on.
+    fn open_chests(&self, _dungeon:&Dungeon) -> bool {
+        self.policy().prioritize_chests
+    }
+
+    fn decide(&self, state:&State, last:Action, old_position:Option<Coords>, trace:&mut DecisionTrace) -> Action {
+        ml::determine_action(state, last, old_position, self, trace)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum StrategyKind {
+    ExploreAndDescend,
+    FarmFloor(u32),
+    SpeedrunToStairs,
+}
+impl StrategyKind {
+    pub fn build(&self, policy:&Policy) -> Box<dyn Strategy + Send + Sync> {
+        match *self {
+            StrategyKind::ExploreAndDescend => Box::new(Descend { policy: policy.clone(), pace: Pace::Explore }),
+            StrategyKind::FarmFloor(floor) => Box::new(FarmFloor::new(floor, policy)),
+            StrategyKind::SpeedrunToStairs => Box::new(Descend { policy: policy.clone(), pace: Pace::Speedrun }),
+        }
+    }
+}
+impl Display for StrategyKind {
+    fn fmt(&self, f:&mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StrategyKind::ExploreAndDescend => write!(f, "explore"),
+            StrategyKind::FarmFloor(floor) => write!(f, "farm:{floor}"),
+            StrategyKind::SpeedrunToStairs => write!(f, "speedrun"),
+        }
+    }
+}
+impl TryFrom<String> for StrategyKind {
+    type Error = String;
+
+    fn try_from(value:String) -> Result<Self, Self::Error> {
+        parse_strategy(&value)
+    }
+}
+
+pub fn parse_strategy(value:&str) -> Result<StrategyKind, String> {
+    match value.trim().split_once(':') {
+        None if value.trim() == "explore" => Ok(StrategyKind::ExploreAndDescend),
+        None if value.trim() == "speedrun" => Ok(StrategyKind::SpeedrunToStairs),
+        Some(("farm", floor)) => floor.trim().parse::<u32>()
+            .map(StrategyKind::FarmFloor)
+            .map_err(|err|format!("invalid floor {floor}: {err}")),
+        _ => Err(format!("expected explore, speedrun or farm:<floor>, got {value}")),
+    }
+}
+
+/// How [`Descend`] treats each floor on the way down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pace {
+    /// Explores and loots as far as the policy asks before descending.
+    Explore,
+    /// Takes the stairs as soon as they are known and ignores chests.
+    Speedrun,
+}
+
+pub struct Descend {
+    policy: Policy,
+    pace: Pace,
+}
+impl Strategy for Descend {
+    fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    fn may_descend(&self, dungeon:&Dungeon) -> bool {
+        match self.pace {
+            Pace::Explore => self.policy.may_descend(dungeon),
+            Pace::Speedrun => self.policy.below_max_floor(dungeon),
+        }
+    }
+
+    fn seek_chests(&self, _dungeon:&Dungeon) -> bool {
+        self.pace == Pace::Explore && self.policy.seek_chests
+    }
+
+    fn open_chests(&self, _dungeon:&Dungeon) -> bool {
+        self.pace == Pace::Explore && self.policy.prioritize_chests
+    }
+}
+
+pub struct FarmFloor {
+    floor: u32,
+    policy: Policy,
+}
+impl FarmFloor {
+    fn new(floor:u32, policy:&Policy) -> Self {
+        Self {
+            floor,
+            policy: Policy {
+                max_floor: Some(floor),
+                ..policy.clone()
+            },
+        }
+    }
+
+    /// Whether the party is still above the farm floor, where it speedruns down.
+    fn travelling(&self, dungeon:&Dungeon) -> bool {
+        dungeon.floor_number().is_some_and(|floor|floor < self.floor)
+    }
+
+    fn cleared(&self, dungeon:&Dungeon) -> bool {
+        dungeon.visible_chests() == 0
+            && dungeon.exploration() >= self.policy.descend_explored_pct
+            && dungeon.visited_pct() >= self.policy.descend_explored_pct
+    }
+}
+impl Strategy for FarmFloor {
+    fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    fn may_descend(&self, dungeon:&Dungeon) -> bool {
+        self.travelling(dungeon) || self.policy.may_descend(dungeon)
+    }
+
+    fn seek_chests(&self, dungeon:&Dungeon) -> bool {
+        !self.travelling(dungeon) && self.policy.seek_chests
+    }
+
+    fn open_chests(&self, dungeon:&Dungeon) -> bool {
+        !self.travelling(dungeon) && self.policy.prioritize_chests
+    }
+
+    fn decide(&self, state:&State, last:Action, old_position:Option<Coords>, trace:&mut DecisionTrace) -> Action {
+        let dungeon = &state.dungeon;
+        if self.travelling(dungeon) {
+            trace.step("travel to farm floor", &[("floor", &dungeon.floor_number()), ("farm_floor", &self.floor)]);
+        }
+        let action = ml::determine_action(state, last, old_position, self, trace);
+        if let Action::FindFight(..) = action
+            && dungeon.floor_number() == Some(self.floor)
+            && self.cleared(dungeon)
+            && let Some(leave) = dungeon.leave_floor() {
+            trace.step("farm floor cleared, leaving to respawn", &[("visited_pct", &dungeon.visited_pct()), ("exploration", &dungeon.exploration())]);
+            println!("Floor {} cleared, leaving to respawn it", self.floor);
+            return leave;
+        }
+        action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::Opt;
+
+    fn policy() -> Policy {
+        Opt::parse_from(["endorbot", "--descend-when", "explored", "--seek-chests"]).policy
+    }
+
+    #[test]
+    fn speedrun_descends_unexplored_floors_and_skips_chests() {
+        let dungeon = State::default().dungeon;
+        let explore = Descend { policy: policy(), pace: Pace::Explore };
+        let speedrun = Descend { policy: policy(), pace: Pace::Speedrun };
+        assert!(!explore.may_descend(&dungeon));
+        assert!(explore.seek_chests(&dungeon) && explore.open_chests(&dungeon));
+        assert!(speedrun.may_descend(&dungeon));
+        assert!(!speedrun.seek_chests(&dungeon) && !speedrun.open_chests(&dungeon));
+    }
+
+    #[test]
+    fn farm_floor_speedruns_to_its_floor_then_stays() {
+        let farm = FarmFloor::new(2, &policy());
+        let mut state = State::default();
+        assert!(farm.may_descend(&state.dungeon));
+        assert!(!farm.seek_chests(&state.dungeon));
+        ml::apply_action(&mut state, &Action::GoDown);
+        assert_eq!(state.dungeon.floor_number(), Some(2));
+        assert!(!farm.may_descend(&state.dungeon));
+        assert!(farm.seek_chests(&state.dungeon));
+    }
+}
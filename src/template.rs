@@ -0,0 +1,76 @@
+use image::{GrayImage, imageops::{self, FilterType}};
+
+const COARSE:u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Match {
+    /// Top left corner of the best match.
+    pub position: (u32, u32),
+    /// Normalized cross-correlation, 1.0 for a perfect match.
+    pub score: f32,
+}
+
+/// Finds where `template` best matches `image` by normalized cross-correlation, so a
+/// uniformly brighter or darker screen still matches.
+///
+/// Searches a downscaled copy first and refines around the best coarse match.
+pub fn find(image:&GrayImage, template:&GrayImage) -> Option<Match> {
+    if template.width() > image.width() || template.height() > image.height() {
+        return None;
+    }
+    let coarse = if template.width().min(template.height()) >= 8 * COARSE { COARSE } else { 1 };
+    let (start, end) = if coarse > 1 {
+        let small = |image:&GrayImage|imageops::resize(image, image.width() / coarse, image.height() / coarse, FilterType::Triangle);
+        let found = search(&small(image), &small(template), (0, 0), (u32::MAX, u32::MAX))?;
+        let (x, y) = (found.position.0 * coarse, found.position.1 * coarse);
+        ((x.saturating_sub(coarse), y.saturating_sub(coarse)), (x + coarse, y + coarse))
+    }
+    else {
+        ((0, 0), (u32::MAX, u32::MAX))
+    };
+    search(image, template, start, end)
+}
+
+fn search(image:&GrayImage, template:&GrayImage, start:(u32, u32), end:(u32, u32)) -> Option<Match> {
+    let size = (template.width() * template.height()) as f32;
+    let mean = template.pixels().map(|pixel|pixel[0] as f32).sum::<f32>() / size;
+    let centered = template.pixels().map(|pixel|pixel[0] as f32 - mean).collect::<Vec<_>>();
+    let norm = centered.iter().map(|value|value * value).sum::<f32>().sqrt();
+    let end = (end.0.min(image.width() - template.width()), end.1.min(image.height() - template.height()));
+    let mut best:Option<Match> = None;
+    for y in start.1..=end.1 {
+        for x in start.0..=end.0 {
+            let score = correlation(image, template.width(), &centered, norm, x, y);
+            if best.is_none_or(|best|score > best.score) {
+                best = Some(Match { position: (x, y), score });
+            }
+        }
+    }
+    best
+}
+
+fn correlation(image:&GrayImage, width:u32, centered:&[f32], norm:f32, x:u32, y:u32) -> f32 {
+    let height = centered.len() as u32 / width;
+    let mut sum = 0.0;
+    let mut sum_squares = 0.0;
+    for ty in 0..height {
+        for tx in 0..width {
+            let value = image.get_pixel(x + tx, y + ty)[0] as f32;
+            sum += value;
+            sum_squares += value * value;
+        }
+    }
+    let size = centered.len() as f32;
+    let mean = sum / size;
+    let image_norm = (sum_squares - size * mean * mean).max(0.0).sqrt();
+    if norm == 0.0 || image_norm == 0.0 {
+        return 0.0;
+    }
+    let mut product = 0.0;
+    for ty in 0..height {
+        for tx in 0..width {
+            product += (image.get_pixel(x + tx, y + ty)[0] as f32 - mean) * centered[(ty * width + tx) as usize];
+        }
+    }
+    product / (norm * image_norm)
+}
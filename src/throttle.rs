@@ -0,0 +1,99 @@
+use std::{collections::VecDeque, fmt::Display, time::{Duration, Instant}};
+
+use crate::{Opt, ml::Action};
+
+const MINUTE:Duration = Duration::from_secs(60);
+const HOUR:Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Throttled {
+    Actions(Duration),
+    Fights(Duration),
+    Runtime(Duration),
+}
+impl Display for Throttled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Throttled::Actions(wait) => write!(f, "Action budget exhausted, waiting {}s", wait.as_secs()),
+            Throttled::Fights(wait) => write!(f, "Fight budget exhausted, waiting {}s", wait.as_secs()),
+            Throttled::Runtime(runtime) => write!(f, "Maximum runtime of {} minutes reached", runtime.as_secs() / 60),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    limit: usize,
+    span: Duration,
+    events: VecDeque<Instant>,
+}
+impl Window {
+    fn new(limit:u32, span:Duration) -> Self {
+        Self {
+            limit: limit as usize,
+            span,
+            events: VecDeque::new(),
+        }
+    }
+
+    fn wait(&mut self, now:Instant) -> Option<Duration> {
+        if self.limit == 0 {
+            return None;
+        }
+        while self.events.front().is_some_and(|event|now.duration_since(*event) >= self.span) {
+            self.events.pop_front();
+        }
+        if self.events.len() < self.limit {
+            return None;
+        }
+        self.events.front().map(|oldest|self.span - now.duration_since(*oldest))
+    }
+
+    fn record(&mut self, now:Instant) {
+        if self.limit > 0 {
+            self.events.push_back(now);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Throttle {
+    started: Instant,
+    max_runtime: Option<Duration>,
+    actions: Window,
+    fights: Window,
+}
+impl Throttle {
+    pub fn new(opt:&Opt) -> Self {
+        Self {
+            started: Instant::now(),
+            max_runtime: (opt.max_runtime_minutes > 0).then(||Duration::from_secs(opt.max_runtime_minutes * 60)),
+            actions: Window::new(opt.max_actions_per_minute, MINUTE),
+            fights: Window::new(opt.max_fights_per_hour, HOUR),
+        }
+    }
+
+    pub fn permit(&mut self, action:&Action, last_action:&Action) -> Result<(), Throttled> {
+        let now = Instant::now();
+        if let Some(max_runtime) = self.max_runtime
+            && now.duration_since(self.started) >= max_runtime {
+            return Err(Throttled::Runtime(max_runtime));
+        }
+        if let Action::Redetect = action {
+            return Ok(());
+        }
+        let starts_fight = action.is_attack() && !last_action.is_attack();
+        if starts_fight
+            && let Some(wait) = self.fights.wait(now) {
+            return Err(Throttled::Fights(wait));
+        }
+        if let Some(wait) = self.actions.wait(now) {
+            return Err(Throttled::Actions(wait));
+        }
+        self.actions.record(now);
+        if starts_fight {
+            self.fights.record(now);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::ml::Action;
+
+const DEFAULT_INTERVALS:&[(&str, u64)] = &[
+    ("CloseAd", 2000),
+    ("TeleportToCity", 350),
+    ("CancelTeleportToCity", 150),
+    ("GotoTown", 350),
+    ("GotoDungeon", 350),
+    ("GoDown", 350),
+    ("GoUp", 350),
+    ("FindFight", 600),
+    ("Fight", 150),
+    ("UseSkill", 150),
+    ("Flee", 350),
+    ("DismissDialog", 350),
+    ("Back", 350),
+    ("OpenChest", 150),
+    ("OpenChestMagical", 150),
+    ("UseHealingItem", 400),
+    ("ReturnToTown", 150),
+    ("Resurrect", 600),
+    ("ResurrectCharacter", 350),
+    ("ConfirmResurrect", 600),
+    ("CancelResurrect", 350),
+    ("LeaveTemple", 350),
+    ("DeclineResurrect", 350),
+    ("StartMacro", 100),
+    ("RunMacro", 500),
+    ("FinishMacro", 100),
+    ("AbortMacro", 100),
+    ("LaunchGame", 10000),
+    ("Rest", 600000),
+];
+const MAX_BACKOFF_SHIFT:u32 = 4;
+
+pub fn parse_interval(value:&str) -> Result<(String, u64), String> {
+    let (action, ms) = value.split_once('=').ok_or_else(||format!("expected <Action>=<ms>, got {value}"))?;
+    let ms = ms.trim().trim_end_matches("ms").parse::<u64>().map_err(|err|format!("invalid interval {ms}: {err}"))?;
+    Ok((action.trim().to_owned(), ms))
+}
+
+pub struct TickRate {
+    intervals: HashMap<String, Duration>,
+    default: Duration,
+    max_backoff: Duration,
+    last_fingerprint: Option<u64>,
+    backoff_shift: u32,
+}
+impl TickRate {
+    pub fn new(overrides:&[(String, u64)], default_ms:u64, max_backoff_ms:u64) -> Self {
+        let mut intervals = DEFAULT_INTERVALS.iter()
+        .map(|(action, ms)|(action.to_string(), Duration::from_millis(*ms)))
+        .collect::<HashMap<_, _>>();
+        for (action, ms) in overrides {
+            intervals.insert(action.clone(), Duration::from_millis(*ms));
+        }
+        Self {
+            intervals,
+            default: Duration::from_millis(default_ms),
+            max_backoff: Duration::from_millis(max_backoff_ms),
+            last_fingerprint: None,
+            backoff_shift: 0,
+        }
+    }
+
+    pub fn observe_frame(&mut self, fingerprint:u64) {
+        if self.last_fingerprint == Some(fingerprint) {
+            self.backoff_shift = (self.backoff_shift + 1).min(MAX_BACKOFF_SHIFT);
+        }
+        else {
+            self.backoff_shift = 0;
+        }
+        self.last_fingerprint = Some(fingerprint);
+    }
+
+    pub fn interval(&self, action:&Action) -> Duration {
+        let interval = self.intervals.get(action.name()).copied().unwrap_or(self.default);
+        if self.backoff_shift == 0 {
+            return interval;
+        }
+        (interval * (1 << self.backoff_shift)).min(self.max_backoff.max(interval))
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::ml::{Coords, Tile};
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TileMap {
+    tiles: HashMap<Coords, Tile>,
+}
+impl TileMap {
+    pub fn get(&self, position:&Coords) -> Option<&Tile> {
+        self.tiles.get(position)
+    }
+
+    pub fn get_mut(&mut self, position:&Coords) -> Option<&mut Tile> {
+        self.tiles.get_mut(position)
+    }
+
+    pub fn contains(&self, position:&Coords) -> bool {
+        self.tiles.contains_key(position)
+    }
+
+    pub fn insert(&mut self, tile:Tile) {
+        self.tiles.insert(tile.position, tile);
+    }
+
+    pub fn remove(&mut self, position:&Coords) -> Option<Tile> {
+        self.tiles.remove(position)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    /// The first tile matching `predicate` in scan order, row by row from the top left.
+    pub fn first(&self, predicate:impl Fn(&Tile) -> bool) -> Option<&Tile> {
+        self.tiles.values()
+        .filter(|tile|predicate(tile))
+        .min_by_key(|tile|(tile.position.y, tile.position.x))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Tile> {
+        self.tiles.values()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Tile> {
+        self.tiles.values_mut()
+    }
+
+    pub fn exploration(&self) -> f32 {
+        let explored = self.tiles.values().filter(|tile|tile.explored).collect::<Vec<_>>();
+        if explored.is_empty() {
+            return 0.0;
+        }
+        let mut boundary = HashSet::new();
+        for tile in &explored {
+            let Coords { x, y } = tile.position;
+            let neighbours = [
+                (tile.north_passable, y.checked_sub(1).map(|y|Coords { x, y })),
+                (tile.east_passable, Some(Coords { x: x + 1, y })),
+                (tile.south_passable, Some(Coords { x, y: y + 1 })),
+                (tile.west_passable, x.checked_sub(1).map(|x|Coords { x, y })),
+            ];
+            for (passable, position) in neighbours {
+                if let (true, Some(position)) = (passable, position)
+                    && !self.tiles.get(&position).is_some_and(|tile|tile.explored) {
+                    boundary.insert(position);
+                }
+            }
+        }
+        explored.len() as f32 * 100.0 / (explored.len() + boundary.len()) as f32
+    }
+
+    pub fn sorted(&self) -> Vec<Tile> {
+        let mut tiles = self.tiles.values().copied().collect::<Vec<_>>();
+        tiles.sort_by_key(|tile|(tile.position.y, tile.position.x));
+        tiles
+    }
+}
+impl FromIterator<Tile> for TileMap {
+    fn from_iter<T: IntoIterator<Item = Tile>>(iter: T) -> Self {
+        Self {
+            tiles: iter.into_iter().map(|tile|(tile.position, tile)).collect(),
+        }
+    }
+}
+impl IntoIterator for TileMap {
+    type Item = Tile;
+    type IntoIter = std::collections::hash_map::IntoValues<Coords, Tile>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tiles.into_values()
+    }
+}
+impl Serialize for TileMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.sorted().serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for TileMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<Tile>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
@@ -0,0 +1,101 @@
+use std::{fmt::Write, sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+const BUCKETS_MICROS:[u64; 12] = [500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Capture,
+    Bitmap,
+    Ocr,
+    Detection,
+    Pathfinding,
+    Action,
+}
+impl Phase {
+    const ALL:[Phase; 6] = [Phase::Capture, Phase::Bitmap, Phase::Ocr, Phase::Detection, Phase::Pathfinding, Phase::Action];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Capture => "capture",
+            Phase::Bitmap => "bitmap",
+            Phase::Ocr => "ocr",
+            Phase::Detection => "detection",
+            Phase::Pathfinding => "pathfinding",
+            Phase::Action => "action",
+        }
+    }
+
+    fn histogram(&self) -> &'static Histogram {
+        &HISTOGRAMS[*self as usize]
+    }
+}
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS_MICROS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+    tick_micros: AtomicU64,
+}
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS_MICROS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+            tick_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration:Duration) {
+        let micros = duration.as_micros() as u64;
+        if let Some(bucket) = BUCKETS_MICROS.iter().position(|bound|micros <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.tick_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+}
+
+static HISTOGRAMS:[Histogram; Phase::ALL.len()] = [const { Histogram::new() }; Phase::ALL.len()];
+
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+pub fn observe(phase:Phase, duration:Duration) {
+    phase.histogram().observe(duration);
+}
+
+pub fn time<T>(phase:Phase, f:impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = f();
+    observe(phase, started.elapsed());
+    result
+}
+
+pub fn render(out:&mut String) {
+    let seconds = |micros:u64|micros as f64 / 1_000_000.0;
+    writeln!(out, "# HELP endorbot_phase_seconds Time spent per tick phase").unwrap();
+    writeln!(out, "# TYPE endorbot_phase_seconds histogram").unwrap();
+    for phase in Phase::ALL {
+        let histogram = phase.histogram();
+        let name = phase.name();
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS_MICROS.iter().zip(&histogram.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            writeln!(out, "endorbot_phase_seconds_bucket{{phase=\"{name}\",le=\"{}\"}} {cumulative}", seconds(*bound)).unwrap();
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        writeln!(out, "endorbot_phase_seconds_bucket{{phase=\"{name}\",le=\"+Inf\"}} {count}").unwrap();
+        writeln!(out, "endorbot_phase_seconds_sum{{phase=\"{name}\"}} {}", seconds(histogram.sum_micros.load(Ordering::Relaxed))).unwrap();
+        writeln!(out, "endorbot_phase_seconds_count{{phase=\"{name}\"}} {count}").unwrap();
+    }
+}
+
+pub fn tick_summary() -> String {
+    Phase::ALL.iter().map(|phase|{
+        let micros = phase.histogram().tick_micros.swap(0, Ordering::Relaxed);
+        format!("{} {:.1}ms", phase.name(), micros as f64 / 1000.0)
+    }).collect::<Vec<_>>().join(", ")
+}
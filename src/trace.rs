@@ -0,0 +1,60 @@
+use std::fmt::{Debug, Write};
+
+use serde::Serialize;
+
+use crate::ml::{Action, State};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceStep {
+    pub branch: &'static str,
+    pub inputs: Vec<(&'static str, String)>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DecisionTrace {
+    pub state: String,
+    pub steps: Vec<TraceStep>,
+    pub action: Option<String>,
+}
+impl DecisionTrace {
+    pub fn new(state:&State) -> Self {
+        Self {
+            state: state.state_type.name().to_owned(),
+            steps: Vec::new(),
+            action: None,
+        }
+    }
+
+    pub fn step(&mut self, branch:&'static str, inputs:&[(&'static str, &dyn Debug)]) {
+        self.steps.push(TraceStep {
+            branch,
+            inputs: inputs.iter().map(|(name, value)|(*name, format!("{value:?}"))).collect(),
+        });
+    }
+
+    pub fn finish(&mut self, action:&Action) {
+        self.action = Some(action.to_string());
+    }
+
+    pub fn to_dot(&self) -> String {
+        let escape = |text:&str|text.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut dot = String::from("digraph decision {\n    rankdir=TB;\n    node [shape=box, fontname=monospace];\n");
+        let _ = writeln!(dot, "    state [label=\"{}\", shape=ellipse];", escape(&self.state));
+        let mut previous = "state".to_owned();
+        for (index, step) in self.steps.iter().enumerate() {
+            let mut label = escape(step.branch);
+            for (name, value) in &step.inputs {
+                let _ = write!(label, "\\l{name} = {}", escape(value));
+            }
+            let _ = writeln!(dot, "    step{index} [label=\"{label}\\l\"];");
+            let _ = writeln!(dot, "    {previous} -> step{index};");
+            previous = format!("step{index}");
+        }
+        if let Some(action) = &self.action {
+            let _ = writeln!(dot, "    action [label=\"{}\", shape=doubleoctagon];", escape(action));
+            let _ = writeln!(dot, "    {previous} -> action;");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
@@ -0,0 +1,99 @@
+use std::io::Write;
+
+const MAGIC:&[u8; 4] = b"EBF1";
+const HEADER_SIZE:usize = 12;
+
+#[derive(Debug)]
+pub enum TransferError {
+    BadMagic,
+    Truncated(usize, usize),
+    Checksum(u32, u32),
+}
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "missing frame header"),
+            Self::Truncated(expected, actual) => write!(f, "expected {expected} bytes, got {actual}"),
+            Self::Checksum(expected, actual) => write!(f, "checksum mismatch, expected {expected:08x}, got {actual:08x}"),
+        }
+    }
+}
+impl std::error::Error for TransferError {}
+
+pub fn write_framed(out:&mut impl Write, payload:&[u8]) -> std::io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&(payload.len() as u32).to_le_bytes())?;
+    out.write_all(&crc32fast::hash(payload).to_le_bytes())?;
+    out.write_all(payload)?;
+    out.flush()
+}
+
+pub fn read_framed(data:&[u8]) -> Result<&[u8], TransferError> {
+    if data.len() < HEADER_SIZE || &data[..4] != MAGIC {
+        return Err(TransferError::BadMagic);
+    }
+    let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+    let expected = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    let payload = &data[HEADER_SIZE..];
+    if payload.len() != len {
+        return Err(TransferError::Truncated(len, payload.len()));
+    }
+    let actual = crc32fast::hash(payload);
+    if actual != expected {
+        return Err(TransferError::Checksum(expected, actual));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD:&[u8] = b"endorbot frame payload";
+
+    fn framed(payload:&[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_framed(&mut out, payload).unwrap();
+        out
+    }
+
+    #[test]
+    fn round_trips_payload() {
+        let data = framed(PAYLOAD);
+        assert_eq!(data.len(), HEADER_SIZE + PAYLOAD.len());
+        assert_eq!(read_framed(&data).unwrap(), PAYLOAD);
+        assert_eq!(read_framed(&framed(&[])).unwrap(), &[] as &[u8]);
+    }
+
+    #[test]
+    fn rejects_truncated_payload() {
+        let data = framed(PAYLOAD);
+        let cut = &data[..data.len() - 3];
+        assert!(matches!(read_framed(cut), Err(TransferError::Truncated(22, 19))));
+        let padded = [&data[..], &[0]].concat();
+        assert!(matches!(read_framed(&padded), Err(TransferError::Truncated(22, 23))));
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut data = framed(PAYLOAD);
+        data[0] = b'X';
+        assert!(matches!(read_framed(&data), Err(TransferError::BadMagic)));
+        assert!(matches!(read_framed(&framed(PAYLOAD)[..HEADER_SIZE - 1]), Err(TransferError::BadMagic)));
+        assert!(matches!(read_framed(&[]), Err(TransferError::BadMagic)));
+    }
+
+    #[test]
+    fn rejects_flipped_byte() {
+        let mut data = framed(PAYLOAD);
+        data[HEADER_SIZE + 5] ^= 0x01;
+        let expected = crc32fast::hash(PAYLOAD);
+        match read_framed(&data) {
+            Err(TransferError::Checksum(header, actual)) => {
+                assert_eq!(header, expected);
+                assert_ne!(actual, expected);
+            },
+            other => panic!("expected a checksum error, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::{net::{TcpListener, TcpStream}, sync::{mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel}, watch}};
+use tokio_tungstenite::tungstenite::{Message, handshake::server::{ErrorResponse, Request, Response}, http::StatusCode};
+
+use crate::{LogEntry, control::Control, floor::FloorChanged, ml::{State, StateDiff, StateView}, runtime, server::{query_token, token_matches}};
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize)]
+#[serde(tag = "type", content = "data")]
+enum Push<'a> {
+    Full(StateView<'a>),
+    Diff(&'a StateDiff),
+    Log(&'a LogEntry),
+    FloorChanged(&'a FloorChanged),
+}
+
+#[derive(Default)]
+pub struct Broadcaster {
+    clients: Mutex<Vec<UnboundedSender<Arc<String>>>>,
+}
+impl Broadcaster {
+    pub fn publish(&self, old:&State, new:&State) {
+        if let Some(diff) = new.diff(old) {
+            self.send(&Push::Diff(&diff));
+        }
+    }
+
+    pub fn publish_log(&self, entry:&LogEntry) {
+        self.send(&Push::Log(entry));
+    }
+
+    pub fn publish_floor_changed(&self, change:&FloorChanged) {
+        self.send(&Push::FloorChanged(change));
+    }
+
+    fn send(&self, push:&Push) {
+        let mut clients = self.clients.lock();
+        if clients.is_empty() {
+            return;
+        }
+        let message = Arc::new(serde_json::to_string(push).unwrap());
+        clients.retain(|client|client.send(message.clone()).is_ok());
+    }
+
+    fn subscribe(&self) -> UnboundedReceiver<Arc<String>> {
+        let (sender, receiver) = unbounded_channel();
+        self.clients.lock().push(sender);
+        receiver
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn authorize(token:Option<&str>, request:&Request, response:Response) -> Result<Response, ErrorResponse> {
+    let Some(token) = token else {
+        return Ok(response);
+    };
+    if query_token(request.uri().query()).is_some_and(|given|token_matches(token, given)) {
+        return Ok(response);
+    }
+    let mut error = ErrorResponse::new(Some("Unauthorized".to_owned()));
+    *error.status_mut() = StatusCode::UNAUTHORIZED;
+    Err(error)
+}
+
+pub fn spawn(addr:&str, state:watch::Receiver<Arc<State>>, broadcaster:Arc<Broadcaster>, control:Arc<Control>, token:Option<String>) {
+    let addr = addr.to_owned();
+    runtime::get().spawn(async move {
+        let listener = match TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                println!("Failed to bind websocket server to {addr}: {err}");
+                return;
+            },
+        };
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        println!("Failed to accept websocket connection: {err}");
+                        continue;
+                    },
+                },
+                _ = control.shutdown_requested() => break,
+            };
+            tokio::spawn(serve(stream, state.clone(), broadcaster.clone(), control.clone(), token.clone()));
+        }
+    });
+}
+
+#[allow(clippy::result_large_err)]
+async fn serve(stream:TcpStream, state:watch::Receiver<Arc<State>>, broadcaster:Arc<Broadcaster>, control:Arc<Control>, token:Option<String>) {
+    let Ok(socket) = tokio_tungstenite::accept_hdr_async(stream, |request:&Request, response|authorize(token.as_deref(), request, response)).await else {
+        return;
+    };
+    let (mut sender, mut incoming) = socket.split();
+    let mut receiver = broadcaster.subscribe();
+    let full = serde_json::to_string(&Push::Full(state.borrow().view())).unwrap();
+    if sender.send(Message::text(full)).await.is_err() {
+        return;
+    }
+    loop {
+        tokio::select! {
+            message = receiver.recv() => match message {
+                Some(message) => if sender.send(Message::text(message.as_str())).await.is_err() {
+                    break;
+                },
+                None => break,
+            },
+            // Pings are answered while reading, anything else from the browser is ignored.
+            message = incoming.next() => if !matches!(message, Some(Ok(_))) {
+                break;
+            },
+            _ = control.shutdown_requested() => {
+                let _ = sender.send(Message::Close(None)).await;
+                break;
+            },
+        }
+    }
+}
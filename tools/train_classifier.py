@@ -0,0 +1,109 @@
+#!/usr/bin/env python3
+"""Trains the screen classifier used by --classifier-model.
+
+The dataset is a directory with one subdirectory of screenshots per state name, as
+written by `endorbot classifier dataset`. Move mislabelled screenshots to the right
+directory before training. Writes an ONNX model and a .labels file next to it.
+
+Needs torch, numpy and pillow.
+"""
+
+import argparse
+import random
+from pathlib import Path
+
+import numpy as np
+import torch
+from PIL import Image
+from torch import nn
+
+EXTENSIONS = {".png", ".jpg", ".jpeg", ".webp"}
+
+
+def load(dataset, size):
+    labels = sorted(path.name for path in dataset.iterdir() if path.is_dir())
+    samples = []
+    for index, label in enumerate(labels):
+        for path in sorted((dataset / label).iterdir()):
+            if path.suffix.lower() in EXTENSIONS:
+                image = Image.open(path).convert("RGB").resize(size, Image.BILINEAR)
+                pixels = np.asarray(image, dtype=np.float32).transpose(2, 0, 1) / 255.0
+                samples.append((torch.from_numpy(pixels), index))
+    return labels, samples
+
+
+def model(classes):
+    return nn.Sequential(
+        nn.Conv2d(3, 16, 3, stride=2, padding=1), nn.ReLU(),
+        nn.Conv2d(16, 32, 3, stride=2, padding=1), nn.ReLU(),
+        nn.Conv2d(32, 64, 3, stride=2, padding=1), nn.ReLU(),
+        nn.Conv2d(64, 64, 3, stride=2, padding=1), nn.ReLU(),
+        nn.AdaptiveAvgPool2d(1), nn.Flatten(),
+        nn.Linear(64, classes),
+    )
+
+
+def batches(samples, size, augment):
+    for start in range(0, len(samples), size):
+        chunk = samples[start:start + size]
+        images = torch.stack([image for image, _ in chunk])
+        if augment:
+            # The game dims and brightens with the device settings.
+            images = (images * torch.empty(len(chunk), 1, 1, 1).uniform_(0.8, 1.2)).clamp(0.0, 1.0)
+        yield images, torch.tensor([label for _, label in chunk])
+
+
+def accuracy(net, samples):
+    if not samples:
+        return float("nan")
+    net.eval()
+    correct = 0
+    with torch.no_grad():
+        for images, labels in batches(samples, 64, False):
+            correct += (net(images).argmax(1) == labels).sum().item()
+    return correct / len(samples)
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("dataset", type=Path)
+    parser.add_argument("--out", type=Path, default=Path("models/screens.onnx"))
+    parser.add_argument("--width", type=int, default=72)
+    parser.add_argument("--height", type=int, default=160)
+    parser.add_argument("--epochs", type=int, default=30)
+    parser.add_argument("--validation", type=float, default=0.1)
+    args = parser.parse_args()
+
+    labels, samples = load(args.dataset, (args.width, args.height))
+    if len(labels) < 2:
+        parser.error(f"{args.dataset} needs screenshots of at least two states")
+    random.seed(0)
+    random.shuffle(samples)
+    held_out = int(len(samples) * args.validation)
+    validation, training = samples[:held_out], samples[held_out:]
+    print(f"{len(training)} training and {len(validation)} validation screenshots of {len(labels)} states")
+
+    net = model(len(labels))
+    optimizer = torch.optim.Adam(net.parameters(), lr=1e-3)
+    loss = nn.CrossEntropyLoss()
+    for epoch in range(args.epochs):
+        net.train()
+        random.shuffle(training)
+        total = 0.0
+        for images, targets in batches(training, 32, True):
+            optimizer.zero_grad()
+            batch_loss = loss(net(images), targets)
+            batch_loss.backward()
+            optimizer.step()
+            total += batch_loss.item() * len(targets)
+        print(f"epoch {epoch + 1}: loss {total / len(training):.4f}, validation accuracy {accuracy(net, validation):.3f}")
+
+    net.eval()
+    args.out.parent.mkdir(parents=True, exist_ok=True)
+    torch.onnx.export(net, torch.zeros(1, 3, args.height, args.width), args.out, input_names=["frame"], output_names=["logits"])
+    args.out.with_suffix(".labels").write_text("\n".join(labels) + "\n")
+    print(f"Wrote {args.out} and {args.out.with_suffix('.labels')}")
+
+
+if __name__ == "__main__":
+    main()